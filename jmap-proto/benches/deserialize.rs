@@ -0,0 +1,59 @@
+//! Compares deserializing `Principal/get`-sized responses into the borrowing
+//! data types against parsing them into an owned [`serde_json::Value`].
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use jmap_proto::extensions::{contacts::js_contact::Card, sharing::Principal};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+const OBJECTS: usize = 1000;
+
+fn principals() -> String {
+    let list: Vec<_> = (0..OBJECTS)
+        .map(|i| {
+            json!({
+                "id": format!("P{i}"),
+                "type": "individual",
+                "name": format!("Principal {i}"),
+                "description": "A principal used for benchmarking",
+                "email": format!("principal{i}@example.com"),
+                "timeZone": "Europe/London",
+                "capabilities": {"urn:ietf:params:jmap:calendars": {}},
+                "accounts": null,
+            })
+        })
+        .collect();
+    serde_json::to_string(&list).unwrap()
+}
+
+fn cards() -> String {
+    let list: Vec<_> = (0..OBJECTS)
+        .map(|i| {
+            json!({
+                "uid": format!("urn:uuid:00000000-0000-0000-0000-{i:012}"),
+                "fullName": format!("Contact {i}"),
+                "notes": "A card used for benchmarking",
+            })
+        })
+        .collect();
+    serde_json::to_string(&list).unwrap()
+}
+
+fn compare<'a, T: Deserialize<'a>>(c: &mut Criterion, name: &str, json: &'a str) {
+    let mut group = c.benchmark_group(name);
+    group.bench_function("borrowed", |b| {
+        b.iter(|| black_box(serde_json::from_str::<Vec<T>>(json).unwrap()));
+    });
+    group.bench_function("value", |b| {
+        b.iter(|| black_box(serde_json::from_str::<Value>(json).unwrap()));
+    });
+    group.finish();
+}
+
+fn deserialize(c: &mut Criterion) {
+    compare::<Principal<'_>>(c, "Principal", &principals());
+    compare::<Card<'_>>(c, "Card", &cards());
+}
+
+criterion_group!(benches, deserialize);
+criterion_main!(benches);
@@ -1,25 +1,114 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt};
 
 use chrono::{FixedOffset, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{Error, Unexpected, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_with::{DeserializeAs, SerializeAs};
 
 /// Where "Int" is given as a data type, it means an integer in the range
 /// -2^53+1 <= value <= 2^53-1, the safe range for integers stored in a
 /// floating-point double, represented as a JSON "Number".
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash, Default)]
+///
+/// Values outside of the range are rejected on deserialization.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Hash, Default)]
 pub struct Int(i64);
 
+impl Int {
+    pub const MAX: i64 = (1 << 53) - 1;
+    pub const MIN: i64 = -Self::MAX;
+
+    /// Wraps the value, or returns `None` if it's outside of the range.
+    pub fn new(value: i64) -> Option<Self> {
+        (Self::MIN..=Self::MAX)
+            .contains(&value)
+            .then_some(Self(value))
+    }
+
+    pub fn get(self) -> i64 {
+        self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for Int {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = i64::deserialize(deserializer)?;
+
+        Self::new(value).ok_or_else(|| {
+            D::Error::invalid_value(
+                Unexpected::Signed(value),
+                &"an integer between -2^53+1 and 2^53-1",
+            )
+        })
+    }
+}
+
 /// Where "UnsignedInt" is given as a data type, it means an "Int" where
 /// the value MUST be in the range 0 <= value <= 2^53-1.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+///
+/// Values outside of the range are rejected on deserialization.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Hash)]
 pub struct UnsignedInt(u64);
 
-impl From<u64> for UnsignedInt {
-    fn from(value: u64) -> Self {
-        Self(value)
+impl UnsignedInt {
+    pub const MAX: u64 = (1 << 53) - 1;
+
+    /// Wraps the value, or returns `None` if it's outside of the range.
+    pub fn new(value: u64) -> Option<Self> {
+        (value <= Self::MAX).then_some(Self(value))
+    }
+
+    /// Wraps the value, clamped to the range. Only for limits, where any
+    /// value past the range is as good as unlimited.
+    pub fn saturating(value: u64) -> Self {
+        Self(value.min(Self::MAX))
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for UnsignedInt {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = u64::deserialize(deserializer)?;
+
+        Self::new(value).ok_or_else(|| {
+            D::Error::invalid_value(
+                Unexpected::Unsigned(value),
+                &"an integer between 0 and 2^53-1",
+            )
+        })
     }
 }
 
+impl TryFrom<u64> for UnsignedInt {
+    type Error = OutOfRange;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        Self::new(value).ok_or(OutOfRange)
+    }
+}
+
+/// A value was outside of the range of an [`Int`] or [`UnsignedInt`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfRange;
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("integer out of range")
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
 /// All record ids are assigned by the server and are immutable.
 ///
 /// Where "Id" is given as a data type, it means a "String" of at least 1
@@ -45,8 +134,121 @@ impl From<u64> for UnsignedInt {
 ///
 /// A good solution to these issues is to prefix every id with a single
 /// alphabetical character.
+///
+/// Ids that don't meet these requirements are rejected on deserialization.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Hash)]
+pub struct Id<'a>(pub Cow<'a, str>);
+
+impl Id<'_> {
+    /// Whether the id is between 1 and 255 octets in size and contains only
+    /// characters from the "URL and Filename Safe" base64 alphabet.
+    pub fn is_valid(&self) -> bool {
+        (1..=255).contains(&self.0.len())
+            && self
+                .0
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Id<'a> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(IdVisitor {
+            allow_reference: false,
+        })
+    }
+}
+
+/// Deserializes an [`Id`] that may instead be a `#creationId` reference the
+/// server couldn't resolve, for positions where the record it refers to is
+/// rejected on its own as not found rather than failing the whole call.
+pub(crate) struct IdOrReference;
+
+impl<'de: 'a, 'a> DeserializeAs<'de, Id<'a>> for IdOrReference {
+    fn deserialize_as<D>(deserializer: D) -> Result<Id<'a>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(IdVisitor {
+            allow_reference: true,
+        })
+    }
+}
+
+impl SerializeAs<Id<'_>> for IdOrReference {
+    fn serialize_as<S: Serializer>(source: &Id<'_>, serializer: S) -> Result<S::Ok, S::Error> {
+        source.serialize(serializer)
+    }
+}
+
+struct IdVisitor {
+    allow_reference: bool,
+}
+
+impl IdVisitor {
+    fn validate<'a, E: Error>(&self, id: Id<'a>) -> Result<Id<'a>, E> {
+        if id.is_valid() || (self.allow_reference && CreationId::from_reference(&id.0).is_some()) {
+            Ok(id)
+        } else {
+            Err(E::invalid_value(Unexpected::Str(&id.0), &EXPECTED_ID))
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for IdVisitor {
+    type Value = Id<'de>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(EXPECTED_ID)
+    }
+
+    fn visit_borrowed_str<E: Error>(self, v: &'de str) -> Result<Self::Value, E> {
+        self.validate(Id(Cow::Borrowed(v)))
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+        self.validate(Id(Cow::Owned(v.to_string())))
+    }
+
+    fn visit_string<E: Error>(self, v: String) -> Result<Self::Value, E> {
+        self.validate(Id(Cow::Owned(v)))
+    }
+}
+
+/// A temporary id set by the client for a record it's creating, which is
+/// only meaningful within the request it was given in.
+///
+/// Kept apart from [`Id`] so that a creation id can't be used where the id
+/// of a real record is expected, it has to be resolved to one first.
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
-pub struct Id<'a>(#[serde(borrow)] pub Cow<'a, str>);
+#[serde(transparent)]
+pub struct CreationId<'a>(#[serde(borrow)] pub Id<'a>);
+
+impl<'a> CreationId<'a> {
+    /// Records may reference other records created earlier in the same
+    /// request by prefixing the creation id the client gave them with a
+    /// `#`, since their real id isn't known to the client yet.
+    pub const REFERENCE_PREFIX: char = '#';
+
+    /// Parses a `#creationId` reference, returning `None` if the value isn't
+    /// one.
+    ///
+    /// Only values that are a valid [`Id`] once the `#` is removed are
+    /// treated as references, so free text such as `#1 fan` isn't one.
+    pub fn from_reference(value: &'a str) -> Option<Self> {
+        Some(Id(Cow::Borrowed(
+            value.strip_prefix(Self::REFERENCE_PREFIX)?,
+        )))
+        .filter(Id::is_valid)
+        .map(Self)
+    }
+}
+
+const EXPECTED_ID: &str =
+    "an id of 1 to 255 characters from the URL and filename safe base64 alphabet";
 
 /// Where "Date" is given as a type, it means a string in "date-time"
 /// format [RFC3339].  To ensure a normalised form, the "time-secfrac"
@@ -59,9 +261,21 @@ pub struct Date(chrono::DateTime<FixedOffset>);
 /// Where "UTCDate" is given as a type, it means a "Date" where the
 /// "time-offset" component MUST be "Z" (i.e., it must be in UTC time).
 /// For example, "2014-10-30T06:12:00Z".
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct UtcDate(chrono::DateTime<Utc>);
 
+impl From<chrono::DateTime<Utc>> for UtcDate {
+    fn from(value: chrono::DateTime<Utc>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<UtcDate> for chrono::DateTime<Utc> {
+    fn from(value: UtcDate) -> Self {
+        value.0
+    }
+}
+
 /// A (preferably short) string representing the state of this object
 /// on the server.  If the value of any other property on the Session
 /// object changes, this string will change.  The current value is
@@ -71,3 +285,87 @@ pub struct UtcDate(chrono::DateTime<Utc>);
 /// need to refetch the object.
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SessionState<'a>(#[serde(borrow)] pub Cow<'a, str>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parses_as_id(id: &str) -> bool {
+        serde_json::from_str::<Id<'_>>(&serde_json::to_string(id).unwrap()).is_ok()
+    }
+
+    #[test]
+    fn valid_id_is_accepted() {
+        assert_eq!(
+            serde_json::from_str::<Id<'_>>(r#""Ab-9_z""#).unwrap(),
+            Id(Cow::Borrowed("Ab-9_z"))
+        );
+        assert!(parses_as_id(&"a".repeat(255)));
+    }
+
+    #[test]
+    fn empty_id_is_rejected() {
+        assert!(!parses_as_id(""));
+    }
+
+    #[test]
+    fn over_long_id_is_rejected() {
+        assert!(!parses_as_id(&"a".repeat(256)));
+    }
+
+    #[test]
+    fn id_containing_slash_is_rejected() {
+        assert!(!parses_as_id("a/b"));
+    }
+
+    #[test]
+    fn reference_is_only_accepted_where_allowed() {
+        let reference = serde_json::Value::from("#abc");
+
+        assert!(!parses_as_id("#abc"));
+        assert_eq!(
+            IdOrReference::deserialize_as(&reference).unwrap(),
+            Id(Cow::Borrowed("#abc"))
+        );
+        assert!(IdOrReference::deserialize_as(&serde_json::Value::from("#a/b")).is_err());
+    }
+
+    #[test]
+    fn int_accepts_its_bounds() {
+        assert_eq!(
+            serde_json::from_str::<Int>("9007199254740991").unwrap(),
+            Int(Int::MAX)
+        );
+        assert_eq!(
+            serde_json::from_str::<Int>("-9007199254740991").unwrap(),
+            Int(Int::MIN)
+        );
+    }
+
+    #[test]
+    fn int_rejects_values_past_its_bounds() {
+        assert!(serde_json::from_str::<Int>("9007199254740992").is_err());
+        assert!(serde_json::from_str::<Int>("-9007199254740992").is_err());
+        assert_eq!(Int::new(1 << 53), None);
+    }
+
+    #[test]
+    fn unsigned_int_bounds() {
+        assert_eq!(
+            serde_json::from_str::<UnsignedInt>("9007199254740991").unwrap(),
+            UnsignedInt(UnsignedInt::MAX)
+        );
+        assert!(serde_json::from_str::<UnsignedInt>("9007199254740992").is_err());
+        assert!(serde_json::from_str::<UnsignedInt>("-1").is_err());
+    }
+
+    #[test]
+    fn unsigned_int_conversion_is_range_checked() {
+        assert_eq!(
+            UnsignedInt::try_from((1 << 53) - 1),
+            Ok(UnsignedInt(UnsignedInt::MAX))
+        );
+        assert_eq!(UnsignedInt::try_from(1 << 53), Err(OutOfRange));
+        assert_eq!(UnsignedInt::saturating(u64::MAX).get(), UnsignedInt::MAX);
+    }
+}
@@ -0,0 +1,13 @@
+//! Transitional support for response fields renamed to fix their wire
+//! format.
+//!
+//! Responses with renamed fields accept either name, or both, when
+//! deserialised, and only serialise the new name unless asked to also
+//! emit the old one for clients that haven't caught up yet.
+
+/// A response with fields that have been renamed.
+pub trait RenamedFields {
+    /// Also emits each renamed field under its old name when serialised.
+    #[must_use]
+    fn with_legacy_field_names(self) -> Self;
+}
@@ -15,16 +15,16 @@ use crate::common::{Id, UnsignedInt};
 #[serde(rename_all = "camelCase")]
 pub struct UploadResponse<'a> {
     /// The id of the account used for the call.
-    account_id: Id<'a>,
+    pub account_id: Id<'a>,
     /// The id representing the binary data uploaded.  The data for this
     /// id is immutable.  The id *only* refers to the binary data, not any
     /// metadata.
-    blob_id: Id<'a>,
+    pub blob_id: Id<'a>,
     /// The media type of the file (as specified in [RFC6838],
     /// Section 4.2) as set in the Content-Type header of the upload HTTP
     /// request.
     #[serde(rename = "type", borrow)]
-    type_: Cow<'a, str>,
+    pub type_: Cow<'a, str>,
     /// The size of the file in octets.
-    size: UnsignedInt,
+    pub size: UnsignedInt,
 }
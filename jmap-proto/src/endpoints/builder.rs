@@ -0,0 +1,153 @@
+//! Building a [`Request`] call by call, for clients of a JMAP server.
+
+use std::borrow::Cow;
+
+use serde_json::Value;
+
+use crate::endpoints::{Argument, Arguments, Invocation, Request, ResultReference};
+
+/// Builds a [`Request`], assigning each method call an id so that later
+/// calls can take arguments from its result.
+///
+/// ```
+/// use jmap_proto::endpoints::builder::RequestBuilder;
+///
+/// let mut builder = RequestBuilder::new(["urn:ietf:params:jmap:core"]);
+///
+/// let query = builder
+///     .call("AddressBook/query")
+///     .argument("accountId", "a1")
+///     .id();
+///
+/// builder
+///     .call("AddressBook/get")
+///     .argument("accountId", "a1")
+///     .reference("ids", &query, "/ids");
+///
+/// let request = serde_json::to_value(builder.build()).unwrap();
+///
+/// assert_eq!(
+///     request["methodCalls"][1],
+///     serde_json::json!([
+///         "AddressBook/get",
+///         {
+///             "accountId": "a1",
+///             "#ids": {
+///                 "resultOf": "c0",
+///                 "name": "AddressBook/query",
+///                 "path": "/ids",
+///             },
+///         },
+///         "c1",
+///     ]),
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RequestBuilder<'a> {
+    using: Vec<Cow<'a, str>>,
+    method_calls: Vec<Invocation<'a>>,
+}
+
+impl<'a> RequestBuilder<'a> {
+    /// Starts a request using the given capabilities.
+    pub fn new<C: Into<Cow<'a, str>>>(using: impl IntoIterator<Item = C>) -> Self {
+        Self {
+            using: using.into_iter().map(Into::into).collect(),
+            method_calls: Vec::new(),
+        }
+    }
+
+    /// Adds a capability to those the request uses.
+    pub fn using(&mut self, capability: impl Into<Cow<'a, str>>) -> &mut Self {
+        self.using.push(capability.into());
+        self
+    }
+
+    /// Adds a call to the method, without any arguments, to the end of the
+    /// request.
+    pub fn call(&mut self, name: impl Into<Cow<'a, str>>) -> MethodCallBuilder<'a, '_> {
+        let request_id = format!("c{}", self.method_calls.len());
+
+        self.method_calls.push(Invocation {
+            name: name.into(),
+            arguments: Arguments::default(),
+            request_id: request_id.into(),
+        });
+
+        MethodCallBuilder(self.method_calls.last_mut().unwrap())
+    }
+
+    pub fn build(self) -> Request<'a> {
+        Request {
+            using: self.using,
+            method_calls: self.method_calls,
+            created_ids: None,
+        }
+    }
+}
+
+/// A method call added to a [`RequestBuilder`], which arguments can still be
+/// added to.
+#[derive(Debug)]
+pub struct MethodCallBuilder<'a, 'b>(&'b mut Invocation<'a>);
+
+impl<'a> MethodCallBuilder<'a, '_> {
+    /// Sets the argument to the given value.
+    pub fn argument(self, name: impl Into<Cow<'a, str>>, value: impl Into<Value>) -> Self {
+        self.0
+            .arguments
+            .0
+            .insert(name.into(), Argument::Absolute(value.into()));
+        self
+    }
+
+    /// Takes the argument from the result of an earlier call, at the given
+    /// path into its response of the same name.
+    pub fn reference(
+        self,
+        name: impl Into<Cow<'a, str>>,
+        call: &CallId<'a>,
+        path: impl Into<Cow<'a, str>>,
+    ) -> Self {
+        let response = call.name.clone();
+        self.reference_response(name, call, response, path)
+    }
+
+    /// Takes the argument from the result of an earlier call, at the given
+    /// path into its response of the given name, for calls that respond
+    /// with more than one.
+    pub fn reference_response(
+        self,
+        name: impl Into<Cow<'a, str>>,
+        call: &CallId<'a>,
+        response: impl Into<Cow<'a, str>>,
+        path: impl Into<Cow<'a, str>>,
+    ) -> Self {
+        self.0.arguments.0.insert(
+            name.into(),
+            Argument::Reference(ResultReference {
+                result_of: call.id.clone(),
+                name: response.into(),
+                path: path.into(),
+            }),
+        );
+        self
+    }
+
+    /// The id of the call, for later calls to reference its result by.
+    pub fn id(&self) -> CallId<'a> {
+        CallId {
+            id: self.0.request_id.clone(),
+            name: self.0.name.clone(),
+        }
+    }
+}
+
+/// Identifies a method call within a [`RequestBuilder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallId<'a> {
+    /// The id assigned to the call.
+    pub id: Cow<'a, str>,
+    /// The name of the method called.
+    pub name: Cow<'a, str>,
+}
@@ -1,6 +1,8 @@
 pub mod blob;
+pub mod builder;
 pub mod core;
 pub mod object;
+pub mod push_subscription;
 pub mod session;
 
 use std::{borrow::Cow, collections::HashMap, fmt::Formatter};
@@ -10,11 +12,13 @@ use serde::{
     ser::{SerializeMap, SerializeSeq},
     Deserialize, Deserializer, Serialize, Serializer,
 };
-use serde_json::Value;
+use serde_json::{value::RawValue, Value};
 use serde_with::serde_as;
 
 use crate::{
-    common::{Id, SessionState},
+    common::{CreationId, Id, SessionState},
+    pointer,
+    pointer::{ReferencePath, Token},
     util::strip_prefix_from_cow,
 };
 
@@ -30,24 +34,27 @@ const REFERENCE_OCTOTHORPE: &str = "#";
 pub struct Arguments<'a>(pub HashMap<Cow<'a, str>, Argument<'a>>);
 
 impl Arguments<'_> {
-    /// Resolves a pointer, as defined in [RFC 6901]
+    /// Resolves the path of a result reference against the arguments.
     ///
-    /// [RFC 6901]: https://datatracker.ietf.org/doc/html/rfc6901
-    pub fn pointer(&self, pointer: &str) -> Option<Cow<Value>> {
-        if pointer.is_empty() {
+    /// Only the argument the path leads into is parsed if it's still held
+    /// as raw JSON.
+    pub fn pointer(&self, path: &ReferencePath<'_>) -> Option<Cow<'_, Value>> {
+        let Some((key, rest)) = path.tokens().split_first() else {
             return Some(Cow::Owned(serde_json::to_value(self).unwrap()));
-        }
-
-        let pointer = pointer.strip_prefix('/')?;
-
-        let mut pointer = pointer.splitn(2, pointer);
-
-        if let Argument::Absolute(value) = self.0.get(pointer.next()?)? {
-            value
-                .pointer(pointer.next().unwrap_or(""))
-                .map(Cow::Borrowed)
-        } else {
-            None
+        };
+
+        // the arguments are an object, there's no array to map through
+        let Token::Member(key) = key else {
+            return None;
+        };
+
+        match self.0.get(key.as_ref())? {
+            Argument::Absolute(value) => pointer::resolve_tokens(value, rest),
+            Argument::Raw(value) => {
+                let value = serde_json::from_str::<Value>(value.get()).ok()?;
+                pointer::resolve_tokens(&value, rest).map(|value| Cow::Owned(value.into_owned()))
+            }
+            Argument::Reference(_) => None,
         }
     }
 }
@@ -65,6 +72,7 @@ impl<'a> Serialize for Arguments<'a> {
                     ser.serialize_entry(&format!("{REFERENCE_OCTOTHORPE}{key}"), v)?
                 }
                 Argument::Absolute(v) => ser.serialize_entry(key, v)?,
+                Argument::Raw(v) => ser.serialize_entry(key, v)?,
             }
         }
 
@@ -98,9 +106,10 @@ impl<'de> Deserialize<'de> for Arguments<'de> {
                             .0
                             .insert(key, Argument::Reference(map.next_value()?));
                     } else {
-                        arguments
-                            .0
-                            .insert(key, Argument::Absolute(map.next_value()?));
+                        arguments.0.insert(
+                            key,
+                            Argument::Raw(Cow::Borrowed(map.next_value::<&'de RawValue>()?)),
+                        );
                     }
                 }
 
@@ -108,7 +117,7 @@ impl<'de> Deserialize<'de> for Arguments<'de> {
             }
         }
 
-        deserializer.deserialize_seq(Visitor {})
+        deserializer.deserialize_map(Visitor {})
     }
 }
 
@@ -116,6 +125,12 @@ impl<'de> Deserialize<'de> for Arguments<'de> {
 pub enum Argument<'a> {
     Reference(ResultReference<'a>),
     Absolute(Value),
+    /// An argument kept as the JSON it was written as, rather than parsed
+    /// into a [`Value`].
+    ///
+    /// Arguments are deserialized as this, borrowing from the input, so they
+    /// can only be deserialized from JSON held in memory.
+    Raw(Cow<'a, RawValue>),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -232,8 +247,8 @@ pub struct Request<'a> {
     /// real id is unknown when the request is created, the client can instead
     /// specify the creation id it assigned, prefixed with a "#" (see
     /// Section 5.3 for more details).
-    #[serde(borrow)]
-    pub created_ids: Option<HashMap<Id<'a>, Id<'a>>>,
+    #[serde(borrow, skip_serializing_if = "Option::is_none")]
+    pub created_ids: Option<HashMap<CreationId<'a>, Id<'a>>>,
 }
 
 #[serde_as]
@@ -251,10 +266,68 @@ pub struct Response<'a> {
     /// include all creation ids passed in the original createdIds
     /// parameter of the Request object, as well as any additional ones
     /// added for newly created records.
-    #[serde(borrow)]
-    pub created_ids: Option<HashMap<Id<'a>, Id<'a>>>,
+    ///
+    /// This is only returned if the createdIds argument was given in the
+    /// Request object.
+    #[serde(borrow, skip_serializing_if = "Option::is_none")]
+    pub created_ids: Option<HashMap<CreationId<'a>, Id<'a>>>,
     /// The current value of the "state" string on the Session object, as
     /// described in Section 2.  Clients may use this to detect if this
     /// object has changed and needs to be refetched.
     pub session_state: SessionState<'a>,
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn pointer_resolves_raw_and_absolute_arguments_alike() {
+        let json =
+            json!({ "list": [{ "id": "a" }, { "id": "b" }], "a/b": { "c~d": 1 } }).to_string();
+        let raw: Arguments<'_> = serde_json::from_str(&json).unwrap();
+        assert!(matches!(raw.0["list"], Argument::Raw(_)));
+
+        let absolute = Arguments(
+            raw.0
+                .keys()
+                .map(|key| {
+                    let value = raw.pointer(
+                        &ReferencePath::parse(&format!("/{}", pointer::escape(key))).unwrap(),
+                    );
+                    (key.clone(), Argument::Absolute(value.unwrap().into_owned()))
+                })
+                .collect(),
+        );
+
+        for arguments in [&raw, &absolute] {
+            let resolve = |path| {
+                arguments
+                    .pointer(&ReferencePath::parse(path).unwrap())
+                    .map(Cow::into_owned)
+            };
+
+            assert_eq!(resolve("/list/*/id"), Some(json!(["a", "b"])));
+            assert_eq!(resolve("/a~1b/c~0d"), Some(json!(1)));
+            assert_eq!(resolve("/list/2"), None);
+            assert_eq!(resolve("/*"), None);
+        }
+    }
+
+    #[test]
+    fn references_are_split_from_arguments() {
+        let json = json!({
+            "#ids": { "resultOf": "c0", "name": "Foo/query", "path": "/ids" },
+        })
+        .to_string();
+        let arguments: Arguments<'_> = serde_json::from_str(&json).unwrap();
+
+        assert!(matches!(arguments.0["ids"], Argument::Reference(_)));
+        assert_eq!(
+            arguments.pointer(&ReferencePath::parse("/ids").unwrap()),
+            None
+        );
+    }
+}
@@ -29,6 +29,23 @@ pub struct ChangesParams<'a> {
     max_changes: Option<UnsignedInt>,
 }
 
+impl<'a> ChangesParams<'a> {
+    /// The id of the account the call is made within.
+    pub fn account_id(&self) -> &Id<'a> {
+        &self.account_id
+    }
+
+    /// The state the client wants the changes since.
+    pub fn since_state(&self) -> &ObjectState<'a> {
+        &self.since_state
+    }
+
+    /// The most ids the client wants returned, if it gave a limit.
+    pub fn max_changes(&self) -> Option<UnsignedInt> {
+        self.max_changes
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ChangesResponse<'a> {
@@ -55,3 +72,25 @@ pub struct ChangesResponse<'a> {
     /// state.
     destroyed: Vec<Id<'a>>,
 }
+
+impl<'a> ChangesResponse<'a> {
+    pub fn new(
+        account_id: Id<'a>,
+        old_state: ObjectState<'a>,
+        new_state: ObjectState<'a>,
+        has_more_changes: bool,
+        created: Vec<Id<'a>>,
+        updated: Vec<Id<'a>>,
+        destroyed: Vec<Id<'a>>,
+    ) -> Self {
+        Self {
+            account_id,
+            old_state,
+            new_state,
+            has_more_changes,
+            created,
+            updated,
+            destroyed,
+        }
+    }
+}
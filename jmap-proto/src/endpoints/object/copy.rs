@@ -19,7 +19,7 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    common::Id,
+    common::{CreationId, Id},
     endpoints::object::{set::SetError, ObjectState},
 };
 
@@ -50,7 +50,7 @@ pub struct CopyParams<'a, T> {
     /// the record to be copied.  When creating the copy, any other
     /// properties included are used instead of the current value for that
     /// property on the original.
-    create: HashMap<Id<'a>, T>,
+    create: HashMap<CreationId<'a>, T>,
     /// If true, an attempt will be made to destroy the original records
     /// that were successfully copied: after emitting the "Foo/copy"
     /// response, but before processing the next method, the server MUST
@@ -65,6 +65,48 @@ pub struct CopyParams<'a, T> {
     destroy_from_if_in_state: Option<ObjectState<'a>>,
 }
 
+impl<'a, T> CopyParams<'a, T> {
+    /// The id of the account the records are copied from.
+    pub fn from_account_id(&self) -> &Id<'a> {
+        &self.from_account_id
+    }
+
+    /// The state the client expects the data type to be in within the
+    /// account copied from, if it asked for the call to be guarded by one.
+    pub fn if_from_in_state(&self) -> Option<&ObjectState<'a>> {
+        self.if_from_in_state.as_ref()
+    }
+
+    /// The id of the account the records are copied to.
+    pub fn account_id(&self) -> &Id<'a> {
+        &self.account_id
+    }
+
+    /// The state the client expects the data type to be in within the
+    /// account copied to, if it asked for the call to be guarded by one.
+    pub fn if_in_state(&self) -> Option<&ObjectState<'a>> {
+        self.if_in_state.as_ref()
+    }
+
+    /// The records to copy, each holding the id of its original and the
+    /// properties to set in place of the original's, keyed by their
+    /// creation ids.
+    pub fn create(&self) -> &HashMap<CreationId<'a>, T> {
+        &self.create
+    }
+
+    /// Whether the originals are destroyed once they've been copied.
+    pub fn on_success_destroy_original(&self) -> bool {
+        self.on_success_destroy_original
+    }
+
+    /// The state the implicit call destroying the originals is guarded by,
+    /// if any.
+    pub fn destroy_from_if_in_state(&self) -> Option<&ObjectState<'a>> {
+        self.destroy_from_if_in_state.as_ref()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CopyResponse<'a, T> {
@@ -91,9 +133,40 @@ pub struct CopyResponse<'a, T> {
     ///
     /// This argument is null if no Foo objects were successfully copied.
     #[serde(default, borrow)]
-    created: HashMap<Id<'a>, T>,
+    created: HashMap<CreationId<'a>, T>,
     /// A map of the creation id to a SetError object for each record that
     /// failed to be copied, or null if none.
     #[serde(default, borrow)]
-    not_created: HashMap<Id<'a>, SetError<'a>>,
+    not_created: HashMap<CreationId<'a>, SetError<'a>>,
+}
+
+impl<'a, T> CopyResponse<'a, T> {
+    /// A response in which nothing has been copied yet, leaving the state of
+    /// the account copied to as it was.
+    pub fn new(from_account_id: Id<'a>, account_id: Id<'a>, old_state: ObjectState<'a>) -> Self {
+        Self {
+            from_account_id,
+            account_id,
+            new_state: old_state.clone(),
+            old_state: Some(old_state),
+            created: HashMap::new(),
+            not_created: HashMap::new(),
+        }
+    }
+
+    /// Sets the state the copies led to.
+    pub fn set_new_state(&mut self, new_state: ObjectState<'a>) {
+        self.new_state = new_state;
+    }
+
+    /// Records a copied object, along with the properties of the copy set
+    /// by the server.
+    pub fn insert_created(&mut self, creation_id: CreationId<'a>, properties: T) {
+        self.created.insert(creation_id, properties);
+    }
+
+    /// Records an object that couldn't be copied.
+    pub fn insert_not_created(&mut self, creation_id: CreationId<'a>, error: SetError<'a>) {
+        self.not_created.insert(creation_id, error);
+    }
 }
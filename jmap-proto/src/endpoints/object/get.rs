@@ -5,7 +5,11 @@ use std::borrow::Cow;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, BorrowCow};
 
-use crate::{common::Id, endpoints::object::ObjectState};
+use crate::{
+    common::{Id, IdOrReference},
+    compat::RenamedFields,
+    endpoints::object::ObjectState,
+};
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -17,6 +21,10 @@ pub struct GetParams<'a> {
     /// of the data type are returned, if this is supported for that data
     /// type and the number of records does not exceed the
     /// "maxObjectsInGet" limit.
+    ///
+    /// A `#creationId` reference the server couldn't resolve is accepted
+    /// here, and returned in "notFound".
+    #[serde_as(as = "Option<Vec<IdOrReference>>")]
     ids: Option<Vec<Id<'a>>>,
     /// If supplied, only the properties listed in the array are returned
     /// for each Foo object.  If null, all properties of the object are
@@ -28,7 +36,23 @@ pub struct GetParams<'a> {
     properties: Option<Vec<Cow<'a, str>>>,
 }
 
-// TODO: requestTooLarge error variant
+impl<'a> GetParams<'a> {
+    /// The id of the account the call is made within.
+    pub fn account_id(&self) -> &Id<'a> {
+        &self.account_id
+    }
+
+    /// The ids of the objects to return, or `None` for every object.
+    pub fn ids(&self) -> Option<&[Id<'a>]> {
+        self.ids.as_deref()
+    }
+
+    /// The properties to return for each object, or `None` for all of them.
+    pub fn properties(&self) -> Option<&[Cow<'a, str>]> {
+        self.properties.as_deref()
+    }
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -58,5 +82,48 @@ pub struct GetResponse<'a, T> {
     /// do not exist.  The array is empty if all requested ids were found
     /// or if the "ids" argument passed in was either null or an empty
     /// array.
-    id: Vec<Id<'a>>,
+    #[serde(borrow, default)]
+    not_found: Vec<Id<'a>>,
+    /// `notFound` under `id`, the name it was once mistakenly serialised
+    /// as, for clients built against it.
+    #[serde(
+        borrow,
+        default,
+        rename = "id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    legacy_not_found: Option<Vec<Id<'a>>>,
+}
+
+impl<'a, T> GetResponse<'a, T> {
+    pub fn new(
+        account_id: Id<'a>,
+        state: ObjectState<'a>,
+        list: Vec<T>,
+        not_found: Vec<Id<'a>>,
+    ) -> Self {
+        Self {
+            account_id,
+            state,
+            list,
+            not_found,
+            legacy_not_found: None,
+        }
+    }
+
+    /// The ids requested that don't exist, from whichever of `notFound` and
+    /// its old name the response was sent with.
+    pub fn not_found(&self) -> &[Id<'a>] {
+        match &self.legacy_not_found {
+            Some(legacy) if self.not_found.is_empty() => legacy,
+            _ => &self.not_found,
+        }
+    }
+}
+
+impl<T> RenamedFields for GetResponse<'_, T> {
+    fn with_legacy_field_names(mut self) -> Self {
+        self.legacy_not_found = Some(self.not_found.clone());
+        self
+    }
 }
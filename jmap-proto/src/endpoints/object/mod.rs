@@ -19,4 +19,4 @@ pub mod set;
 /// objects for the type or call "Foo/changes" to get the exact
 /// changes.
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct ObjectState<'a>(#[serde(borrow)] Cow<'a, str>);
+pub struct ObjectState<'a>(#[serde(borrow)] pub Cow<'a, str>);
@@ -25,7 +25,8 @@ pub struct QueryParams<'a> {
     account_id: Id<'a>,
     /// Determines the set of Foos returned in the results.  If null, all
     /// objects in the account of this type are included in the results.
-    filter: Filter<'a>,
+    #[serde(default)]
+    filter: Option<Filter<'a>>,
     /// Lists the names of properties to compare between two Foo records,
     /// and how to compare them, to determine which comes first in the
     /// sort.  If two Foo records have an identical value for the first
@@ -53,6 +54,38 @@ pub struct QueryParams<'a> {
     calculate_total: bool,
 }
 
+impl<'a> QueryParams<'a> {
+    /// The id of the account the call is made within.
+    pub fn account_id(&self) -> &Id<'a> {
+        &self.account_id
+    }
+
+    /// The filter the results must match, if any.
+    pub fn filter(&self) -> Option<&Filter<'a>> {
+        self.filter.as_ref()
+    }
+
+    /// The comparators to sort the results by, in order of precedence.
+    pub fn sort(&self) -> &[Comparator<'a>] {
+        &self.sort
+    }
+
+    /// Where in the results the returned ids start.
+    pub fn offset(&self) -> &Offset<'a> {
+        &self.offset
+    }
+
+    /// The maximum number of ids to return, if any.
+    pub fn limit(&self) -> Option<UnsignedInt> {
+        self.limit
+    }
+
+    /// Whether the total number of results should be returned.
+    pub fn calculate_total(&self) -> bool {
+        self.calculate_total
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct QueryResponse<'a> {
@@ -91,6 +124,44 @@ pub struct QueryResponse<'a> {
     limit: Option<UnsignedInt>,
 }
 
+impl<'a> QueryResponse<'a> {
+    /// Builds the response to a query whose changes can't be calculated,
+    /// with `total` set only if it was asked for.
+    pub fn new(
+        account_id: Id<'a>,
+        query_state: QueryState<'a>,
+        position: UnsignedInt,
+        ids: Vec<Id<'a>>,
+        total: Option<UnsignedInt>,
+    ) -> Self {
+        Self {
+            account_id,
+            query_state,
+            can_calculate_changes: false,
+            position,
+            ids,
+            total,
+            limit: None,
+        }
+    }
+
+    /// Marks the query as one whose changes can be fetched with
+    /// `Foo/queryChanges`.
+    #[must_use]
+    pub fn with_changes(mut self) -> Self {
+        self.can_calculate_changes = true;
+        self
+    }
+
+    /// Sets the limit the server applied in place of the one the client
+    /// asked for, if any.
+    #[must_use]
+    pub fn with_limit(mut self, limit: Option<UnsignedInt>) -> Self {
+        self.limit = limit;
+        self
+    }
+}
+
 /// The queryState string only represents the ordered list of ids that
 /// match the particular query (including its sort/filter).  There is
 /// no requirement for it to change if a property on an object
@@ -106,10 +177,10 @@ pub struct QueryResponse<'a> {
 /// require fetching the records again, just the list of ids) or call
 /// "Foo/queryChanges" to get the difference.
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct QueryState<'a>(#[serde(borrow)] Cow<'a, str>);
+pub struct QueryState<'a>(#[serde(borrow)] pub Cow<'a, str>);
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
-#[serde(untagged)]
+#[serde(untagged, from = "RawOffset<'a>")]
 pub enum Offset<'a> {
     Position {
         /// The zero-based index of the first id in the full list of results
@@ -145,6 +216,40 @@ pub enum Offset<'a> {
     Default,
 }
 
+/// The arguments making up an [`Offset`], as sent by the client. An untagged
+/// enum can't be flattened into the arguments alongside others, so the
+/// variant is picked once they've been read.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawOffset<'a> {
+    position: Option<Int>,
+    #[serde(borrow)]
+    anchor: Option<Id<'a>>,
+    #[serde(default)]
+    anchor_offset: Int,
+}
+
+impl<'a> From<RawOffset<'a>> for Offset<'a> {
+    fn from(raw: RawOffset<'a>) -> Self {
+        // an anchor takes precedence over a position
+        match raw {
+            RawOffset {
+                anchor: Some(anchor),
+                anchor_offset,
+                ..
+            } => Self::Anchor {
+                anchor,
+                anchor_offset,
+            },
+            RawOffset {
+                position: Some(position),
+                ..
+            } => Self::Position { position },
+            RawOffset { .. } => Self::Default,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Comparator<'a> {
@@ -162,6 +267,23 @@ pub struct Comparator<'a> {
     collation: Option<Cow<'a, str>>,
 }
 
+impl<'a> Comparator<'a> {
+    /// The name of the property compared.
+    pub fn property(&self) -> &str {
+        &self.property
+    }
+
+    /// Whether the comparator sorts in ascending order.
+    pub fn is_ascending(&self) -> bool {
+        self.is_ascending
+    }
+
+    /// The collation to compare strings with, if the client chose one.
+    pub fn collation(&self) -> Option<&str> {
+        self.collation.as_deref()
+    }
+}
+
 const fn default_is_ascending() -> bool {
     true
 }
@@ -186,7 +308,19 @@ pub struct FilterOperator<'a> {
     conditions: Vec<Filter<'a>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl<'a> FilterOperator<'a> {
+    /// How the conditions are combined.
+    pub fn operator(&self) -> Operator {
+        self.operator
+    }
+
+    /// The filters combined by the operator.
+    pub fn conditions(&self) -> &[Filter<'a>] {
+        &self.conditions
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Operator {
     /// All of the conditions must match for the filter to match.
@@ -198,3 +332,31 @@ pub enum Operator {
     /// match.
     Not,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_is_read_alongside_other_arguments() {
+        let offset = |json| {
+            serde_json::from_str::<QueryParams<'_>>(json)
+                .unwrap()
+                .offset()
+                .clone()
+        };
+
+        assert!(matches!(
+            offset(r#"{"accountId": "a", "sort": [], "limit": 3}"#),
+            Offset::Default
+        ));
+        assert!(matches!(
+            offset(r#"{"accountId": "a", "limit": 3, "position": 2}"#),
+            Offset::Position { .. }
+        ));
+        assert!(matches!(
+            offset(r#"{"accountId": "a", "position": 2, "anchor": "b"}"#),
+            Offset::Anchor { .. }
+        ));
+    }
+}
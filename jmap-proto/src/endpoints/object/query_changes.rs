@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     common::{Id, UnsignedInt},
-    endpoints::object::query::{Comparator, Filter, QueryParams, QueryState},
+    endpoints::object::query::{Comparator, Filter, QueryState},
 };
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -44,6 +44,39 @@ pub struct QueryChangesParams<'a> {
     calculate_total: bool,
 }
 
+impl<'a> QueryChangesParams<'a> {
+    /// The id of the account the call is made within.
+    pub fn account_id(&self) -> &Id<'a> {
+        &self.account_id
+    }
+
+    /// The filter the query's results match, if any.
+    pub fn filter(&self) -> Option<&Filter<'a>> {
+        self.filter.as_ref()
+    }
+
+    /// The comparators the query's results are sorted by, in order of
+    /// precedence.
+    pub fn sort(&self) -> &[Comparator<'a>] {
+        &self.sort
+    }
+
+    /// The state of the query the client wants the changes since.
+    pub fn since_query_state(&self) -> &QueryState<'a> {
+        &self.since_query_state
+    }
+
+    /// The most changes the client wants returned, if it gave a limit.
+    pub fn max_changes(&self) -> Option<UnsignedInt> {
+        self.max_changes
+    }
+
+    /// Whether the total number of results should be returned.
+    pub fn calculate_total(&self) -> bool {
+        self.calculate_total
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct QueryChangesResponse<'a> {
@@ -55,7 +88,7 @@ pub struct QueryChangesResponse<'a> {
     old_query_state: QueryState<'a>,
     /// This is the state the query will be in after applying the set of
     /// changes to the old state.
-    new_query_state: QueryParams<'a>,
+    new_query_state: QueryState<'a>,
     /// The total number of Foos in the results (given the "filter").
     /// This argument MUST be omitted if the "calculateTotal" request
     /// argument is not true.
@@ -94,6 +127,26 @@ pub struct QueryChangesResponse<'a> {
     added: Vec<AddedItem<'a>>,
 }
 
+impl<'a> QueryChangesResponse<'a> {
+    pub fn new(
+        account_id: Id<'a>,
+        old_query_state: QueryState<'a>,
+        new_query_state: QueryState<'a>,
+        total: Option<UnsignedInt>,
+        removed: Vec<Id<'a>>,
+        added: Vec<AddedItem<'a>>,
+    ) -> Self {
+        Self {
+            account_id,
+            old_query_state,
+            new_query_state,
+            total,
+            removed,
+            added,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct AddedItem<'a> {
@@ -101,3 +154,9 @@ pub struct AddedItem<'a> {
     id: Id<'a>,
     index: UnsignedInt,
 }
+
+impl<'a> AddedItem<'a> {
+    pub fn new(id: Id<'a>, index: UnsignedInt) -> Self {
+        Self { id, index }
+    }
+}
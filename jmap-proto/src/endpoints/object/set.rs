@@ -11,11 +11,15 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_with::{serde_as, BorrowCow};
 
-use crate::{common::Id, endpoints::object::ObjectState};
+use crate::{
+    common::{CreationId, Id, IdOrReference},
+    endpoints::object::ObjectState,
+    pointer::{Pointer, PointerError},
+};
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", bound(deserialize = "T: Deserialize<'de>"))]
 pub struct SetParams<'a, T> {
     /// The id of the account to use.
     account_id: Id<'a>,
@@ -36,17 +40,71 @@ pub struct SetParams<'a, T> {
     /// The client MUST omit any properties that may only be set by the
     /// server (for example, the "id" property on most object types).
     #[serde(default)]
-    create: HashMap<Id<'a>, T>,
+    create: HashMap<CreationId<'a>, T>,
     /// A map of an id to a Patch object to apply to the current Foo
     /// object with that id, or null if no objects are to be updated.
+    ///
+    /// A `#creationId` reference the server couldn't resolve is accepted as
+    /// an id here and in "destroy", and rejected with a "notFound" error.
     #[serde(default)]
+    #[serde_as(as = "HashMap<IdOrReference, _>")]
     update: HashMap<Id<'a>, PatchObject<'a>>,
     /// A list of ids for Foo objects to permanently delete, or null if no
     /// objects are to be destroyed.
+    ///
+    /// Destroying an id that doesn't exist, including one that was destroyed
+    /// by an earlier call, doesn't fail the call. The id is instead returned
+    /// in "notDestroyed" with a "notFound" error, so retrying a destroy is
+    /// always safe.
     #[serde(default)]
+    #[serde_as(as = "Vec<IdOrReference>")]
     destroy: Vec<Id<'a>>,
 }
 
+impl<'a, T> SetParams<'a, T> {
+    /// The id of the account the call is made within.
+    pub fn account_id(&self) -> &Id<'a> {
+        &self.account_id
+    }
+
+    /// The state the client expects the data type to be in before any
+    /// change is applied, if it asked for the call to be guarded by one.
+    pub fn if_in_state(&self) -> Option<&ObjectState<'a>> {
+        self.if_in_state.as_ref()
+    }
+
+    /// Whether the call creates or updates any objects, rather than only
+    /// destroying them.
+    pub fn creates_or_updates(&self) -> bool {
+        !self.create.is_empty() || !self.update.is_empty()
+    }
+
+    /// The objects to create, keyed by their creation ids.
+    pub fn create(&self) -> &HashMap<CreationId<'a>, T> {
+        &self.create
+    }
+
+    /// The patches to apply, keyed by the ids of the objects they apply to.
+    pub fn update(&self) -> &HashMap<Id<'a>, PatchObject<'a>> {
+        &self.update
+    }
+
+    /// The ids of the objects to destroy.
+    pub fn destroy(&self) -> &[Id<'a>] {
+        &self.destroy
+    }
+
+    /// The number of objects the call creates, updates or destroys.
+    pub fn len(&self) -> usize {
+        self.create.len() + self.update.len() + self.destroy.len()
+    }
+
+    /// Whether the call asks for no changes at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 /// A *PatchObject* is of type "String[*]" and represents an unordered
 /// set of patches.  The keys are a path in JSON Pointer format
 /// [RFC6901], with an implicit leading "/" (i.e., prefix each key
@@ -54,7 +112,72 @@ pub struct SetParams<'a, T> {
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-pub struct PatchObject<'a>(#[serde_as(as = "HashMap<BorrowCow, _>")] HashMap<Cow<'a, str>, Value>);
+pub struct PatchObject<'a>(
+    #[serde_as(as = "HashMap<BorrowCow, _>")] pub HashMap<Cow<'a, str>, Value>,
+);
+
+impl PatchObject<'_> {
+    /// Applies the patch to an object, each path being set to its value, or
+    /// removed if the value is null.
+    ///
+    /// Every part of a path but the last must already exist on the object,
+    /// and no path may be a prefix of another. Patches breaking either rule,
+    /// or reaching into an array, fail with an `invalidPatch` error and leave
+    /// the object untouched.
+    pub fn apply(&self, object: &Value) -> Result<Value, SetError<'static>> {
+        let mut patches = self
+            .0
+            .iter()
+            .map(|(path, value)| Ok((Pointer::parse_patch(path)?, value)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error: PointerError| {
+                SetError::invalid_patch(Some(Cow::Owned(error.to_string())))
+            })?;
+
+        // a path sorts immediately before the paths it's a prefix of
+        patches.sort_by(|(a, _), (b, _)| a.tokens().cmp(b.tokens()));
+
+        if patches
+            .windows(2)
+            .any(|pair| pair[1].0.tokens().starts_with(pair[0].0.tokens()))
+        {
+            return Err(SetError::invalid_patch(Some(Cow::Borrowed(
+                "no path may be a prefix of another",
+            ))));
+        }
+
+        let mut object = object.clone();
+
+        for (path, value) in patches {
+            let Some((last, parents)) = path.tokens().split_last() else {
+                return Err(SetError::invalid_patch(Some(Cow::Borrowed(
+                    "the object itself can't be patched",
+                ))));
+            };
+
+            let parent = parents
+                .iter()
+                .try_fold(&mut object, |object, token| {
+                    object.as_object_mut()?.get_mut(token.as_ref())
+                })
+                .and_then(Value::as_object_mut)
+                .ok_or_else(|| {
+                    SetError::invalid_patch(Some(Cow::Owned(format!(
+                        "{} doesn't lead to an object",
+                        path.tokens()[..parents.len()].join("/")
+                    ))))
+                })?;
+
+            if value.is_null() {
+                parent.remove(last.as_ref());
+            } else {
+                parent.insert(last.to_string(), value.clone());
+            }
+        }
+
+        Ok(object)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -78,7 +201,7 @@ pub struct SetResult<'a, T> {
     ///
     /// This argument is null if no Foo objects were successfully created.
     #[serde(default, borrow)]
-    created: HashMap<Id<'a>, T>,
+    created: HashMap<CreationId<'a>, T>,
     /// The keys in this map are the ids of all Foos that were
     /// successfully updated.
     ///
@@ -97,7 +220,7 @@ pub struct SetResult<'a, T> {
     /// A map of the creation id to a SetError object for each record that
     /// failed to be created, or null if all successful.
     #[serde(default, borrow)]
-    not_created: HashMap<Id<'a>, SetError<'a>>,
+    not_created: HashMap<CreationId<'a>, SetError<'a>>,
     /// A map of the Foo id to a SetError object for each record that
     /// failed to be updated, or null if all successful.
     #[serde(default, borrow)]
@@ -108,6 +231,61 @@ pub struct SetResult<'a, T> {
     not_destroyed: HashMap<Id<'a>, SetError<'a>>,
 }
 
+impl<'a, T> SetResult<'a, T> {
+    /// A result in which nothing has been created, updated or destroyed yet,
+    /// leaving the state as it was.
+    pub fn new(account_id: Id<'a>, old_state: ObjectState<'a>) -> Self {
+        Self {
+            account_id,
+            new_state: old_state.clone(),
+            old_state: Some(old_state),
+            created: HashMap::new(),
+            updated: HashMap::new(),
+            destroyed: Vec::new(),
+            not_created: HashMap::new(),
+            not_updated: HashMap::new(),
+            not_destroyed: HashMap::new(),
+        }
+    }
+
+    /// Sets the state the changes led to.
+    pub fn set_new_state(&mut self, new_state: ObjectState<'a>) {
+        self.new_state = new_state;
+    }
+
+    /// Records a created object, along with the properties of it the client
+    /// didn't send.
+    pub fn insert_created(&mut self, creation_id: CreationId<'a>, properties: T) {
+        self.created.insert(creation_id, properties);
+    }
+
+    /// Records an updated object, along with any properties of it that
+    /// changed in a way the client didn't ask for.
+    pub fn insert_updated(&mut self, id: Id<'a>, changed: Option<T>) {
+        self.updated.insert(id, changed);
+    }
+
+    /// Records a destroyed object.
+    pub fn push_destroyed(&mut self, id: Id<'a>) {
+        self.destroyed.push(id);
+    }
+
+    /// Records an object that couldn't be created.
+    pub fn insert_not_created(&mut self, creation_id: CreationId<'a>, error: SetError<'a>) {
+        self.not_created.insert(creation_id, error);
+    }
+
+    /// Records an object that couldn't be updated.
+    pub fn insert_not_updated(&mut self, id: Id<'a>, error: SetError<'a>) {
+        self.not_updated.insert(id, error);
+    }
+
+    /// Records an object that couldn't be destroyed.
+    pub fn insert_not_destroyed(&mut self, id: Id<'a>, error: SetError<'a>) {
+        self.not_destroyed.insert(id, error);
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SetError<'a> {
@@ -126,6 +304,69 @@ pub struct SetError<'a> {
     properties: Vec<Cow<'a, str>>,
 }
 
+impl<'a> SetError<'a> {
+    /// Builds an `invalidProperties` error listing the given properties.
+    pub fn invalid_properties(
+        properties: Vec<Cow<'a, str>>,
+        description: Option<Cow<'a, str>>,
+    ) -> Self {
+        Self {
+            type_: SetErrorKind::InvalidProperties,
+            description,
+            properties,
+        }
+    }
+
+    /// Builds an `overQuota` error, for a create that would exceed a limit on
+    /// the number of objects of its type.
+    pub fn over_quota(description: Option<Cow<'a, str>>) -> Self {
+        Self {
+            type_: SetErrorKind::OverQuota,
+            description,
+            properties: Vec::new(),
+        }
+    }
+
+    /// Builds a `forbidden` error, for a change the user isn't permitted to
+    /// make.
+    pub fn forbidden(description: Option<Cow<'a, str>>) -> Self {
+        Self {
+            type_: SetErrorKind::Forbidden,
+            description,
+            properties: Vec::new(),
+        }
+    }
+
+    /// Builds an `invalidPatch` error, for a patch that couldn't be applied.
+    pub fn invalid_patch(description: Option<Cow<'a, str>>) -> Self {
+        Self {
+            type_: SetErrorKind::InvalidPatch,
+            description,
+            properties: Vec::new(),
+        }
+    }
+
+    /// Builds a `willDestroy` error, for an update to an object the same
+    /// call destroys.
+    pub fn will_destroy() -> Self {
+        Self {
+            type_: SetErrorKind::WillDestroy,
+            description: None,
+            properties: Vec::new(),
+        }
+    }
+
+    /// Builds a `notFound` error, for an id given to update or destroy that
+    /// doesn't exist.
+    pub fn not_found(description: Option<Cow<'a, str>>) -> Self {
+        Self {
+            type_: SetErrorKind::NotFound,
+            description,
+            properties: Vec::new(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum SetErrorKind {
@@ -168,3 +409,39 @@ pub enum SetErrorKind {
     /// another one or destroy the existing one.
     Singleton,
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn patch(patch: &'static str) -> PatchObject<'static> {
+        serde_json::from_str(patch).unwrap()
+    }
+
+    #[test]
+    fn patch_sets_and_removes_nested_properties() {
+        let object = json!({"name": "Friends", "shareWith": {"a": {"mayRead": true}}});
+
+        let patched = patch(r#"{"name": "Family", "shareWith/a/mayRead": null}"#)
+            .apply(&object)
+            .unwrap();
+
+        assert_eq!(patched, json!({"name": "Family", "shareWith": {"a": {}}}));
+    }
+
+    #[test]
+    fn patch_with_overlapping_or_dangling_paths_is_invalid() {
+        let object = json!({"shareWith": {}});
+
+        for invalid in [
+            r#"{"shareWith": {}, "shareWith/a": {}}"#,
+            r#"{"missing/a": true}"#,
+            r#"{"shareWith/a/mayRead": true}"#,
+        ] {
+            let error = patch(invalid).apply(&object).unwrap_err();
+            assert!(matches!(error.type_, SetErrorKind::InvalidPatch));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Clients that can't hold a connection open to the server, such as those on
+//! mobile devices, can instead register the URL of a push service they're
+//! reachable through. The server then POSTs each `StateChange` to that URL.
+//!
+//! Push subscriptions belong to the user rather than to any account, so the
+//! methods on them take no `accountId` and return no `state`.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    common::{CreationId, Id, UtcDate},
+    endpoints::object::set::{PatchObject, SetError},
+    events::Event,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscription<'a> {
+    /// The id of the push subscription.
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Id<'a>>,
+    /// An id that uniquely identifies the client and device it's running on,
+    /// which must not change between sessions.
+    #[serde(borrow)]
+    pub device_client_id: Cow<'a, str>,
+    /// An absolute URL of the push service, which must use the `https`
+    /// scheme. Never returned by `PushSubscription/get`.
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<Cow<'a, str>>,
+    /// Client-generated keys used to encrypt the push payload. Never
+    /// returned by `PushSubscription/get`.
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<PushSubscriptionKeys<'a>>,
+    /// The verification code the server sent to the push service, which
+    /// the client sets to prove the subscription can be delivered to.
+    #[serde(borrow, default)]
+    pub verification_code: Option<Cow<'a, str>>,
+    /// The time at which the subscription stops being delivered to.
+    #[serde(default)]
+    pub expires: Option<UtcDate>,
+    /// The data types the client wants to be notified about, or null for
+    /// all of them.
+    #[serde(borrow, default)]
+    pub types: Option<Vec<Cow<'a, str>>>,
+}
+
+/// The keys used to encrypt push payloads, as defined by [RFC 8291].
+///
+/// [RFC 8291]: https://datatracker.ietf.org/doc/html/rfc8291
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionKeys<'a> {
+    /// The P-256 ECDH public key of the client, base64url encoded.
+    #[serde(borrow)]
+    pub p256dh: Cow<'a, str>,
+    /// The authentication secret, base64url encoded.
+    #[serde(borrow)]
+    pub auth: Cow<'a, str>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionGetParams<'a> {
+    /// The ids of the subscriptions to return, or null for all of them.
+    #[serde(borrow, default)]
+    pub ids: Option<Vec<Id<'a>>>,
+    /// The properties to return for each subscription, or null for all of
+    /// them. The `id` is always returned.
+    #[serde(borrow, default)]
+    pub properties: Option<Vec<Cow<'a, str>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionGetResponse<'a> {
+    /// The subscriptions requested, each containing only the properties
+    /// that were asked for.
+    pub list: Vec<Value>,
+    /// The ids requested that don't exist.
+    #[serde(borrow)]
+    pub not_found: Vec<Id<'a>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionSetParams<'a> {
+    /// Subscriptions to create, keyed by their creation id.
+    #[serde(borrow, default)]
+    pub create: Option<HashMap<CreationId<'a>, PushSubscription<'a>>>,
+    /// Patches to apply to existing subscriptions.
+    #[serde(borrow, default)]
+    pub update: Option<HashMap<Id<'a>, PatchObject<'a>>>,
+    /// The ids of the subscriptions to destroy.
+    #[serde(borrow, default)]
+    pub destroy: Option<Vec<Id<'a>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionSetResponse<'a> {
+    /// The server-set properties of each subscription created, keyed by
+    /// its creation id.
+    #[serde(borrow)]
+    pub created: Option<HashMap<CreationId<'a>, Value>>,
+    /// Any property changed in a way not requested by the patch, or null if
+    /// none, keyed by the id of each subscription updated.
+    #[serde(borrow)]
+    pub updated: Option<HashMap<Id<'a>, Option<Value>>>,
+    /// The ids of the subscriptions destroyed.
+    #[serde(borrow)]
+    pub destroyed: Option<Vec<Id<'a>>>,
+    /// The reason each subscription that couldn't be created failed.
+    #[serde(borrow)]
+    pub not_created: Option<HashMap<CreationId<'a>, SetError<'a>>>,
+    /// The reason each subscription that couldn't be updated failed.
+    #[serde(borrow)]
+    pub not_updated: Option<HashMap<Id<'a>, SetError<'a>>>,
+    /// The reason each subscription that couldn't be destroyed failed.
+    #[serde(borrow)]
+    pub not_destroyed: Option<HashMap<Id<'a>, SetError<'a>>>,
+}
+
+/// Sent to the URL of a new subscription so the client can prove that it
+/// receives what's pushed to it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PushVerification<'a> {
+    #[serde(borrow)]
+    pub push_subscription_id: Id<'a>,
+    #[serde(borrow)]
+    pub verification_code: Cow<'a, str>,
+}
+
+impl<'a> Event for PushVerification<'a> {
+    const NAME: &'static str = "PushVerification";
+}
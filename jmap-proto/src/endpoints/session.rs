@@ -82,6 +82,21 @@ pub struct Session<'a> {
     pub state: SessionState<'a>,
 }
 
+impl Session<'_> {
+    /// The names of the session's top-level properties, as serialised.
+    pub const PROPERTIES: [&'static str; 9] = [
+        "capabilities",
+        "accounts",
+        "primaryAccounts",
+        "username",
+        "apiUrl",
+        "downloadUrl",
+        "uploadUrl",
+        "eventSourceUrl",
+        "state",
+    ];
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -140,9 +155,47 @@ pub struct Account<'a> {
     /// keys is an object with further information about the account's
     /// permissions and restrictions with respect to this capability,
     /// as defined in the capability's specification.
-    pub account_capabilities: AccountCapabilities,
+    #[serde(borrow)]
+    pub account_capabilities: AccountCapabilities<'a>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct AccountCapabilities {}
+/// The metadata of a capability, as held under its URI within a
+/// capabilities map.
+pub trait Capability: Serialize {
+    /// The URI the metadata is keyed by (eg. `urn:ietf:params:jmap:contacts`).
+    const URI: &'static str;
+}
+
+/// The capabilities of an account, keyed by their URI.
+///
+/// Metadata is kept as it was sent so that capabilities unknown to this crate
+/// are passed through untouched, known capabilities can be read out with
+/// [`AccountCapabilities::get`].
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(transparent)]
+pub struct AccountCapabilities<'a>(#[serde(borrow)] pub HashMap<Cow<'a, str>, Value>);
+
+impl<'a> AccountCapabilities<'a> {
+    /// Parses the metadata of a capability, or returns `None` if the account
+    /// doesn't have the capability.
+    pub fn get<'s, C>(&'s self) -> Option<Result<C, serde_json::Error>>
+    where
+        C: Capability + Deserialize<'s>,
+    {
+        self.0.get(C::URI).map(C::deserialize)
+    }
+
+    /// Whether the account has the capability with the given URI.
+    pub fn contains(&self, uri: &str) -> bool {
+        self.0.contains_key(uri)
+    }
+
+    /// Adds the capability to the account, replacing any existing metadata
+    /// for it.
+    pub fn insert<C: Capability>(&mut self, capability: &C) {
+        self.0.insert(
+            Cow::Borrowed(C::URI),
+            serde_json::to_value(capability).unwrap(),
+        );
+    }
+}
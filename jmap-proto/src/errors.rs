@@ -16,6 +16,102 @@ pub struct RequestError {
     pub meta: HashMap<String, Value>,
 }
 
+impl RequestError {
+    /// Builds a `limit` problem for a request that would exceed the named
+    /// limit (eg. `maxCallsInRequest`).
+    pub fn limit(limit: &str, detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            type_: ProblemType::OverLimit,
+            status: 400,
+            detail: detail.into(),
+            meta: HashMap::from([("limit".to_string(), Value::String(limit.to_string()))]),
+        }
+    }
+
+    /// Builds an `unknownCapability` problem for a request using a
+    /// capability the server doesn't support.
+    pub fn unknown_capability(capability: &str) -> Self {
+        Self {
+            type_: ProblemType::UnknownCapability,
+            status: 400,
+            detail: format!("The capability {capability} is not supported").into(),
+            meta: HashMap::new(),
+        }
+    }
+
+    /// Builds a problem for a request body that isn't I-JSON.
+    pub fn not_json(detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            type_: ProblemType::NotJson,
+            status: 400,
+            detail: detail.into(),
+            meta: HashMap::new(),
+        }
+    }
+
+    /// Builds a problem for a request body that's JSON, but not a `Request`.
+    pub fn not_request(detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            type_: ProblemType::NotRequest,
+            status: 400,
+            detail: detail.into(),
+            meta: HashMap::new(),
+        }
+    }
+
+    /// Builds a problem for a request whose URI template variable, such as
+    /// `blobId`, was filled in with a value that isn't valid for it.
+    pub fn invalid_variable(variable: &str, detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            type_: ProblemType::Blank,
+            status: 400,
+            detail: detail.into(),
+            meta: HashMap::from([("variable".to_string(), Value::String(variable.to_string()))]),
+        }
+    }
+
+    /// Builds a problem for a request the server failed to handle.
+    pub fn internal(detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            type_: ProblemType::Blank,
+            status: 500,
+            detail: detail.into(),
+            meta: HashMap::new(),
+        }
+    }
+
+    /// Builds a problem for a request the server can't handle right now.
+    pub fn unavailable(detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            type_: ProblemType::Blank,
+            status: 503,
+            detail: detail.into(),
+            meta: HashMap::new(),
+        }
+    }
+
+    /// Builds a problem for a request the authenticated user isn't allowed
+    /// to make.
+    pub fn forbidden(detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            type_: ProblemType::Blank,
+            status: 403,
+            detail: detail.into(),
+            meta: HashMap::new(),
+        }
+    }
+
+    /// Builds a problem for a resource, such as a blob, that doesn't exist.
+    pub fn not_found(detail: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            type_: ProblemType::Blank,
+            status: 404,
+            detail: detail.into(),
+            meta: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ProblemType {
     /// The client included a capability in the "using" property of the
@@ -37,6 +133,10 @@ pub enum ProblemType {
     /// object, containing the name of the limit being applied.
     #[serde(rename = "urn:ietf:params:jmap:error:limit")]
     OverLimit,
+    /// The problem has no further semantics beyond that of the HTTP status
+    /// code (RFC 7807).
+    #[serde(rename = "about:blank")]
+    Blank,
 }
 
 /// If a method encounters an error, the appropriate "error" response
@@ -48,7 +148,8 @@ pub enum ProblemType {
 /// normal.  Errors at the method level MUST NOT generate an HTTP-level
 /// error.
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, Display)]
-#[serde(tag = "type")]
+#[serde(tag = "type", rename_all = "camelCase")]
+#[strum(serialize_all = "camelCase")]
 pub enum MethodError {
     /// Some internal server resource was temporarily unavailable.
     ///
@@ -90,6 +191,31 @@ pub enum MethodError {
     /// This method modifies state, but the account is read-only (as returned on
     /// the corresponding Account object in the JMAP Session resource).
     AccountReadOnly,
+    /// An "ifInState" argument was supplied, and it does not match the
+    /// current state.
+    ///
+    /// None of the changes requested by the method call are applied.
+    StateMismatch,
+    /// An anchor argument was supplied to a `/query` call, but it cannot be
+    /// found in the results of the query.
+    AnchorNotFound,
+    /// The sort is syntactically valid, but it includes a property the
+    /// server does not support sorting on, or a collation method it does
+    /// not recognise.
+    UnsupportedSort,
+    /// The filter is syntactically valid, but the server cannot process it.
+    UnsupportedFilter,
+    /// The number of ids requested by the client exceeds the maximum number
+    /// the server is willing to process in a single method call.
+    RequestTooLarge,
+    /// The server cannot calculate the changes from the state string given
+    /// by the client, usually because the client's state is too old or was
+    /// never handed out. The client MUST invalidate its cache of the data
+    /// type and refetch it.
+    CannotCalculateChanges,
+    /// There are more changes than the client's `maxChanges` argument. The
+    /// client MUST invalidate its cache of the query and fetch it again.
+    TooManyChanges,
 }
 
 impl MethodError {
@@ -106,4 +232,20 @@ impl MethodError {
             request_id,
         }
     }
+
+    /// Builds the error response along with a "description" explaining what
+    /// the problem was, intended to help client developers debug rather than
+    /// to be shown to end users.
+    pub fn into_invocation_with_description(
+        self,
+        request_id: Cow<'_, str>,
+        description: String,
+    ) -> Invocation<'_> {
+        let mut invocation = self.into_invocation(request_id);
+        invocation.arguments.0.insert(
+            Cow::Borrowed("description"),
+            Argument::Absolute(Value::String(description)),
+        );
+        invocation
+    }
 }
@@ -10,8 +10,10 @@ use crate::{common::Id, endpoints::object::ObjectState, events::Event};
 #[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct StateChange<'a> {
+    /// The new state of each data type that changed, keyed by the account
+    /// the change was made within.
     #[serde(borrow)]
-    changed: HashMap<Id<'a>, HashMap<Cow<'a, str>, ObjectState<'a>>>,
+    pub changed: HashMap<Id<'a>, HashMap<Cow<'a, str>, ObjectState<'a>>>,
 }
 
 impl<'a> Event for StateChange<'a> {
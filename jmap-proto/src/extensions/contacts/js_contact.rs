@@ -1,12 +1,56 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, cmp::Ordering, collections::HashMap};
 
 use chrono::NaiveDate;
 use serde::{
-    ser::SerializeMap, Deserialize, Serialize, Serializer, __private::ser::FlatMapSerializer,
+    __private::ser::FlatMapSerializer, ser::SerializeMap, Deserialize, Serialize, Serializer,
 };
 use serde_json::Value;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use strum::{Display, EnumString};
 
-use crate::common::{Id, UnsignedInt, UtcDate};
+use crate::{
+    common::{Id, UnsignedInt, UtcDate},
+    endpoints::object::set::SetError,
+    extensions::contacts::language_tag,
+    pointer,
+    pointer::Pointer,
+};
+
+/// How large the maps on a [`Card`] may grow, enforced by
+/// [`Card::validate`].
+#[derive(Copy, Clone, Debug)]
+pub struct CardLimits {
+    /// The maximum number of entries in any of the id-keyed maps, such as
+    /// `anniversaries` or `photos`.
+    pub max_map_entries: usize,
+    /// The maximum size, in octets, each of the free-form `localizations`
+    /// and `timeZones` maps may serialise to.
+    pub max_free_form_map_size: usize,
+    /// Whether values outside the enumerations of the spec, listed by
+    /// [`Card::unknown_values`], are rejected rather than kept verbatim.
+    pub reject_unknown_values: bool,
+}
+
+/// How the categories given to [`Card::has_categories`] must match.
+#[derive(Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum CategoryMatch {
+    /// The card is in at least one of the categories.
+    #[default]
+    Any,
+    /// The card is in every one of the categories.
+    All,
+}
+
+/// An identifier for a Card or CardGroup, associating it as the same
+/// across different systems, address books and views.
+///
+/// Unlike an [`Id`], which the server assigns, the uid is chosen by
+/// whatever created the card and is usually a URI such as `urn:uuid:...`
+/// (RFC 9553 section 2.1.9), so it can be any string.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
+#[serde(transparent)]
+pub struct Uid<'a>(#[serde(borrow)] pub Cow<'a, str>);
 
 #[derive(Deserialize, Copy, Clone, Debug, Hash, Eq, PartialEq)]
 pub struct TypeWrapper<T>(T);
@@ -41,10 +85,10 @@ pub struct CardGroup<'a> {
     /// An identifier, used to associate the object as the same across different
     /// systems, addressbooks and views.
     #[serde(borrow)]
-    uid: Id<'a>,
+    uid: Uid<'a>,
     /// The set is represented as an object, with each key being the uid of another Card or
     /// CardGroup. The value for each key in the object MUST be true.
-    members: HashMap<Id<'a>, bool>,
+    members: HashMap<Uid<'a>, bool>,
     /// The user-visible name for the group, e.g. "Friends". This may be any UTF-8 string of at
     /// least 1 character in length and maximum 255 octets in size. The same name may be used by
     /// two different groups.
@@ -60,7 +104,7 @@ pub struct Card<'a> {
     /// An identifier, used to associate the object as the same across different
     /// systems, addressbooks and views.
     #[serde(borrow)]
-    uid: Id<'a>,
+    uid: Uid<'a>,
     /// The identifier for the product that created the Card object.
     prod_id: Option<Cow<'a, str>>,
     /// The date and time when this Card object was created.
@@ -72,7 +116,7 @@ pub struct Card<'a> {
     /// Relates the object to other Card and CardGroup objects. This is
     /// represented as a map, where each key is the
     #[serde(default)]
-    related_to: HashMap<Id<'a>, TypeWrapper<Relation>>,
+    related_to: HashMap<Uid<'a>, TypeWrapper<Relation>>,
     /// Language used for free-form text on this card.
     language: Option<Cow<'a, str>>,
     /// The name components of the name of the entity represented by this Card.
@@ -87,7 +131,7 @@ pub struct Card<'a> {
     /// Implementations SHOULD prefer using the name property over this one
     /// and SHOULD NOT store the concatenated name component values in this
     /// property.
-    #[serde(default)]
+    #[serde(default, borrow)]
     full_name: Cow<'a, str>,
     /// The nick names of the entity represented by this card.
     #[serde(default)]
@@ -139,7 +183,7 @@ pub struct Card<'a> {
     #[serde(default)]
     personal_info: HashMap<Id<'a>, TypeWrapper<PersonalInfo<'a>>>,
     /// Arbitrary notes about the entity represented by this card.
-    #[serde(default)]
+    #[serde(default, borrow)]
     notes: Cow<'a, str>,
     /// The set of free-text or URI categories that relate to the card. The set is represented as
     /// an object, with each key being a category. The value for each key in the object MUST be
@@ -152,6 +196,393 @@ pub struct Card<'a> {
     time_zones: HashMap<Cow<'a, str>, Value>,
 }
 
+impl Card<'_> {
+    /// The full name of the entity the card represents, if one is set.
+    pub fn full_name(&self) -> Option<&str> {
+        Some(self.full_name.as_ref()).filter(|name| !name.is_empty())
+    }
+
+    /// Joins the name components into a full name, in order. Separator
+    /// components are used verbatim, other components are separated by a
+    /// single space where no separator was given.
+    pub fn derive_full_name(&self) -> String {
+        let mut full_name = String::new();
+        let mut needs_space = false;
+
+        for TypeWrapper(component) in &self.name {
+            if component.type_ == NameComponentKind::Separator {
+                needs_space = false;
+            } else if std::mem::replace(&mut needs_space, true) {
+                full_name.push(' ');
+            }
+
+            full_name.push_str(&component.value);
+        }
+
+        full_name
+    }
+
+    /// Sets the server-managed `created` and `updated` timestamps on a card
+    /// being created.
+    ///
+    /// Both may only be set by the server, so a card created with either
+    /// already set is rejected with an `invalidProperties` error.
+    pub fn set_created(&mut self, now: UtcDate) -> Result<(), SetError<'static>> {
+        let mut invalid = Vec::new();
+
+        if self.created.is_some() {
+            invalid.push(Cow::Borrowed("created"));
+        }
+
+        if self.updated.is_some() {
+            invalid.push(Cow::Borrowed("updated"));
+        }
+
+        if !invalid.is_empty() {
+            return Err(SetError::invalid_properties(
+                invalid,
+                Some(Cow::Borrowed("created and updated are set by the server")),
+            ));
+        }
+
+        self.created = Some(now);
+        self.updated = Some(now);
+
+        Ok(())
+    }
+
+    /// Sets the server-managed `updated` timestamp on a card after a patch
+    /// has been applied to `current`.
+    ///
+    /// Clients may send back `created` and `updated` as part of an update
+    /// only if they're identical to their current values, otherwise an
+    /// `invalidProperties` error is returned. Cards stored before `created`
+    /// was managed by the server are left without one.
+    pub fn set_updated(
+        &mut self,
+        current: &Card<'_>,
+        now: UtcDate,
+    ) -> Result<(), SetError<'static>> {
+        let mut invalid = Vec::new();
+
+        if self.created != current.created {
+            invalid.push(Cow::Borrowed("created"));
+        }
+
+        if self.updated != current.updated {
+            invalid.push(Cow::Borrowed("updated"));
+        }
+
+        if !invalid.is_empty() {
+            return Err(SetError::invalid_properties(
+                invalid,
+                Some(Cow::Borrowed(
+                    "created and updated are set by the server and can't be changed",
+                )),
+            ));
+        }
+
+        self.updated = Some(now);
+
+        Ok(())
+    }
+
+    /// The properties of the card set by the server rather than the client,
+    /// to be returned in the `created` and `updated` maps of a `/set`
+    /// response.
+    pub fn server_set_properties(&self) -> serde_json::Map<String, Value> {
+        let mut properties = serde_json::Map::new();
+
+        if let Some(created) = &self.created {
+            properties.insert(
+                "created".to_string(),
+                serde_json::to_value(created).unwrap(),
+            );
+        }
+
+        if let Some(updated) = &self.updated {
+            properties.insert(
+                "updated".to_string(),
+                serde_json::to_value(updated).unwrap(),
+            );
+        }
+
+        properties
+    }
+
+    /// Compares two cards by the given property, for sorting `/query`
+    /// results. Returns `None` for properties cards can't be sorted by.
+    ///
+    /// Cards without the property sort before those with it.
+    pub fn compare_by(&self, other: &Card<'_>, property: &str) -> Option<Ordering> {
+        match property {
+            "created" => Some(self.created.cmp(&other.created)),
+            "updated" => Some(self.updated.cmp(&other.updated)),
+            _ => None,
+        }
+    }
+
+    /// Whether the card is in the given categories, for filtering `/query`
+    /// results. Categories are compared exactly, as they're free text that
+    /// may as well be URIs.
+    ///
+    /// Categories set to `false` in the card's map don't count.
+    pub fn has_categories<S: AsRef<str>>(&self, categories: &[S], mode: CategoryMatch) -> bool {
+        let has = |category: &S| self.categories.get(category.as_ref()).is_some_and(|&v| v);
+
+        match mode {
+            CategoryMatch::Any => categories.iter().any(has),
+            CategoryMatch::All => categories.iter().all(has),
+        }
+    }
+
+    /// The paths of the values on the card outside the enumerations of the
+    /// spec, such as a `home` context or a vendor's phone feature, which are
+    /// kept verbatim rather than rejected.
+    pub fn unknown_values(&self) -> Vec<Cow<'static, str>> {
+        fn unknown<'k, K: Known + 'k>(
+            out: &mut Vec<Cow<'static, str>>,
+            path: String,
+            values: impl IntoIterator<Item = &'k K>,
+        ) {
+            out.extend(
+                values
+                    .into_iter()
+                    .filter(|value| !value.is_known())
+                    .map(|value| property_path(&path, &value.to_string())),
+            );
+        }
+
+        let mut out = Vec::new();
+
+        if self.kind.as_ref().is_some_and(|kind| !kind.is_known()) {
+            out.push(Cow::Borrowed("kind"));
+        }
+
+        for (uid, TypeWrapper(relation)) in &self.related_to {
+            let path = format!("relatedTo/{}/relation", pointer::escape(&uid.0));
+            unknown(&mut out, path, relation.relation.keys());
+        }
+
+        for (id, TypeWrapper(email)) in &self.emails {
+            let path = format!("emails/{}/contexts", pointer::escape(&id.0));
+            unknown(&mut out, path, email.contexts.keys());
+        }
+
+        for (id, TypeWrapper(phone)) in &self.phones {
+            let id = pointer::escape(&id.0);
+            unknown(
+                &mut out,
+                format!("phones/{id}/features"),
+                phone.features.keys(),
+            );
+            unknown(
+                &mut out,
+                format!("phones/{id}/contexts"),
+                phone.contexts.keys(),
+            );
+        }
+
+        for (id, TypeWrapper(resource)) in &self.online {
+            let path = format!("online/{}/context", pointer::escape(&id.0));
+            unknown(&mut out, path, resource.context.keys());
+        }
+
+        for (id, TypeWrapper(address)) in &self.address {
+            let path = format!("address/{}/context", pointer::escape(&id.0));
+            unknown(&mut out, path, address.context.keys());
+        }
+
+        for (tag, TypeWrapper(language)) in &self.preferred_contact_languages {
+            if language
+                .context
+                .as_ref()
+                .is_some_and(|context| !context.is_known())
+            {
+                out.push(Cow::Owned(format!(
+                    "preferredContactLanguages/{}/context",
+                    pointer::escape(tag)
+                )));
+            }
+        }
+
+        out.sort_unstable();
+        out
+    }
+
+    /// Validates the client-chosen keys of the card's id-keyed maps, ensuring
+    /// each is a valid [`Id`] and that no map, `relatedTo` included, holds
+    /// more entries than the `limits` allow, along with the free-form `localizations` and
+    /// `timeZones` maps, which must also each serialise to no more octets
+    /// than the `limits` allow.
+    ///
+    /// Returns an `invalidProperties` error naming every offending property,
+    /// or the path to the offending entry of a free-form map.
+    pub fn validate(&self, limits: CardLimits) -> Result<(), SetError<'static>> {
+        fn check<T>(
+            invalid: &mut Vec<Cow<'static, str>>,
+            property: &'static str,
+            map: &HashMap<Id<'_>, T>,
+            max_entries: usize,
+        ) {
+            if map.len() > max_entries || !map.keys().all(Id::is_valid) {
+                invalid.push(Cow::Borrowed(property));
+            }
+        }
+
+        let CardLimits {
+            max_map_entries,
+            max_free_form_map_size,
+            reject_unknown_values,
+        } = limits;
+
+        let mut invalid = Vec::new();
+
+        // keyed by the uids of other cards, which can be any string
+        if self.related_to.len() > max_map_entries {
+            invalid.push(Cow::Borrowed("relatedTo"));
+        }
+
+        check(
+            &mut invalid,
+            "organizations",
+            &self.organizations,
+            max_map_entries,
+        );
+        check(&mut invalid, "titles", &self.titles, max_map_entries);
+        check(&mut invalid, "emails", &self.emails, max_map_entries);
+        check(&mut invalid, "phones", &self.phones, max_map_entries);
+        check(&mut invalid, "online", &self.online, max_map_entries);
+        check(&mut invalid, "photos", &self.photos, max_map_entries);
+        check(&mut invalid, "address", &self.address, max_map_entries);
+        check(
+            &mut invalid,
+            "anniversaries",
+            &self.anniversaries,
+            max_map_entries,
+        );
+        check(
+            &mut invalid,
+            "personalInfo",
+            &self.personal_info,
+            max_map_entries,
+        );
+
+        check_free_form(
+            &mut invalid,
+            "localizations",
+            &self.localizations,
+            limits,
+            |tag, patch| language_tag::is_well_formed(tag) && is_localization(patch),
+        );
+        check_free_form(
+            &mut invalid,
+            "timeZones",
+            &self.time_zones,
+            limits,
+            |id, time_zone| id.starts_with('/') && is_time_zone(time_zone),
+        );
+
+        if reject_unknown_values {
+            invalid.extend(self.unknown_values());
+        }
+
+        if invalid.is_empty() {
+            Ok(())
+        } else {
+            Err(SetError::invalid_properties(
+                invalid,
+                Some(Cow::Owned(format!(
+                    "map keys must be valid ids and maps may contain at most \
+                     {max_map_entries} entries, localizations must be patches keyed by \
+                     language tags and timeZones definitions keyed by custom time zone ids, \
+                     each at most {max_free_form_map_size} octets"
+                ))),
+            ))
+        }
+    }
+}
+
+/// Checks a map of arbitrary values is within the `limits` once serialised,
+/// and that every entry is accepted by `is_valid`, naming each offending
+/// entry by its path.
+fn check_free_form(
+    invalid: &mut Vec<Cow<'static, str>>,
+    property: &'static str,
+    map: &HashMap<Cow<'_, str>, Value>,
+    limits: CardLimits,
+    is_valid: impl Fn(&str, &Value) -> bool,
+) {
+    if map.len() > limits.max_map_entries
+        || serde_json::to_vec(map).map_or(true, |v| v.len() > limits.max_free_form_map_size)
+    {
+        invalid.push(Cow::Borrowed(property));
+    }
+
+    let mut keys: Vec<_> = map
+        .iter()
+        .filter(|(key, value)| !is_valid(key, value))
+        .map(|(key, _)| key)
+        .collect();
+    keys.sort_unstable();
+
+    invalid.extend(keys.into_iter().map(|key| property_path(property, key)));
+}
+
+/// The path to an entry of a map property, with the key escaped as a JSON
+/// pointer reference token so that keys containing `/` stay unambiguous.
+fn property_path(property: &str, key: &str) -> Cow<'static, str> {
+    Cow::Owned(format!("{property}/{}", pointer::escape(key)))
+}
+
+/// Whether the value is a patch object, none of whose paths target the
+/// `localizations` property itself.
+fn is_localization(patch: &Value) -> bool {
+    patch.as_object().is_some_and(|patch| {
+        patch.keys().all(|path| {
+            Pointer::parse_patch(path)
+                .is_ok_and(|path| path.tokens().first().map(AsRef::as_ref) != Some("localizations"))
+        })
+    })
+}
+
+/// Whether the value has the shape of an RFC 8984 `TimeZone`: a `tzId`, and
+/// at least one `standard` or `daylight` rule, each with the start and
+/// offsets of the transition.
+fn is_time_zone(time_zone: &Value) -> bool {
+    fn is_rules(rules: Option<&Value>) -> Option<bool> {
+        let rules = rules?;
+
+        Some(rules.as_array().is_some_and(|rules| {
+            rules.iter().all(|rule| {
+                rule.as_object().is_some_and(|rule| {
+                    has_type(rule, "TimeZoneRule")
+                        && ["start", "offsetFrom", "offsetTo"]
+                            .iter()
+                            .all(|key| rule.get(*key).is_some_and(Value::is_string))
+                })
+            })
+        }))
+    }
+
+    fn has_type(object: &serde_json::Map<String, Value>, kind: &str) -> bool {
+        object.get("@type").is_none_or(|v| v.as_str() == Some(kind))
+    }
+
+    let Some(time_zone) = time_zone.as_object() else {
+        return false;
+    };
+
+    let standard = is_rules(time_zone.get("standard"));
+    let daylight = is_rules(time_zone.get("daylight"));
+
+    has_type(time_zone, "TimeZone")
+        && time_zone.get("tzId").is_some_and(Value::is_string)
+        && (standard.is_some() || daylight.is_some())
+        && standard.unwrap_or(true)
+        && daylight.unwrap_or(true)
+}
+
 /// Defines personal information about the entity represented by this card.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
@@ -284,15 +715,38 @@ impl TypedStruct for Address<'_> {
     const KIND: &'static str = "Address";
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
-#[serde(rename_all = "camelCase", untagged)]
+/// The contexts in which an address may be used, this extends the common
+/// [`Context`] values with address-specific ones.
+#[derive(SerializeDisplay, DeserializeFromStr, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum AddressContext {
     /// An address to be used for billing.
     Billing,
     /// An address to be used for delivering physical items
     Postal,
-    /// A normal context
-    Other(Context),
+    /// Any of the common contexts, including ones unknown to us.
+    Context(Context),
+}
+
+impl std::fmt::Display for AddressContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Billing => f.write_str("billing"),
+            Self::Postal => f.write_str("postal"),
+            Self::Context(context) => context.fmt(f),
+        }
+    }
+}
+
+impl std::str::FromStr for AddressContext {
+    type Err = strum::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "billing" => Self::Billing,
+            "postal" => Self::Postal,
+            other => Self::Context(other.parse()?),
+        })
+    }
 }
 
 ///  The street address. The concatenation of the component values, separated by whitespace, SHOULD
@@ -330,7 +784,7 @@ pub enum StreetComponentKind {
 }
 
 /// Defines the preferred method to contact the holder of this card.
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct ContactLanguage {
     /// Defines the context in which to use this language.
@@ -452,8 +906,10 @@ impl TypedStruct for Phone<'_> {
 }
 
 /// The email addresses to contact the entity represented by this card.
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
-#[serde(rename_all = "kebab-case")]
+#[derive(
+    SerializeDisplay, DeserializeFromStr, Display, EnumString, Clone, Debug, PartialEq, Eq, Hash,
+)]
+#[strum(serialize_all = "kebab-case")]
 pub enum PhoneFeature {
     /// The number is for calling by voice.
     Voice,
@@ -472,6 +928,21 @@ pub enum PhoneFeature {
     /// The number is for some other purpose. The label property MAY be included
     /// to display next to the number to help the user identify its purpose.
     Other,
+    /// A feature unknown to us, the original value is preserved so it can be
+    /// written back out verbatim.
+    #[strum(default)]
+    Unknown(String),
+}
+
+impl PhoneFeature {
+    /// Maps a value of the vCard `TYPE` parameter of a `TEL` property to a
+    /// feature, those unknown to us being kept lowercased. The `home` and
+    /// `work` values are contexts rather than features, and should be mapped
+    /// with [`Context::from_vcard_type`] instead.
+    pub fn from_vcard_type(value: &str) -> Self {
+        let value = value.to_ascii_lowercase();
+        value.parse().unwrap_or(Self::Unknown(value))
+    }
 }
 
 /// The email addresses to contact the entity represented by this card.
@@ -584,8 +1055,10 @@ impl TypedStruct for Relation {
 /// should be used. For example, someone might have distinct phone numbers
 /// for work and private contexts. The Context data type enumerates common
 /// contexts.
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
-#[serde(rename_all = "kebab-case")]
+#[derive(
+    SerializeDisplay, DeserializeFromStr, Display, EnumString, Clone, Debug, PartialEq, Eq, Hash,
+)]
+#[strum(serialize_all = "kebab-case")]
 pub enum Context {
     /// The contact information may be used to contact the card holder in a
     /// private context.
@@ -596,10 +1069,29 @@ pub enum Context {
     /// The contact information may be used to contact the card holder in some
     /// other context.
     Other,
+    /// A context unknown to us (eg. `home` or a vendor extension), the
+    /// original value is preserved so it can be written back out verbatim.
+    #[strum(default)]
+    Unknown(String),
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, Hash, PartialEq, Eq)]
-#[serde(rename_all = "kebab-case")]
+impl Context {
+    /// Maps a value of the vCard `TYPE` parameter to a context, as per
+    /// RFC 9555 `home` becomes `private`. Parameter values are
+    /// case-insensitive, so those unknown to us are kept lowercased.
+    pub fn from_vcard_type(value: &str) -> Self {
+        match value.to_ascii_lowercase().as_str() {
+            "home" => Self::Private,
+            "work" => Self::Work,
+            other => Self::Unknown(other.to_string()),
+        }
+    }
+}
+
+#[derive(
+    SerializeDisplay, DeserializeFromStr, Display, EnumString, Clone, Debug, Hash, PartialEq, Eq,
+)]
+#[strum(serialize_all = "kebab-case")]
 pub enum RelationKind {
     Contact,
     Acquaintance,
@@ -621,11 +1113,27 @@ pub enum RelationKind {
     Me,
     Agent,
     Emergency,
+    /// A relation unknown to us, the original value is preserved so it can be
+    /// written back out verbatim.
+    #[strum(default)]
+    Unknown(String),
+}
+
+impl RelationKind {
+    /// Maps a value of the vCard `TYPE` parameter of a `RELATED` property to
+    /// a relation, the two sharing their values. Those unknown to us are
+    /// kept lowercased.
+    pub fn from_vcard_type(value: &str) -> Self {
+        let value = value.to_ascii_lowercase();
+        value.parse().unwrap_or(Self::Unknown(value))
+    }
 }
 
 /// The kind of the entity the Card represents.
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, Hash, Eq, PartialEq)]
-#[serde(rename_all = "camelCase")]
+#[derive(
+    SerializeDisplay, DeserializeFromStr, Display, EnumString, Clone, Debug, Hash, Eq, PartialEq,
+)]
+#[strum(serialize_all = "camelCase")]
 pub enum CardKind {
     /// A single person
     Individual,
@@ -637,4 +1145,293 @@ pub enum CardKind {
     Device,
     /// A software application
     Application,
+    /// A kind unknown to us, the original value is preserved so it can be
+    /// written back out verbatim.
+    #[strum(default)]
+    Unknown(String),
+}
+
+/// Implemented by the enumerations that keep values unknown to us
+/// verbatim, see [`Card::unknown_values`].
+trait Known: std::fmt::Display {
+    /// Whether the value is one defined by the spec.
+    fn is_known(&self) -> bool;
+}
+
+impl Known for Context {
+    fn is_known(&self) -> bool {
+        !matches!(self, Self::Unknown(_))
+    }
+}
+
+impl Known for AddressContext {
+    fn is_known(&self) -> bool {
+        match self {
+            Self::Billing | Self::Postal => true,
+            Self::Context(context) => context.is_known(),
+        }
+    }
+}
+
+impl Known for PhoneFeature {
+    fn is_known(&self) -> bool {
+        !matches!(self, Self::Unknown(_))
+    }
+}
+
+impl Known for RelationKind {
+    fn is_known(&self) -> bool {
+        !matches!(self, Self::Unknown(_))
+    }
+}
+
+impl Known for CardKind {
+    fn is_known(&self) -> bool {
+        !matches!(self, Self::Unknown(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const UID: &str = "urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6";
+    const OTHER_UID: &str = "urn:uuid:3b8c9a2e-1f0d-4e8a-9a55-0d5cbb1c6c1e";
+
+    #[test]
+    fn uids_can_be_uris() {
+        let card = json!({
+            "uid": UID,
+            "relatedTo": {
+                OTHER_UID: { "@type": "Relation", "relation": { "friend": true } },
+            },
+        });
+        let json = card.to_string();
+        let parsed: Card<'_> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.uid, Uid(Cow::Borrowed(UID)));
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap()["relatedTo"],
+            card["relatedTo"]
+        );
+
+        let json = json!({ "uid": UID, "members": { OTHER_UID: true } }).to_string();
+        let group: CardGroup<'_> = serde_json::from_str(&json).unwrap();
+        assert!(group.members.contains_key(&Uid(Cow::Borrowed(OTHER_UID))));
+    }
+
+    #[test]
+    fn full_name_is_derived_from_name_components() {
+        let full_name = |components: &[(&str, &str)]| {
+            let name: Vec<_> = components
+                .iter()
+                .map(
+                    |(kind, value)| json!({"@type": "NameComponent", "type": kind, "value": value}),
+                )
+                .collect();
+            let json = json!({"uid": UID, "name": name}).to_string();
+
+            serde_json::from_str::<Card<'_>>(&json)
+                .unwrap()
+                .derive_full_name()
+        };
+
+        assert_eq!(full_name(&[]), "");
+        assert_eq!(full_name(&[("personal", "Jane")]), "Jane");
+        assert_eq!(
+            full_name(&[("personal", "Jane"), ("surname", "Doe")]),
+            "Jane Doe"
+        );
+        assert_eq!(
+            full_name(&[
+                ("prefix", "Dr"),
+                ("personal", "Jane"),
+                ("additional", "Q"),
+                ("surname", "Doe"),
+                ("separator", ", "),
+                ("suffix", "Esq."),
+            ]),
+            "Dr Jane Q Doe, Esq."
+        );
+
+        // separators are used verbatim, wherever they fall
+        assert_eq!(
+            full_name(&[("surname", "Doe"), ("separator", ","), ("personal", "Jane")]),
+            "Doe,Jane"
+        );
+        assert_eq!(
+            full_name(&[
+                ("separator", "("),
+                ("personal", "Jane"),
+                ("separator", ")"),
+                ("separator", "\n"),
+            ]),
+            "(Jane)\n"
+        );
+    }
+
+    #[test]
+    fn card_borrows_from_the_request() {
+        let card = json!({
+            "uid": UID,
+            "fullName": "Jane Doe",
+            "notes": "Met at the conference",
+        });
+        let json = card.to_string();
+        let parsed: Card<'_> = serde_json::from_str(&json).unwrap();
+
+        assert!(matches!(parsed.uid.0, Cow::Borrowed(_)));
+        assert!(matches!(parsed.full_name, Cow::Borrowed("Jane Doe")));
+        assert!(matches!(
+            parsed.notes,
+            Cow::Borrowed("Met at the conference")
+        ));
+
+        let round_tripped = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(round_tripped["uid"], card["uid"]);
+        assert_eq!(round_tripped["fullName"], card["fullName"]);
+        assert_eq!(round_tripped["notes"], card["notes"]);
+    }
+
+    const LIMITS: CardLimits = CardLimits {
+        max_map_entries: 4,
+        max_free_form_map_size: 1024,
+        reject_unknown_values: false,
+    };
+
+    fn invalid_properties(card: &Value) -> Value {
+        let json = card.to_string();
+        let card: Card<'_> = serde_json::from_str(&json).unwrap();
+
+        card.validate(LIMITS).map_or_else(
+            |e| serde_json::to_value(e).unwrap()["properties"].clone(),
+            |()| json!([]),
+        )
+    }
+
+    #[test]
+    fn localization_patches_are_parsed_as_patch_paths() {
+        let card = json!({
+            "uid": UID,
+            "localizations": {
+                "de": { "titles~1t1/name": "Chef", "name/full": "Hans" },
+            },
+        });
+        assert_eq!(invalid_properties(&card), json!([]));
+
+        for path in ["localizations/en", "titles/t~2"] {
+            let card = json!({ "uid": UID, "localizations": { "de": { path: "x" } } });
+            assert_eq!(invalid_properties(&card), json!(["localizations/de"]));
+        }
+    }
+
+    fn unknown_values_fixture() -> Value {
+        json!({
+            "uid": UID,
+            "kind": "x-robot",
+            "relatedTo": {
+                OTHER_UID: { "relation": { "friend": true, "x-rival": true } },
+            },
+            "emails": {
+                "e1": { "email": "a@example.com", "contexts": { "home": true, "work": true } },
+            },
+            "phones": {
+                "p1": {
+                    "phone": "+1-555-0100",
+                    "features": { "x-satellite": true, "voice": true },
+                    "contexts": { "Home": true, "home": true },
+                },
+            },
+            "online": {
+                "o1": { "resource": "x", "type": "username", "context": { "x-game": true } },
+            },
+            "address": {
+                "a1": { "context": { "billing": true, "x-holiday": true } },
+            },
+            "preferredContactLanguages": {
+                "de": { "context": "x-travel" },
+            },
+        })
+    }
+
+    #[test]
+    fn unknown_enum_values_round_trip() {
+        let fixture = unknown_values_fixture();
+        let json = fixture.to_string();
+        let card: Card<'_> = serde_json::from_str(&json).unwrap();
+        let serialized = serde_json::to_value(&card).unwrap();
+
+        for path in [
+            "/kind",
+            "/relatedTo/urn:uuid:3b8c9a2e-1f0d-4e8a-9a55-0d5cbb1c6c1e/relation",
+            "/emails/e1/contexts",
+            "/phones/p1/features",
+            "/phones/p1/contexts",
+            "/online/o1/context",
+            "/address/a1/context",
+            "/preferredContactLanguages/de/context",
+        ] {
+            assert_eq!(serialized.pointer(path), fixture.pointer(path), "{path}");
+        }
+
+        let reparsed = serialized.to_string();
+        let reparsed: Card<'_> = serde_json::from_str(&reparsed).unwrap();
+        assert_eq!(serde_json::to_value(&reparsed).unwrap(), serialized);
+    }
+
+    #[test]
+    fn unknown_values_are_only_rejected_when_asked() {
+        let expected = json!([
+            "address/a1/context/x-holiday",
+            "emails/e1/contexts/home",
+            "kind",
+            "online/o1/context/x-game",
+            "phones/p1/contexts/Home",
+            "phones/p1/contexts/home",
+            "phones/p1/features/x-satellite",
+            "preferredContactLanguages/de/context",
+            "relatedTo/urn:uuid:3b8c9a2e-1f0d-4e8a-9a55-0d5cbb1c6c1e/relation/x-rival",
+        ]);
+
+        let json = unknown_values_fixture().to_string();
+        let card: Card<'_> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            serde_json::to_value(card.unknown_values()).unwrap(),
+            expected
+        );
+        assert!(card.validate(LIMITS).is_ok());
+
+        let strict = CardLimits {
+            reject_unknown_values: true,
+            ..LIMITS
+        };
+        let error = serde_json::to_value(card.validate(strict).unwrap_err()).unwrap();
+        assert_eq!(error["properties"], expected);
+    }
+
+    #[test]
+    fn vcard_types_map_onto_enumerations() {
+        assert_eq!(Context::from_vcard_type("HOME"), Context::Private);
+        assert_eq!(Context::from_vcard_type("work"), Context::Work);
+        assert_eq!(
+            Context::from_vcard_type("X-Holiday"),
+            Context::Unknown("x-holiday".to_string())
+        );
+
+        assert_eq!(PhoneFeature::from_vcard_type("CELL"), PhoneFeature::Cell);
+        assert_eq!(
+            PhoneFeature::from_vcard_type("X-Satellite"),
+            PhoneFeature::Unknown("x-satellite".to_string())
+        );
+
+        assert_eq!(
+            RelationKind::from_vcard_type("Co-Worker"),
+            RelationKind::CoWorker
+        );
+        assert_eq!(
+            RelationKind::from_vcard_type("x-rival"),
+            RelationKind::Unknown("x-rival".to_string())
+        );
+    }
 }
@@ -0,0 +1,119 @@
+//! Syntactic checks of [RFC5646] language tags, as used to key the
+//! localizations of a card. Tags are only checked to be well-formed, not
+//! that their subtags are registered.
+//!
+//! [RFC5646]: https://www.rfc-editor.org/rfc/rfc5646
+
+use std::iter::Peekable;
+
+/// Tags that don't follow the regular syntax, but are well-formed for
+/// historical reasons.
+const IRREGULAR: &[&str] = &[
+    "en-GB-oed",
+    "i-ami",
+    "i-bnn",
+    "i-default",
+    "i-enochian",
+    "i-hak",
+    "i-klingon",
+    "i-lux",
+    "i-mingo",
+    "i-navajo",
+    "i-pwn",
+    "i-tao",
+    "i-tay",
+    "i-tsu",
+    "sgn-BE-FR",
+    "sgn-BE-NL",
+    "sgn-CH-DE",
+];
+
+/// Whether the tag is a well-formed language tag, matching the
+/// `Language-Tag` production of RFC 5646 case-insensitively.
+pub fn is_well_formed(tag: &str) -> bool {
+    if IRREGULAR.iter().any(|v| v.eq_ignore_ascii_case(tag)) {
+        return true;
+    }
+
+    let mut subtags = tag.split('-').peekable();
+
+    if subtags.peek().is_some_and(|v| v.eq_ignore_ascii_case("x")) {
+        return is_private_use(&mut subtags);
+    }
+
+    // language, with up to three extended language subtags following a
+    // short one
+    match subtags.next() {
+        Some(language) if is_alpha(language, 2, 3) => {
+            for _ in 0..3 {
+                if subtags.next_if(|v| is_alpha(v, 3, 3)).is_none() {
+                    break;
+                }
+            }
+        }
+        Some(language) if is_alpha(language, 4, 8) => {}
+        _ => return false,
+    }
+
+    // script
+    subtags.next_if(|v| is_alpha(v, 4, 4));
+
+    // region
+    subtags.next_if(|v| is_alpha(v, 2, 2) || is_digit(v, 3));
+
+    // variants
+    while subtags.next_if(|v| is_variant(v)).is_some() {}
+
+    // extensions, each a singleton followed by at least one subtag
+    while subtags
+        .next_if(|v| is_alphanum(v, 1, 1) && !v.eq_ignore_ascii_case("x"))
+        .is_some()
+    {
+        if subtags.next_if(|v| is_alphanum(v, 2, 8)).is_none() {
+            return false;
+        }
+
+        while subtags.next_if(|v| is_alphanum(v, 2, 8)).is_some() {}
+    }
+
+    match subtags.peek() {
+        None => true,
+        Some(v) if v.eq_ignore_ascii_case("x") => is_private_use(&mut subtags),
+        Some(_) => false,
+    }
+}
+
+/// Whether the remaining subtags are an `x` followed by at least one
+/// private use subtag.
+fn is_private_use<'a>(subtags: &mut Peekable<impl Iterator<Item = &'a str>>) -> bool {
+    subtags.next();
+
+    let mut any = false;
+
+    for subtag in subtags {
+        if !is_alphanum(subtag, 1, 8) {
+            return false;
+        }
+
+        any = true;
+    }
+
+    any
+}
+
+fn is_variant(subtag: &str) -> bool {
+    is_alphanum(subtag, 5, 8)
+        || (subtag.len() == 4 && subtag.as_bytes()[0].is_ascii_digit() && is_alphanum(subtag, 4, 4))
+}
+
+fn is_alpha(subtag: &str, min: usize, max: usize) -> bool {
+    (min..=max).contains(&subtag.len()) && subtag.bytes().all(|v| v.is_ascii_alphabetic())
+}
+
+fn is_digit(subtag: &str, len: usize) -> bool {
+    subtag.len() == len && subtag.bytes().all(|v| v.is_ascii_digit())
+}
+
+fn is_alphanum(subtag: &str, min: usize, max: usize) -> bool {
+    (min..=max).contains(&subtag.len()) && subtag.bytes().all(|v| v.is_ascii_alphanumeric())
+}
@@ -1 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+use crate::endpoints::session::Capability;
+
 pub mod js_contact;
+pub mod language_tag;
+
+/// Information about an account under the `urn:ietf:params:jmap:contacts` key
+/// of its capabilities.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ContactsAccountCapabilities {
+    /// The user may create an address book in this account.
+    pub may_create_address_book: bool,
+}
+
+impl Capability for ContactsAccountCapabilities {
+    const URI: &'static str = "urn:ietf:params:jmap:contacts";
+}
@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+use crate::endpoints::session::Capability;
+
+/// Information about an account under the `urn:ietf:params:jmap:quota` key of
+/// its capabilities, the capability has no metadata of its own.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaAccountCapabilities {}
+
+impl Capability for QuotaAccountCapabilities {
+    const URI: &'static str = "urn:ietf:params:jmap:quota";
+}
@@ -2,10 +2,11 @@ use std::{borrow::Cow, collections::HashMap};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use serde_with::{serde_as, BorrowCow};
 
 use crate::{
     common::{Id, UtcDate},
-    endpoints::session::Account,
+    endpoints::session::{Account, Capability},
 };
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -21,6 +22,10 @@ pub struct PrincipalsAccountCapabilities<'a> {
     pub current_user_principal_id: Option<Id<'a>>,
 }
 
+impl Capability for PrincipalsAccountCapabilities<'_> {
+    const URI: &'static str = "urn:ietf:params:jmap:principals";
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct PrincipalsOwnerAccountCapabilities<'a> {
@@ -33,6 +38,20 @@ pub struct PrincipalsOwnerAccountCapabilities<'a> {
     pub principal_id: Id<'a>,
 }
 
+impl Capability for PrincipalsOwnerAccountCapabilities<'_> {
+    const URI: &'static str = "urn:ietf:params:jmap:principals:owner";
+}
+
+/// Information about a principal under the `urn:ietf:params:jmap:principals`
+/// key of its capabilities.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PrincipalsPrincipalCapabilities {
+    /// The type of the principal.
+    #[serde(rename = "type")]
+    pub type_: PrincipalType,
+}
+
 /// A Principal represents an individual, group, location (e.g. a room),
 /// resource (e.g. a projector) or other entity in a collaborative environment.
 /// Sharing in JMAP is generally configured by assigning rights to certain data
@@ -46,6 +65,7 @@ pub struct PrincipalsOwnerAccountCapabilities<'a> {
 /// In most systems the user will have access to a single Account containing
 /// Principal objects, but they may have access to multiple if, for example,
 /// aggregating data from different places.
+#[serde_as]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Principal<'a> {
@@ -58,18 +78,18 @@ pub struct Principal<'a> {
     pub name: Cow<'a, str>,
     /// A longer description of the principal, for example details about the facilities of a
     /// resource, or null if no description available.
-    #[serde(borrow)]
+    #[serde_as(as = "Option<BorrowCow>")]
     pub description: Option<Cow<'a, str>>,
     /// An email address for the principal, or null if no email is available.
-    #[serde(borrow)]
+    #[serde_as(as = "Option<BorrowCow>")]
     pub email: Option<Cow<'a, str>>,
     /// The time zone for this principal, if known. If not null, the value MUST
     /// be a time zone id from the IANA Time Zone Database TZDB.
-    #[serde(borrow)]
+    #[serde_as(as = "Option<BorrowCow>")]
     pub time_zone: Option<Cow<'a, str>>,
     /// A map of JMAP capability URIs to domain specific information about the principal in
     /// relation to that capability, as defined in the document that registered the capability.
-    #[serde(borrow)]
+    #[serde_as(as = "HashMap<BorrowCow, _>")]
     pub capabilities: HashMap<Cow<'a, str>, Value>,
     /// A map of account id to Account object for each JMAP Account containing data for this
     /// principal that the user has access to, or null if none.
@@ -135,3 +155,40 @@ pub struct Person<'a> {
     /// associated principal.
     pub principal: Option<Cow<'a, str>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use serde_json::json;
+
+    use super::Principal;
+
+    #[test]
+    fn principal_borrows_from_the_request() {
+        let principal = json!({
+            "id": "P1",
+            "type": "individual",
+            "name": "Jane Doe",
+            "description": "Head of sales",
+            "email": "jane@example.com",
+            "timeZone": "Europe/London",
+            "capabilities": {"urn:ietf:params:jmap:calendars": {}},
+            "accounts": null,
+        });
+        let json = principal.to_string();
+
+        let parsed: Principal<'_> = serde_json::from_str(&json).unwrap();
+        assert!(matches!(parsed.id.0, Cow::Borrowed("P1")));
+        assert!(matches!(parsed.name, Cow::Borrowed("Jane Doe")));
+        assert!(matches!(parsed.description, Some(Cow::Borrowed(_))));
+        assert!(matches!(parsed.email, Some(Cow::Borrowed(_))));
+        assert!(matches!(parsed.time_zone, Some(Cow::Borrowed(_))));
+        assert!(parsed
+            .capabilities
+            .keys()
+            .all(|uri| matches!(uri, Cow::Borrowed(_))));
+
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), principal);
+    }
+}
@@ -1,8 +1,11 @@
 pub mod common;
+pub mod compat;
 pub mod endpoints;
 pub mod errors;
 pub mod events;
 pub mod extensions;
+pub mod pointer;
 pub(crate) mod util;
+pub mod websocket;
 
 pub use serde_json::Value;
@@ -0,0 +1,304 @@
+//! JSON pointers, as defined in [RFC 6901], parsed in one place so that
+//! result references, patches and query paths can't disagree on escaping.
+//!
+//! The paths of result references additionally allow `*` to map through an
+//! array ([RFC 8620 section 3.7]), so they're parsed into a
+//! [`ReferencePath`] where the wildcard is its own token. Everywhere else a
+//! [`Pointer`] is used, in which `*` is just the name of a member.
+//!
+//! [RFC 6901]: https://datatracker.ietf.org/doc/html/rfc6901
+//! [RFC 8620 section 3.7]: https://datatracker.ietf.org/doc/html/rfc8620#section-3.7
+
+use std::{borrow::Cow, fmt};
+
+use serde_json::Value;
+
+/// The token of a [`ReferencePath`] that maps through an array.
+const WILDCARD: &str = "*";
+
+/// Reasons a pointer couldn't be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerError {
+    /// A non-empty pointer didn't start with a `/`.
+    MissingLeadingSlash,
+    /// A `~` wasn't followed by `0` or `1`.
+    InvalidEscape,
+}
+
+impl fmt::Display for PointerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingLeadingSlash => f.write_str("pointer must start with a /"),
+            Self::InvalidEscape => f.write_str("~ must be followed by 0 or 1"),
+        }
+    }
+}
+
+impl std::error::Error for PointerError {}
+
+/// A JSON pointer, split into its unescaped reference tokens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pointer<'a>(Vec<Cow<'a, str>>);
+
+impl<'a> Pointer<'a> {
+    /// Parses a pointer, the empty pointer referring to the whole document.
+    pub fn parse(pointer: &'a str) -> Result<Self, PointerError> {
+        split(pointer)?
+            .map(unescape)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// Parses the key of a `PatchObject`, which is a pointer with an
+    /// implicit leading `/`.
+    pub fn parse_patch(path: &'a str) -> Result<Self, PointerError> {
+        path.split('/')
+            .map(unescape)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// The unescaped reference tokens of the pointer, outermost first.
+    pub fn tokens(&self) -> &[Cow<'a, str>] {
+        &self.0
+    }
+
+    /// Finds the value the pointer refers to within `value`.
+    pub fn resolve<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        self.0
+            .iter()
+            .try_fold(value, |value, token| member(value, token))
+    }
+}
+
+/// A token of a [`ReferencePath`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token<'a> {
+    /// A member of an object or index into an array.
+    Member(Cow<'a, str>),
+    /// Applies the rest of the path to every item of an array.
+    Wildcard,
+}
+
+/// The path of a result reference, a JSON pointer in which a `*` token maps
+/// through an array.
+///
+/// A wildcard can't be escaped, so there's no way to reference a member
+/// literally named `*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReferencePath<'a>(Vec<Token<'a>>);
+
+impl<'a> ReferencePath<'a> {
+    /// Parses a path, the empty path referring to the whole document.
+    pub fn parse(path: &'a str) -> Result<Self, PointerError> {
+        split(path)?
+            .map(|token| {
+                if token == WILDCARD {
+                    Ok(Token::Wildcard)
+                } else {
+                    unescape(token).map(Token::Member)
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// The tokens of the path, outermost first.
+    pub fn tokens(&self) -> &[Token<'a>] {
+        &self.0
+    }
+
+    /// Finds the value the path refers to within `value`.
+    ///
+    /// Where a wildcard is met the rest of the path is applied to each item
+    /// of the array, collecting the results into a new array. Results that
+    /// are themselves arrays are flattened into it.
+    pub fn resolve<'v>(&self, value: &'v Value) -> Option<Cow<'v, Value>> {
+        resolve_tokens(value, &self.0)
+    }
+}
+
+/// Resolves the tokens of a [`ReferencePath`], which may be the remainder
+/// of a longer path.
+pub(crate) fn resolve_tokens<'v>(value: &'v Value, tokens: &[Token<'_>]) -> Option<Cow<'v, Value>> {
+    let Some((token, rest)) = tokens.split_first() else {
+        return Some(Cow::Borrowed(value));
+    };
+
+    match token {
+        Token::Member(token) => resolve_tokens(member(value, token)?, rest),
+        Token::Wildcard => {
+            let mut out = Vec::new();
+
+            for item in value.as_array()? {
+                match resolve_tokens(item, rest)? {
+                    Cow::Borrowed(Value::Array(items)) => out.extend(items.iter().cloned()),
+                    Cow::Owned(Value::Array(items)) => out.extend(items),
+                    item => out.push(item.into_owned()),
+                }
+            }
+
+            Some(Cow::Owned(Value::Array(out)))
+        }
+    }
+}
+
+/// Escapes a member name to be used as a reference token.
+pub fn escape(token: &str) -> Cow<'_, str> {
+    if token.contains(['~', '/']) {
+        Cow::Owned(token.replace('~', "~0").replace('/', "~1"))
+    } else {
+        Cow::Borrowed(token)
+    }
+}
+
+/// Splits a pointer into its still-escaped reference tokens.
+fn split(pointer: &str) -> Result<impl Iterator<Item = &str>, PointerError> {
+    let tokens = if pointer.is_empty() {
+        None
+    } else {
+        Some(
+            pointer
+                .strip_prefix('/')
+                .ok_or(PointerError::MissingLeadingSlash)?
+                .split('/'),
+        )
+    };
+
+    Ok(tokens.into_iter().flatten())
+}
+
+fn unescape(token: &str) -> Result<Cow<'_, str>, PointerError> {
+    if !token.contains('~') {
+        return Ok(Cow::Borrowed(token));
+    }
+
+    let mut out = String::with_capacity(token.len());
+    let mut chars = token.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '~' => match chars.next() {
+                Some('0') => out.push('~'),
+                Some('1') => out.push('/'),
+                _ => return Err(PointerError::InvalidEscape),
+            },
+            c => out.push(c),
+        }
+    }
+
+    Ok(Cow::Owned(out))
+}
+
+/// Looks up a member of an object, or an item of an array by its index.
+/// Indexes must be plain decimal numbers, without leading zeros.
+fn member<'v>(value: &'v Value, token: &str) -> Option<&'v Value> {
+    match value {
+        Value::Object(map) => map.get(token),
+        Value::Array(items) => {
+            if !token.bytes().all(|v| v.is_ascii_digit())
+                || token.starts_with('0') && token.len() > 1
+            {
+                return None;
+            }
+
+            items.get(token.parse::<usize>().ok()?)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn escapes_round_trip() {
+        let pointer = format!("/{}/{}", escape("a/b"), escape("c~d"));
+        assert_eq!(pointer, "/a~1b/c~0d");
+
+        assert_eq!(
+            Pointer::parse(&pointer).unwrap().tokens(),
+            [Cow::Borrowed("a/b"), Cow::Borrowed("c~d")]
+        );
+        assert_eq!(
+            ReferencePath::parse(&pointer).unwrap().tokens(),
+            [
+                Token::Member(Cow::Borrowed("a/b")),
+                Token::Member(Cow::Borrowed("c~d"))
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_pointers_are_rejected_alike() {
+        for (pointer, error) in [
+            ("a", PointerError::MissingLeadingSlash),
+            ("/a~", PointerError::InvalidEscape),
+            ("/a~2", PointerError::InvalidEscape),
+        ] {
+            assert_eq!(Pointer::parse(pointer), Err(error));
+            assert_eq!(ReferencePath::parse(pointer), Err(error));
+        }
+
+        assert_eq!(
+            Pointer::parse_patch("a~2"),
+            Err(PointerError::InvalidEscape)
+        );
+    }
+
+    #[test]
+    fn patch_paths_have_an_implicit_leading_slash() {
+        assert_eq!(
+            Pointer::parse_patch("emails/e~11").unwrap(),
+            Pointer::parse("/emails/e~11").unwrap()
+        );
+    }
+
+    #[test]
+    fn wildcard_is_only_special_in_reference_paths() {
+        let value = json!({ "list": [{ "id": "a" }, { "id": "b" }], "*": 1 });
+
+        assert_eq!(
+            ReferencePath::parse("/list/*/id")
+                .unwrap()
+                .resolve(&value)
+                .unwrap()
+                .into_owned(),
+            json!(["a", "b"])
+        );
+        assert_eq!(
+            Pointer::parse("/*").unwrap().resolve(&value),
+            Some(&json!(1))
+        );
+        assert_eq!(ReferencePath::parse("/*").unwrap().resolve(&value), None);
+    }
+
+    #[test]
+    fn wildcard_flattens_nested_arrays() {
+        let value = json!({ "list": [{ "ids": ["a", "b"] }, { "ids": ["c"] }] });
+
+        assert_eq!(
+            ReferencePath::parse("/list/*/ids")
+                .unwrap()
+                .resolve(&value)
+                .unwrap()
+                .into_owned(),
+            json!(["a", "b", "c"])
+        );
+    }
+
+    #[test]
+    fn array_indexes_must_be_canonical() {
+        let value = json!([10, 11]);
+
+        assert_eq!(
+            Pointer::parse("/1").unwrap().resolve(&value),
+            Some(&json!(11))
+        );
+        assert_eq!(Pointer::parse("/01").unwrap().resolve(&value), None);
+        assert_eq!(Pointer::parse("/-").unwrap().resolve(&value), None);
+    }
+}
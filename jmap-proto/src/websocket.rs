@@ -0,0 +1,92 @@
+//! Messages exchanged over a WebSocket, as defined in [RFC 8887].
+//!
+//! Every message is a JSON object tagged with its type in `@type`. A client
+//! message's [`ClientMessageHeader`] is read first to find out what the rest
+//! of it is, as a `Request` borrows its arguments from the message and so
+//! can't be read through an internally tagged enum.
+//!
+//! [RFC 8887]: https://datatracker.ietf.org/doc/html/rfc8887
+
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{endpoints::Response, errors::RequestError, events::Event};
+
+/// The subprotocol clients negotiate when opening the WebSocket.
+pub const SUBPROTOCOL: &str = "jmap";
+
+/// The `urn:ietf:params:jmap:websocket` session capability.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebSocketCapabilities<'a> {
+    /// The `wss://` URL to open the WebSocket at.
+    #[serde(borrow)]
+    pub url: Cow<'a, str>,
+    /// Whether the server pushes `StateChange`s over the WebSocket once
+    /// asked to with a `WebSocketPushEnable`.
+    pub supports_push: bool,
+}
+
+/// The kinds of message a client may send.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClientMessageType {
+    /// A `Request`, with an optional `id` echoed back on its response.
+    Request,
+    /// Starts pushing `StateChange`s to the client.
+    WebSocketPushEnable,
+    /// Stops pushing `StateChange`s to the client.
+    WebSocketPushDisable,
+}
+
+/// The fields common to every message sent by a client.
+#[derive(Deserialize, Debug)]
+pub struct ClientMessageHeader<'a> {
+    #[serde(rename = "@type")]
+    pub type_: ClientMessageType,
+    /// The id of a `Request`, echoed back as the `requestId` of the
+    /// `Response` or `RequestError` sent for it.
+    #[serde(borrow, default)]
+    pub id: Option<Cow<'a, str>>,
+}
+
+/// Asks the server to push `StateChange`s over the WebSocket.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct WebSocketPushEnable {
+    /// The data types to push changes to, or all of them if `None`.
+    pub data_types: Option<Vec<String>>,
+    /// The state the client last saw, to be sent any changes made since.
+    pub push_state: Option<String>,
+}
+
+/// The `Response` to a `Request` sent over the WebSocket.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebSocketResponse<'a> {
+    #[serde(flatten)]
+    pub response: Response<'a>,
+    /// The `id` of the `Request`, if it had one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<Cow<'a, str>>,
+}
+
+impl Event for WebSocketResponse<'_> {
+    const NAME: &'static str = "Response";
+}
+
+/// A problem with a message sent over the WebSocket, the equivalent of the
+/// problem details returned by the API endpoint.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebSocketRequestError<'a> {
+    #[serde(flatten)]
+    pub error: RequestError,
+    /// The `id` of the `Request` the problem is with, if it had one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<Cow<'a, str>>,
+}
+
+impl Event for WebSocketRequestError<'_> {
+    const NAME: &'static str = "RequestError";
+}
@@ -1,7 +1,23 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+
+use jmap_proto::{
+    common::UnsignedInt, endpoints::session::CoreCapability,
+    extensions::contacts::js_contact::CardLimits,
+};
+use oxide_auth::endpoint::Scope;
 use serde::Deserialize;
 
-use crate::store::StoreConfig;
+use crate::store::{BlobStoreConfig, StoreConfig};
 
+/// The server's configuration, read from the file given on the command line.
+///
+/// Some settings can be changed without a restart by editing the file and
+/// sending the server SIGHUP, see [`ReloadableConfig`]. Changes to any
+/// other setting are logged and ignored until the server is restarted.
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
@@ -9,6 +25,18 @@ pub struct Config {
     /// etc after being fed through Argon2 for key derivation. This key should
     /// be at least 32 bytes long.
     pub private_key: String,
+    /// The cost of Argon2, as used for hashing passwords and deriving keys
+    /// from `private-key`. Passwords keep being checked against the costs
+    /// they were hashed with, so these can be changed at any time.
+    ///
+    /// ```toml
+    /// [argon2]
+    /// m-cost = 19456
+    /// t-cost = 2
+    /// p-cost = 1
+    /// ```
+    #[serde(default)]
+    pub argon2: Argon2Config,
     /// Storage configuration, supported databases are currently `rocksdb`.
     ///
     /// ```toml
@@ -16,13 +44,494 @@ pub struct Config {
     /// type = "rocksdb"
     /// path = "db"
     /// ```
+    ///
+    /// Read-only traffic can be spread across processes by running
+    /// additional instances as read replicas of the same database:
+    ///
+    /// ```toml
+    /// [store]
+    /// type = "rocksdb"
+    /// path = "db"
+    /// role = "read-replica"
+    /// secondary-path = "db-replica"
+    /// ```
     pub store: StoreConfig,
+    /// Where the contents of blobs are kept, which can differ from the main
+    /// store. Blobs are kept in the main store if not otherwise configured,
+    /// they can also be written to a directory or an S3-compatible bucket:
+    ///
+    /// ```toml
+    /// [blob-store]
+    /// type = "filesystem"
+    /// path = "blobs"
+    /// ```
+    ///
+    /// ```toml
+    /// [blob-store]
+    /// type = "s3"
+    /// endpoint = "http://localhost:9000"
+    /// bucket = "jogre"
+    /// access-key-id = "minioadmin"
+    /// secret-access-key = "minioadmin"
+    /// path-style = true
+    /// ```
+    #[serde(default)]
+    pub blob_store: BlobStoreConfig,
+    /// How often, in seconds, blobs that no object references are looked
+    /// for and deleted.
+    #[serde(default = "Config::default_blobs_gc_interval")]
+    pub blobs_gc_interval: u64,
+    /// How long, in seconds, an uploaded blob is kept while nothing
+    /// references it, giving clients time to create the object that uses it.
+    #[serde(default = "Config::default_blobs_unlinked_ttl")]
+    pub blobs_unlinked_ttl: u64,
+    /// URL of the primary instance, returned to clients that attempt a write
+    /// against a read replica.
+    pub primary_url: Option<url::Url>,
     /// Capabilities of the server as advertised to the client, and enforced
     /// at the server.
     #[serde(default)]
     pub core_capabilities: CoreCapabilities,
+    /// Limits enforced on requests to the API endpoint that aren't
+    /// advertised to clients.
+    #[serde(default)]
+    pub request_limits: RequestLimits,
     /// Base URL of the server
     pub base_url: url::Url,
+    /// The address and port the server listens on.
+    #[serde(default = "Config::default_listen")]
+    pub listen: SocketAddr,
+    /// OAuth configuration, including the clients that are allowed to
+    /// request access on behalf of users.
+    ///
+    /// ```toml
+    /// [[oauth.client]]
+    /// id = "my-client"
+    /// redirect-uri = "https://example.com/callback"
+    /// scope = "jmap"
+    /// access-token-ttl = 900
+    /// ```
+    #[serde(default)]
+    pub oauth: OAuthConfig,
+    /// Streams every change made within the server to `/debug/events`, for
+    /// watching what a client does while developing against the server.
+    /// Never enabled unless configured:
+    ///
+    /// ```toml
+    /// [debug-events]
+    /// enabled = true
+    /// admins = ["root"]
+    /// ```
+    #[serde(default)]
+    pub debug_events: DebugEventsConfig,
+    /// Delivery of changes to push subscriptions. Each push service host is
+    /// delivered to independently, and deliveries to a host are paused for
+    /// a while once too many of them fail in a row.
+    ///
+    /// ```toml
+    /// [push]
+    /// max-concurrent-per-host = 4
+    /// failure-threshold = 5
+    /// ```
+    #[serde(default)]
+    pub push: PushConfig,
+    /// Responses smaller than this, in octets, are sent uncompressed since
+    /// compressing them costs more than it saves. Larger responses are
+    /// gzipped for clients that accept it.
+    #[serde(default = "Config::default_compression_min_size")]
+    pub compression_min_size: u16,
+    /// Whether response fields that have been renamed to fix their wire
+    /// format are also sent under their old names, for a transition window
+    /// while clients catch up. Clients still reading the old names can say
+    /// so with the `JMAP-Legacy-Field-Names` header, and are counted in
+    /// `jmap_legacy_field_names_requests_total`, so it's known when this can
+    /// be turned off.
+    #[serde(default)]
+    pub legacy_field_names: bool,
+    /// An existing account to keep `Principal` and `ShareNotification`
+    /// objects in, rather than the dedicated `principals` account created on
+    /// first start. Every user is given read access to it.
+    pub principals_account: Option<uuid::Uuid>,
+    /// Which logs are written, as a comma separated list of `target=level`
+    /// directives and a default level (eg. `info,jogre_server=debug`).
+    #[serde(default = "Config::default_log_filter")]
+    pub log_filter: String,
+}
+
+impl Config {
+    const fn default_listen() -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 8888))
+    }
+
+    const fn default_compression_min_size() -> u16 {
+        1024
+    }
+
+    const fn default_blobs_gc_interval() -> u64 {
+        15 * 60
+    }
+
+    const fn default_blobs_unlinked_ttl() -> u64 {
+        60 * 60
+    }
+
+    fn default_log_filter() -> String {
+        "info".to_string()
+    }
+}
+
+/// The settings that can be changed while the server is running by sending
+/// it SIGHUP, read through [`crate::context::Context::config`] wherever
+/// they're enforced.
+///
+/// The log filter and OAuth clients can also be reloaded, but are swapped
+/// within the subsystems that use them rather than being held here.
+#[derive(Clone, Debug)]
+pub struct ReloadableConfig {
+    pub core_capabilities: CoreCapabilities,
+    pub request_limits: RequestLimits,
+}
+
+impl ReloadableConfig {
+    /// Settings, as paths into the config file, whose changes are applied
+    /// on reload. Anything beneath one of these is reloadable too.
+    pub const PATHS: &'static [&'static str] = &[
+        "core-capabilities",
+        "request-limits",
+        "oauth.client",
+        "log-filter",
+    ];
+
+    /// Whether a change to the setting at the given path can be applied on
+    /// reload.
+    pub fn is_reloadable(path: &str) -> bool {
+        Self::PATHS.iter().any(|prefix| {
+            path == *prefix
+                || path
+                    .strip_prefix(*prefix)
+                    .is_some_and(|rest| rest.starts_with('.'))
+        })
+    }
+}
+
+impl From<&Config> for ReloadableConfig {
+    fn from(config: &Config) -> Self {
+        Self {
+            core_capabilities: config.core_capabilities,
+            request_limits: config.request_limits,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct PushConfig {
+    /// The maximum number of deliveries in flight to a single push service
+    /// host.
+    #[serde(default = "PushConfig::default_max_concurrent_per_host")]
+    pub max_concurrent_per_host: usize,
+    /// The number of consecutive failed deliveries to a host after which
+    /// deliveries to it are paused.
+    #[serde(default = "PushConfig::default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long, in milliseconds, to wait before retrying a host after its
+    /// first failure, doubling with each failure after it.
+    #[serde(default = "PushConfig::default_initial_backoff")]
+    pub initial_backoff: u64,
+    /// The longest, in milliseconds, to wait before retrying a host.
+    #[serde(default = "PushConfig::default_max_backoff")]
+    pub max_backoff: u64,
+    /// The longest, in seconds, a push subscription may last before the
+    /// client has to extend it.
+    #[serde(default = "PushConfig::default_max_subscription_lifetime")]
+    pub max_subscription_lifetime: u64,
+    /// The maximum number of push subscriptions each user may have.
+    #[serde(default = "PushConfig::default_max_subscriptions_per_user")]
+    pub max_subscriptions_per_user: usize,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_per_host: Self::default_max_concurrent_per_host(),
+            failure_threshold: Self::default_failure_threshold(),
+            initial_backoff: Self::default_initial_backoff(),
+            max_backoff: Self::default_max_backoff(),
+            max_subscription_lifetime: Self::default_max_subscription_lifetime(),
+            max_subscriptions_per_user: Self::default_max_subscriptions_per_user(),
+        }
+    }
+}
+
+impl PushConfig {
+    const fn default_max_concurrent_per_host() -> usize {
+        4
+    }
+
+    const fn default_failure_threshold() -> u32 {
+        5
+    }
+
+    const fn default_initial_backoff() -> u64 {
+        1000
+    }
+
+    const fn default_max_backoff() -> u64 {
+        5 * 60 * 1000
+    }
+
+    const fn default_max_subscription_lifetime() -> u64 {
+        7 * 24 * 60 * 60
+    }
+
+    const fn default_max_subscriptions_per_user() -> usize {
+        32
+    }
+}
+
+#[derive(Deserialize, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+#[allow(clippy::struct_field_names)] // named as in the Argon2 spec
+pub struct Argon2Config {
+    /// Memory size, in KiB.
+    #[serde(default = "Argon2Config::default_m_cost")]
+    pub m_cost: u32,
+    /// Number of iterations.
+    #[serde(default = "Argon2Config::default_t_cost")]
+    pub t_cost: u32,
+    /// Degree of parallelism.
+    #[serde(default = "Argon2Config::default_p_cost")]
+    pub p_cost: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            m_cost: Self::default_m_cost(),
+            t_cost: Self::default_t_cost(),
+            p_cost: Self::default_p_cost(),
+        }
+    }
+}
+
+impl Argon2Config {
+    /// The variant passwords are hashed with.
+    pub const ALGORITHM: argon2::Algorithm = argon2::Algorithm::Argon2id;
+
+    pub const VERSION: argon2::Version = argon2::Version::V0x13;
+
+    const fn default_m_cost() -> u32 {
+        argon2::Params::DEFAULT_M_COST
+    }
+
+    const fn default_t_cost() -> u32 {
+        argon2::Params::DEFAULT_T_COST
+    }
+
+    const fn default_p_cost() -> u32 {
+        argon2::Params::DEFAULT_P_COST
+    }
+
+    /// Builds an Argon2id instance with the configured costs, or fails if
+    /// they're out of range.
+    pub fn build(self) -> Result<argon2::Argon2<'static>, argon2::Error> {
+        let params = argon2::Params::new(self.m_cost, self.t_cost, self.p_cost, None)?;
+
+        Ok(argon2::Argon2::new(Self::ALGORITHM, Self::VERSION, params))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DebugEventsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The maximum number of streams that may be open at once, across all
+    /// users.
+    #[serde(default = "DebugEventsConfig::default_max_connections")]
+    pub max_connections: usize,
+    /// Users that may watch changes to any account, rather than just the
+    /// accounts they have access to.
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+impl Default for DebugEventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_connections: Self::default_max_connections(),
+            admins: Vec::new(),
+        }
+    }
+}
+
+impl DebugEventsConfig {
+    const fn default_max_connections() -> usize {
+        4
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OAuthConfig {
+    /// Clients registered with the server at startup.
+    #[serde(default, rename = "client")]
+    pub clients: Vec<OAuthClient>,
+    /// How often, in seconds, tokens that can no longer be used are removed
+    /// from the store.
+    #[serde(default = "OAuthConfig::default_token_sweep_interval")]
+    pub token_sweep_interval: u64,
+    /// How many wrong passwords can be given for a user before logging in
+    /// as them is refused until the window has passed.
+    #[serde(default = "OAuthConfig::default_max_failed_logins")]
+    pub max_failed_logins: u32,
+    /// How long, in seconds, failed logins are counted for, from the first
+    /// failure.
+    #[serde(default = "OAuthConfig::default_failed_login_window")]
+    pub failed_login_window: u64,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        Self {
+            clients: Vec::new(),
+            token_sweep_interval: Self::default_token_sweep_interval(),
+            max_failed_logins: Self::default_max_failed_logins(),
+            failed_login_window: Self::default_failed_login_window(),
+        }
+    }
+}
+
+impl OAuthConfig {
+    const fn default_token_sweep_interval() -> u64 {
+        15 * 60
+    }
+
+    const fn default_max_failed_logins() -> u32 {
+        5
+    }
+
+    const fn default_failed_login_window() -> u64 {
+        15 * 60
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OAuthClient {
+    /// The `client_id` the client identifies itself with.
+    pub id: String,
+    /// The URI the user is redirected back to after authorising the client.
+    pub redirect_uri: url::Url,
+    /// The scope granted to the client if it doesn't request one itself.
+    pub scope: Scope,
+    /// The client's secret, confidential clients must provide this when
+    /// exchanging authorization codes. Clients without a secret are
+    /// registered as public clients.
+    pub secret: Option<String>,
+    /// How long, in seconds, access tokens issued to the client are valid
+    /// for.
+    #[serde(default = "OAuthClient::default_access_token_ttl")]
+    pub access_token_ttl: u64,
+    /// How long, in seconds, a refresh token can be used for from when the
+    /// user first authorised the client, regardless of how often it's
+    /// refreshed.
+    #[serde(default = "OAuthClient::default_refresh_token_lifetime")]
+    pub refresh_token_lifetime: u64,
+    /// How long, in seconds, a refresh token can go unused before it
+    /// expires. Each refresh resets the timeout.
+    #[serde(default = "OAuthClient::default_refresh_token_idle_timeout")]
+    pub refresh_token_idle_timeout: u64,
+}
+
+impl OAuthClient {
+    const fn default_access_token_ttl() -> u64 {
+        60 * 60
+    }
+
+    const fn default_refresh_token_lifetime() -> u64 {
+        90 * 24 * 60 * 60
+    }
+
+    const fn default_refresh_token_idle_timeout() -> u64 {
+        14 * 24 * 60 * 60
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+#[allow(clippy::struct_field_names)] // every limit is a maximum
+pub struct RequestLimits {
+    /// The maximum number of capabilities a client may list in the `using`
+    /// property of a single request.
+    #[serde(default = "RequestLimits::default_max_using")]
+    pub max_using: u64,
+    /// The maximum number of entries in each of the id-keyed maps on a
+    /// single card, such as `anniversaries` or `personalInfo`.
+    #[serde(default = "RequestLimits::default_max_card_map_entries")]
+    pub max_card_map_entries: u64,
+    /// The maximum size, in octets, of each of the free-form
+    /// `localizations` and `timeZones` maps on a single card.
+    #[serde(default = "RequestLimits::default_max_card_free_form_map_size")]
+    pub max_card_free_form_map_size: u64,
+    /// The most ids a single `Foo/query` call returns, however many the
+    /// client asks for.
+    #[serde(default = "RequestLimits::default_max_objects_in_query")]
+    pub max_objects_in_query: u64,
+    /// The most address books a single account may hold, past which
+    /// `AddressBook/set` refuses to create more.
+    #[serde(default = "RequestLimits::default_max_address_books_per_account")]
+    pub max_address_books_per_account: u64,
+    /// Whether cards holding values outside the enumerations of the spec,
+    /// such as a `home` context, are rejected rather than stored verbatim.
+    #[serde(default)]
+    pub reject_unknown_card_values: bool,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_using: Self::default_max_using(),
+            max_card_map_entries: Self::default_max_card_map_entries(),
+            max_card_free_form_map_size: Self::default_max_card_free_form_map_size(),
+            max_objects_in_query: Self::default_max_objects_in_query(),
+            max_address_books_per_account: Self::default_max_address_books_per_account(),
+            reject_unknown_card_values: false,
+        }
+    }
+}
+
+impl RequestLimits {
+    const fn default_max_using() -> u64 {
+        64
+    }
+
+    const fn default_max_card_map_entries() -> u64 {
+        256
+    }
+
+    const fn default_max_card_free_form_map_size() -> u64 {
+        64 * 1024
+    }
+
+    /// The limits cards are validated against.
+    pub fn card_limits(&self) -> CardLimits {
+        CardLimits {
+            max_map_entries: usize::try_from(self.max_card_map_entries).unwrap_or(usize::MAX),
+            max_free_form_map_size: usize::try_from(self.max_card_free_form_map_size)
+                .unwrap_or(usize::MAX),
+            reject_unknown_values: self.reject_unknown_card_values,
+        }
+    }
+
+    const fn default_max_objects_in_query() -> u64 {
+        1000
+    }
+
+    const fn default_max_address_books_per_account() -> u64 {
+        100
+    }
 }
 
 #[derive(Deserialize, Copy, Clone, Debug)]
@@ -105,4 +614,31 @@ impl CoreCapabilities {
     const fn default_max_objects_in_set() -> u64 {
         500
     }
+
+    /// The capability advertised in the session for these limits, along
+    /// with the collation algorithms the server supports.
+    pub fn to_capability(self, collation_algorithms: BTreeSet<Cow<'_, str>>) -> CoreCapability<'_> {
+        // destructured so that a new limit can't be left unadvertised
+        let Self {
+            max_size_upload,
+            max_concurrent_upload,
+            max_size_request,
+            max_concurrent_requests,
+            max_calls_in_request,
+            max_objects_in_get,
+            max_objects_in_set,
+        } = self;
+
+        // a limit too large to advertise is as good as no limit at all
+        CoreCapability {
+            max_size_upload: UnsignedInt::saturating(max_size_upload),
+            max_concurrent_upload: UnsignedInt::saturating(max_concurrent_upload),
+            max_size_request: UnsignedInt::saturating(max_size_request),
+            max_concurrent_requests: UnsignedInt::saturating(max_concurrent_requests),
+            max_calls_in_request: UnsignedInt::saturating(max_calls_in_request),
+            max_objects_in_get: UnsignedInt::saturating(max_objects_in_get),
+            max_objects_in_set: UnsignedInt::saturating(max_objects_in_set),
+            collation_algorithms,
+        }
+    }
 }
@@ -1,53 +1,224 @@
-use std::sync::Arc;
+use std::{fmt, sync::Arc, time::Duration};
 
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::sync::Semaphore;
+
+use self::{
+    concurrency::{ConcurrencyLimiter, KeyedLock},
+    events::EventBus,
+    push::{HttpsTransport, PushDispatcher},
+};
 use crate::{
-    config::{Config, CoreCapabilities},
+    config::{Config, DebugEventsConfig, ReloadableConfig},
     extensions,
     extensions::{
+        router::RouterError,
         sharing::{Principals, PrincipalsOwner},
         ExtensionRegistry, ExtensionRouterRegistry,
     },
-    store::Store,
+    methods::routes,
+    store,
+    store::{BlobStore, Store},
 };
 
+pub mod blob_gc;
+pub mod concurrency;
+pub mod events;
 pub mod oauth2;
+pub mod push;
 
 pub struct Context {
     pub oauth2: oauth2::OAuth2,
     pub store: Arc<Store>,
+    pub blob_store: Arc<BlobStore>,
     pub base_url: url::Url,
-    pub core_capabilities: CoreCapabilities,
+    pub primary_url: Option<url::Url>,
+    /// The settings that can change while the server is running, loaded
+    /// afresh wherever they're enforced.
+    pub config: Arc<ArcSwap<ReloadableConfig>>,
+    /// Limits the number of requests to the API endpoint each user may have
+    /// in flight, as advertised by `maxConcurrentRequests`.
+    pub api_concurrency: ConcurrencyLimiter,
+    /// Limits the number of uploads each account may have in flight, as
+    /// advertised by `maxConcurrentUpload`.
+    pub upload_concurrency: ConcurrencyLimiter,
+    /// Held by `Foo/set` calls for the account and data type they change, so
+    /// two racing calls can't both pass an `ifInState` check.
+    pub object_writes: KeyedLock,
     pub extension_registry: ExtensionRegistry,
     pub extension_router_registry: ExtensionRouterRegistry,
+    pub metrics: PrometheusHandle,
+    /// Changes made to data within the server, as they happen.
+    pub events: EventBus,
+    pub debug_events: DebugEventsConfig,
+    /// Caps the number of open `/debug/events` streams.
+    pub debug_event_streams: Arc<Semaphore>,
+    /// Delivers changes to the push subscriptions registered by clients.
+    pub push: PushDispatcher<HttpsTransport>,
+    /// Responses smaller than this, in octets, aren't compressed.
+    pub compression_min_size: u16,
+    /// Whether renamed response fields are also sent under their old names.
+    pub legacy_field_names: bool,
+    /// Hashes passwords with the configured costs.
+    pub argon2: Arc<argon2::Argon2<'static>>,
+    /// The current time, as stamped on the objects the server writes.
+    pub clock: Arc<dyn Fn() -> DateTime<Utc> + Send + Sync>,
 }
 
 impl Context {
-    pub fn new(config: Config) -> Self {
-        let derived_keys = Arc::new(DerivedKeys::new(&config.private_key));
-        let store = Arc::new(Store::from_config(config.store));
+    pub fn new(config: Config, metrics: PrometheusHandle) -> Result<Self, ContextError> {
+        let argon2 = Arc::new(config.argon2.build().expect("argon2 costs out of range"));
+        let derived_keys = Arc::new(DerivedKeys::new(&argon2, &config.private_key));
+        let reloadable = Arc::new(ArcSwap::from_pointee(ReloadableConfig::from(&config)));
+        let events = EventBus::new();
+        let store = Arc::new(Store::from_config(config.store, events.clone())?);
+        let blob_store = Arc::new(BlobStore::from_config(config.blob_store, store.clone()));
+
+        // read replicas can't write, the primary collects blobs for them
+        if !store.is_read_only() {
+            blob_gc::spawn_blob_gc(
+                Arc::downgrade(&store),
+                Arc::downgrade(&blob_store),
+                Duration::from_secs(config.blobs_gc_interval),
+                Duration::from_secs(config.blobs_unlinked_ttl),
+            );
+        }
 
         let extension_registry = ExtensionRegistry {
             core: extensions::core::Core {
-                core_capabilities: config.core_capabilities,
+                config: reloadable.clone(),
             },
+            jogre: extensions::jogre::Jogre {},
             contacts: extensions::contacts::Contacts {},
             sharing_principals: Principals {},
             sharing_principals_owner: PrincipalsOwner {},
+            websocket: extensions::websocket::WebSocket::new(
+                routes::WEBSOCKET
+                    .uri_template(&config.base_url)
+                    .parse()
+                    .unwrap(),
+            ),
         };
 
-        let extension_router_registry = extension_registry.build_router_registry();
+        let extension_router_registry = extension_registry.build_router_registry()?;
 
-        Self {
-            oauth2: oauth2::OAuth2::new(store.clone(), derived_keys),
+        let push = PushDispatcher::new(HttpsTransport::new(), config.push);
+        tokio::spawn(push::deliver_state_changes(
+            events.subscribe(),
+            store.clone(),
+            push.clone(),
+        ));
+
+        Ok(Self {
+            oauth2: oauth2::OAuth2::new(
+                store.clone(),
+                derived_keys,
+                argon2.clone(),
+                &config.oauth,
+                config.base_url.scheme() == "https",
+            ),
             store,
+            blob_store,
             base_url: config.base_url,
-            core_capabilities: config.core_capabilities,
+            primary_url: config.primary_url,
+            config: reloadable,
+            api_concurrency: ConcurrencyLimiter::new(
+                config.core_capabilities.max_concurrent_requests,
+            ),
+            upload_concurrency: ConcurrencyLimiter::new(
+                config.core_capabilities.max_concurrent_upload,
+            ),
+            object_writes: KeyedLock::default(),
             extension_registry,
             extension_router_registry,
+            metrics,
+            events,
+            debug_event_streams: Arc::new(Semaphore::new(config.debug_events.max_connections)),
+            debug_events: config.debug_events,
+            push,
+            compression_min_size: config.compression_min_size,
+            legacy_field_names: config.legacy_field_names,
+            argon2,
+            clock: Arc::new(Utc::now),
+        })
+    }
+}
+
+#[cfg(test)]
+impl Context {
+    /// Builds a context with the default config over a fresh store at
+    /// `store_path`, whose health is checked every second.
+    pub fn for_tests(store_path: &std::path::Path) -> Self {
+        Self::for_tests_at(store_path, "http://127.0.0.1:8888")
+    }
+
+    /// Builds a context as [`Self::for_tests`] does, served from `base_url`.
+    pub fn for_tests_at(store_path: &std::path::Path, base_url: &str) -> Self {
+        // the recorder is global, so every test context shares the one
+        // installed on first use and sees the calls made by the others
+        static METRICS: std::sync::OnceLock<PrometheusHandle> = std::sync::OnceLock::new();
+
+        let config = toml::from_str(&format!(
+            "private-key = \"testtesttesttesttesttesttesttest\"\n\
+             base-url = {base_url:?}\n\
+             [store]\n\
+             type = \"rocksdb\"\n\
+             path = {store_path:?}\n\
+             health-check-interval = 1\n"
+        ))
+        .unwrap();
+
+        let metrics = METRICS.get_or_init(|| {
+            metrics_exporter_prometheus::PrometheusBuilder::new()
+                .install_recorder()
+                .unwrap()
+        });
+
+        Self::new(config, metrics.clone()).unwrap()
+    }
+}
+
+/// Failures bringing up the server's shared state.
+#[derive(Debug)]
+pub enum ContextError {
+    /// The store couldn't be opened.
+    Store(store::Error),
+    /// The extensions' routers were inconsistent.
+    Router(RouterError),
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Store(error) => write!(f, "failed to open store: {error}"),
+            Self::Router(error) => write!(f, "failed to build router: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ContextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Store(error) => Some(error),
+            Self::Router(error) => Some(error),
         }
     }
 }
 
+impl From<store::Error> for ContextError {
+    fn from(error: store::Error) -> Self {
+        Self::Store(error)
+    }
+}
+
+impl From<RouterError> for ContextError {
+    fn from(error: RouterError) -> Self {
+        Self::Router(error)
+    }
+}
+
 pub struct DerivedKeys {
     pub(crate) csrf_hmac_key: [u8; argon2::Params::DEFAULT_OUTPUT_LEN],
 }
@@ -57,15 +228,9 @@ impl DerivedKeys {
     const CSRF: &'static [u8] = b"CSRFTOKEN";
 
     /// Instantiates a new [`DerivedKeys`], dropping the private key.
-    fn new(private_key: &str) -> Self {
-        let argon2 = argon2::Argon2::new(
-            argon2::Algorithm::Argon2id,
-            argon2::Version::V0x13,
-            argon2::Params::DEFAULT,
-        );
-
+    fn new(argon2: &argon2::Argon2, private_key: &str) -> Self {
         Self {
-            csrf_hmac_key: Self::derive_key(&argon2, private_key, Self::CSRF),
+            csrf_hmac_key: Self::derive_key(argon2, private_key, Self::CSRF),
         }
     }
 
@@ -0,0 +1,83 @@
+//! Garbage collection of blobs that no object references.
+//!
+//! Uploads aren't referenced by anything until the client creates an object
+//! using them, so blobs are only collected once they've gone unreferenced
+//! for a grace period after they were last uploaded.
+
+use std::{sync::Weak, time::Duration};
+
+use chrono::{DateTime, Utc};
+use tracing::{error, info};
+
+use crate::store::{BlobProvider, BlobReferenceProvider, BlobStore, Store};
+
+/// Periodically collects blobs that have gone unreferenced for longer than
+/// `unreferenced_ttl`, until the store is dropped.
+pub fn spawn_blob_gc(
+    store: Weak<Store>,
+    blob_store: Weak<BlobStore>,
+    every: Duration,
+    unreferenced_ttl: Duration,
+) {
+    let unreferenced_ttl =
+        chrono::Duration::from_std(unreferenced_ttl).expect("blob ttl out of range");
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+
+        loop {
+            interval.tick().await;
+
+            let (Some(store), Some(blob_store)) = (store.upgrade(), blob_store.upgrade()) else {
+                break;
+            };
+
+            let removed = collect_garbage(&store, &blob_store, Utc::now() - unreferenced_ttl).await;
+
+            if removed > 0 {
+                info!(removed, "Collected unreferenced blobs");
+            }
+        }
+    });
+}
+
+/// Deletes every blob that no object references and that hasn't been
+/// uploaded since `uploaded_before`, returning how many were deleted.
+pub async fn collect_garbage(
+    store: &Store,
+    blob_store: &BlobStore,
+    uploaded_before: DateTime<Utc>,
+) -> usize {
+    let candidates = match store.get_unreferenced_blobs(uploaded_before).await {
+        Ok(candidates) => candidates,
+        Err(error) => {
+            error!(?error, "Failed to find unreferenced blobs");
+            return 0;
+        }
+    };
+
+    let mut removed = 0;
+
+    for blob in candidates {
+        // the blob is forgotten before its contents are deleted so it can't
+        // be fetched once they're gone, and is skipped if it was referenced
+        // or uploaded again since it was found
+        match store.forget_blob(blob, uploaded_before).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(error) => {
+                error!(?error, ?blob, "Failed to forget unreferenced blob");
+                continue;
+            }
+        }
+
+        if let Err(error) = blob_store.delete_blob(blob).await {
+            error!(?error, ?blob, "Failed to delete unreferenced blob contents");
+            continue;
+        }
+
+        removed += 1;
+    }
+
+    removed
+}
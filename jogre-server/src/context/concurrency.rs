@@ -0,0 +1,135 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// How long clients turned away by a limiter are told to wait before trying
+/// again. Slots are freed as soon as requests finish rather than refilling
+/// at a fixed rate, so this is just a hint short enough that a client won't
+/// sit idle once its earlier requests have completed.
+pub const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Caps the number of requests each user, or other key such as an account,
+/// may have in flight at once, akin to a semaphore per key.
+///
+/// Keys are only tracked while they have a request in flight, so the limiter
+/// doesn't grow with the number of users.
+pub struct ConcurrencyLimiter {
+    limit: AtomicU64,
+    in_flight: Mutex<HashMap<String, u64>>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit: AtomicU64::new(limit),
+            in_flight: Mutex::default(),
+        }
+    }
+
+    /// The maximum number of requests a user may have in flight.
+    pub fn limit(&self) -> u64 {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Changes the limit, requests already in flight over a lowered limit
+    /// are left to finish.
+    pub fn set_limit(&self, limit: u64) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Takes one of the user's slots for the lifetime of the returned permit,
+    /// or returns `None` if the user is already at the limit.
+    pub fn try_acquire(&self, user: &str) -> Option<ConcurrencyPermit<'_>> {
+        let limit = self.limit();
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        match in_flight.get_mut(user) {
+            Some(count) if *count >= limit => return None,
+            Some(count) => *count += 1,
+            None if limit == 0 => return None,
+            None => {
+                in_flight.insert(user.to_string(), 1);
+            }
+        }
+
+        Some(ConcurrencyPermit {
+            limiter: self,
+            user: user.to_string(),
+        })
+    }
+}
+
+/// A slot taken from a [`ConcurrencyLimiter`], released when dropped.
+pub struct ConcurrencyPermit<'a> {
+    limiter: &'a ConcurrencyLimiter,
+    user: String,
+}
+
+impl Drop for ConcurrencyPermit<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.limiter.in_flight.lock().unwrap();
+
+        if let Entry::Occupied(mut entry) = in_flight.entry(std::mem::take(&mut self.user)) {
+            *entry.get_mut() -= 1;
+
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// Serialises work on each key, such as changes to a data type within an
+/// account, while letting work on different keys run concurrently.
+///
+/// Keys are only tracked while they're held or waited on.
+#[derive(Default)]
+pub struct KeyedLock {
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl KeyedLock {
+    /// Waits until nobody else holds the key, and holds it for the lifetime
+    /// of the returned guard.
+    pub async fn lock(&self, key: &str) -> KeyedLockGuard<'_> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+
+        KeyedLockGuard {
+            locks: self,
+            key: key.to_string(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+/// A key held in a [`KeyedLock`], released when dropped.
+pub struct KeyedLockGuard<'a> {
+    locks: &'a KeyedLock,
+    key: String,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for KeyedLockGuard<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.locks.lock().unwrap();
+        self.guard = None;
+
+        // anyone waiting on the key holds a reference of their own
+        if let Entry::Occupied(entry) = locks.entry(std::mem::take(&mut self.key)) {
+            if Arc::strong_count(entry.get()) == 1 {
+                entry.remove();
+            }
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Number of events held for each subscriber before the slowest start
+/// missing events.
+const CAPACITY: usize = 1024;
+
+/// Number of the most recent events held to be replayed to subscribers
+/// picking up where an earlier one left off.
+pub(crate) const REPLAY_CAPACITY: usize = 1024;
+
+/// A change made to data within the server.
+///
+/// Events are handed to anything subscribed to the bus, including debugging
+/// tools, so they only ever carry identifiers and never token or password
+/// material.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum DomainEvent {
+    /// Objects of a data type were created, updated or destroyed within an
+    /// account.
+    #[serde(rename_all = "camelCase")]
+    ObjectsChanged {
+        account_id: Uuid,
+        /// The name of the data type, eg. `AddressBook`.
+        data_type: Cow<'static, str>,
+        /// The state of the data type after the change.
+        new_state: String,
+    },
+    /// The state of everything visible to a user changed, such as when an
+    /// account was attached to them.
+    #[serde(rename_all = "camelCase")]
+    UserStateChanged {
+        user_id: Uuid,
+        /// The user's sequence number after the change.
+        new_state: u64,
+    },
+}
+
+impl DomainEvent {
+    /// The account the event happened within, if it relates to a single
+    /// account.
+    pub fn account_id(&self) -> Option<Uuid> {
+        match self {
+            Self::ObjectsChanged { account_id, .. } => Some(*account_id),
+            Self::UserStateChanged { .. } => None,
+        }
+    }
+
+    /// The name of the data type the event relates to, if it relates to a
+    /// single data type.
+    pub fn data_type(&self) -> Option<&str> {
+        match self {
+            Self::ObjectsChanged { data_type, .. } => Some(data_type),
+            Self::UserStateChanged { .. } => None,
+        }
+    }
+}
+
+/// A [`DomainEvent`] along with when it was published.
+#[derive(Serialize, Debug)]
+pub struct TimestampedEvent {
+    /// Increases with every event published by the bus, starting from 1.
+    pub id: u64,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: DomainEvent,
+}
+
+/// Broadcasts [`DomainEvent`]s to every subscriber.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<TimestampedEvent>>,
+    /// Picked afresh each time the server starts, so event ids handed out
+    /// by an earlier run aren't mistaken for ones from this run.
+    epoch: u64,
+    recent: Arc<Mutex<RecentEvents>>,
+}
+
+/// The events most recently published.
+#[derive(Default)]
+struct RecentEvents {
+    /// The id of the last event published, or 0 if there hasn't been one.
+    last_id: u64,
+    events: VecDeque<Arc<TimestampedEvent>>,
+}
+
+/// What a subscriber picking up where an earlier one left off missed.
+pub enum Missed {
+    /// These events, oldest first, if any.
+    Events(Vec<Arc<TimestampedEvent>>),
+    /// An unknown number of events, as they've since been forgotten or the
+    /// event id wasn't handed out by this run of the server.
+    Unknown,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            epoch: rand::random(),
+            recent: Arc::default(),
+        }
+    }
+
+    pub fn publish(&self, event: DomainEvent) {
+        // held while sending, so subscribers see events in the order they
+        // were recorded
+        let mut recent = self.recent.lock().unwrap();
+        recent.last_id += 1;
+
+        let event = Arc::new(TimestampedEvent {
+            id: recent.last_id,
+            at: Utc::now(),
+            event,
+        });
+
+        if recent.events.len() == REPLAY_CAPACITY {
+            recent.events.pop_front();
+        }
+
+        recent.events.push_back(event.clone());
+
+        // having nobody subscribed isn't an error
+        drop(self.sender.send(event));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<TimestampedEvent>> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribes to events published after the one with the given event
+    /// id, returning any that have already been published.
+    pub fn subscribe_since(
+        &self,
+        event_id: &str,
+    ) -> (broadcast::Receiver<Arc<TimestampedEvent>>, Missed) {
+        let recent = self.recent.lock().unwrap();
+        let receiver = self.sender.subscribe();
+
+        let oldest_held = recent
+            .events
+            .front()
+            .map_or(recent.last_id + 1, |event| event.id);
+
+        let missed = match self.parse_event_id(event_id) {
+            Some(id) if id + 1 >= oldest_held && id <= recent.last_id => Missed::Events(
+                recent
+                    .events
+                    .iter()
+                    .filter(|event| event.id > id)
+                    .cloned()
+                    .collect(),
+            ),
+            _ => Missed::Unknown,
+        };
+
+        (receiver, missed)
+    }
+
+    /// The id of the last event published, or 0 if there hasn't been one.
+    pub fn last_id(&self) -> u64 {
+        self.recent.lock().unwrap().last_id
+    }
+
+    /// The id handed to clients for the event with the given id, which can
+    /// be given back to [`Self::subscribe_since`].
+    pub fn event_id(&self, id: u64) -> String {
+        format!("{:x}-{id}", self.epoch)
+    }
+
+    fn parse_event_id(&self, event_id: &str) -> Option<u64> {
+        let (epoch, id) = event_id.split_once('-')?;
+
+        if u64::from_str_radix(epoch, 16).ok()? != self.epoch {
+            return None;
+        }
+
+        id.parse().ok()
+    }
+}
@@ -1,9 +1,16 @@
 use std::{
     borrow::Cow,
+    collections::HashMap,
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
 };
 
+use arc_swap::ArcSwap;
+use argon2::Argon2;
 use askama::Template;
 use axum::{
     async_trait,
@@ -12,64 +19,96 @@ use axum::{
     http::{Method, Request},
     BoxError, RequestExt,
 };
+use chrono::{DateTime, Utc};
 use oxide_auth::{
-    endpoint::{OAuthError, OwnerConsent, QueryParameter, Scope, Scopes, Solicitation, WebRequest},
+    code_grant::extensions::Pkce,
+    endpoint::{
+        OAuthError, OwnerConsent, QueryParameter, Scope, Scopes, Solicitation, WebRequest,
+        WebResponse,
+    },
     frontends::simple::{
         endpoint,
         endpoint::{Error, ResponseCreator, Vacant},
+        extensions::{AccessTokenRequest, AuthorizationRequest},
     },
     primitives::{
-        grant::Grant,
-        issuer::{IssuedToken, RefreshedToken},
-        prelude::{AuthMap, Client, ClientMap, RandomGenerator, TokenMap},
-        registrar::RegisteredUrl,
+        generator::TagGrant,
+        grant::{Extensions, Grant},
+        issuer::{IssuedToken, RefreshedToken, TokenType},
+        prelude::{Client, ClientMap, RandomGenerator},
+        registrar::{RegisteredUrl, Registrar},
     },
 };
 use oxide_auth_async::endpoint::{
     access_token::AccessTokenFlow, authorization::AuthorizationFlow, refresh::RefreshFlow,
-    resource::ResourceFlow, OwnerSolicitor,
+    resource::ResourceFlow, AccessTokenExtension, AuthorizationExtension, OwnerSolicitor,
 };
 use oxide_auth_axum::{OAuthRequest, OAuthResponse, WebError};
 use tower_cookies::Cookies;
-use tracing::info;
-use url::Url;
+use tracing::{error, info, warn};
 
 use crate::{
+    config::{OAuthClient, OAuthConfig},
     context::DerivedKeys,
-    store::{Store, UserProvider},
+    store::{self, IssuedOAuthToken, LoginFailureProvider, OAuthProvider, Store, UserProvider},
     util::CsrfToken,
 };
 
 pub struct OAuth2 {
-    pub registrar: ClientMap,
+    /// Swapped out when the config is reloaded, each request sticks with
+    /// the clients that were registered when it started.
+    clients: ArcSwap<RegisteredClients>,
+    lockout: LoginLockout,
     pub authorizer: Authorizer,
     pub issuer: Issuer,
     pub derived_keys: Arc<DerivedKeys>,
     pub store: Arc<Store>,
+    /// Used to rehash passwords hashed with outdated costs as users log in.
+    argon2: Arc<Argon2<'static>>,
+    /// Whether cookies are only sent over HTTPS, which they are whenever the
+    /// server is served over it.
+    secure_cookies: bool,
 }
 
 impl OAuth2 {
-    pub fn new(store: Arc<Store>, derived_keys: Arc<DerivedKeys>) -> Self {
-        let mut registrar = ClientMap::new();
-
-        registrar.register_client(Client::public(
-            "abcdef",
-            RegisteredUrl::from("https://google.com/".parse::<Url>().unwrap()),
-            "test".parse::<Scope>().unwrap(),
-        ));
-
-        let authorizer = Authorizer::default();
-        let issuer = Issuer::default();
+    pub fn new(
+        store: Arc<Store>,
+        derived_keys: Arc<DerivedKeys>,
+        argon2: Arc<Argon2<'static>>,
+        config: &OAuthConfig,
+        secure_cookies: bool,
+    ) -> Self {
+        let clients = Arc::new(RegisteredClients::new(&config.clients));
+        let authorizer = Authorizer::new(store.clone());
+        let issuer = Issuer::new(store.clone(), clients.clone());
+
+        // read replicas can't write, the primary sweeps tokens for them
+        if !store.is_read_only() {
+            spawn_token_sweep(
+                Arc::downgrade(&store),
+                Duration::from_secs(config.token_sweep_interval),
+            );
+        }
 
         Self {
-            registrar,
+            clients: ArcSwap::new(clients),
+            lockout: LoginLockout::from(config),
             authorizer,
             issuer,
             derived_keys,
             store,
+            argon2,
+            secure_cookies,
         }
     }
 
+    /// Replaces the registered clients, tokens already issued to a client
+    /// that's no longer registered can't be refreshed.
+    pub fn reload_clients(&self, clients: &[OAuthClient]) {
+        self.clients
+            .store(Arc::new(RegisteredClients::new(clients)));
+    }
+
     pub async fn resource(
         &self,
         request: OAuthRequest,
@@ -93,9 +132,30 @@ impl OAuth2 {
         &self,
         request: OAuthRequestWrapper,
     ) -> Result<OAuthResponse, endpoint::Error<OAuthRequestWrapper>> {
-        AccessTokenFlow::prepare(self.endpoint())?
-            .execute(request)
-            .await
+        // clients refresh their tokens through the token endpoint (RFC 6749
+        // section 6), so hand those requests over to the refresh flow
+        if request.grant_type().as_deref() == Some("refresh_token") {
+            return self.refresh(request).await;
+        }
+
+        let endpoint = self.endpoint();
+        let verifier_rejected = endpoint.pkce.verifier_rejected.clone();
+
+        let response = AccessTokenFlow::prepare(endpoint)?.execute(request).await?;
+
+        if verifier_rejected.load(Ordering::Relaxed) {
+            // oxide-auth reports any failed extension as `invalid_request`,
+            // but RFC 7636 requires a bad verifier to be an `invalid_grant`
+            let mut response = OAuthResponse::default();
+            response.client_error().map_err(Error::Web)?;
+            response
+                .body_json(r#"{"error":"invalid_grant"}"#)
+                .map_err(Error::Web)?;
+
+            return Ok(response);
+        }
+
+        Ok(response)
     }
 
     pub async fn refresh(
@@ -107,27 +167,120 @@ impl OAuth2 {
             .await
     }
 
+    /// Revokes an access or refresh token along with its counterpart, as
+    /// defined by RFC 7009. Unknown tokens, or tokens issued to another
+    /// client, are ignored so as not to leak their existence.
+    pub async fn revoke(
+        &self,
+        client_id: &str,
+        client_secret: Option<&str>,
+        token: &str,
+        token_type_hint: Option<&str>,
+    ) -> Result<(), RevokeError> {
+        self.clients
+            .load()
+            .registrar
+            .check(client_id, client_secret.map(str::as_bytes))
+            .map_err(|_| RevokeError::InvalidClient)?;
+
+        // the hint only decides which lookup to try first
+        let issued = if token_type_hint == Some("refresh_token") {
+            match self.store.get_by_refresh_token(token).await {
+                Ok(None) => self.store.get_by_access_token(token).await,
+                v => v,
+            }
+        } else {
+            match self.store.get_by_access_token(token).await {
+                Ok(None) => self.store.get_by_refresh_token(token).await,
+                v => v,
+            }
+        }
+        .map_err(|error| {
+            error!(?error, "Failed to fetch token for revocation");
+            RevokeError::Store
+        })?;
+
+        let Some(issued) = issued.filter(|issued| issued.grant.client_id == client_id) else {
+            return Ok(());
+        };
+
+        self.store.remove_token(&issued).await.map_err(|error| {
+            error!(?error, "Failed to revoke token");
+            RevokeError::Store
+        })
+    }
+
     fn endpoint(&self) -> Endpoint<'_> {
+        let clients = self.clients.load_full();
+
         Endpoint {
-            registrar: &self.registrar,
+            clients: clients.clone(),
             authorizer: self.authorizer.clone(),
-            issuer: self.issuer.clone(),
+            issuer: self.issuer.with_clients(clients.clone()),
             solicitor: Solicitor {
                 derived_keys: &self.derived_keys,
                 store: &self.store,
+                argon2: &self.argon2,
+                lockout: self.lockout,
+                secure_cookies: self.secure_cookies,
             },
             scopes: vec![Scope::from_str("test").unwrap()],
+            pkce: PkceExtension::new(clients),
             response: Vacant,
         }
     }
 }
 
+#[derive(Debug)]
+pub enum RevokeError {
+    /// The client failed to authenticate.
+    InvalidClient,
+    /// The store failed to look up or remove the token.
+    Store,
+}
+
+/// The clients registered with the server along with how long the tokens
+/// issued to each remain usable.
+pub struct RegisteredClients {
+    registrar: ClientMap,
+    lifetimes: HashMap<String, TokenLifetimes>,
+}
+
+impl RegisteredClients {
+    fn new(clients: &[OAuthClient]) -> Self {
+        let mut registrar = ClientMap::new();
+
+        for client in clients {
+            let redirect_uri = RegisteredUrl::from(client.redirect_uri.clone());
+            let scope = client.scope.clone();
+
+            registrar.register_client(match &client.secret {
+                Some(secret) => {
+                    Client::confidential(&client.id, redirect_uri, scope, secret.as_bytes())
+                }
+                None => Client::public(&client.id, redirect_uri, scope),
+            });
+        }
+
+        let lifetimes = clients
+            .iter()
+            .map(|client| (client.id.clone(), TokenLifetimes::from(client)))
+            .collect();
+
+        Self {
+            registrar,
+            lifetimes,
+        }
+    }
+}
+
 pub struct Endpoint<'a> {
-    registrar: &'a ClientMap,
+    clients: Arc<RegisteredClients>,
     authorizer: Authorizer,
     issuer: Issuer,
     solicitor: Solicitor<'a>,
     scopes: Vec<Scope>,
+    pkce: PkceExtension,
     response: Vacant,
 }
 
@@ -139,7 +292,7 @@ where
     type Error = Error<T>;
 
     fn registrar(&self) -> Option<&(dyn oxide_auth_async::primitives::Registrar + Sync)> {
-        Some(&self.registrar)
+        Some(&self.clients.registrar)
     }
 
     fn authorizer_mut(
@@ -160,6 +313,10 @@ where
         Some(&mut self.scopes)
     }
 
+    fn extension(&mut self) -> Option<&mut (dyn oxide_auth_async::endpoint::Extension + Send)> {
+        Some(&mut self.pkce)
+    }
+
     fn response(
         &mut self,
         request: &mut T,
@@ -177,51 +334,292 @@ where
     }
 }
 
+/// Applies PKCE (RFC 7636) to the authorization code flow, only the `S256`
+/// method is accepted. Public clients have no secret to authenticate the
+/// code exchange with so must always send a challenge, confidential clients
+/// may choose to.
+pub struct PkceExtension {
+    clients: Arc<RegisteredClients>,
+    required: Pkce,
+    optional: Pkce,
+    /// Set when a token request is rejected due to a missing or mismatched
+    /// `code_verifier`.
+    verifier_rejected: Arc<AtomicBool>,
+}
+
+impl PkceExtension {
+    fn new(clients: Arc<RegisteredClients>) -> Self {
+        Self {
+            clients,
+            required: Pkce::required(),
+            optional: Pkce::optional(),
+            verifier_rejected: Arc::default(),
+        }
+    }
+
+    fn for_client(&self, client_id: Option<&str>) -> &Pkce {
+        // only public clients can pass the registrar's check without a secret
+        let is_public = client_id.is_some_and(|id| self.clients.registrar.check(id, None).is_ok());
+
+        if is_public {
+            &self.required
+        } else {
+            &self.optional
+        }
+    }
+}
+
+impl oxide_auth_async::endpoint::Extension for PkceExtension {
+    fn authorization(&mut self) -> Option<&mut (dyn AuthorizationExtension + Send)> {
+        Some(self)
+    }
+
+    fn access_token(&mut self) -> Option<&mut (dyn AccessTokenExtension + Send)> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl AuthorizationExtension for PkceExtension {
+    async fn extend(
+        &mut self,
+        request: &(dyn AuthorizationRequest + Sync),
+    ) -> Result<Extensions, ()> {
+        let pkce = self.for_client(request.client_id().as_deref());
+        let mut extensions = Extensions::new();
+
+        if let Some(challenge) = pkce.challenge(
+            request.extension("code_challenge_method"),
+            request.extension("code_challenge"),
+        )? {
+            extensions.set(pkce, challenge);
+        }
+
+        Ok(extensions)
+    }
+}
+
+#[async_trait]
+impl AccessTokenExtension for PkceExtension {
+    async fn extend(
+        &mut self,
+        request: &(dyn AccessTokenRequest + Sync),
+        mut data: Extensions,
+    ) -> Result<Extensions, ()> {
+        let pkce = self.for_client(request.client_id().as_deref());
+
+        pkce.verify(data.remove(pkce), request.extension("code_verifier"))
+            .map_err(|()| self.verifier_rejected.store(true, Ordering::Relaxed))?;
+
+        Ok(Extensions::new())
+    }
+}
+
+/// Periodically removes tokens that can no longer be used from the store,
+/// until the store is dropped.
+fn spawn_token_sweep(store: Weak<Store>, every: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+
+        loop {
+            interval.tick().await;
+
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+
+            match store.remove_expired_tokens().await {
+                Ok(0) => {}
+                Ok(removed) => info!(removed, "Swept expired OAuth tokens"),
+                Err(error) => error!(?error, "Failed to sweep expired OAuth tokens"),
+            }
+
+            match store.remove_expired_login_failures().await {
+                Ok(0) => {}
+                Ok(removed) => info!(removed, "Swept expired failed login counts"),
+                Err(error) => error!(?error, "Failed to sweep expired failed login counts"),
+            }
+        }
+    });
+}
+
+/// How long the tokens issued to a client remain usable.
+#[derive(Copy, Clone)]
+struct TokenLifetimes {
+    access_token: chrono::Duration,
+    refresh_token: chrono::Duration,
+    refresh_idle_timeout: chrono::Duration,
+}
+
+impl From<&OAuthClient> for TokenLifetimes {
+    fn from(client: &OAuthClient) -> Self {
+        let seconds = |v| {
+            chrono::Duration::from_std(Duration::from_secs(v))
+                .expect("oauth token lifetime out of range")
+        };
+
+        Self {
+            access_token: seconds(client.access_token_ttl),
+            refresh_token: seconds(client.refresh_token_lifetime),
+            refresh_idle_timeout: seconds(client.refresh_token_idle_timeout),
+        }
+    }
+}
+
+/// Issues access and refresh tokens, persisting them to the store so they
+/// survive restarts.
 #[derive(Clone)]
 pub struct Issuer {
-    issuer: Arc<Mutex<TokenMap<RandomGenerator>>>,
+    store: Arc<Store>,
+    generator: Arc<RandomGenerator>,
+    clients: Arc<RegisteredClients>,
 }
 
-impl Default for Issuer {
-    fn default() -> Self {
+impl Issuer {
+    pub fn new(store: Arc<Store>, clients: Arc<RegisteredClients>) -> Self {
+        Self {
+            store,
+            generator: Arc::new(RandomGenerator::new(16)),
+            clients,
+        }
+    }
+
+    /// The same issuer, issuing tokens to a different set of clients.
+    fn with_clients(&self, clients: Arc<RegisteredClients>) -> Self {
         Self {
-            issuer: Arc::new(Mutex::new(TokenMap::new(RandomGenerator::new(16)))),
+            clients,
+            ..self.clone()
         }
     }
+
+    /// Issues a new access and refresh token for the grant. Refreshed tokens
+    /// pass along when the refresh token they replace was due to expire, so
+    /// refreshing can't extend a refresh token's lifetime.
+    async fn issue_token(
+        &mut self,
+        mut grant: Grant,
+        refresh_until: Option<DateTime<Utc>>,
+    ) -> Result<IssuedToken, ()> {
+        let Some(lifetimes) = self.clients.lifetimes.get(&grant.client_id).copied() else {
+            error!(
+                client_id = grant.client_id,
+                "Token requested by unknown client"
+            );
+            return Err(());
+        };
+
+        let now = Utc::now();
+        grant.until = now + lifetimes.access_token;
+
+        let access_token = self.generator.tag(0, &grant)?;
+        let refresh_token = self.generator.tag(1, &grant)?;
+        let until = grant.until;
+
+        self.store
+            .store_token(IssuedOAuthToken {
+                access_token: access_token.clone(),
+                refresh_token: Some(refresh_token.clone()),
+                grant: grant.into(),
+                refresh_until: refresh_until.unwrap_or(now + lifetimes.refresh_token),
+                refresh_idle_until: now + lifetimes.refresh_idle_timeout,
+            })
+            .await
+            .map_err(|error| error!(?error, "Failed to persist issued token"))?;
+
+        Ok(IssuedToken {
+            token: access_token,
+            refresh: Some(refresh_token),
+            until,
+            token_type: TokenType::Bearer,
+        })
+    }
 }
 
 #[async_trait]
 impl oxide_auth_async::primitives::Issuer for Issuer {
     async fn issue(&mut self, grant: Grant) -> Result<IssuedToken, ()> {
-        oxide_auth::primitives::issuer::Issuer::issue(&mut self.issuer.lock().unwrap(), grant)
+        self.issue_token(grant, None).await
     }
 
     async fn refresh(&mut self, token: &str, grant: Grant) -> Result<RefreshedToken, ()> {
-        oxide_auth::primitives::issuer::Issuer::refresh(
-            &mut self.issuer.lock().unwrap(),
-            token,
-            grant,
-        )
+        let Some(existing) = self
+            .store
+            .get_by_refresh_token(token)
+            .await
+            .map_err(|error| error!(?error, "Failed to fetch refresh token"))?
+        else {
+            return Err(());
+        };
+
+        self.store
+            .remove_token(&existing)
+            .await
+            .map_err(|error| error!(?error, "Failed to remove refreshed token"))?;
+
+        let issued = self
+            .issue_token(grant, Some(existing.refresh_until))
+            .await?;
+
+        Ok(RefreshedToken {
+            token: issued.token,
+            refresh: issued.refresh,
+            until: issued.until,
+            token_type: issued.token_type,
+        })
     }
 
     async fn recover_token(&mut self, token: &str) -> Result<Option<Grant>, ()> {
-        oxide_auth::primitives::issuer::Issuer::recover_token(&self.issuer.lock().unwrap(), token)
+        let token = self
+            .store
+            .get_by_access_token(token)
+            .await
+            .map_err(|error| error!(?error, "Failed to fetch access token"))?;
+
+        token
+            .map(|token| token.grant)
+            .filter(|grant| !grant.is_expired())
+            .map(Grant::try_from)
+            .transpose()
+            .map_err(|error| error!(%error, "Access token has a malformed grant"))
     }
 
     async fn recover_refresh(&mut self, token: &str) -> Result<Option<Grant>, ()> {
-        oxide_auth::primitives::issuer::Issuer::recover_refresh(&self.issuer.lock().unwrap(), token)
+        let token = self
+            .store
+            .get_by_refresh_token(token)
+            .await
+            .map_err(|error| error!(?error, "Failed to fetch refresh token"))?;
+
+        // the grant is handed back with the refresh token's expiry, so expired
+        // refresh tokens are rejected with an `invalid_grant` error
+        let Some((grant, until)) = token.and_then(|token| {
+            let until = token.refresh_expires_at()?;
+            Some((token.grant, until))
+        }) else {
+            return Ok(None);
+        };
+
+        let mut grant = Grant::try_from(grant)
+            .map_err(|error| error!(%error, "Refresh token has a malformed grant"))?;
+        grant.until = until;
+
+        Ok(Some(grant))
     }
 }
 
+/// Issues authorization codes, persisting them to the store so they survive
+/// restarts.
 #[derive(Clone)]
 pub struct Authorizer {
-    auth: Arc<Mutex<AuthMap<RandomGenerator>>>,
+    store: Arc<Store>,
+    generator: Arc<RandomGenerator>,
 }
 
-impl Default for Authorizer {
-    fn default() -> Self {
+impl Authorizer {
+    pub fn new(store: Arc<Store>) -> Self {
         Self {
-            auth: Arc::new(Mutex::new(AuthMap::new(RandomGenerator::new(16)))),
+            store,
+            generator: Arc::new(RandomGenerator::new(16)),
         }
     }
 }
@@ -229,23 +627,55 @@ impl Default for Authorizer {
 #[async_trait]
 impl oxide_auth_async::primitives::Authorizer for Authorizer {
     async fn authorize(&mut self, grant: Grant) -> Result<String, ()> {
-        oxide_auth::primitives::authorizer::Authorizer::authorize(
-            &mut self.auth.lock().unwrap(),
-            grant,
-        )
+        let code = self.generator.tag(0, &grant)?;
+
+        self.store
+            .store_auth_code(&code, grant.into())
+            .await
+            .map_err(|error| error!(?error, "Failed to persist authorization code"))?;
+
+        Ok(code)
     }
 
     async fn extract(&mut self, token: &str) -> Result<Option<Grant>, ()> {
-        oxide_auth::primitives::authorizer::Authorizer::extract(
-            &mut self.auth.lock().unwrap(),
-            token,
-        )
+        let grant = self
+            .store
+            .take_auth_code(token)
+            .await
+            .map_err(|error| error!(?error, "Failed to fetch authorization code"))?;
+
+        grant
+            .filter(|grant| !grant.is_expired())
+            .map(Grant::try_from)
+            .transpose()
+            .map_err(|error| error!(%error, "Authorization code has a malformed grant"))
     }
 }
 
 pub struct Solicitor<'a> {
     derived_keys: &'a DerivedKeys,
     store: &'a Store,
+    argon2: &'a Argon2<'static>,
+    lockout: LoginLockout,
+    secure_cookies: bool,
+}
+
+/// How many wrong passwords can be given for a user before logging in as
+/// them is refused for the rest of the window.
+#[derive(Copy, Clone)]
+struct LoginLockout {
+    max_failures: u32,
+    window: chrono::Duration,
+}
+
+impl From<&OAuthConfig> for LoginLockout {
+    fn from(config: &OAuthConfig) -> Self {
+        Self {
+            max_failures: config.max_failed_logins,
+            window: chrono::Duration::from_std(Duration::from_secs(config.failed_login_window))
+                .expect("failed login window out of range"),
+        }
+    }
 }
 
 #[async_trait]
@@ -267,15 +697,13 @@ impl OwnerSolicitor<OAuthRequestWrapper> for Solicitor<'_> {
         solicitation: Solicitation<'_>,
     ) -> OwnerConsent<OAuthResponse> {
         let auth_state = if req.method == Method::GET {
-            AuthState::Unauthenticated(None)
+            Ok(AuthState::Unauthenticated(None))
         } else if let Some(((username, password), csrf_token)) = req.inner.body().and_then(|body| {
             body.unique_value("username")
                 .zip(body.unique_value("password"))
                 .zip(body.unique_value("csrf_token"))
         }) {
-            attempt_authentication(
-                self.derived_keys,
-                self.store,
+            self.attempt_authentication(
                 &req.cookie_jar,
                 &username,
                 password.into_owned(),
@@ -283,15 +711,21 @@ impl OwnerSolicitor<OAuthRequestWrapper> for Solicitor<'_> {
             )
             .await
         } else {
-            AuthState::Unauthenticated(Some(UnauthenticatedState::MissingUserPass))
+            Ok(AuthState::Unauthenticated(Some(
+                UnauthenticatedState::MissingUserPass,
+            )))
         };
 
         match auth_state {
-            AuthState::Unauthenticated(reason) => {
+            Err(error) => {
+                error!(%error, "Store failed while handling request");
+                OwnerConsent::Error(WebError::InternalError(None))
+            }
+            Ok(AuthState::Unauthenticated(reason)) => {
                 info!("Soliciting auth from user due to {reason:?}");
 
                 let csrf_token = CsrfToken::new(self.derived_keys);
-                csrf_token.write_cookie(&req.cookie_jar);
+                csrf_token.write_cookie(&req.cookie_jar, self.secure_cookies);
 
                 let response = OAuthResponse::default()
                     .content_type("text/html")
@@ -308,36 +742,99 @@ impl OwnerSolicitor<OAuthRequestWrapper> for Solicitor<'_> {
 
                 OwnerConsent::InProgress(response)
             }
-            AuthState::Authenticated(username) => OwnerConsent::Authorized(username),
+            Ok(AuthState::Authenticated(username)) => OwnerConsent::Authorized(username),
         }
     }
 }
 
-async fn attempt_authentication(
-    derived_keys: &DerivedKeys,
-    store: &Store,
-    cookies: &Cookies,
-    username: &str,
-    password: String,
-    csrf_token: &str,
-) -> AuthState {
-    if !CsrfToken::verify(derived_keys, cookies, csrf_token) {
-        return AuthState::Unauthenticated(Some(UnauthenticatedState::InvalidCsrfToken));
-    }
+impl Solicitor<'_> {
+    /// Checks the credentials given to the login form, failing only if the
+    /// store does.
+    async fn attempt_authentication(
+        &self,
+        cookies: &Cookies,
+        username: &str,
+        password: String,
+        csrf_token: &str,
+    ) -> Result<AuthState, store::Error> {
+        if !CsrfToken::verify(self.derived_keys, cookies, csrf_token) {
+            return Ok(AuthState::Unauthenticated(Some(
+                UnauthenticatedState::InvalidCsrfToken,
+            )));
+        }
+
+        // failures are counted however the username is cased or padded, so
+        // the count can't be sidestepped by varying it
+        let failures_key = username.trim().to_lowercase();
+
+        // checked first so that a locked out attack costs no password hashing
+        if self
+            .store
+            .get_login_failures(&failures_key)
+            .await?
+            .is_some_and(|failures| failures.count >= self.lockout.max_failures)
+        {
+            return Ok(AuthState::Unauthenticated(Some(
+                UnauthenticatedState::LockedOut,
+            )));
+        }
+
+        // read replicas can't write the new hash, it's left to whenever the
+        // user next logs in against the primary
+        let rehash = !self.store.is_read_only();
 
-    let Some(user) = store.get_by_username(username).await.unwrap() else {
-        return AuthState::Unauthenticated(Some(UnauthenticatedState::InvalidUserPass));
-    };
+        let authenticated = match self.store.get_by_username(username).await? {
+            Some(mut user) => {
+                let argon2 = self.argon2.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    if !user.verify_password(&password) {
+                        return None;
+                    }
+
+                    let rehashed = rehash && user.needs_rehash(&argon2);
+
+                    if rehashed {
+                        user.set_password(&password, &argon2);
+                    }
+
+                    Some((user, rehashed))
+                })
+                .await
+                .unwrap()
+            }
+            None => None,
+        };
 
-    tokio::task::spawn_blocking(move || {
-        if user.verify_password(&password) {
-            AuthState::Authenticated(user.username)
+        if let Some((user, rehashed)) = authenticated {
+            self.store.clear_login_failures(&failures_key).await?;
+
+            let user_id = user.id;
+
+            // the old hash still verifies, so failing to replace it is no
+            // reason to turn the user away
+            if rehashed {
+                match self.store.update_user(user).await {
+                    Ok(()) => info!(user = %user_id, "Rehashed password with current costs"),
+                    Err(error) => warn!(user = %user_id, %error, "Failed to rehash password"),
+                }
+            }
+
+            // grants are issued to the user's id rather than their username,
+            // so they aren't tied to how the user logs in
+            Ok(AuthState::Authenticated(user_id.to_string()))
         } else {
-            AuthState::Unauthenticated(Some(UnauthenticatedState::InvalidUserPass))
+            // unknown users are counted too, so a lockout doesn't reveal
+            // which usernames exist
+            self.store
+                .record_login_failure(&failures_key, self.lockout.window)
+                .await?;
+
+            Ok(AuthState::Unauthenticated(Some(
+                UnauthenticatedState::InvalidUserPass,
+            )))
         }
-    })
-    .await
-    .unwrap()
+    }
 }
 
 #[derive(Template)]
@@ -358,6 +855,8 @@ pub enum UnauthenticatedState {
     InvalidUserPass,
     MissingUserPass,
     InvalidCsrfToken,
+    /// Too many wrong passwords have been given for the user recently.
+    LockedOut,
 }
 
 pub struct OAuthRequestWrapper {
@@ -366,6 +865,13 @@ pub struct OAuthRequestWrapper {
     cookie_jar: Cookies,
 }
 
+impl OAuthRequestWrapper {
+    /// The `grant_type` given in the body of the request, if any.
+    fn grant_type(&self) -> Option<Cow<'_, str>> {
+        self.inner.body()?.unique_value("grant_type")
+    }
+}
+
 impl WebRequest for OAuthRequestWrapper {
     type Error = WebError;
     type Response = OAuthResponse;
@@ -401,3 +907,89 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use oxide_auth_async::primitives::{Authorizer as _, Issuer as _};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::context::events::EventBus;
+
+    fn open_store(dir: &TempDir) -> Arc<Store> {
+        let config =
+            toml::from_str(&format!("type = \"rocksdb\"\npath = {:?}", dir.path())).unwrap();
+
+        Arc::new(Store::from_config(config, EventBus::new()).unwrap())
+    }
+
+    fn clients() -> Arc<RegisteredClients> {
+        Arc::new(RegisteredClients::new(&[OAuthClient {
+            id: "client".to_string(),
+            redirect_uri: "https://client.example/callback".parse().unwrap(),
+            scope: Scope::from_str("test").unwrap(),
+            secret: None,
+            access_token_ttl: 60 * 60,
+            refresh_token_lifetime: 24 * 60 * 60,
+            refresh_token_idle_timeout: 24 * 60 * 60,
+        }]))
+    }
+
+    fn grant() -> Grant {
+        Grant {
+            owner_id: "owner".to_string(),
+            client_id: "client".to_string(),
+            scope: Scope::from_str("test").unwrap(),
+            redirect_uri: "https://client.example/callback".parse().unwrap(),
+            until: Utc::now() + chrono::Duration::minutes(10),
+            extensions: Extensions::new(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn issued_token_survives_rebuilding_issuer() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let issued = Issuer::new(open_store(&dir), clients())
+            .issue(grant())
+            .await
+            .unwrap();
+
+        // everything holding the first store has been dropped, so it's
+        // reopened from disk as if the server had restarted
+        let mut rebuilt = Issuer::new(open_store(&dir), clients());
+
+        let recovered = rebuilt.recover_token(&issued.token).await.unwrap().unwrap();
+        assert_eq!(recovered.owner_id, "owner");
+        assert_eq!(recovered.client_id, "client");
+        assert_eq!(recovered.scope, Scope::from_str("test").unwrap());
+
+        let refresh = issued.refresh.unwrap();
+        assert!(rebuilt.recover_refresh(&refresh).await.unwrap().is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn authorization_code_can_only_be_redeemed_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+
+        let code = Authorizer::new(store.clone())
+            .authorize(grant())
+            .await
+            .unwrap();
+
+        let redemptions = futures::future::join_all((0..8).map(|_| {
+            let mut authorizer = Authorizer::new(store.clone());
+            let code = code.clone();
+
+            tokio::spawn(async move { authorizer.extract(&code).await.unwrap() })
+        }))
+        .await;
+
+        let redeemed = redemptions
+            .into_iter()
+            .filter(|redemption| redemption.as_ref().unwrap().is_some())
+            .count();
+        assert_eq!(redeemed, 1);
+    }
+}
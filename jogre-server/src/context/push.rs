@@ -0,0 +1,593 @@
+//! Delivery of `StateChange` events to push subscriptions.
+//!
+//! Deliveries are grouped by the host of the push service they're sent to,
+//! each host having its own queue, limit on deliveries in flight and circuit
+//! breaker so that a push service that's failing or slow only holds up
+//! deliveries to itself. Changes waiting on a host are coalesced per
+//! subscription, so however long a host is unavailable for, each subscription
+//! has at most one delivery queued carrying the latest state of everything
+//! that changed in the meantime.
+//!
+//! Payloads are sent unencrypted, subscriptions with `keys` are refused when
+//! they're created.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    http::{header, Request, StatusCode},
+};
+use chrono::Utc;
+use futures::future::join_all;
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use jmap_proto::{
+    common::Id,
+    endpoints::{object::ObjectState, push_subscription::PushVerification},
+    events::{state_change::StateChange, Event},
+};
+use metrics::{gauge, increment_counter};
+use rand::Rng;
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver},
+    time::Instant,
+};
+use tracing::{error, info, warn};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    config::PushConfig,
+    context::events::{DomainEvent, TimestampedEvent},
+    extensions::ExtensionRegistry,
+    store::{AccountProvider, ObjectProvider, PushSubscription, PushSubscriptionProvider, Store},
+};
+
+/// How long a push service has to accept a delivery before it's considered
+/// failed.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends a JSON payload to the URL of a push subscription.
+#[async_trait]
+pub trait PushTransport: Send + Sync + 'static {
+    type Error: Debug + Send;
+
+    async fn deliver(&self, url: &Url, body: Bytes) -> Result<(), Self::Error>;
+}
+
+/// Delivers pushes as HTTPS POST requests.
+pub struct HttpsTransport {
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl HttpsTransport {
+    pub fn new() -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_only()
+            .enable_http1()
+            .enable_http2()
+            .build();
+
+        Self {
+            client: Client::builder().build(connector),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpsTransportError {
+    Request(hyper::Error),
+    /// The push service responded with a status other than success.
+    Status(StatusCode),
+    Timeout,
+}
+
+#[async_trait]
+impl PushTransport for HttpsTransport {
+    type Error = HttpsTransportError;
+
+    async fn deliver(&self, url: &Url, body: Bytes) -> Result<(), Self::Error> {
+        let request = Request::post(url.as_str())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = tokio::time::timeout(DELIVERY_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| HttpsTransportError::Timeout)?
+            .map_err(HttpsTransportError::Request)?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(HttpsTransportError::Status(response.status()))
+        }
+    }
+}
+
+/// Queues changes for delivery to push subscriptions.
+pub struct PushDispatcher<T> {
+    transport: Arc<T>,
+    config: Arc<PushConfig>,
+    hosts: Arc<Mutex<HashMap<String, Arc<Host>>>>,
+}
+
+impl<T> Clone for PushDispatcher<T> {
+    fn clone(&self) -> Self {
+        Self {
+            transport: self.transport.clone(),
+            config: self.config.clone(),
+            hosts: self.hosts.clone(),
+        }
+    }
+}
+
+impl<T: PushTransport> PushDispatcher<T> {
+    pub fn new(transport: T, config: PushConfig) -> Self {
+        Self {
+            transport: Arc::new(transport),
+            config: Arc::new(config),
+            hosts: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &PushConfig {
+        &self.config
+    }
+
+    /// Sends the verification code of a new subscription to its URL.
+    ///
+    /// This is attempted once, outside of the host's queue, as a client that
+    /// never receives it can just create the subscription again.
+    pub fn send_verification(&self, url: Url, verification: &PushVerification<'_>) {
+        let body = Bytes::from(serde_json::to_vec(&verification.clone().into_event()).unwrap());
+        let transport = self.transport.clone();
+
+        tokio::spawn(async move {
+            if let Err(error) = transport.deliver(&url, body).await {
+                warn!(?error, %url, "Failed to deliver push verification");
+            }
+        });
+    }
+
+    /// Queues a change to a data type within an account for delivery to a
+    /// subscription, merging it into any change already waiting on it.
+    pub fn enqueue(
+        &self,
+        subscription: Uuid,
+        url: &Url,
+        account_id: Uuid,
+        data_type: Cow<'static, str>,
+        new_state: String,
+    ) {
+        let Some(host_name) = host_name(url) else {
+            warn!(%url, "Push subscription URL has no host");
+            return;
+        };
+
+        // the queue is written to while the hosts are locked, so a worker
+        // can't find its queue empty and exit between the host being looked
+        // up and the change being queued
+        let mut hosts = self.hosts.lock().unwrap();
+
+        let host = hosts.entry(host_name.clone()).or_insert_with(|| {
+            let host = Arc::new(Host {
+                name: host_name,
+                queue: Mutex::default(),
+            });
+
+            tokio::spawn(host.clone().run(
+                self.transport.clone(),
+                self.config.clone(),
+                self.hosts.clone(),
+            ));
+
+            host
+        });
+
+        let mut queue = host.queue.lock().unwrap();
+
+        queue
+            .entry(subscription)
+            .or_insert_with(|| PendingChange {
+                url: url.clone(),
+                changed: HashMap::new(),
+            })
+            .changed
+            .entry(account_id)
+            .or_default()
+            .insert(data_type, new_state);
+
+        host.record_queue_depth(queue.len());
+    }
+}
+
+/// The push service host deliveries are grouped by, including the port so
+/// that services sharing a hostname are kept apart.
+fn host_name(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+
+    Some(match url.port_or_known_default() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    })
+}
+
+/// The latest state of each data type that changed within each account since
+/// a subscription was last delivered to.
+struct PendingChange {
+    url: Url,
+    changed: HashMap<Uuid, HashMap<Cow<'static, str>, String>>,
+}
+
+impl PendingChange {
+    /// Merges in a change that failed to deliver, keeping the state from any
+    /// change queued since as it's newer.
+    fn merge_older(&mut self, older: PendingChange) {
+        for (account_id, types) in older.changed {
+            let account = self.changed.entry(account_id).or_default();
+
+            for (data_type, state) in types {
+                account.entry(data_type).or_insert(state);
+            }
+        }
+    }
+
+    fn to_body(&self) -> Bytes {
+        Bytes::from(serde_json::to_vec(&self.to_state_change().into_event()).unwrap())
+    }
+
+    fn to_state_change(&self) -> StateChange<'_> {
+        StateChange {
+            changed: self
+                .changed
+                .iter()
+                .map(|(account_id, types)| {
+                    (
+                        Id(account_id.to_string().into()),
+                        types
+                            .iter()
+                            .map(|(data_type, state)| {
+                                (
+                                    Cow::Borrowed(data_type.as_ref()),
+                                    ObjectState(Cow::Borrowed(state.as_str())),
+                                )
+                            })
+                            .collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The deliveries waiting on a single push service host.
+struct Host {
+    name: String,
+    queue: Mutex<HashMap<Uuid, PendingChange>>,
+}
+
+impl Host {
+    /// Delivers queued changes until the queue is empty, at which point the
+    /// host is forgotten until another change is queued for it.
+    async fn run<T: PushTransport>(
+        self: Arc<Self>,
+        transport: Arc<T>,
+        config: Arc<PushConfig>,
+        hosts: Arc<Mutex<HashMap<String, Arc<Host>>>>,
+    ) {
+        let mut breaker = CircuitBreaker::default();
+
+        loop {
+            if let BreakerState::Open { until } = breaker.state {
+                tokio::time::sleep_until(until).await;
+                breaker.half_open();
+                self.record_breaker_state(&breaker);
+            }
+
+            let batch = self.take(breaker.batch_size(&config));
+
+            if batch.is_empty() {
+                let mut hosts = hosts.lock().unwrap();
+
+                if self.queue.lock().unwrap().is_empty() {
+                    hosts.remove(&self.name);
+                    return;
+                }
+
+                continue;
+            }
+
+            let results = join_all(batch.into_iter().map(|(subscription, change)| {
+                let transport = &transport;
+
+                async move {
+                    let res = transport.deliver(&change.url, change.to_body()).await;
+                    (subscription, change, res)
+                }
+            }))
+            .await;
+
+            let was_open = breaker.is_open();
+
+            for (subscription, change, res) in results {
+                if let Err(error) = res {
+                    warn!(?error, host = self.name, %subscription, "Failed to deliver push");
+                    increment_counter!(
+                        "jmap_push_deliveries_total",
+                        "host" => self.name.clone(),
+                        "outcome" => "failure",
+                    );
+
+                    breaker.record_failure(&config);
+                    self.requeue(subscription, change);
+                } else {
+                    increment_counter!(
+                        "jmap_push_deliveries_total",
+                        "host" => self.name.clone(),
+                        "outcome" => "success",
+                    );
+
+                    breaker.record_success();
+                }
+            }
+
+            match (was_open, breaker.is_open()) {
+                (false, true) => error!(
+                    host = self.name,
+                    "Pausing push deliveries after repeated failures"
+                ),
+                (true, false) => info!(host = self.name, "Resuming push deliveries"),
+                _ => {}
+            }
+
+            self.record_breaker_state(&breaker);
+
+            if let Some(delay) = breaker.retry_delay(&config) {
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+
+    /// Takes up to `n` changes from the queue to be delivered.
+    fn take(&self, n: usize) -> Vec<(Uuid, PendingChange)> {
+        let mut queue = self.queue.lock().unwrap();
+
+        let subscriptions: Vec<_> = queue.keys().take(n).copied().collect();
+        let batch = subscriptions
+            .into_iter()
+            .filter_map(|subscription| {
+                queue
+                    .remove(&subscription)
+                    .map(|change| (subscription, change))
+            })
+            .collect();
+
+        self.record_queue_depth(queue.len());
+
+        batch
+    }
+
+    /// Puts a change that failed to deliver back on the queue.
+    fn requeue(&self, subscription: Uuid, change: PendingChange) {
+        let mut queue = self.queue.lock().unwrap();
+
+        match queue.get_mut(&subscription) {
+            Some(newer) => newer.merge_older(change),
+            None => {
+                queue.insert(subscription, change);
+            }
+        }
+
+        self.record_queue_depth(queue.len());
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn record_queue_depth(&self, depth: usize) {
+        gauge!("jmap_push_queue_depth", depth as f64, "host" => self.name.clone());
+    }
+
+    fn record_breaker_state(&self, breaker: &CircuitBreaker) {
+        let state = match breaker.state {
+            BreakerState::Closed => 0.0,
+            BreakerState::HalfOpen => 1.0,
+            BreakerState::Open { .. } => 2.0,
+        };
+
+        gauge!("jmap_push_breaker_state", state, "host" => self.name.clone());
+    }
+}
+
+#[derive(Default)]
+enum BreakerState {
+    /// Deliveries are being made as normal.
+    #[default]
+    Closed,
+    /// Deliveries are paused until the given time.
+    Open { until: Instant },
+    /// A single delivery is being attempted to find out whether the host has
+    /// recovered.
+    HalfOpen,
+}
+
+/// Tracks failures delivering to a host, pausing deliveries to it after too
+/// many consecutive failures.
+#[derive(Default)]
+struct CircuitBreaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    /// The number of times the breaker has opened since the host last
+    /// accepted a delivery, extending how long it stays open each time.
+    trips: u32,
+}
+
+impl CircuitBreaker {
+    fn is_open(&self) -> bool {
+        matches!(self.state, BreakerState::Open { .. })
+    }
+
+    fn half_open(&mut self) {
+        self.state = BreakerState::HalfOpen;
+    }
+
+    /// The number of deliveries that may be attempted at once.
+    fn batch_size(&self, config: &PushConfig) -> usize {
+        match self.state {
+            BreakerState::Closed => config.max_concurrent_per_host.max(1),
+            BreakerState::HalfOpen | BreakerState::Open { .. } => 1,
+        }
+    }
+
+    fn record_success(&mut self) {
+        self.state = BreakerState::Closed;
+        self.consecutive_failures = 0;
+        self.trips = 0;
+    }
+
+    fn record_failure(&mut self, config: &PushConfig) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+
+        let trip = match self.state {
+            BreakerState::Closed => self.consecutive_failures >= config.failure_threshold,
+            BreakerState::HalfOpen => true,
+            BreakerState::Open { .. } => false,
+        };
+
+        if trip {
+            self.state = BreakerState::Open {
+                until: Instant::now() + backoff(config, self.trips),
+            };
+            self.trips = self.trips.saturating_add(1);
+        }
+    }
+
+    /// How long to wait before the next batch while the breaker is closed
+    /// but the host has been failing.
+    fn retry_delay(&self, config: &PushConfig) -> Option<Duration> {
+        match self.state {
+            BreakerState::Closed if self.consecutive_failures > 0 => {
+                Some(backoff(config, self.consecutive_failures - 1))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Exponential backoff with jitter, so that deliveries retried after an
+/// outage don't all arrive at the push service at once.
+fn backoff(config: &PushConfig, attempt: u32) -> Duration {
+    let max = config
+        .initial_backoff
+        .saturating_mul(1_u64.checked_shl(attempt).unwrap_or(u64::MAX))
+        .min(config.max_backoff);
+
+    Duration::from_millis(max / 2 + rand::thread_rng().gen_range(0..=max / 2))
+}
+
+/// Queues a push to the subscriptions of each user who can see a change,
+/// until the bus closes.
+pub async fn deliver_state_changes<T: PushTransport>(
+    mut events: Receiver<Arc<TimestampedEvent>>,
+    store: Arc<Store>,
+    dispatcher: PushDispatcher<T>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            // the next change to each user pushes their latest state, which
+            // covers anything missed
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "Push delivery fell behind, skipped changes");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        match &event.event {
+            DomainEvent::UserStateChanged { user_id, .. } => {
+                deliver_user_state_change(&store, &dispatcher, *user_id).await;
+            }
+            DomainEvent::ObjectsChanged {
+                account_id,
+                data_type,
+                new_state,
+            } => {
+                // changes queued on a subscription are coalesced, so each
+                // object written by a `/set` doesn't get a push of its own
+                for (user_id, _) in store.get_users_for_account(*account_id).await.unwrap() {
+                    for subscription in active_subscriptions(&store, user_id).await {
+                        if subscription.wants(data_type) {
+                            dispatcher.enqueue(
+                                subscription.id,
+                                &subscription.url,
+                                *account_id,
+                                data_type.clone(),
+                                new_state.clone(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Pushes the state of every data type within every account the user can
+/// see to each of their subscriptions.
+async fn deliver_user_state_change<T: PushTransport>(
+    store: &Store,
+    dispatcher: &PushDispatcher<T>,
+    user_id: Uuid,
+) {
+    let subscriptions = active_subscriptions(store, user_id).await;
+
+    if subscriptions.is_empty() {
+        return;
+    }
+
+    // each type is pushed at its own state, so clients don't refetch
+    // types that haven't actually changed
+    let mut states = Vec::new();
+
+    for account in store.get_accounts_for_user(user_id).await.unwrap() {
+        for data_type in ExtensionRegistry::data_types() {
+            let state = store.state_for(account.id, data_type).await.unwrap();
+            states.push((account.id, data_type, state.0.into_owned()));
+        }
+    }
+
+    for subscription in &subscriptions {
+        for (account_id, data_type, state) in &states {
+            if subscription.wants(data_type) {
+                dispatcher.enqueue(
+                    subscription.id,
+                    &subscription.url,
+                    *account_id,
+                    Cow::Borrowed(data_type),
+                    state.clone(),
+                );
+            }
+        }
+    }
+}
+
+/// The user's subscriptions that can be pushed to.
+async fn active_subscriptions(store: &Store, user_id: Uuid) -> Vec<PushSubscription> {
+    let now = Utc::now();
+
+    store
+        .get_push_subscriptions_for_user(user_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|subscription| subscription.verified && subscription.expires > now)
+        .collect()
+}
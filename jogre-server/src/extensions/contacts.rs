@@ -1,9 +1,41 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
 
+use axum::async_trait;
+use jmap_proto::{
+    common::UnsignedInt,
+    endpoints::object::{
+        query::{Comparator, Filter, Offset, Operator, QueryParams, QueryResponse, QueryState},
+        query_changes::{AddedItem, QueryChangesParams, QueryChangesResponse},
+        set::{PatchObject, SetError},
+        ObjectState,
+    },
+    errors::MethodError,
+    extensions::contacts::{
+        js_contact::{Card, CategoryMatch},
+        ContactsAccountCapabilities,
+    },
+    Value,
+};
 use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
 use uuid::Uuid;
 
-use crate::extensions::{router::ExtensionRouter, Get, JmapDataExtension, JmapExtension};
+use crate::{
+    extensions::{
+        core::collation::Collation, router::ExtensionRouter, to_id, Changes, CopyObjects, DataType,
+        Get, JmapAccountCapabilityExtension, JmapDataExtension, JmapEndpoint, JmapExtension,
+        MethodCall, Set, StoredDataType,
+    },
+    pagination::{Window, WindowError, WindowStart, Windowed},
+    store::{
+        self, decode_object_state, encode_object_state, Account, ObjectChanges, ObjectProvider,
+        Store,
+    },
+};
 
 pub struct Contacts {}
 
@@ -11,36 +43,1607 @@ impl JmapExtension for Contacts {
     const EXTENSION: &'static str = "urn:ietf:params:jmap:contacts";
 
     fn router(&self) -> ExtensionRouter<Self> {
-        ExtensionRouter::default().register(Get::<AddressBook>::default())
+        ExtensionRouter::default()
+            .register(Get::<AddressBook>::default())
+            .register(Changes::<AddressBook>::default())
+            .register(Set::<AddressBook>::default())
+            .register(AddressBookQuery)
+            .register(AddressBookQueryChanges)
+            .register(Get::<ContactCard>::default())
+            .register(Changes::<ContactCard>::default())
+            .register(Set::<ContactCard>::default())
+            .register(ContactCardQuery)
+            .register(ContactCardQueryChanges)
+            .register(CopyObjects::<ContactCard>::default())
+    }
+}
+
+impl JmapAccountCapabilityExtension for Contacts {
+    type Metadata = ContactsAccountCapabilities;
+
+    fn build(&self, _user: Uuid, account: &Account) -> Option<Self::Metadata> {
+        Some(ContactsAccountCapabilities {
+            may_create_address_book: !account.is_read_only,
+        })
     }
 }
 
 impl JmapDataExtension<AddressBook> for Contacts {
     const ENDPOINT: &'static str = "AddressBook";
+    const METHODS: &'static [&'static str] = &["get", "changes", "set", "query", "queryChanges"];
+    const WRITABLE: bool = true;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct ContactMetadata {
-    pub may_create_address_book: bool,
+impl JmapDataExtension<ContactCard> for Contacts {
+    const ENDPOINT: &'static str = "ContactCard";
+    const METHODS: &'static [&'static str] = &["get", "changes", "set", "query", "queryChanges"];
+    const WRITABLE: bool = true;
 }
 
+impl DataType for AddressBook {}
+
+impl DataType for ContactCard {}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AddressBook {
-    id: Uuid,
-    name: String,
-    is_subscribed: bool,
-    owner: Uuid,
-    share_with: HashMap<Uuid, AddressBookRights>,
+    pub id: Uuid,
+    pub name: String,
+    pub is_subscribed: bool,
+    pub owner: Uuid,
+    pub share_with: HashMap<Uuid, AddressBookRights>,
+}
+
+impl AddressBook {
+    /// Whether the user may add, change and remove cards in the book.
+    fn may_write(&self, user: Uuid) -> bool {
+        self.owner == user || self.share_with.get(&user).is_some_and(|r| r.may_write)
+    }
+
+    /// Builds a book from the properties of one sent by a client, or patched
+    /// by one. `id` and `owner` are set by the server, so may only be given
+    /// if they match.
+    fn from_properties(
+        id: Uuid,
+        owner: Uuid,
+        properties: &Value,
+    ) -> Result<Self, SetError<'static>> {
+        let Value::Object(properties) = properties else {
+            return Err(SetError::invalid_properties(
+                Vec::new(),
+                Some("An address book must be an object".into()),
+            ));
+        };
+
+        let mut book = Self {
+            id,
+            name: String::new(),
+            is_subscribed: false,
+            owner,
+            share_with: HashMap::new(),
+        };
+
+        let mut invalid = Vec::new();
+
+        for (property, value) in properties {
+            let valid = match property.as_str() {
+                "id" => value.as_str() == Some(&id.to_string()),
+                "owner" => value.as_str() == Some(&owner.to_string()),
+                "name" => value
+                    .as_str()
+                    .filter(|name| !name.is_empty())
+                    .map(|name| book.name = name.to_string())
+                    .is_some(),
+                "isSubscribed" => value
+                    .as_bool()
+                    .map(|is_subscribed| book.is_subscribed = is_subscribed)
+                    .is_some(),
+                "shareWith" if value.is_null() => true,
+                "shareWith" => serde_json::from_value(value.clone())
+                    .map(|share_with| book.share_with = share_with)
+                    .is_ok(),
+                _ => false,
+            };
+
+            if !valid {
+                invalid.push(Cow::Owned(property.clone()));
+            }
+        }
+
+        if !properties.contains_key("name") {
+            invalid.push(Cow::Borrowed("name"));
+        }
+
+        if invalid.is_empty() {
+            Ok(book)
+        } else {
+            invalid.sort();
+            Err(SetError::invalid_properties(invalid, None))
+        }
+    }
+}
+
+#[async_trait]
+impl StoredDataType for AddressBook {
+    const PROPERTIES: &'static [&'static str] =
+        &["id", "name", "isSubscribed", "owner", "shareWith"];
+
+    fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+
+    fn quota(call: &MethodCall<'_>) -> Option<u64> {
+        Some(
+            call.context
+                .config
+                .load()
+                .request_limits
+                .max_address_books_per_account,
+        )
+    }
+
+    /// Whether the user owns the book or has had it shared with them.
+    fn is_visible_to(&self, user: Uuid) -> bool {
+        self.owner == user || self.share_with.contains_key(&user)
+    }
+
+    fn may_update(&self, user: Uuid) -> bool {
+        self.owner == user || self.share_with.get(&user).is_some_and(|r| r.may_admin)
+    }
+
+    fn may_destroy(&self, user: Uuid) -> bool {
+        self.owner == user || self.share_with.get(&user).is_some_and(|r| r.may_delete)
+    }
+
+    async fn create(
+        call: &MethodCall<'_>,
+        _account: Uuid,
+        id: Uuid,
+        properties: &Value,
+    ) -> Result<Result<Self, SetError<'static>>, MethodError> {
+        let server_set: Vec<_> = ["id", "owner"]
+            .into_iter()
+            .filter(|property| properties.get(property).is_some())
+            .map(Cow::Borrowed)
+            .collect();
+
+        if !server_set.is_empty() {
+            return Ok(Err(SetError::invalid_properties(
+                server_set,
+                Some("Set by the server".into()),
+            )));
+        }
+
+        Ok(Self::from_properties(id, call.user_id, properties))
+    }
+
+    async fn update(
+        &self,
+        _call: &MethodCall<'_>,
+        _account: Uuid,
+        patch: &PatchObject<'_>,
+    ) -> Result<Result<(Self, Option<Value>), SetError<'static>>, MethodError> {
+        Ok(patch
+            .apply(&self.to_value())
+            .and_then(|patched| Self::from_properties(self.id, self.owner, &patched))
+            .map(|book| (book, None)))
+    }
+
+    fn server_set_properties(&self) -> serde_json::Map<String, Value> {
+        let Value::Object(mut properties) = self.to_value() else {
+            unreachable!("books serialize to objects");
+        };
+
+        // the only properties that aren't either server-set or defaulted
+        properties.remove("id");
+        properties.remove("name");
+        properties
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 #[allow(clippy::struct_excessive_bools)]
 pub struct AddressBookRights {
-    may_read: bool,
-    may_write: bool,
-    may_admin: bool,
-    may_delete: bool,
+    pub may_read: bool,
+    pub may_write: bool,
+    pub may_admin: bool,
+    pub may_delete: bool,
+}
+
+/// A card within one or more of an account's address books.
+#[derive(Debug)]
+pub struct ContactCard {
+    pub id: Uuid,
+    pub address_book_ids: HashSet<Uuid>,
+    /// The card's properties, kept as the client sent them so that those
+    /// [`Card`] doesn't model survive a round trip.
+    pub card: serde_json::Map<String, Value>,
+}
+
+impl ContactCard {
+    /// Builds a card from the properties of one sent by a client, or patched
+    /// by one, validating it as a [`Card`] against the configured limits and
+    /// checking the user may add cards to each of its address books.
+    ///
+    /// `stamp` sets the server-managed timestamps on the card before it's
+    /// validated.
+    async fn from_properties(
+        call: &MethodCall<'_>,
+        account: Uuid,
+        id: Uuid,
+        properties: &Value,
+        stamp: impl FnOnce(&mut Card<'_>) -> Result<(), SetError<'static>> + Send,
+    ) -> Result<Result<Self, SetError<'static>>, MethodError> {
+        let Value::Object(properties) = properties else {
+            return Ok(Err(SetError::invalid_properties(
+                Vec::new(),
+                Some("A card must be an object".into()),
+            )));
+        };
+
+        let mut card = properties.clone();
+
+        if card
+            .remove("id")
+            .is_some_and(|v| v.as_str() != Some(&id.to_string()))
+        {
+            return Ok(Err(SetError::invalid_properties(
+                vec!["id".into()],
+                Some("Set by the server".into()),
+            )));
+        }
+
+        let Some(address_book_ids) = card
+            .remove("addressBookIds")
+            .and_then(|v| serde_json::from_value::<HashMap<Uuid, bool>>(v).ok())
+            .filter(|ids| !ids.is_empty() && ids.values().all(|&v| v))
+        else {
+            return Ok(Err(SetError::invalid_properties(
+                vec!["addressBookIds".into()],
+                Some("A card must be in at least one address book".into()),
+            )));
+        };
+
+        let limits = call.context.config.load().request_limits.card_limits();
+
+        let (server_set, unknown_values) = match with_card(&card, |mut card| {
+            stamp(&mut card)?;
+            card.validate(limits)?;
+            Ok((card.server_set_properties(), card.unknown_values()))
+        }) {
+            Ok(stamped) => stamped,
+            Err(error) => return Ok(Err(error)),
+        };
+
+        card.extend(server_set);
+
+        // only reached when they aren't rejected, so are kept verbatim
+        if !unknown_values.is_empty() {
+            warn!(card = %id, ?unknown_values, "Storing card with values outside the spec");
+        }
+
+        let card = Self {
+            id,
+            address_book_ids: address_book_ids.into_keys().collect(),
+            card,
+        };
+
+        Ok(card
+            .check_address_books(call, account)
+            .await?
+            .map(|()| card))
+    }
+
+    /// Checks every address book the card is in exists and accepts cards
+    /// from the user.
+    async fn check_address_books(
+        &self,
+        call: &MethodCall<'_>,
+        account: Uuid,
+    ) -> Result<Result<(), SetError<'static>>, MethodError> {
+        for &book in &self.address_book_ids {
+            let book = call
+                .context
+                .store
+                .get_object::<AddressBook>(account, "AddressBook", book)
+                .await
+                .map_err(|error| call.server_fail(&error))?
+                .filter(|book| book.is_visible_to(call.user_id));
+
+            match book {
+                None => {
+                    return Ok(Err(SetError::invalid_properties(
+                        vec!["addressBookIds".into()],
+                        Some("No such address book".into()),
+                    )))
+                }
+                Some(book) if !book.may_write(call.user_id) => {
+                    return Ok(Err(SetError::forbidden(Some(
+                        "Cards can't be added to this address book".into(),
+                    ))))
+                }
+                Some(_) => {}
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+/// The uid of the card describing the user themselves, rather than one of
+/// their contacts, which clients keep in the user's personal account.
+pub fn own_card_uid(user: Uuid) -> String {
+    format!("urn:uuid:{user}")
+}
+
+impl ContactCard {
+    /// The full name on the user's own card within the account, if they
+    /// have one and it's named.
+    pub async fn own_full_name(
+        store: &Store,
+        account: Uuid,
+        user: Uuid,
+    ) -> Result<Option<String>, store::Error> {
+        let uid = Value::String(own_card_uid(user));
+
+        let card = store
+            .query_objects::<Self, _, _>(account, "ContactCard", move |mut cards| {
+                // stops at the first failure of the store, as well as the card
+                Iterator::find(&mut cards, |card| match card {
+                    Ok((_, card)) => card.card.get("uid") == Some(&uid),
+                    Err(_) => true,
+                })
+                .transpose()
+            })
+            .await??;
+
+        Ok(card.and_then(|(_, card)| {
+            with_card(&card.card, |card| Ok(card.full_name().map(str::to_string)))
+                .ok()
+                .flatten()
+        }))
+    }
+}
+
+/// Parses a [`Card`], which borrows from its serialised form, for the
+/// duration of `f`. A card that can't be parsed fails with the property it
+/// failed at.
+fn with_card<R>(
+    card: &serde_json::Map<String, Value>,
+    f: impl FnOnce(Card<'_>) -> Result<R, SetError<'static>>,
+) -> Result<R, SetError<'static>> {
+    let json = serde_json::to_string(card).unwrap();
+
+    let card = serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(&json))
+        .map_err(|error| {
+            let property = match error.path().iter().next() {
+                Some(serde_path_to_error::Segment::Map { key }) => vec![Cow::Owned(key.clone())],
+                _ => Vec::new(),
+            };
+
+            SetError::invalid_properties(property, Some(error.inner().to_string().into()))
+        })?;
+
+    f(card)
+}
+
+#[async_trait]
+impl StoredDataType for ContactCard {
+    const PROPERTIES: &'static [&'static str] = &[
+        "id",
+        "addressBookIds",
+        "@type",
+        "uid",
+        "prodId",
+        "created",
+        "updated",
+        "kind",
+        "relatedTo",
+        "language",
+        "name",
+        "fullName",
+        "nickNames",
+        "organizations",
+        "titles",
+        "emails",
+        "phones",
+        "online",
+        "photos",
+        "preferredContactMethod",
+        "preferredContactLanguages",
+        "address",
+        "localizations",
+        "anniversaries",
+        "personalInfo",
+        "notes",
+        "categories",
+        "timeZones",
+    ];
+    const ID_PROPERTIES: &'static [&'static str] = &["addressBookIds"];
+
+    fn to_value(&self) -> Value {
+        let mut value = self.card.clone();
+        value.insert("id".to_string(), Value::String(self.id.to_string()));
+        value.insert(
+            "addressBookIds".to_string(),
+            self.address_book_ids
+                .iter()
+                .map(|id| (id.to_string(), Value::Bool(true)))
+                .collect(),
+        );
+        Value::Object(value)
+    }
+
+    async fn create(
+        call: &MethodCall<'_>,
+        account: Uuid,
+        id: Uuid,
+        properties: &Value,
+    ) -> Result<Result<Self, SetError<'static>>, MethodError> {
+        if properties.get("id").is_some() {
+            return Ok(Err(SetError::invalid_properties(
+                vec!["id".into()],
+                Some("Set by the server".into()),
+            )));
+        }
+
+        let now = (call.context.clock)().into();
+
+        Self::from_properties(call, account, id, properties, |card| card.set_created(now)).await
+    }
+
+    async fn update(
+        &self,
+        call: &MethodCall<'_>,
+        account: Uuid,
+        patch: &PatchObject<'_>,
+    ) -> Result<Result<(Self, Option<Value>), SetError<'static>>, MethodError> {
+        let patched = match patch.apply(&self.to_value()) {
+            Ok(patched) => patched,
+            Err(error) => return Ok(Err(error)),
+        };
+
+        let json = serde_json::to_string(&self.card).unwrap();
+        let current = serde_json::from_str::<Card<'_>>(&json).map_err(|error| {
+            error!(card = %self.id, %error, "Stored card no longer parses");
+            MethodError::ServerFail
+        })?;
+
+        let now = (call.context.clock)().into();
+
+        Ok(
+            Self::from_properties(call, account, self.id, &patched, move |card| {
+                card.set_updated(&current, now)
+            })
+            .await?
+            .map(|card| {
+                // the only property that changes without the client asking
+                let updated = card.card.get("updated").cloned();
+                let changed = updated.map(|updated| serde_json::json!({ "updated": updated }));
+
+                (card, changed)
+            }),
+        )
+    }
+
+    fn server_set_properties(&self) -> serde_json::Map<String, Value> {
+        ["created", "updated"]
+            .into_iter()
+            .filter_map(|property| Some((property.to_string(), self.card.get(property)?.clone())))
+            .collect()
+    }
+}
+
+/// `AddressBook/query`, listing the books within an account that the user
+/// can see.
+///
+/// Books can be filtered by a substring of their `name` and by
+/// `isSubscribed`, and sorted by `name`. Books that compare equal, including
+/// all of them when no sort is given, are kept in order of their ids.
+pub struct AddressBookQuery;
+
+#[async_trait]
+impl JmapEndpoint<Contacts> for AddressBookQuery {
+    type Parameters<'de> = QueryParams<'de>;
+    type Response<'s> = QueryResponse<'s>;
+
+    const NAMESPACE: &'static str = "AddressBook";
+    const ENDPOINT: &'static str = "query";
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Contacts,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        let query = Self::parse(params.filter(), params.sort())?;
+
+        let account_id = call.require_account(params.account_id()).await?.id;
+
+        let store = &call.context.store;
+
+        // read before the books so the state never claims to include
+        // changes the results don't
+        let state = store
+            .state_for(account_id, Self::NAMESPACE)
+            .await
+            .map_err(|error| call.server_fail(&error))?;
+
+        let window = Window {
+            start: window_start(params.offset())?,
+            limit: params.limit().map(UnsignedInt::get),
+            max_limit: Some(
+                call.context
+                    .config
+                    .load()
+                    .request_limits
+                    .max_objects_in_query,
+            ),
+            calculate_total: params.calculate_total(),
+        };
+
+        let windowed = Self::run(call, account_id, query, window).await?;
+
+        Ok(QueryResponse::new(
+            params.account_id().clone(),
+            QueryState(state.0),
+            unsigned_int(windowed.position)?,
+            windowed.ids.iter().map(to_id).collect(),
+            windowed.total.map(unsigned_int).transpose()?,
+        )
+        .with_changes()
+        .with_limit(windowed.limit.map(unsigned_int).transpose()?))
+    }
+}
+
+/// The filter and sort of an `AddressBook/query`, along with whether each
+/// sort is ascending.
+type BookQuery = (Option<AddressBookFilter>, Vec<(bool, Collation)>);
+
+impl AddressBookQuery {
+    /// Parses the filter and sort of a query, so that either failing to
+    /// parse fails the call before any book is read.
+    fn parse(
+        filter: Option<&Filter<'_>>,
+        sort: &[Comparator<'_>],
+    ) -> Result<BookQuery, MethodError> {
+        let sort = sort.iter().map(sort_by).collect::<Result<Vec<_>, _>>()?;
+
+        // filters have no collation of their own, so match names the same
+        // way they're sorted
+        let collation = sort.first().map_or(Collation::DEFAULT, |v| v.1);
+
+        let filter = filter
+            .map(|filter| AddressBookFilter::parse(filter, collation))
+            .transpose()?;
+
+        Ok((filter, sort))
+    }
+
+    /// The window of the books within the account matching the query that
+    /// the user can see.
+    async fn run(
+        call: &MethodCall<'_>,
+        account_id: Uuid,
+        (filter, sort): BookQuery,
+        window: Window<Uuid>,
+    ) -> Result<Windowed<Uuid>, MethodError> {
+        let user = call.user_id;
+
+        call.context
+            .store
+            .query_objects(account_id, Self::NAMESPACE, move |books| {
+                let mut failure = None;
+
+                let books = books
+                    .map_while(|book| book.map_err(|error| failure = Some(error)).ok())
+                    .map(|(_, book): (Uuid, AddressBook)| book)
+                    .filter(|book| book.is_visible_to(user))
+                    .filter(|book| filter.as_ref().is_none_or(|filter| filter.matches(book)));
+
+                // books are read in order of their ids, so without a sort
+                // none past the end of the window are read at all
+                let windowed = if sort.is_empty() {
+                    window.apply(books.map(|book| book.id))
+                } else {
+                    let mut books: Vec<_> = books.collect();
+
+                    // a stable sort keeps books that compare equal in order
+                    // of their ids
+                    books.sort_by(|a, b| {
+                        sort.iter()
+                            .map(|&(is_ascending, collation)| {
+                                let ordering = collation.compare(&a.name, &b.name);
+
+                                if is_ascending {
+                                    ordering
+                                } else {
+                                    ordering.reverse()
+                                }
+                            })
+                            .find(|ordering| ordering.is_ne())
+                            .unwrap_or(Ordering::Equal)
+                    });
+
+                    window.apply(books.into_iter().map(|book| book.id))
+                };
+
+                failure.map_or(Ok(windowed), Err)
+            })
+            .await
+            .and_then(|windowed| windowed)
+            .map_err(|error| call.server_fail(&error))?
+            .map_err(|WindowError::AnchorNotFound| MethodError::AnchorNotFound)
+    }
+}
+
+/// `AddressBook/queryChanges`, updating a client's cached results of an
+/// `AddressBook/query`.
+pub struct AddressBookQueryChanges;
+
+#[async_trait]
+impl JmapEndpoint<Contacts> for AddressBookQueryChanges {
+    type Parameters<'de> = QueryChangesParams<'de>;
+    type Response<'s> = QueryChangesResponse<'s>;
+
+    const NAMESPACE: &'static str = "AddressBook";
+    const ENDPOINT: &'static str = "queryChanges";
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Contacts,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        let query = AddressBookQuery::parse(params.filter(), params.sort())?;
+
+        let account_id = call.require_account(params.account_id()).await?.id;
+
+        let changes = changes_since(call, account_id, Self::NAMESPACE, &params).await?;
+        let results = AddressBookQuery::run(call, account_id, query, Window::everything()).await?;
+
+        query_changes(&params, &changes, &results.ids)
+    }
+}
+
+/// `ContactCard/query`, listing the cards within an account.
+///
+/// Cards can be filtered by their `categories`, matching any or all of
+/// those given as `categoriesMatch` asks, and sorted by `created` and
+/// `updated`, those without the property sorting first. Cards that compare
+/// equal, including all of them when no sort is given, are kept in order of
+/// their ids.
+pub struct ContactCardQuery;
+
+#[async_trait]
+impl JmapEndpoint<Contacts> for ContactCardQuery {
+    type Parameters<'de> = QueryParams<'de>;
+    type Response<'s> = QueryResponse<'s>;
+
+    const NAMESPACE: &'static str = "ContactCard";
+    const ENDPOINT: &'static str = "query";
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Contacts,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        let query = Self::parse(params.filter(), params.sort())?;
+
+        let account_id = call.require_account(params.account_id()).await?.id;
+
+        let store = &call.context.store;
+
+        // read before the cards so the state never claims to include
+        // changes the results don't
+        let state = store
+            .state_for(account_id, Self::NAMESPACE)
+            .await
+            .map_err(|error| call.server_fail(&error))?;
+
+        let window = Window {
+            start: window_start(params.offset())?,
+            limit: params.limit().map(UnsignedInt::get),
+            max_limit: Some(
+                call.context
+                    .config
+                    .load()
+                    .request_limits
+                    .max_objects_in_query,
+            ),
+            calculate_total: params.calculate_total(),
+        };
+
+        let windowed = Self::run(call, account_id, query, window).await?;
+
+        Ok(QueryResponse::new(
+            params.account_id().clone(),
+            QueryState(state.0),
+            unsigned_int(windowed.position)?,
+            windowed.ids.iter().map(to_id).collect(),
+            windowed.total.map(unsigned_int).transpose()?,
+        )
+        .with_changes()
+        .with_limit(windowed.limit.map(unsigned_int).transpose()?))
+    }
+}
+
+/// The filter and sort of a `ContactCard/query`, along with whether each
+/// sort is ascending.
+type CardQuery = (Option<ContactCardFilter>, Vec<(bool, String)>);
+
+impl ContactCardQuery {
+    /// Parses the filter and sort of a query, so that either failing to
+    /// parse fails the call before any card is read.
+    fn parse(
+        filter: Option<&Filter<'_>>,
+        sort: &[Comparator<'_>],
+    ) -> Result<CardQuery, MethodError> {
+        let sort = sort
+            .iter()
+            .map(|comparator| match comparator.property() {
+                property @ ("created" | "updated") => {
+                    Ok((comparator.is_ascending(), property.to_string()))
+                }
+                _ => Err(MethodError::UnsupportedSort),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let filter = filter.map(ContactCardFilter::parse).transpose()?;
+
+        Ok((filter, sort))
+    }
+
+    /// The window of the cards within the account matching the query.
+    async fn run(
+        call: &MethodCall<'_>,
+        account_id: Uuid,
+        (filter, sort): CardQuery,
+        window: Window<Uuid>,
+    ) -> Result<Windowed<Uuid>, MethodError> {
+        call.context
+            .store
+            .query_objects(account_id, Self::NAMESPACE, move |cards| {
+                let mut failure = None;
+
+                // cards borrow from their JSON, which must outlive them
+                let cards = cards
+                    .map_while(|card| card.map_err(|error| failure = Some(error)).ok())
+                    .map(|(id, card): (Uuid, ContactCard)| {
+                        (id, serde_json::to_string(&card.card).unwrap())
+                    })
+                    .filter(|(_, json)| {
+                        filter.as_ref().is_none_or(|filter| {
+                            serde_json::from_str::<Card<'_>>(json)
+                                .is_ok_and(|card| filter.matches(&card))
+                        })
+                    });
+
+                // cards are read in order of their ids, so without a sort
+                // none past the end of the window are read at all
+                let windowed = if sort.is_empty() {
+                    window.apply(cards.map(|(id, _)| id))
+                } else {
+                    let json: Vec<_> = cards.collect();
+
+                    let mut cards: Vec<_> = json
+                        .iter()
+                        .map(|(id, json)| (*id, serde_json::from_str::<Card<'_>>(json).ok()))
+                        .collect();
+
+                    // a stable sort keeps cards that compare equal in order
+                    // of their ids, as do cards that no longer parse
+                    cards.sort_by(|(_, a), (_, b)| {
+                        let (Some(a), Some(b)) = (a, b) else {
+                            return Ordering::Equal;
+                        };
+
+                        sort.iter()
+                            .map(|(is_ascending, property)| {
+                                let ordering = a.compare_by(b, property).unwrap_or(Ordering::Equal);
+
+                                if *is_ascending {
+                                    ordering
+                                } else {
+                                    ordering.reverse()
+                                }
+                            })
+                            .find(|ordering| ordering.is_ne())
+                            .unwrap_or(Ordering::Equal)
+                    });
+
+                    window.apply(cards.into_iter().map(|(id, _)| id))
+                };
+
+                failure.map_or(Ok(windowed), Err)
+            })
+            .await
+            .and_then(|windowed| windowed)
+            .map_err(|error| call.server_fail(&error))?
+            .map_err(|WindowError::AnchorNotFound| MethodError::AnchorNotFound)
+    }
+}
+
+/// `ContactCard/queryChanges`, updating a client's cached results of a
+/// `ContactCard/query`.
+pub struct ContactCardQueryChanges;
+
+#[async_trait]
+impl JmapEndpoint<Contacts> for ContactCardQueryChanges {
+    type Parameters<'de> = QueryChangesParams<'de>;
+    type Response<'s> = QueryChangesResponse<'s>;
+
+    const NAMESPACE: &'static str = "ContactCard";
+    const ENDPOINT: &'static str = "queryChanges";
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Contacts,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        let query = ContactCardQuery::parse(params.filter(), params.sort())?;
+
+        let account_id = call.require_account(params.account_id()).await?.id;
+
+        let changes = changes_since(call, account_id, Self::NAMESPACE, &params).await?;
+        let results = ContactCardQuery::run(call, account_id, query, Window::everything()).await?;
+
+        query_changes(&params, &changes, &results.ids)
+    }
+}
+
+/// Every change to the data type since the client's query state. Read
+/// before the query's current results, so the state the client is given
+/// never claims to include changes they don't.
+async fn changes_since(
+    call: &MethodCall<'_>,
+    account_id: Uuid,
+    namespace: &str,
+    params: &QueryChangesParams<'_>,
+) -> Result<ObjectChanges, MethodError> {
+    if params.max_changes().is_some_and(|max| max.get() == 0) {
+        return Err(MethodError::InvalidArguments);
+    }
+
+    // the state of a query is that of its data type
+    let since = decode_object_state(&ObjectState(params.since_query_state().0.clone()))
+        .ok_or(MethodError::CannotCalculateChanges)?;
+
+    call.context
+        .store
+        .get_object_changes(account_id, namespace, since, None)
+        .await
+        .map_err(|error| call.server_fail(&error))?
+        .ok_or(MethodError::CannotCalculateChanges)
+}
+
+/// The difference between a query's results at the client's state and its
+/// current `results`, given the `changes` between them.
+///
+/// The filters and sorts of both data types are on mutable properties, so
+/// anything updated may have moved within the results or left them. Those
+/// are all removed, then added back at wherever they now are along with
+/// anything created. Everything else keeps its order relative to the rest.
+fn query_changes<'de>(
+    params: &QueryChangesParams<'de>,
+    changes: &ObjectChanges,
+    results: &[Uuid],
+) -> Result<QueryChangesResponse<'de>, MethodError> {
+    let removed: Vec<_> = changes
+        .updated
+        .iter()
+        .chain(&changes.destroyed)
+        .map(to_id)
+        .collect();
+
+    let candidates: HashSet<_> = changes.created.iter().chain(&changes.updated).collect();
+
+    let added = results
+        .iter()
+        .enumerate()
+        .filter(|(_, id)| candidates.contains(id))
+        .map(|(index, id)| Ok(AddedItem::new(to_id(id), unsigned_int(index as u64)?)))
+        .collect::<Result<Vec<_>, MethodError>>()?;
+
+    if params
+        .max_changes()
+        .is_some_and(|max| (removed.len() + added.len()) as u64 > max.get())
+    {
+        return Err(MethodError::TooManyChanges);
+    }
+
+    let total = params
+        .calculate_total()
+        .then(|| unsigned_int(results.len() as u64))
+        .transpose()?;
+
+    Ok(QueryChangesResponse::new(
+        params.account_id().clone(),
+        params.since_query_state().clone(),
+        QueryState(encode_object_state(changes.new_state).0),
+        total,
+        removed,
+        added,
+    ))
+}
+
+/// Converts a position, count or limit for the response. None can exceed
+/// the range without there being more objects, or a larger configured limit,
+/// than an `UnsignedInt` can count.
+fn unsigned_int(value: u64) -> Result<UnsignedInt, MethodError> {
+    UnsignedInt::try_from(value).map_err(|_| MethodError::ServerFail)
+}
+
+/// Whether books are sorted ascending, and the collation their names are
+/// compared with.
+fn sort_by(comparator: &Comparator<'_>) -> Result<(bool, Collation), MethodError> {
+    if comparator.property() != "name" {
+        return Err(MethodError::UnsupportedSort);
+    }
+
+    let collation = match comparator.collation() {
+        Some(name) => Collation::from_name(name).ok_or(MethodError::UnsupportedSort)?,
+        None => Collation::DEFAULT,
+    };
+
+    Ok((comparator.is_ascending(), collation))
+}
+
+/// Where the window of results starts. An anchor that isn't a valid id can't
+/// be among the results, so is never found.
+fn window_start(offset: &Offset<'_>) -> Result<WindowStart<Uuid>, MethodError> {
+    Ok(match offset {
+        Offset::Position { position } => WindowStart::Position(position.get()),
+        Offset::Anchor {
+            anchor,
+            anchor_offset,
+        } => WindowStart::Anchor {
+            anchor: Uuid::parse_str(&anchor.0).map_err(|_| MethodError::AnchorNotFound)?,
+            offset: anchor_offset.get(),
+        },
+        Offset::Default => WindowStart::Position(0),
+    })
+}
+
+/// A filter on address books, checked for conditions that aren't supported
+/// before any book is read.
+enum AddressBookFilter {
+    Operator(Operator, Vec<AddressBookFilter>),
+    /// The book's name contains the string, as compared by the collation.
+    Name(String, Collation),
+    IsSubscribed(bool),
+}
+
+impl AddressBookFilter {
+    fn parse(filter: &Filter<'_>, collation: Collation) -> Result<Self, MethodError> {
+        match filter {
+            Filter::Operator(operator) => Ok(Self::Operator(
+                operator.operator(),
+                operator
+                    .conditions()
+                    .iter()
+                    .map(|filter| Self::parse(filter, collation))
+                    .collect::<Result<_, _>>()?,
+            )),
+            // each property of a condition must match
+            Filter::Condition(condition) => Ok(Self::Operator(
+                Operator::And,
+                condition
+                    .iter()
+                    .map(|(property, value)| match (property.as_ref(), value) {
+                        ("name", Value::String(name)) => Ok(Self::Name(name.clone(), collation)),
+                        ("isSubscribed", Value::Bool(is_subscribed)) => {
+                            Ok(Self::IsSubscribed(*is_subscribed))
+                        }
+                        _ => Err(MethodError::UnsupportedFilter),
+                    })
+                    .collect::<Result<_, _>>()?,
+            )),
+        }
+    }
+
+    fn matches(&self, book: &AddressBook) -> bool {
+        match self {
+            Self::Operator(Operator::And, filters) => filters.iter().all(|v| v.matches(book)),
+            Self::Operator(Operator::Or, filters) => filters.iter().any(|v| v.matches(book)),
+            Self::Operator(Operator::Not, filters) => !filters.iter().any(|v| v.matches(book)),
+            Self::Name(name, collation) => collation.contains(&book.name, name),
+            Self::IsSubscribed(is_subscribed) => book.is_subscribed == *is_subscribed,
+        }
+    }
+}
+
+/// A filter on cards, checked for conditions that aren't supported before
+/// any card is read.
+enum ContactCardFilter {
+    Operator(Operator, Vec<ContactCardFilter>),
+    /// The card is in the categories, as [`Card::has_categories`] matches
+    /// them.
+    Categories(Vec<String>, CategoryMatch),
+}
+
+impl ContactCardFilter {
+    fn parse(filter: &Filter<'_>) -> Result<Self, MethodError> {
+        match filter {
+            Filter::Operator(operator) => Ok(Self::Operator(
+                operator.operator(),
+                operator
+                    .conditions()
+                    .iter()
+                    .map(Self::parse)
+                    .collect::<Result<_, _>>()?,
+            )),
+            Filter::Condition(condition) => {
+                let mut categories = None;
+                let mut mode = CategoryMatch::default();
+
+                for (property, value) in condition {
+                    match property.as_ref() {
+                        "categories" => {
+                            categories = Some(
+                                serde_json::from_value(value.clone())
+                                    .map_err(|_| MethodError::InvalidArguments)?,
+                            );
+                        }
+                        "categoriesMatch" => {
+                            mode = serde_json::from_value(value.clone())
+                                .map_err(|_| MethodError::InvalidArguments)?;
+                        }
+                        _ => return Err(MethodError::UnsupportedFilter),
+                    }
+                }
+
+                Ok(match categories {
+                    Some(categories) => Self::Categories(categories, mode),
+                    // an empty condition matches every card
+                    None => Self::Operator(Operator::And, Vec::new()),
+                })
+            }
+        }
+    }
+
+    fn matches(&self, card: &Card<'_>) -> bool {
+        match self {
+            Self::Operator(Operator::And, filters) => filters.iter().all(|v| v.matches(card)),
+            Self::Operator(Operator::Or, filters) => filters.iter().any(|v| v.matches(card)),
+            Self::Operator(Operator::Not, filters) => !filters.iter().any(|v| v.matches(card)),
+            Self::Categories(categories, mode) => card.has_categories(categories, *mode),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{
+        atomic::{AtomicI64, Ordering as AtomicOrdering},
+        Arc,
+    };
+
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::{
+        context::Context,
+        extensions::tests::{call, user},
+    };
+
+    /// Creates an address book in the account, returning its id.
+    async fn address_book(context: &Context, user_id: Uuid, account_id: Uuid) -> String {
+        let response = call(
+            context,
+            user_id,
+            &Contacts {},
+            Set::<AddressBook>::default(),
+            &format!(
+                r#"{{"accountId": "{account_id}", "create": {{"b": {{"name": "Friends"}}}}}}"#
+            ),
+        )
+        .await
+        .unwrap();
+
+        response["created"]["b"]["id"].as_str().unwrap().to_string()
+    }
+
+    /// Creates a card in the address book, returning the `/set` response.
+    pub(crate) async fn create_card(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+        mut card: Value,
+    ) -> Value {
+        card["addressBookIds"] =
+            serde_json::json!({ address_book(context, user_id, account_id).await: true });
+
+        call(
+            context,
+            user_id,
+            &Contacts {},
+            Set::<ContactCard>::default(),
+            &serde_json::json!({"accountId": account_id, "create": {"c": card}}).to_string(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn valid_card_is_stored_verbatim() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let card = serde_json::json!({
+            "uid": "urn:uuid:1",
+            "fullName": "Alice",
+            "emails": {"e1": {"email": "alice@example.com"}},
+            "x-vendor": "kept",
+        });
+        let response = create_card(&context, user_id, account_id, card).await;
+        let id = response["created"]["c"]["id"].as_str().unwrap();
+
+        let response = call(
+            &context,
+            user_id,
+            &Contacts {},
+            Get::<ContactCard>::default(),
+            &format!(r#"{{"accountId": "{account_id}", "ids": ["{id}"]}}"#),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response["list"][0]["fullName"], "Alice");
+        assert_eq!(response["list"][0]["x-vendor"], "kept");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn card_with_invalid_map_key_is_not_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let card = serde_json::json!({
+            "uid": "urn:uuid:1",
+            "emails": {"not an id": {"email": "alice@example.com"}},
+        });
+        let response = create_card(&context, user_id, account_id, card).await;
+
+        assert_eq!(response["notCreated"]["c"]["type"], "invalidProperties");
+        assert_eq!(
+            response["notCreated"]["c"]["properties"],
+            serde_json::json!(["emails"])
+        );
+        assert!(context
+            .store
+            .list_objects::<ContactCard>(account_id, "ContactCard")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn card_with_map_over_the_limit_is_not_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let limit = context.config.load().request_limits.max_card_map_entries;
+        let emails: serde_json::Map<_, _> = (0..=limit)
+            .map(|i| {
+                (
+                    format!("e{i}"),
+                    serde_json::json!({"email": "alice@example.com"}),
+                )
+            })
+            .collect();
+
+        let card = serde_json::json!({"uid": "urn:uuid:1", "emails": emails});
+        let response = create_card(&context, user_id, account_id, card).await;
+
+        assert_eq!(response["notCreated"]["c"]["type"], "invalidProperties");
+        assert_eq!(
+            response["notCreated"]["c"]["properties"],
+            serde_json::json!(["emails"])
+        );
+    }
+
+    /// Stops the context's clock, returning the seconds since the epoch it
+    /// reads, which tests move on by hand.
+    fn stop_clock(context: &mut Context) -> Arc<AtomicI64> {
+        let now = Arc::new(AtomicI64::new(1_700_000_000));
+        let clock = now.clone();
+
+        context.clock = Arc::new(move || {
+            DateTime::from_timestamp(clock.load(AtomicOrdering::Relaxed), 0).unwrap()
+        });
+
+        now
+    }
+
+    /// The timestamp the stopped clock reads at `seconds`, as sent to clients.
+    fn timestamp(seconds: i64) -> Value {
+        serde_json::to_value(DateTime::from_timestamp(seconds, 0).unwrap()).unwrap()
+    }
+
+    async fn update_card(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+        id: &str,
+        patch: Value,
+    ) -> Value {
+        call(
+            context,
+            user_id,
+            &Contacts {},
+            Set::<ContactCard>::default(),
+            &serde_json::json!({"accountId": account_id, "update": {id: patch}}).to_string(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn updated_advances_with_every_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut context = Context::for_tests(dir.path());
+        let now = stop_clock(&mut context);
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let created = now.load(AtomicOrdering::Relaxed);
+        let card = serde_json::json!({"uid": "urn:uuid:1", "fullName": "Alice"});
+        let response = create_card(&context, user_id, account_id, card).await;
+
+        assert_eq!(response["created"]["c"]["created"], timestamp(created));
+        assert_eq!(response["created"]["c"]["updated"], timestamp(created));
+        let id = response["created"]["c"]["id"].as_str().unwrap();
+
+        let updated = now.fetch_add(60, AtomicOrdering::Relaxed) + 60;
+        let response = update_card(
+            &context,
+            user_id,
+            account_id,
+            id,
+            serde_json::json!({"fullName": "Alice B"}),
+        )
+        .await;
+
+        assert_eq!(response["updated"][id]["updated"], timestamp(updated));
+
+        let response = call(
+            &context,
+            user_id,
+            &Contacts {},
+            Get::<ContactCard>::default(),
+            &format!(r#"{{"accountId": "{account_id}", "ids": ["{id}"]}}"#),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response["list"][0]["created"], timestamp(created));
+        assert_eq!(response["list"][0]["updated"], timestamp(updated));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn client_set_timestamps_are_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let card = serde_json::json!({"uid": "urn:uuid:1", "created": "2000-01-01T00:00:00Z"});
+        let response = create_card(&context, user_id, account_id, card).await;
+
+        assert_eq!(response["notCreated"]["c"]["type"], "invalidProperties");
+        assert_eq!(
+            response["notCreated"]["c"]["properties"],
+            serde_json::json!(["created"])
+        );
+
+        let card = serde_json::json!({"uid": "urn:uuid:2"});
+        let response = create_card(&context, user_id, account_id, card).await;
+        let id = response["created"]["c"]["id"].as_str().unwrap();
+
+        let response = update_card(
+            &context,
+            user_id,
+            account_id,
+            id,
+            serde_json::json!({"created": "2000-01-01T00:00:00Z"}),
+        )
+        .await;
+
+        assert_eq!(response["notUpdated"][id]["type"], "invalidProperties");
+        assert_eq!(
+            response["notUpdated"][id]["properties"],
+            serde_json::json!(["created"])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn query_sorts_by_updated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut context = Context::for_tests(dir.path());
+        let now = stop_clock(&mut context);
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let mut ids = Vec::new();
+
+        for uid in ["urn:uuid:1", "urn:uuid:2"] {
+            now.fetch_add(60, AtomicOrdering::Relaxed);
+            let response = create_card(
+                &context,
+                user_id,
+                account_id,
+                serde_json::json!({"uid": uid}),
+            )
+            .await;
+            ids.push(response["created"]["c"]["id"].clone());
+        }
+
+        // the first card is now the most recently updated
+        now.fetch_add(60, AtomicOrdering::Relaxed);
+        update_card(
+            &context,
+            user_id,
+            account_id,
+            ids[0].as_str().unwrap(),
+            serde_json::json!({"fullName": "Alice"}),
+        )
+        .await;
+
+        for (is_ascending, expected) in [(true, [&ids[1], &ids[0]]), (false, [&ids[0], &ids[1]])] {
+            let response = call(
+                &context,
+                user_id,
+                &Contacts {},
+                ContactCardQuery,
+                &serde_json::json!({
+                    "accountId": account_id,
+                    "sort": [{"property": "updated", "isAscending": is_ascending}],
+                })
+                .to_string(),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(response["ids"], serde_json::json!(expected));
+        }
+    }
+
+    /// Runs `ContactCard/query` sorted by `updated`, as a client caching it
+    /// would.
+    async fn query_by_updated(context: &Context, user_id: Uuid, account_id: Uuid) -> Value {
+        call(
+            context,
+            user_id,
+            &Contacts {},
+            ContactCardQuery,
+            &serde_json::json!({"accountId": account_id, "sort": [{"property": "updated"}]})
+                .to_string(),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn query_changes(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+        since: &Value,
+        max_changes: Option<u64>,
+    ) -> Result<Value, MethodError> {
+        call(
+            context,
+            user_id,
+            &Contacts {},
+            ContactCardQueryChanges,
+            &serde_json::json!({
+                "accountId": account_id,
+                "sort": [{"property": "updated"}],
+                "sinceQueryState": since,
+                "maxChanges": max_changes,
+                "calculateTotal": true,
+            })
+            .to_string(),
+        )
+        .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn query_changes_bring_cached_results_up_to_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut context = Context::for_tests(dir.path());
+        let now = stop_clock(&mut context);
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let mut ids = Vec::new();
+
+        for uid in ["urn:uuid:1", "urn:uuid:2", "urn:uuid:3"] {
+            now.fetch_add(60, AtomicOrdering::Relaxed);
+            let response = create_card(
+                &context,
+                user_id,
+                account_id,
+                serde_json::json!({"uid": uid}),
+            )
+            .await;
+            ids.push(response["created"]["c"]["id"].as_str().unwrap().to_string());
+        }
+
+        let cached = query_by_updated(&context, user_id, account_id).await;
+        assert_eq!(cached["canCalculateChanges"], true);
+
+        // the first card moves to the end, the second leaves the results and
+        // a new one joins
+        now.fetch_add(60, AtomicOrdering::Relaxed);
+        update_card(
+            &context,
+            user_id,
+            account_id,
+            &ids[0],
+            serde_json::json!({"fullName": "Alice"}),
+        )
+        .await;
+        call(
+            &context,
+            user_id,
+            &Contacts {},
+            Set::<ContactCard>::default(),
+            &serde_json::json!({"accountId": account_id, "destroy": [ids[1]]}).to_string(),
+        )
+        .await
+        .unwrap();
+        now.fetch_add(60, AtomicOrdering::Relaxed);
+        create_card(
+            &context,
+            user_id,
+            account_id,
+            serde_json::json!({"uid": "urn:uuid:4"}),
+        )
+        .await;
+
+        let changes = query_changes(&context, user_id, account_id, &cached["queryState"], None)
+            .await
+            .unwrap();
+
+        let mut results = cached["ids"].as_array().unwrap().clone();
+        results.retain(|id| !changes["removed"].as_array().unwrap().contains(id));
+        for added in changes["added"].as_array().unwrap() {
+            let index = usize::try_from(added["index"].as_u64().unwrap()).unwrap();
+            results.insert(index, added["id"].clone());
+        }
+
+        let fresh = query_by_updated(&context, user_id, account_id).await;
+        assert_eq!(Value::Array(results), fresh["ids"]);
+        assert_eq!(changes["oldQueryState"], cached["queryState"]);
+        assert_eq!(changes["newQueryState"], fresh["queryState"]);
+        assert_eq!(changes["total"], 3);
+
+        assert!(matches!(
+            query_changes(
+                &context,
+                user_id,
+                account_id,
+                &cached["queryState"],
+                Some(1)
+            )
+            .await,
+            Err(MethodError::TooManyChanges)
+        ));
+        assert!(matches!(
+            query_changes(&context, user_id, account_id, &"zz".into(), None).await,
+            Err(MethodError::CannotCalculateChanges)
+        ));
+    }
+
+    async fn query_ids(context: &Context, user_id: Uuid, account_id: Uuid, filter: Value) -> Value {
+        let response = call(
+            context,
+            user_id,
+            &Contacts {},
+            ContactCardQuery,
+            &serde_json::json!({"accountId": account_id, "filter": filter}).to_string(),
+        )
+        .await
+        .unwrap();
+
+        let mut ids = response["ids"].as_array().unwrap().clone();
+        ids.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+        Value::Array(ids)
+    }
+
+    /// Creates a card in each set of categories, returning their ids.
+    async fn categorised_cards(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+        categories: &[&[&str]],
+    ) -> Vec<Value> {
+        let mut ids = Vec::new();
+
+        for (i, categories) in categories.iter().enumerate() {
+            let categories: serde_json::Map<_, _> = categories
+                .iter()
+                .map(|category| (category.to_string(), Value::Bool(true)))
+                .collect();
+
+            let card =
+                serde_json::json!({"uid": format!("urn:uuid:{i}"), "categories": categories});
+            let response = create_card(context, user_id, account_id, card).await;
+            ids.push(response["created"]["c"]["id"].clone());
+        }
+
+        ids
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn query_filters_by_a_single_category() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let ids = categorised_cards(
+            &context,
+            user_id,
+            account_id,
+            &[&["work"], &["family"], &["work", "family"], &[]],
+        )
+        .await;
+
+        let mut expected = vec![ids[0].clone(), ids[2].clone()];
+        expected.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+
+        assert_eq!(
+            query_ids(
+                &context,
+                user_id,
+                account_id,
+                serde_json::json!({"categories": ["work"]})
+            )
+            .await,
+            Value::Array(expected)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn query_filters_by_all_of_several_categories() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let ids = categorised_cards(
+            &context,
+            user_id,
+            account_id,
+            &[&["work"], &["family"], &["work", "family"], &[]],
+        )
+        .await;
+
+        assert_eq!(
+            query_ids(
+                &context,
+                user_id,
+                account_id,
+                serde_json::json!({"categories": ["work", "family"], "categoriesMatch": "all"})
+            )
+            .await,
+            serde_json::json!([ids[2]])
+        );
+
+        // any is the default
+        assert_eq!(
+            query_ids(
+                &context,
+                user_id,
+                account_id,
+                serde_json::json!({"categories": ["work", "family"]})
+            )
+            .await
+            .as_array()
+            .unwrap()
+            .len(),
+            3
+        );
+    }
 }
@@ -1,25 +1,35 @@
-use std::collections::BTreeSet;
+use std::{borrow::Cow, sync::Arc};
 
-use jmap_proto::endpoints::session::CoreCapability;
+use arc_swap::ArcSwap;
+use axum::async_trait;
+use jmap_proto::{endpoints::session::CoreCapability, errors::MethodError};
 use uuid::Uuid;
 
+use self::collation::Collation;
 use crate::{
-    config::CoreCapabilities,
+    config::ReloadableConfig,
     extensions::{
         router::ExtensionRouter, JmapEndpoint, JmapExtension, JmapSessionCapabilityExtension,
+        MethodCall,
     },
 };
 
+pub mod collation;
+pub mod push_subscription;
+
 #[derive(Clone)]
 pub struct Core {
-    pub(crate) core_capabilities: CoreCapabilities,
+    pub(crate) config: Arc<ArcSwap<ReloadableConfig>>,
 }
 
 impl JmapExtension for Core {
     const EXTENSION: &'static str = "urn:ietf:params:jmap:core";
 
     fn router(&self) -> ExtensionRouter<Self> {
-        ExtensionRouter::default().register(Echo)
+        ExtensionRouter::default()
+            .register(Echo)
+            .register(push_subscription::PushSubscriptionGet)
+            .register(push_subscription::PushSubscriptionSet)
     }
 }
 
@@ -27,28 +37,33 @@ impl JmapSessionCapabilityExtension for Core {
     type Metadata = CoreCapability<'static>;
 
     fn build(&self, _user: Uuid) -> Self::Metadata {
-        CoreCapability {
-            max_size_upload: self.core_capabilities.max_size_upload.into(),
-            max_concurrent_upload: self.core_capabilities.max_concurrent_upload.into(),
-            max_size_request: self.core_capabilities.max_size_request.into(),
-            max_concurrent_requests: self.core_capabilities.max_concurrent_requests.into(),
-            max_calls_in_request: self.core_capabilities.max_calls_in_request.into(),
-            max_objects_in_get: self.core_capabilities.max_objects_in_get.into(),
-            max_objects_in_set: self.core_capabilities.max_objects_in_set.into(),
-            collation_algorithms: BTreeSet::default(),
-        }
+        self.config.load().core_capabilities.to_capability(
+            Collation::ALL
+                .into_iter()
+                .map(|collation| Cow::Borrowed(collation.name()))
+                .collect(),
+        )
     }
 }
 
 pub struct Echo;
 
+#[async_trait]
 impl JmapEndpoint<Core> for Echo {
-    type Parameters<'de> = &'de serde_json::value::RawValue;
-    type Response<'s> = &'s serde_json::value::RawValue;
+    // arguments reach endpoints as a map of each argument, which can't be
+    // deserialized back into a single raw value
+    type Parameters<'de> = serde_json::Map<String, serde_json::Value>;
+    type Response<'s> = serde_json::Map<String, serde_json::Value>;
 
+    const NAMESPACE: &'static str = "Core";
     const ENDPOINT: &'static str = "echo";
 
-    fn handle<'de>(&self, _extension: &Core, params: Self::Parameters<'de>) -> Self::Response<'de> {
-        params
+    async fn handle<'de>(
+        &self,
+        _extension: &Core,
+        _call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        Ok(params)
     }
 }
@@ -0,0 +1,62 @@
+//! The collation algorithms ([RFC4790]) strings can be compared with when
+//! filtering and sorting `/query` results.
+//!
+//! [RFC4790]: https://www.rfc-editor.org/rfc/rfc4790
+
+use std::{borrow::Cow, cmp::Ordering};
+
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Collation {
+    /// Compares the octets of the strings.
+    Octet,
+    /// Compares the strings with ASCII letters folded to the same case.
+    AsciiCasemap,
+    /// Compares the strings once case is folded and they're decomposed
+    /// ([RFC5051]), so that e.g. `É` and `e\u{301}` match.
+    ///
+    /// [RFC5051]: https://www.rfc-editor.org/rfc/rfc5051
+    UnicodeCasemap,
+}
+
+impl Collation {
+    /// Every collation supported, as advertised in the session.
+    pub const ALL: [Self; 3] = [Self::Octet, Self::AsciiCasemap, Self::UnicodeCasemap];
+
+    /// Used where the client doesn't name a collation, as recommended for
+    /// comparing text in RFC 8620.
+    pub const DEFAULT: Self = Self::UnicodeCasemap;
+
+    /// Looks up a collation by its registered identifier.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|v| v.name() == name)
+    }
+
+    /// The identifier the collation is registered under.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Octet => "i;octet",
+            Self::AsciiCasemap => "i;ascii-casemap",
+            Self::UnicodeCasemap => "i;unicode-casemap",
+        }
+    }
+
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        self.fold(a).cmp(&self.fold(b))
+    }
+
+    /// Whether `needle` appears anywhere within `haystack`.
+    pub fn contains(self, haystack: &str, needle: &str) -> bool {
+        self.fold(haystack).contains(self.fold(needle).as_ref())
+    }
+
+    /// Maps the string to the form compared octet by octet.
+    fn fold(self, value: &str) -> Cow<'_, str> {
+        match self {
+            Self::Octet => Cow::Borrowed(value),
+            Self::AsciiCasemap => Cow::Owned(value.to_ascii_lowercase()),
+            Self::UnicodeCasemap => Cow::Owned(value.nfkd().flat_map(char::to_lowercase).collect()),
+        }
+    }
+}
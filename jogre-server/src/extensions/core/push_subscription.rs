@@ -0,0 +1,397 @@
+//! `PushSubscription/get` and `PushSubscription/set`, which register URLs
+//! for changes to be pushed to.
+//!
+//! Subscriptions belong to the user making the call, and aren't visible to
+//! anyone else. A new subscription is sent a `PushVerification`, and nothing
+//! else is pushed to it until the client sets the code it was sent.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use axum::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use jmap_proto::{
+    common::{Id, UtcDate},
+    endpoints::{
+        object::set::{PatchObject, SetError},
+        push_subscription::{
+            PushSubscription as ProtoPushSubscription, PushSubscriptionGetParams,
+            PushSubscriptionGetResponse, PushSubscriptionSetParams, PushSubscriptionSetResponse,
+            PushVerification,
+        },
+    },
+    errors::MethodError,
+    Value,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::json;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    extensions::{core::Core, ExtensionRegistry, JmapEndpoint, MethodCall},
+    store::{PushSubscription, PushSubscriptionProvider},
+};
+
+/// Every property of a push subscription, which `properties` may select
+/// from.
+const PROPERTIES: &[&str] = &[
+    "id",
+    "deviceClientId",
+    "url",
+    "keys",
+    "verificationCode",
+    "expires",
+    "types",
+];
+
+pub struct PushSubscriptionGet;
+
+#[async_trait]
+impl JmapEndpoint<Core> for PushSubscriptionGet {
+    type Parameters<'de> = PushSubscriptionGetParams<'de>;
+    type Response<'s> = PushSubscriptionGetResponse<'s>;
+
+    const NAMESPACE: &'static str = "PushSubscription";
+    const ENDPOINT: &'static str = "get";
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Core,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        if let Some(properties) = &params.properties {
+            if !properties.iter().all(|p| PROPERTIES.contains(&p.as_ref())) {
+                return Err(MethodError::InvalidArguments);
+            }
+        }
+
+        let subscriptions = live_subscriptions(call).await?;
+        let mut response = PushSubscriptionGetResponse::default();
+
+        let found: Vec<&PushSubscription> = match params.ids {
+            Some(ids) => ids
+                .into_iter()
+                .filter_map(|id| {
+                    let subscription = parse_id(&id).and_then(|id| subscriptions.get(&id));
+
+                    if subscription.is_none() {
+                        response.not_found.push(id);
+                    }
+
+                    subscription
+                })
+                .collect(),
+            None => subscriptions.values().collect(),
+        };
+
+        for subscription in found {
+            let mut value = serde_json::to_value(to_proto(subscription)).unwrap();
+
+            if let (Some(properties), Value::Object(object)) = (&params.properties, &mut value) {
+                object.retain(|k, _| k == "id" || properties.iter().any(|p| p == k));
+            }
+
+            response.list.push(value);
+        }
+
+        Ok(response)
+    }
+}
+
+pub struct PushSubscriptionSet;
+
+#[async_trait]
+impl JmapEndpoint<Core> for PushSubscriptionSet {
+    type Parameters<'de> = PushSubscriptionSetParams<'de>;
+    type Response<'s> = PushSubscriptionSetResponse<'s>;
+
+    const NAMESPACE: &'static str = "PushSubscription";
+    const ENDPOINT: &'static str = "set";
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Core,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        let store = &call.context.store;
+        let mut subscriptions = live_subscriptions(call).await?;
+        let mut response = PushSubscriptionSetResponse::default();
+
+        for (creation_id, subscription) in params.create.unwrap_or_default() {
+            match create(call, subscription, subscriptions.len()) {
+                Ok(subscription) => {
+                    call.context.push.send_verification(
+                        subscription.url.clone(),
+                        &PushVerification {
+                            push_subscription_id: Id(subscription.id.to_string().into()),
+                            verification_code: Cow::Borrowed(&subscription.verification_code),
+                        },
+                    );
+
+                    response.created.get_or_insert_with(HashMap::new).insert(
+                        creation_id,
+                        json!({
+                            "id": subscription.id.to_string(),
+                            "expires": UtcDate::from(subscription.expires),
+                        }),
+                    );
+
+                    store
+                        .put_push_subscription(subscription.clone())
+                        .await
+                        .map_err(|error| call.server_fail(&error))?;
+                    subscriptions.insert(subscription.id, subscription);
+                }
+                Err(e) => {
+                    response
+                        .not_created
+                        .get_or_insert_with(HashMap::new)
+                        .insert(creation_id, e);
+                }
+            }
+        }
+
+        for (id, patch) in params.update.unwrap_or_default() {
+            let Some(subscription) = parse_id(&id).and_then(|id| subscriptions.get_mut(&id)) else {
+                response
+                    .not_updated
+                    .get_or_insert_with(HashMap::new)
+                    .insert(id, SetError::not_found(None));
+                continue;
+            };
+
+            match update(call, subscription.clone(), patch) {
+                Ok((updated, changed)) => {
+                    store
+                        .put_push_subscription(updated.clone())
+                        .await
+                        .map_err(|error| call.server_fail(&error))?;
+                    *subscription = updated;
+
+                    response
+                        .updated
+                        .get_or_insert_with(HashMap::new)
+                        .insert(id, changed);
+                }
+                Err(e) => {
+                    response
+                        .not_updated
+                        .get_or_insert_with(HashMap::new)
+                        .insert(id, e);
+                }
+            }
+        }
+
+        for id in params.destroy.unwrap_or_default() {
+            let existed = match parse_id(&id).filter(|id| subscriptions.contains_key(id)) {
+                Some(uuid) => {
+                    subscriptions.remove(&uuid);
+                    store
+                        .delete_push_subscription(call.user_id, uuid)
+                        .await
+                        .map_err(|error| call.server_fail(&error))?
+                }
+                None => false,
+            };
+
+            if existed {
+                response.destroyed.get_or_insert_with(Vec::new).push(id);
+            } else {
+                response
+                    .not_destroyed
+                    .get_or_insert_with(HashMap::new)
+                    .insert(id, SetError::not_found(None));
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+/// Validates a subscription being created, filling in its server-set
+/// properties.
+fn create(
+    call: &MethodCall<'_>,
+    subscription: ProtoPushSubscription<'_>,
+    existing: usize,
+) -> Result<PushSubscription, SetError<'static>> {
+    let config = call.context.push.config();
+
+    if existing >= config.max_subscriptions_per_user {
+        return Err(SetError::over_quota(Some(
+            format!(
+                "Users may have at most {} push subscriptions",
+                config.max_subscriptions_per_user
+            )
+            .into(),
+        )));
+    }
+
+    if subscription.keys.is_some() {
+        return Err(SetError::invalid_properties(
+            vec!["keys".into()],
+            Some("Encrypted push isn't supported".into()),
+        ));
+    }
+
+    let mut invalid = Vec::new();
+
+    // both are set by the server
+    if subscription.id.is_some() {
+        invalid.push("id".into());
+    }
+
+    if subscription.verification_code.is_some() {
+        invalid.push("verificationCode".into());
+    }
+
+    let url = subscription
+        .url
+        .and_then(|url| Url::parse(&url).ok())
+        .filter(|url| url.scheme() == "https");
+
+    if url.is_none() {
+        invalid.push("url".into());
+    }
+
+    let types = subscription
+        .types
+        .map(|types| types.into_iter().map(Cow::into_owned).collect());
+
+    if !valid_types(types.as_ref()) {
+        invalid.push("types".into());
+    }
+
+    let (Some(url), true) = (url, invalid.is_empty()) else {
+        return Err(SetError::invalid_properties(invalid, None));
+    };
+
+    Ok(PushSubscription {
+        id: Uuid::new_v4(),
+        user_id: call.user_id,
+        device_client_id: subscription.device_client_id.into_owned(),
+        url,
+        verification_code: rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect(),
+        verified: false,
+        expires: clamp_expiry(call, subscription.expires.map(DateTime::from)),
+        types,
+    })
+}
+
+/// Applies a patch to a subscription, returning it along with any property
+/// the server changed from what the patch asked for.
+///
+/// Only `verificationCode`, `expires` and `types` may be changed.
+fn update(
+    call: &MethodCall<'_>,
+    mut subscription: PushSubscription,
+    patch: PatchObject<'_>,
+) -> Result<(PushSubscription, Option<Value>), SetError<'static>> {
+    let mut invalid = Vec::new();
+    let mut changed = None;
+
+    for (property, value) in patch.0 {
+        let valid = match property.as_ref() {
+            "verificationCode" => match value {
+                Value::String(code) if code == subscription.verification_code => {
+                    subscription.verified = true;
+                    true
+                }
+                _ => false,
+            },
+            "expires" => match serde_json::from_value::<Option<UtcDate>>(value) {
+                Ok(requested) => {
+                    let requested = requested.map(DateTime::from);
+                    subscription.expires = clamp_expiry(call, requested);
+
+                    if requested != Some(subscription.expires) {
+                        changed = Some(json!({ "expires": UtcDate::from(subscription.expires) }));
+                    }
+
+                    true
+                }
+                Err(_) => false,
+            },
+            "types" => match serde_json::from_value::<Option<Vec<String>>>(value) {
+                Ok(types) if valid_types(types.as_ref()) => {
+                    subscription.types = types;
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+
+        if !valid {
+            invalid.push(Cow::Owned(property.into_owned()));
+        }
+    }
+
+    if invalid.is_empty() {
+        Ok((subscription, changed))
+    } else {
+        Err(SetError::invalid_properties(invalid, None))
+    }
+}
+
+/// The user's subscriptions that haven't yet expired, keyed by their id.
+async fn live_subscriptions(
+    call: &MethodCall<'_>,
+) -> Result<HashMap<Uuid, PushSubscription>, MethodError> {
+    let now = Utc::now();
+
+    Ok(call
+        .context
+        .store
+        .get_push_subscriptions_for_user(call.user_id)
+        .await
+        .map_err(|error| call.server_fail(&error))?
+        .into_iter()
+        .filter(|subscription| subscription.expires > now)
+        .map(|subscription| (subscription.id, subscription))
+        .collect())
+}
+
+/// Limits the expiry asked for by the client to the longest a subscription
+/// may last, which is also used if the client didn't ask for one.
+fn clamp_expiry(call: &MethodCall<'_>, requested: Option<DateTime<Utc>>) -> DateTime<Utc> {
+    let lifetime =
+        i64::try_from(call.context.push.config().max_subscription_lifetime).unwrap_or(i64::MAX);
+    let max = Utc::now() + Duration::seconds(lifetime);
+
+    requested.map_or(max, |requested| requested.min(max))
+}
+
+/// Whether every data type asked for is one the server supports.
+fn valid_types(types: Option<&Vec<String>>) -> bool {
+    let supported = ExtensionRegistry::data_types();
+
+    types.is_none_or(|types| types.iter().all(|t| supported.contains(&t.as_str())))
+}
+
+fn parse_id(id: &Id<'_>) -> Option<Uuid> {
+    Uuid::parse_str(&id.0).ok()
+}
+
+fn to_proto(subscription: &PushSubscription) -> ProtoPushSubscription<'_> {
+    ProtoPushSubscription {
+        id: Some(Id(subscription.id.to_string().into())),
+        device_client_id: Cow::Borrowed(&subscription.device_client_id),
+        url: None,
+        keys: None,
+        verification_code: subscription
+            .verified
+            .then_some(Cow::Borrowed(subscription.verification_code.as_str())),
+        expires: Some(subscription.expires.into()),
+        types: subscription
+            .types
+            .as_ref()
+            .map(|types| types.iter().map(|t| Cow::Borrowed(t.as_str())).collect()),
+    }
+}
@@ -0,0 +1,32 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::extensions::{JmapExtension, JmapSessionCapabilityExtension};
+
+/// The argument asking a set-family method for a dry run, echoed back in its
+/// response.
+pub const DRY_RUN: &str = "urn:jogre:dryRun";
+
+/// Represents support for Jogre-specific, namespaced arguments on standard
+/// JMAP methods.
+pub struct Jogre {}
+
+impl JmapExtension for Jogre {
+    const EXTENSION: &'static str = "urn:jogre";
+}
+
+impl JmapSessionCapabilityExtension for Jogre {
+    type Metadata = JogreSessionCapabilities;
+
+    fn build(&self, _user: Uuid) -> Self::Metadata {
+        JogreSessionCapabilities { dry_run: true }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JogreSessionCapabilities {
+    /// Whether set-family methods accept the `urn:jogre:dryRun` argument,
+    /// validating the call in full without persisting any changes.
+    pub dry_run: bool,
+}
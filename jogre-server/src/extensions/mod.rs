@@ -1,21 +1,55 @@
-use std::{borrow::Cow, collections::HashMap, marker::PhantomData};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use jmap_proto::{extensions::sharing as proto_sharing, Value};
-use router::ExtensionRouter;
+use axum::async_trait;
+use jmap_proto::{
+    common::{CreationId, Id},
+    compat::RenamedFields,
+    endpoints::{
+        object::{
+            changes::{ChangesParams, ChangesResponse},
+            copy::{CopyParams, CopyResponse},
+            get::{GetParams, GetResponse},
+            set::{PatchObject, SetError, SetParams, SetResult},
+        },
+        session::{AccountCapabilities, Capability},
+        Arguments,
+    },
+    errors::MethodError,
+    extensions::sharing as proto_sharing,
+    Value,
+};
+use router::{ExtensionRouter, RouterError};
 use serde::{
     de::{value::CowStrDeserializer, DeserializeSeed, MapAccess, Visitor},
     forward_to_deserialize_any, Deserialize, Deserializer, Serialize,
 };
 use serde_json::value::RawValue;
+use tracing::{debug, error};
 use uuid::Uuid;
 
+use crate::{
+    context::Context,
+    store,
+    store::{
+        decode_object_state, encode_object_state, Account, AccountProvider, ObjectProvider,
+        ObjectWrite, Persisted,
+    },
+};
+
 pub mod contacts;
 pub mod core;
+pub mod jogre;
 pub mod router;
 pub mod sharing;
+pub mod websocket;
 
 /// Defines a base extension to the JMAP specification.
-pub trait JmapExtension: Sized {
+pub trait JmapExtension: Sized + Sync {
     /// A URI that describes this extension (eg. `urn:ietf:params:jmap:contacts`).
     const EXTENSION: &'static str;
 
@@ -24,12 +58,92 @@ pub trait JmapExtension: Sized {
     }
 }
 
+/// A JMAP data type (ie. `AddressBook`).
+///
+/// Types that borrow are named by their `'static` form, without any of the
+/// lifetimes their objects borrow with.
+pub trait DataType: 'static {}
+
+/// A data type whose objects are held in the store, served by the generic
+/// [`Get`] and [`Set`] endpoints.
+#[async_trait]
+pub trait StoredDataType: DataType + Persisted + Send + Sync {
+    /// Every property of an object, which the `properties` of `Foo/get` may
+    /// select from.
+    const PROPERTIES: &'static [&'static str];
+
+    /// The properties holding the ids of other objects, either as the value
+    /// or as the keys of a map, in which `#creationId` references are
+    /// resolved.
+    const ID_PROPERTIES: &'static [&'static str] = &[];
+
+    /// The object as it's returned to clients.
+    fn to_value(&self) -> Value;
+
+    /// The most objects of the type an account may hold, or `None` if
+    /// there's no limit.
+    fn quota(_call: &MethodCall<'_>) -> Option<u64> {
+        None
+    }
+
+    /// Whether the user may see the object. Objects the user can't see are
+    /// treated as if they don't exist.
+    fn is_visible_to(&self, _user: Uuid) -> bool {
+        true
+    }
+
+    /// Whether the user may update the object.
+    fn may_update(&self, _user: Uuid) -> bool {
+        true
+    }
+
+    /// Whether the user may destroy the object.
+    fn may_destroy(&self, _user: Uuid) -> bool {
+        true
+    }
+
+    /// Builds an object from the properties the client sent to create it.
+    ///
+    /// Only a failure of the store fails the whole call, anything wrong with
+    /// the object itself fails just its creation.
+    async fn create(
+        call: &MethodCall<'_>,
+        account: Uuid,
+        id: Uuid,
+        properties: &Value,
+    ) -> Result<Result<Self, SetError<'static>>, MethodError>;
+
+    /// Applies a patch sent by the client to the object, returning the
+    /// updated object along with any of its properties that changed in a way
+    /// the patch didn't ask for.
+    async fn update(
+        &self,
+        call: &MethodCall<'_>,
+        account: Uuid,
+        patch: &PatchObject<'_>,
+    ) -> Result<Result<(Self, Option<Value>), SetError<'static>>, MethodError>;
+
+    /// The properties of a newly created object that the server set, other
+    /// than its id, to be returned in the `created` map of `Foo/set`.
+    fn server_set_properties(&self) -> serde_json::Map<String, Value>;
+}
+
 /// Defines an extension that can handle reads/writes.
-pub trait JmapDataExtension<D>: JmapExtension {
+pub trait JmapDataExtension<D: DataType>: JmapExtension {
     /// Endpoint from which this data type is exposed from (ie. `ContactBook`).
     const ENDPOINT: &'static str;
+
+    /// The methods supported on this data type (ie. `get`), each of which
+    /// must have an endpoint registered in the extension's router.
+    const METHODS: &'static [&'static str];
+
+    /// Whether clients may create and update objects of this data type.
+    /// Objects of types that aren't are only created by the server, but may
+    /// still be destroyed by clients if the type supports `set`.
+    const WRITABLE: bool = false;
 }
 
+/// Handles `Foo/get` calls for a data type.
 pub struct Get<D> {
     _phantom: PhantomData<fn(D)>,
 }
@@ -42,23 +156,829 @@ impl<D> Default for Get<D> {
     }
 }
 
-impl<D, Ext: JmapDataExtension<D>> JmapEndpoint<Ext> for Get<D> {
-    type Parameters<'de> = ();
-    type Response<'s> = ();
-    const ENDPOINT: &'static str = "";
+#[async_trait]
+impl<D: StoredDataType, Ext: JmapDataExtension<D>> JmapEndpoint<Ext> for Get<D> {
+    type Parameters<'de> = GetParams<'de>;
+    type Response<'s> = GetResponse<'s, Value>;
+    const NAMESPACE: &'static str = <Ext as JmapDataExtension<D>>::ENDPOINT;
+    const ENDPOINT: &'static str = "get";
+    const ID_ARGUMENTS: IdArguments = IdArguments::Get;
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Ext,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        let namespace = <Ext as JmapDataExtension<D>>::ENDPOINT;
+
+        if let Some(properties) = params.properties() {
+            if !properties
+                .iter()
+                .all(|p| D::PROPERTIES.contains(&p.as_ref()))
+            {
+                return Err(MethodError::InvalidArguments);
+            }
+        }
+
+        let max_objects = call
+            .context
+            .config
+            .load()
+            .core_capabilities
+            .max_objects_in_get;
+
+        if params
+            .ids()
+            .is_some_and(|ids| ids.len() as u64 > max_objects)
+        {
+            return Err(MethodError::RequestTooLarge);
+        }
+
+        let account_id = call.require_account(params.account_id()).await?.id;
+        let store = &call.context.store;
+
+        // read before any object so the state never claims to include
+        // changes the objects returned don't
+        let state = store
+            .state_for(account_id, namespace)
+            .await
+            .map_err(|error| call.server_fail(&error))?;
+
+        let mut found = Vec::new();
+        let mut not_found = Vec::new();
+
+        if let Some(ids) = params.ids() {
+            let mut seen = HashSet::new();
+
+            for id in ids.iter().filter(|id| seen.insert(*id)) {
+                let object = match Uuid::parse_str(&id.0) {
+                    Ok(uuid) => store
+                        .get_object::<D>(account_id, namespace, uuid)
+                        .await
+                        .map_err(|error| call.server_fail(&error))?,
+                    Err(_) => None,
+                };
+
+                match object.filter(|object| object.is_visible_to(call.user_id)) {
+                    Some(object) => found.push(object),
+                    None => not_found.push(id.clone()),
+                }
+            }
+        } else {
+            found = store
+                .list_objects::<D>(account_id, namespace)
+                .await
+                .map_err(|error| call.server_fail(&error))?
+                .into_iter()
+                .map(|(_, object)| object)
+                .filter(|object| object.is_visible_to(call.user_id))
+                .collect();
+
+            if found.len() as u64 > max_objects {
+                return Err(MethodError::RequestTooLarge);
+            }
+        }
+
+        let list = found
+            .iter()
+            .map(|object| {
+                let mut value = object.to_value();
+
+                if let (Some(properties), Value::Object(object)) = (params.properties(), &mut value)
+                {
+                    object.retain(|k, _| k == "id" || properties.iter().any(|p| p == k));
+                }
+
+                value
+            })
+            .collect();
+
+        Ok(call.with_legacy_field_names(GetResponse::new(
+            params.account_id().clone(),
+            state,
+            list,
+            not_found,
+        )))
+    }
+}
+
+/// Handles `Foo/changes` calls for a data type.
+///
+/// Objects created or updated since the client's state that the user can no
+/// longer see are reported as destroyed, so they're dropped from the
+/// client's cache.
+pub struct Changes<D> {
+    _phantom: PhantomData<fn(D)>,
+}
+
+impl<D> Default for Changes<D> {
+    fn default() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<D: StoredDataType, Ext: JmapDataExtension<D>> JmapEndpoint<Ext> for Changes<D> {
+    type Parameters<'de> = ChangesParams<'de>;
+    type Response<'s> = ChangesResponse<'s>;
+    const NAMESPACE: &'static str = <Ext as JmapDataExtension<D>>::ENDPOINT;
+    const ENDPOINT: &'static str = "changes";
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Ext,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        let namespace = <Ext as JmapDataExtension<D>>::ENDPOINT;
+
+        if params.max_changes().is_some_and(|max| max.get() == 0) {
+            return Err(MethodError::InvalidArguments);
+        }
+
+        let account_id = call.require_account(params.account_id()).await?.id;
+        let store = &call.context.store;
+
+        let since =
+            decode_object_state(params.since_state()).ok_or(MethodError::CannotCalculateChanges)?;
+
+        // never more than the client can fetch with a single `Foo/get`
+        let max_objects = call
+            .context
+            .config
+            .load()
+            .core_capabilities
+            .max_objects_in_get;
+        let max_changes = params
+            .max_changes()
+            .map_or(max_objects, |max| max.get().min(max_objects));
 
-    fn handle<'de>(&self, extension: &Ext, params: Self::Parameters<'de>) -> Self::Response<'de> {
-        todo!()
+        let changes = store
+            .get_object_changes(
+                account_id,
+                namespace,
+                since,
+                Some(usize::try_from(max_changes).unwrap_or(usize::MAX)),
+            )
+            .await
+            .map_err(|error| call.server_fail(&error))?
+            .ok_or(MethodError::CannotCalculateChanges)?;
+
+        let mut created = Vec::new();
+        let mut updated = Vec::new();
+        let mut destroyed: Vec<_> = changes.destroyed.iter().map(to_id).collect();
+
+        for id in &changes.created {
+            if is_visible::<D>(call, account_id, namespace, *id).await? {
+                created.push(to_id(id));
+            }
+        }
+
+        // the client may have cached these before the user lost sight of them
+        for id in &changes.updated {
+            if is_visible::<D>(call, account_id, namespace, *id).await? {
+                updated.push(to_id(id));
+            } else {
+                destroyed.push(to_id(id));
+            }
+        }
+
+        Ok(ChangesResponse::new(
+            params.account_id().clone(),
+            params.since_state().clone(),
+            encode_object_state(changes.new_state),
+            changes.has_more_changes,
+            created,
+            updated,
+            destroyed,
+        ))
+    }
+}
+
+fn to_id(id: &Uuid) -> Id<'static> {
+    Id(id.to_string().into())
+}
+
+/// Whether the object exists and the user may see it.
+async fn is_visible<D: StoredDataType>(
+    call: &MethodCall<'_>,
+    account: Uuid,
+    namespace: &str,
+    id: Uuid,
+) -> Result<bool, MethodError> {
+    Ok(call
+        .context
+        .store
+        .get_object::<D>(account, namespace, id)
+        .await
+        .map_err(|error| call.server_fail(&error))?
+        .is_some_and(|object| object.is_visible_to(call.user_id)))
+}
+
+/// Handles `Foo/set` calls for a data type.
+///
+/// The whole call is guarded by `ifInState`, a mismatch fails the call with
+/// `stateMismatch` before any create, update or destroy is applied. Within
+/// a call, destroys are idempotent: ids that don't exist, such as those
+/// already destroyed by a retried request, are reported as `notFound` in
+/// `notDestroyed` rather than failing the call.
+///
+/// Every create, update and destroy is validated before any is written, so
+/// a dry run returns exactly the response a real call would, other than the
+/// state.
+pub struct Set<D> {
+    _phantom: PhantomData<fn(D)>,
+}
+
+impl<D> Default for Set<D> {
+    fn default() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
     }
 }
 
+/// Arguments to a `Foo/set` call, including any namespaced arguments
+/// supported by Jogre.
+#[derive(Deserialize)]
+#[serde(bound = "D: Deserialize<'de>")]
+pub struct SetArguments<'a, D> {
+    #[serde(flatten, borrow)]
+    pub params: SetParams<'a, D>,
+    /// Runs the call through full validation, including `ifInState`, quota
+    /// and access checks, returning the same response as a real call would
+    /// but without persisting anything or bumping the state. Ids in the
+    /// `created` response of a dry run are provisional and must not be
+    /// added to the request's `createdIds`.
+    #[serde(rename = "urn:jogre:dryRun", default)]
+    pub dry_run: bool,
+}
+
+/// The response to a `Foo/set` call, flagged if it was a dry run so that
+/// the ids it created aren't added to the request's `createdIds`.
+#[derive(Serialize)]
+pub struct SetResponse<'a> {
+    #[serde(flatten)]
+    pub result: SetResult<'a, Value>,
+    #[serde(
+        rename = "urn:jogre:dryRun",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub dry_run: bool,
+}
+
+#[async_trait]
+impl<D: StoredDataType, Ext: JmapDataExtension<D>> JmapEndpoint<Ext> for Set<D> {
+    type Parameters<'de> = SetArguments<'de, Value>;
+    type Response<'s> = SetResponse<'s>;
+    const NAMESPACE: &'static str = <Ext as JmapDataExtension<D>>::ENDPOINT;
+    const ENDPOINT: &'static str = "set";
+    const ID_ARGUMENTS: IdArguments = IdArguments::Set(D::ID_PROPERTIES);
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Ext,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        let namespace = <Ext as JmapDataExtension<D>>::ENDPOINT;
+
+        let SetArguments { params, dry_run } = params;
+
+        if !<Ext as JmapDataExtension<D>>::WRITABLE && params.creates_or_updates() {
+            return Err(MethodError::Forbidden);
+        }
+
+        if params.len() as u64
+            > call
+                .context
+                .config
+                .load()
+                .core_capabilities
+                .max_objects_in_set
+        {
+            return Err(MethodError::RequestTooLarge);
+        }
+
+        call.require_write_access(params.account_id()).await?;
+
+        let account_id =
+            Uuid::parse_str(&params.account_id().0).map_err(|_| MethodError::AccountNotFound)?;
+
+        let store = &call.context.store;
+
+        // held until the changes are written, so the state can't move on
+        // between the ifInState check and the writes
+        let _write_lock = call
+            .context
+            .object_writes
+            .lock(&format!("{account_id}/{namespace}"))
+            .await;
+
+        let old_state = store
+            .state_for(account_id, namespace)
+            .await
+            .map_err(|error| call.server_fail(&error))?;
+
+        if params
+            .if_in_state()
+            .is_some_and(|if_in_state| *if_in_state != old_state)
+        {
+            return Err(MethodError::StateMismatch);
+        }
+
+        let mut result = SetResult::new(params.account_id().clone(), old_state);
+        let mut writes = Vec::<ObjectWrite<D>>::new();
+
+        for (creation_id, created) in
+            create_objects(call, account_id, namespace, params.create(), &mut writes).await?
+        {
+            match created {
+                Ok(properties) => result.insert_created(creation_id, properties),
+                Err(error) => result.insert_not_created(creation_id, error),
+            }
+        }
+        update_objects(
+            call,
+            account_id,
+            namespace,
+            &params,
+            &mut result,
+            &mut writes,
+        )
+        .await?;
+        destroy_objects(
+            call,
+            account_id,
+            namespace,
+            &params,
+            &mut result,
+            &mut writes,
+        )
+        .await?;
+
+        if !dry_run && !writes.is_empty() {
+            result.set_new_state(
+                store
+                    .apply_set(account_id, namespace, &writes)
+                    .await
+                    .map_err(|error| call.server_fail(&error))?,
+            );
+        }
+
+        Ok(SetResponse { result, dry_run })
+    }
+}
+
+/// Validates the objects a `Foo/set` or `Foo/copy` call creates, each of
+/// which counts towards the data type's quota as it's made. Returns the
+/// properties the server set on each object created, or why it wasn't.
+async fn create_objects<'a, 'c, D: StoredDataType>(
+    call: &MethodCall<'_>,
+    account_id: Uuid,
+    namespace: &str,
+    creates: impl IntoIterator<Item = (&'c CreationId<'a>, &'c Value)> + Send,
+    writes: &mut Vec<ObjectWrite<D>>,
+) -> Result<Vec<(CreationId<'a>, Result<Value, SetError<'a>>)>, MethodError>
+where
+    'a: 'c,
+{
+    let creates: Vec<_> = creates.into_iter().collect();
+
+    let mut remaining = match D::quota(call) {
+        Some(quota) if !creates.is_empty() => {
+            let existing = call
+                .context
+                .store
+                .query_objects::<D, _, _>(account_id, namespace, |objects| objects.count() as u64)
+                .await
+                .map_err(|error| call.server_fail(&error))?;
+
+            Some(quota.saturating_sub(existing))
+        }
+        _ => None,
+    };
+
+    let mut outcomes = Vec::with_capacity(creates.len());
+
+    for (creation_id, properties) in creates {
+        if remaining == Some(0) {
+            outcomes.push((
+                creation_id.clone(),
+                Err(SetError::over_quota(Some(Cow::Borrowed(
+                    "The account holds as many of these objects as it may",
+                )))),
+            ));
+            continue;
+        }
+
+        let id = Uuid::new_v4();
+
+        match D::create(call, account_id, id, properties).await? {
+            Ok(object) => {
+                let mut properties = object.server_set_properties();
+                properties.insert("id".to_string(), Value::String(id.to_string()));
+
+                outcomes.push((creation_id.clone(), Ok(Value::Object(properties))));
+                writes.push(ObjectWrite::Put(id, object));
+
+                if let Some(remaining) = &mut remaining {
+                    *remaining -= 1;
+                }
+            }
+            Err(error) => outcomes.push((creation_id.clone(), Err(error))),
+        }
+    }
+
+    Ok(outcomes)
+}
+
+/// Validates the patches a `Foo/set` call applies.
+async fn update_objects<'a, D: StoredDataType>(
+    call: &MethodCall<'_>,
+    account_id: Uuid,
+    namespace: &str,
+    params: &SetParams<'a, Value>,
+    result: &mut SetResult<'a, Value>,
+    writes: &mut Vec<ObjectWrite<D>>,
+) -> Result<(), MethodError> {
+    for (id, patch) in params.update() {
+        if params.destroy().contains(id) {
+            result.insert_not_updated(id.clone(), SetError::will_destroy());
+            continue;
+        }
+
+        let Some((uuid, current)) = find::<D>(call, account_id, namespace, id).await? else {
+            result.insert_not_updated(id.clone(), SetError::not_found(None));
+            continue;
+        };
+
+        if !current.may_update(call.user_id) {
+            result.insert_not_updated(id.clone(), SetError::forbidden(None));
+            continue;
+        }
+
+        match current.update(call, account_id, patch).await? {
+            Ok((updated, changed)) => {
+                result.insert_updated(id.clone(), changed);
+                writes.push(ObjectWrite::Put(uuid, updated));
+            }
+            Err(error) => result.insert_not_updated(id.clone(), error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the objects a `Foo/set` call destroys. Ids that don't exist are
+/// `notFound`, and ids given more than once are only destroyed once.
+async fn destroy_objects<'a, D: StoredDataType>(
+    call: &MethodCall<'_>,
+    account_id: Uuid,
+    namespace: &str,
+    params: &SetParams<'a, Value>,
+    result: &mut SetResult<'a, Value>,
+    writes: &mut Vec<ObjectWrite<D>>,
+) -> Result<(), MethodError> {
+    let mut seen = HashSet::new();
+
+    for id in params.destroy().iter().filter(|id| seen.insert(*id)) {
+        match find::<D>(call, account_id, namespace, id).await? {
+            Some((_, current)) if !current.may_destroy(call.user_id) => {
+                result.insert_not_destroyed(id.clone(), SetError::forbidden(None));
+            }
+            Some((uuid, _)) => {
+                result.push_destroyed(id.clone());
+                writes.push(ObjectWrite::Delete(uuid));
+            }
+            None => result.insert_not_destroyed(id.clone(), SetError::not_found(None)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks up an object of the data type by an id given by the client,
+/// treating ids that aren't valid and objects the user can't see as not
+/// found.
+async fn find<D: StoredDataType>(
+    call: &MethodCall<'_>,
+    account: Uuid,
+    namespace: &str,
+    id: &Id<'_>,
+) -> Result<Option<(Uuid, D)>, MethodError> {
+    let Ok(uuid) = Uuid::parse_str(&id.0) else {
+        return Ok(None);
+    };
+
+    let object = call
+        .context
+        .store
+        .get_object::<D>(account, namespace, uuid)
+        .await
+        .map_err(|error| call.server_fail(&error))?;
+
+    Ok(object
+        .filter(|object| object.is_visible_to(call.user_id))
+        .map(|object| (uuid, object)))
+}
+
+/// Handles `Foo/copy` calls for a data type, copying objects into the
+/// account from another the user can see.
+///
+/// Each copy is created just as `Foo/set` would create an object with the
+/// original's properties, other than those set by the server, overridden by
+/// those the client gave. The originals are left alone, it's up to the
+/// caller to destroy them if `onSuccessDestroyOriginal` is set.
+pub struct CopyObjects<D> {
+    _phantom: PhantomData<fn(D)>,
+}
+
+impl<D> Default for CopyObjects<D> {
+    fn default() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<D: StoredDataType, Ext: JmapDataExtension<D>> JmapEndpoint<Ext> for CopyObjects<D> {
+    type Parameters<'de> = CopyParams<'de, Value>;
+    type Response<'s> = CopyResponse<'s, Value>;
+    const NAMESPACE: &'static str = <Ext as JmapDataExtension<D>>::ENDPOINT;
+    const ENDPOINT: &'static str = "copy";
+    const ID_ARGUMENTS: IdArguments = IdArguments::Copy(D::ID_PROPERTIES);
+
+    async fn handle<'de>(
+        &self,
+        _extension: &Ext,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError> {
+        let namespace = <Ext as JmapDataExtension<D>>::ENDPOINT;
+
+        if params.from_account_id() == params.account_id() {
+            return Err(MethodError::InvalidArguments);
+        }
+
+        if params.create().len() as u64
+            > call
+                .context
+                .config
+                .load()
+                .core_capabilities
+                .max_objects_in_set
+        {
+            return Err(MethodError::RequestTooLarge);
+        }
+
+        let from_account_id = call.require_account(params.from_account_id()).await?.id;
+        call.require_write_access(params.account_id()).await?;
+
+        let account_id =
+            Uuid::parse_str(&params.account_id().0).map_err(|_| MethodError::AccountNotFound)?;
+
+        let store = &call.context.store;
+
+        if let Some(if_from_in_state) = params.if_from_in_state() {
+            let from_state = store
+                .state_for(from_account_id, namespace)
+                .await
+                .map_err(|error| call.server_fail(&error))?;
+
+            if *if_from_in_state != from_state {
+                return Err(MethodError::StateMismatch);
+            }
+        }
+
+        // held until the copies are written, as by `Foo/set`
+        let _write_lock = call
+            .context
+            .object_writes
+            .lock(&format!("{account_id}/{namespace}"))
+            .await;
+
+        let old_state = store
+            .state_for(account_id, namespace)
+            .await
+            .map_err(|error| call.server_fail(&error))?;
+
+        if params
+            .if_in_state()
+            .is_some_and(|if_in_state| *if_in_state != old_state)
+        {
+            return Err(MethodError::StateMismatch);
+        }
+
+        let mut result = CopyResponse::new(
+            params.from_account_id().clone(),
+            params.account_id().clone(),
+            old_state,
+        );
+
+        let mut copies = Vec::with_capacity(params.create().len());
+
+        for (creation_id, properties) in params.create() {
+            match copy_of::<D>(call, from_account_id, namespace, properties).await? {
+                Ok(copy) => copies.push((creation_id, copy)),
+                Err(error) => result.insert_not_created(creation_id.clone(), error),
+            }
+        }
+
+        let mut writes = Vec::<ObjectWrite<D>>::new();
+
+        for (creation_id, created) in create_objects(
+            call,
+            account_id,
+            namespace,
+            copies
+                .iter()
+                .map(|(creation_id, copy)| (*creation_id, copy)),
+            &mut writes,
+        )
+        .await?
+        {
+            match created {
+                Ok(properties) => result.insert_created(creation_id, properties),
+                Err(error) => result.insert_not_created(creation_id, error),
+            }
+        }
+
+        if !writes.is_empty() {
+            result.set_new_state(
+                store
+                    .apply_set(account_id, namespace, &writes)
+                    .await
+                    .map_err(|error| call.server_fail(&error))?,
+            );
+        }
+
+        Ok(result)
+    }
+}
+
+/// The properties to create the copy of an object with, given those the
+/// client sent for it, or why it can't be copied.
+async fn copy_of<D: StoredDataType>(
+    call: &MethodCall<'_>,
+    from_account_id: Uuid,
+    namespace: &str,
+    properties: &Value,
+) -> Result<Result<Value, SetError<'static>>, MethodError> {
+    let Some(id) = properties.get("id").and_then(Value::as_str) else {
+        return Ok(Err(SetError::invalid_properties(
+            vec!["id".into()],
+            Some("The id of the object to copy is required".into()),
+        )));
+    };
+
+    let Some((_, original)) =
+        find::<D>(call, from_account_id, namespace, &Id(Cow::Borrowed(id))).await?
+    else {
+        return Ok(Err(SetError::not_found(None)));
+    };
+
+    let Value::Object(mut copy) = original.to_value() else {
+        return Err(MethodError::ServerFail);
+    };
+
+    // the copy is given its own id, and the server sets its own properties
+    // afresh
+    copy.remove("id");
+    for property in original.server_set_properties().keys() {
+        copy.remove(property);
+    }
+
+    if let Value::Object(overrides) = properties {
+        copy.extend(
+            overrides
+                .iter()
+                .filter(|(property, _)| *property != "id")
+                .map(|(property, value)| (property.clone(), value.clone())),
+        );
+    }
+
+    Ok(Ok(Value::Object(copy)))
+}
+
+/// The request a method is being called as part of.
+pub struct MethodCall<'a> {
+    pub context: &'a Context,
+    /// The user making the request.
+    pub user_id: Uuid,
+    /// Set once a call in the request has found the store to have failed,
+    /// after which the rest of the request's calls aren't attempted.
+    pub store_unavailable: AtomicBool,
+}
+
+impl MethodCall<'_> {
+    /// Adds the old names of any renamed fields to the response, if the
+    /// server is configured to send them.
+    pub fn with_legacy_field_names<R: RenamedFields>(&self, response: R) -> R {
+        if self.context.legacy_field_names {
+            response.with_legacy_field_names()
+        } else {
+            response
+        }
+    }
+
+    /// Logs a failure to reach the store, which the client is only told about
+    /// as a `serverFail`. A failure of the store itself, rather than of the
+    /// call's use of it, also marks the store unavailable for the rest of
+    /// the request.
+    pub fn server_fail(&self, error: &store::Error) -> MethodError {
+        error!(%error, "Store failed while handling method call");
+
+        if error.is_fatal() {
+            self.store_unavailable.store(true, Ordering::Relaxed);
+        }
+
+        MethodError::ServerFail
+    }
+
+    /// Whether an earlier call in the request found the store to have
+    /// failed.
+    pub fn is_store_unavailable(&self) -> bool {
+        self.store_unavailable.load(Ordering::Relaxed)
+    }
+
+    /// Looks up an account the user has access to, which every method must
+    /// do before reading anything from the account, so that accounts the
+    /// user can't see are indistinguishable from those that don't exist.
+    pub async fn require_account(&self, account_id: &Id<'_>) -> Result<Account, MethodError> {
+        let Ok(account_id) = Uuid::parse_str(&account_id.0) else {
+            return Err(MethodError::AccountNotFound);
+        };
+
+        self.context
+            .store
+            .get_accounts_for_user(self.user_id)
+            .await
+            .map_err(|error| self.server_fail(&error))?
+            .into_iter()
+            .find(|account| account.id == account_id)
+            .ok_or(MethodError::AccountNotFound)
+    }
+
+    /// Checks that the user may change objects within the account, which
+    /// every method that writes to an account must do before anything else.
+    pub async fn require_write_access(&self, account_id: &Id<'_>) -> Result<(), MethodError> {
+        let account = self.require_account(account_id).await?;
+
+        let access = self
+            .context
+            .store
+            .get_access_level(self.user_id, account.id)
+            .await
+            .map_err(|error| self.server_fail(&error))?;
+
+        if account.is_read_only_for(access) {
+            Err(MethodError::AccountReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
 pub trait JmapEndpoint<E: JmapExtension> {
-    type Parameters<'de>: Deserialize<'de>;
-    type Response<'s>: Serialize + 's;
+    type Parameters<'de>: Deserialize<'de> + Send;
+    type Response<'s>: Serialize + Send + 's;
 
+    /// The part of the method's name before the slash (ie. `Core`).
+    const NAMESPACE: &'static str;
+    /// The part of the method's name after the slash (ie. `echo`).
     const ENDPOINT: &'static str;
+    /// The arguments of the method that hold ids, which clients may give as
+    /// `#creationId` references to records created earlier in the request.
+    const ID_ARGUMENTS: IdArguments = IdArguments::None;
+
+    async fn handle<'de>(
+        &self,
+        extension: &E,
+        call: &MethodCall<'_>,
+        params: Self::Parameters<'de>,
+    ) -> Result<Self::Response<'de>, MethodError>;
+}
 
-    fn handle<'de>(&self, extension: &E, params: Self::Parameters<'de>) -> Self::Response<'de>;
+/// Where the arguments of a method hold ids, so that `#creationId`
+/// references can be resolved in them and nowhere else.
+#[derive(Clone, Copy, Debug)]
+pub enum IdArguments {
+    None,
+    /// The `ids` of a `Foo/get` call.
+    Get,
+    /// The keys of `update` and the ids in `destroy` of a `Foo/set` call,
+    /// along with the given id-valued properties of the objects it creates
+    /// and patches.
+    Set(&'static [&'static str]),
+    /// The `id` of each object a `Foo/copy` call creates, being that of its
+    /// original, along with the given id-valued properties of the object.
+    Copy(&'static [&'static str]),
 }
 
 /// Defines an extension which should be exposed via session capabilities.
@@ -72,40 +992,78 @@ pub trait JmapSessionCapabilityExtension: JmapExtension {
 /// Defines an extension which should be exposed via account capabilities.
 pub trait JmapAccountCapabilityExtension: JmapExtension {
     /// The metadata returned by this endpoint within account capabilities
-    /// from the session endpoint.
+    /// from the session endpoint, keyed by its own URI.
+    type Metadata: Capability;
+
+    /// Builds the metadata for the account, or `None` if the capability
+    /// doesn't apply to it.
+    fn build(&self, user: Uuid, account: &Account) -> Option<Self::Metadata>;
+}
+
+/// Defines an extension which contributes capability-specific information
+/// to the `capabilities` of `Principal` objects.
+pub trait JmapPrincipalCapabilityExtension: JmapExtension {
+    /// The metadata returned by this extension within a principal's
+    /// capabilities.
     type Metadata: Serialize;
 
-    fn build(&self, user: Uuid, account: Uuid) -> Self::Metadata;
+    fn build(&self, principal: &proto_sharing::Principal<'_>) -> Self::Metadata;
 }
 
 pub struct ExtensionRouterRegistry {
     pub core: ExtensionRouter<core::Core>,
+    pub contacts: ExtensionRouter<contacts::Contacts>,
 }
 
 impl ExtensionRouterRegistry {
-    pub fn handle(
+    /// Calls the method, returning `None` if no endpoint is registered for
+    /// it.
+    pub async fn handle(
         &self,
-        uri: &str,
-        registry: &ExtensionRegistry,
+        method: &str,
+        call: &MethodCall<'_>,
         params: ResolvedArguments<'_>,
-    ) -> Option<HashMap<String, Value>> {
-        let Some((namespace, uri)) = uri.split_once('/') else {
-            return None;
-        };
+    ) -> Option<Result<Arguments<'static>, MethodError>> {
+        let registry = &call.context.extension_registry;
 
-        match namespace {
-            "Core" => self.core.handle(&registry.core, uri, params),
-            _ => None,
+        // core also serves methods outside of the `Core` namespace, such as
+        // `PushSubscription/get`
+        if self.core.contains(method) {
+            self.core.handle(&registry.core, method, call, params).await
+        } else {
+            self.contacts
+                .handle(&registry.contacts, method, call, params)
+                .await
         }
     }
+
+    /// The full names of every method that can be called, in order.
+    pub fn methods(&self) -> impl Iterator<Item = &str> {
+        self.core.methods().chain(self.contacts.methods())
+    }
+
+    /// Where the arguments of the method hold ids, or `None` if no endpoint
+    /// is registered for it.
+    pub fn id_arguments(&self, method: &str) -> Option<IdArguments> {
+        self.core
+            .id_arguments(method)
+            .or_else(|| self.contacts.id_arguments(method))
+    }
+
+    /// Whether an endpoint is registered for the method.
+    pub fn contains(&self, method: &str) -> bool {
+        self.methods().any(|registered| registered == method)
+    }
 }
 
 /// Registry containing all extensions that can be handled by Jogre.
 pub struct ExtensionRegistry {
     pub core: core::Core,
+    pub jogre: jogre::Jogre,
     pub contacts: contacts::Contacts,
     pub sharing_principals: sharing::Principals,
     pub sharing_principals_owner: sharing::PrincipalsOwner,
+    pub websocket: websocket::WebSocket,
 }
 
 impl ExtensionRegistry {
@@ -116,6 +1074,10 @@ impl ExtensionRegistry {
             Cow::Borrowed(core::Core::EXTENSION),
             serde_json::to_value(JmapSessionCapabilityExtension::build(&self.core, user)).unwrap(),
         );
+        out.insert(
+            Cow::Borrowed(jogre::Jogre::EXTENSION),
+            serde_json::to_value(JmapSessionCapabilityExtension::build(&self.jogre, user)).unwrap(),
+        );
         out.insert(
             Cow::Borrowed(sharing::Principals::EXTENSION),
             serde_json::to_value(JmapSessionCapabilityExtension::build(
@@ -124,19 +1086,116 @@ impl ExtensionRegistry {
             ))
             .unwrap(),
         );
+        out.insert(
+            Cow::Borrowed(websocket::WebSocket::EXTENSION),
+            serde_json::to_value(JmapSessionCapabilityExtension::build(&self.websocket, user))
+                .unwrap(),
+        );
         out
     }
 
-    pub fn build_router_registry(&self) -> ExtensionRouterRegistry {
-        ExtensionRouterRegistry {
-            core: self.core.router(),
+    /// The URIs of every capability a request may be `using`.
+    pub const CAPABILITIES: &'static [&'static str] = &[
+        core::Core::EXTENSION,
+        jogre::Jogre::EXTENSION,
+        contacts::Contacts::EXTENSION,
+        sharing::Principals::EXTENSION,
+        sharing::PrincipalsOwner::EXTENSION,
+        websocket::WebSocket::EXTENSION,
+    ];
+
+    /// Builds the capabilities of an account from the session endpoint,
+    /// leaving out those that don't apply to the account.
+    pub fn build_account_capabilities(
+        &self,
+        user: Uuid,
+        account: &Account,
+    ) -> AccountCapabilities<'static> {
+        fn insert<E: JmapAccountCapabilityExtension>(
+            out: &mut AccountCapabilities<'static>,
+            extension: &E,
+            user: Uuid,
+            account: &Account,
+        ) {
+            if let Some(metadata) = extension.build(user, account) {
+                out.insert(&metadata);
+            }
+        }
+
+        let mut out = AccountCapabilities::default();
+        insert(&mut out, &self.contacts, user, account);
+        insert(&mut out, &self.sharing_principals, user, account);
+        insert(&mut out, &self.sharing_principals_owner, user, account);
+        out
+    }
+
+    /// Fills in the capabilities of a principal with the information each
+    /// extension provides about it.
+    pub fn populate_principal_capabilities(&self, principal: &mut proto_sharing::Principal<'_>) {
+        principal.capabilities.insert(
+            Cow::Borrowed(sharing::Principals::EXTENSION),
+            serde_json::to_value(JmapPrincipalCapabilityExtension::build(
+                &self.sharing_principals,
+                principal,
+            ))
+            .unwrap(),
+        );
+    }
+
+    /// The names of every data type exposed by the registered extensions.
+    pub fn data_types() -> Vec<&'static str> {
+        vec![
+            <contacts::Contacts as JmapDataExtension<contacts::AddressBook>>::ENDPOINT,
+            <contacts::Contacts as JmapDataExtension<contacts::ContactCard>>::ENDPOINT,
+            <sharing::Principals as JmapDataExtension<proto_sharing::Principal<'static>>>::ENDPOINT,
+            <sharing::Principals as JmapDataExtension<
+                proto_sharing::ShareNotification<'static>,
+            >>::ENDPOINT,
+        ]
+    }
+
+    /// Builds the routers of every extension, failing if any method is
+    /// registered twice or a data type is missing a method it claims to
+    /// support.
+    pub fn build_router_registry(&self) -> Result<ExtensionRouterRegistry, RouterError> {
+        let core = self.core.router();
+        core.validate()?;
+
+        let contacts = self.contacts.router();
+        contacts.validate()?;
+        contacts.validate_data_type::<contacts::AddressBook>()?;
+        contacts.validate_data_type::<contacts::ContactCard>()?;
+
+        let principals = self.sharing_principals.router();
+        principals.validate()?;
+        principals.validate_data_type::<proto_sharing::Principal<'static>>()?;
+        principals.validate_data_type::<proto_sharing::ShareNotification<'static>>()?;
+
+        let registry = ExtensionRouterRegistry { core, contacts };
+
+        for method in registry.methods() {
+            debug!(method, "Registered method");
         }
+
+        Ok(registry)
     }
 }
 
 /// A list of key => value pairs representing the built parameters for the
 /// incoming request with all references to other requests resolved.
-pub struct ResolvedArguments<'a>(pub HashMap<Cow<'a, str>, Cow<'a, Value>>);
+pub struct ResolvedArguments<'a>(pub HashMap<Cow<'a, str>, ResolvedArgument<'a>>);
+
+/// A single argument of a call, once any reference to another call's
+/// response has been resolved.
+pub enum ResolvedArgument<'a> {
+    /// An argument given directly in the request, still held as the JSON it
+    /// was written as so it can be deserialized straight from the request
+    /// body.
+    Raw(Cow<'a, RawValue>),
+    /// An argument taken from another call's response, or rewritten by the
+    /// server.
+    Value(Cow<'a, Value>),
+}
 
 impl<'de> Deserializer<'de> for ResolvedArguments<'de> {
     type Error = serde_json::Error;
@@ -159,8 +1218,8 @@ impl<'de> Deserializer<'de> for ResolvedArguments<'de> {
 }
 
 struct ResolvedArgumentsVisitor<'de> {
-    iter: <HashMap<Cow<'de, str>, Cow<'de, Value>> as IntoIterator>::IntoIter,
-    value: Option<Cow<'de, Value>>,
+    iter: <HashMap<Cow<'de, str>, ResolvedArgument<'de>> as IntoIterator>::IntoIter,
+    value: Option<ResolvedArgument<'de>>,
 }
 
 impl<'de> MapAccess<'de> for ResolvedArgumentsVisitor<'de> {
@@ -189,8 +1248,406 @@ impl<'de> MapAccess<'de> for ResolvedArgumentsVisitor<'de> {
             .ok_or(serde::de::Error::custom("value is missing"))?;
 
         match value {
-            Cow::Owned(v) => seed.deserialize(v),
-            Cow::Borrowed(v) => seed.deserialize(v),
+            ResolvedArgument::Raw(Cow::Borrowed(v)) => {
+                seed.deserialize(&mut serde_json::Deserializer::from_str(v.get()))
+            }
+            // nothing can borrow from an owned value past this call, so it's
+            // parsed up front instead
+            ResolvedArgument::Raw(Cow::Owned(v)) => {
+                seed.deserialize(serde_json::from_str::<Value>(v.get())?)
+            }
+            ResolvedArgument::Value(Cow::Owned(v)) => seed.deserialize(v),
+            ResolvedArgument::Value(Cow::Borrowed(v)) => seed.deserialize(v),
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        extensions::contacts::{AddressBook, Contacts},
+        store::{AccountAccessLevel, User, UserProvider},
+    };
+
+    /// A user along with the id of their personal account.
+    pub(crate) async fn user(context: &Context, username: &str) -> (Uuid, Uuid) {
+        let user = User::new(username.to_string(), "password", &context.argon2);
+        context.store.create_user(user).await.unwrap()
+    }
+
+    /// Calls the endpoint as the user, returning its response as JSON.
+    pub(crate) async fn call<Ext: JmapExtension, E: JmapEndpoint<Ext>>(
+        context: &Context,
+        user_id: Uuid,
+        extension: &Ext,
+        endpoint: E,
+        arguments: &str,
+    ) -> Result<Value, MethodError> {
+        let call = MethodCall {
+            context,
+            user_id,
+            store_unavailable: AtomicBool::new(false),
+        };
+
+        let response = endpoint
+            .handle(extension, &call, serde_json::from_str(arguments).unwrap())
+            .await?;
+
+        Ok(serde_json::to_value(response).unwrap())
+    }
+
+    async fn set(context: &Context, user_id: Uuid, arguments: &str) -> Result<Value, MethodError> {
+        call(
+            context,
+            user_id,
+            &Contacts {},
+            Set::<AddressBook>::default(),
+            arguments,
+        )
+        .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dry_run_validates_in_full_without_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let arguments = |dry_run: bool| {
+            format!(
+                r#"{{
+                    "accountId": "{account_id}",
+                    "create": {{
+                        "valid": {{"name": "Friends"}},
+                        "invalid": {{"name": "", "owner": "{user_id}"}}
+                    }},
+                    "urn:jogre:dryRun": {dry_run}
+                }}"#
+            )
+        };
+
+        let dry_run = set(&context, user_id, &arguments(true)).await.unwrap();
+
+        assert_eq!(dry_run["urn:jogre:dryRun"], true);
+        assert_eq!(dry_run["newState"], dry_run["oldState"]);
+        assert!(dry_run["created"]["valid"]["id"].is_string());
+        assert_eq!(dry_run["created"]["valid"]["owner"], user_id.to_string());
+        assert!(context
+            .store
+            .list_objects::<AddressBook>(account_id, "AddressBook")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            context
+                .store
+                .state_for(account_id, "AddressBook")
+                .await
+                .unwrap(),
+            encode_object_state(0)
+        );
+
+        let real = set(&context, user_id, &arguments(false)).await.unwrap();
+
+        assert!(real.get("urn:jogre:dryRun").is_none());
+        assert_ne!(real["newState"], real["oldState"]);
+        assert_eq!(real["notCreated"], dry_run["notCreated"]);
+        assert_eq!(
+            context
+                .store
+                .list_objects::<AddressBook>(account_id, "AddressBook")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dry_run_still_checks_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let error = set(
+            &context,
+            user_id,
+            &format!(
+                r#"{{
+                    "accountId": "{account_id}",
+                    "ifInState": "stale",
+                    "destroy": [],
+                    "urn:jogre:dryRun": true
+                }}"#
+            ),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, MethodError::StateMismatch));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn destroying_twice_reports_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let created = set(
+            &context,
+            user_id,
+            &format!(
+                r#"{{"accountId": "{account_id}", "create": {{"a": {{"name": "Friends"}}}}}}"#
+            ),
+        )
+        .await
+        .unwrap();
+        let id = created["created"]["a"]["id"].as_str().unwrap();
+
+        let destroy = format!(r#"{{"accountId": "{account_id}", "destroy": ["{id}", "{id}"]}}"#);
+
+        let first = set(&context, user_id, &destroy).await.unwrap();
+        assert_eq!(first["destroyed"], serde_json::json!([id]));
+        assert!(first["notDestroyed"].get(id).is_none());
+
+        // a retry of the whole call still succeeds, reporting the book gone
+        let retry = set(&context, user_id, &destroy).await.unwrap();
+        assert_eq!(retry["destroyed"], serde_json::json!([]));
+        assert_eq!(retry["notDestroyed"][id]["type"], "notFound");
+        assert_eq!(retry["newState"], first["newState"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stale_state_fails_the_whole_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let created = set(
+            &context,
+            user_id,
+            &format!(
+                r#"{{"accountId": "{account_id}", "create": {{"a": {{"name": "Friends"}}}}}}"#
+            ),
+        )
+        .await
+        .unwrap();
+        let id = created["created"]["a"]["id"].as_str().unwrap();
+        let stale = created["oldState"].as_str().unwrap();
+
+        let error = set(
+            &context,
+            user_id,
+            &format!(
+                r#"{{
+                    "accountId": "{account_id}",
+                    "ifInState": "{stale}",
+                    "create": {{"b": {{"name": "Family"}}}},
+                    "destroy": ["{id}"]
+                }}"#
+            ),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, MethodError::StateMismatch));
+        assert_eq!(
+            context
+                .store
+                .list_objects::<AddressBook>(account_id, "AddressBook")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn readers_may_not_change_a_shared_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (alice, account_id) = user(&context, "alice").await;
+        let (reader, _) = user(&context, "bob").await;
+        let (stranger, _) = user(&context, "carol").await;
+
+        context
+            .store
+            .set_account_access(account_id, reader, AccountAccessLevel::Reader)
+            .await
+            .unwrap();
+
+        let created = set(
+            &context,
+            alice,
+            &format!(
+                r#"{{"accountId": "{account_id}", "create": {{"a": {{"name": "Friends"}}}}}}"#
+            ),
+        )
+        .await
+        .unwrap();
+        let id = created["created"]["a"]["id"].as_str().unwrap();
+
+        let mutation = format!(
+            r#"{{
+                "accountId": "{account_id}",
+                "create": {{"b": {{"name": "Family"}}}},
+                "destroy": ["{id}"]
+            }}"#
+        );
+
+        assert!(matches!(
+            set(&context, reader, &mutation).await,
+            Err(MethodError::AccountReadOnly)
+        ));
+        assert!(matches!(
+            set(&context, stranger, &mutation).await,
+            Err(MethodError::AccountNotFound)
+        ));
+
+        let response = call(
+            &context,
+            alice,
+            &Contacts {},
+            Get::<AddressBook>::default(),
+            &format!(r#"{{"accountId": "{account_id}"}}"#),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response["list"].as_array().unwrap().len(), 1);
+        assert_eq!(response["list"][0]["id"], id);
+    }
+
+    async fn changes(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+        since_state: &str,
+        max_changes: Option<u64>,
+    ) -> Result<Value, MethodError> {
+        call(
+            context,
+            user_id,
+            &Contacts {},
+            Changes::<AddressBook>::default(),
+            &serde_json::json!({
+                "accountId": account_id,
+                "sinceState": since_state,
+                "maxChanges": max_changes,
+            })
+            .to_string(),
+        )
+        .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn changes_are_paged_by_max_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let mut state = String::new();
+
+        for name in ["Friends", "Family", "Work"] {
+            let created = set(
+                &context,
+                user_id,
+                &serde_json::json!({"accountId": account_id, "create": {"a": {"name": name}}})
+                    .to_string(),
+            )
+            .await
+            .unwrap();
+
+            state = created["newState"].as_str().unwrap().to_string();
+        }
+
+        let first = changes(&context, user_id, account_id, "0", Some(2))
+            .await
+            .unwrap();
+        assert_eq!(first["created"].as_array().unwrap().len(), 2);
+        assert_eq!(first["hasMoreChanges"], true);
+
+        let since = first["newState"].as_str().unwrap();
+        let rest = changes(&context, user_id, account_id, since, Some(2))
+            .await
+            .unwrap();
+        assert_eq!(rest["created"].as_array().unwrap().len(), 1);
+        assert_eq!(rest["hasMoreChanges"], false);
+        assert_eq!(rest["newState"], state);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn changes_from_a_state_never_handed_out_cannot_be_calculated() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        for since in ["not a state", "00", "ff"] {
+            assert!(matches!(
+                changes(&context, user_id, account_id, since, None).await,
+                Err(MethodError::CannotCalculateChanges)
+            ));
+        }
+
+        assert!(matches!(
+            changes(&context, user_id, account_id, "0", Some(0)).await,
+            Err(MethodError::InvalidArguments)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn objects_no_longer_visible_are_reported_destroyed() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (alice, account_id) = user(&context, "alice").await;
+        let (bob, _) = user(&context, "bob").await;
+
+        context
+            .store
+            .set_account_access(account_id, bob, AccountAccessLevel::Reader)
+            .await
+            .unwrap();
+
+        let rights = serde_json::json!({
+            "mayRead": true,
+            "mayWrite": false,
+            "mayAdmin": false,
+            "mayDelete": false,
+        });
+        let created = set(
+            &context,
+            alice,
+            &serde_json::json!({
+                "accountId": account_id,
+                "create": {"a": {"name": "Friends", "shareWith": {bob.to_string(): rights}}}
+            })
+            .to_string(),
+        )
+        .await
+        .unwrap();
+        let id = created["created"]["a"]["id"].clone();
+        let since = created["newState"].as_str().unwrap();
+
+        set(
+            &context,
+            alice,
+            &serde_json::json!({
+                "accountId": account_id,
+                "create": {"b": {"name": "Private"}},
+                "update": {id.as_str().unwrap(): {"shareWith": null}}
+            })
+            .to_string(),
+        )
+        .await
+        .unwrap();
+
+        let response = changes(&context, bob, account_id, since, None)
+            .await
+            .unwrap();
+
+        assert_eq!(response["created"], serde_json::json!([]));
+        assert_eq!(response["updated"], serde_json::json!([]));
+        assert_eq!(response["destroyed"], serde_json::json!([id]));
+    }
+}
@@ -1,50 +1,172 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
 
+use axum::async_trait;
+use jmap_proto::{
+    endpoints::{Argument, Arguments},
+    errors::MethodError,
+};
 use serde::Deserialize;
-use serde_json::{value::RawValue, Value};
+use serde_json::value::RawValue;
+use tracing::debug;
 
-use crate::extensions::{JmapEndpoint, JmapExtension, ResolvedArguments};
+use crate::extensions::{
+    DataType, IdArguments, JmapDataExtension, JmapEndpoint, JmapExtension, MethodCall,
+    ResolvedArguments,
+};
+
+/// Why the methods of the registered extensions couldn't be routed.
+#[derive(Debug)]
+pub enum RouterError {
+    /// More than one endpoint was registered for the method.
+    DuplicateMethod(String),
+    /// A data type claims to support a method that no endpoint was
+    /// registered for.
+    MissingMethod(String),
+}
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DuplicateMethod(method) => {
+                write!(f, "more than one endpoint registered for {method}")
+            }
+            Self::MissingMethod(method) => write!(f, "no endpoint registered for {method}"),
+        }
+    }
+}
+
+impl std::error::Error for RouterError {}
 
 pub struct ExtensionRouter<Ext: JmapExtension> {
-    routes: HashMap<&'static str, Box<dyn ErasedJmapEndpoint<Ext> + Send + Sync>>,
+    routes: BTreeMap<String, Box<dyn ErasedJmapEndpoint<Ext> + Send + Sync>>,
+    /// Methods registered more than once, reported by [`Self::validate`]
+    /// rather than by `register` so routers can be built up by chaining.
+    duplicates: Vec<String>,
 }
 
 impl<Ext: JmapExtension> ExtensionRouter<Ext> {
     pub fn register<E: JmapEndpoint<Ext> + Send + Sync + 'static>(mut self, endpoint: E) -> Self {
-        self.routes.insert(E::ENDPOINT, Box::new(endpoint));
+        let method = format!("{}/{}", E::NAMESPACE, E::ENDPOINT);
+
+        if self.routes.contains_key(&method) {
+            self.duplicates.push(method);
+        } else {
+            self.routes.insert(method, Box::new(endpoint));
+        }
+
         self
     }
 
-    pub fn handle(
+    /// Checks that no method was registered more than once.
+    pub fn validate(&self) -> Result<(), RouterError> {
+        match self.duplicates.first() {
+            Some(method) => Err(RouterError::DuplicateMethod(method.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that every method the data type claims to support has an
+    /// endpoint registered for it.
+    pub fn validate_data_type<D: DataType>(&self) -> Result<(), RouterError>
+    where
+        Ext: JmapDataExtension<D>,
+    {
+        for verb in <Ext as JmapDataExtension<D>>::METHODS {
+            let method = format!("{}/{verb}", <Ext as JmapDataExtension<D>>::ENDPOINT);
+
+            if !self.routes.contains_key(&method) {
+                return Err(RouterError::MissingMethod(method));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The full names of every method registered, in order.
+    pub fn methods(&self) -> impl Iterator<Item = &str> {
+        self.routes.keys().map(String::as_str)
+    }
+
+    /// Whether an endpoint is registered for the method.
+    pub fn contains(&self, method: &str) -> bool {
+        self.routes.contains_key(method)
+    }
+
+    /// Where the arguments of the method hold ids.
+    pub fn id_arguments(&self, method: &str) -> Option<IdArguments> {
+        Some(self.routes.get(method)?.id_arguments())
+    }
+
+    pub async fn handle(
         &self,
         extension: &Ext,
         method: &str,
+        call: &MethodCall<'_>,
         params: ResolvedArguments<'_>,
-    ) -> Option<HashMap<String, Value>> {
-        Some(self.routes.get(method)?.handle(extension, params))
+    ) -> Option<Result<Arguments<'static>, MethodError>> {
+        Some(
+            self.routes
+                .get(method)?
+                .handle(extension, call, params)
+                .await,
+        )
     }
 }
 
 impl<Ext: JmapExtension> Default for ExtensionRouter<Ext> {
     fn default() -> Self {
         Self {
-            routes: HashMap::new(),
+            routes: BTreeMap::new(),
+            duplicates: Vec::new(),
         }
     }
 }
 
+#[async_trait]
 trait ErasedJmapEndpoint<Ext> {
-    fn handle(&self, endpoint: &Ext, params: ResolvedArguments<'_>) -> HashMap<String, Value>;
+    fn id_arguments(&self) -> IdArguments;
+
+    async fn handle(
+        &self,
+        endpoint: &Ext,
+        call: &MethodCall<'_>,
+        params: ResolvedArguments<'_>,
+    ) -> Result<Arguments<'static>, MethodError>;
 }
 
-impl<Ext: JmapExtension, E: JmapEndpoint<Ext>> ErasedJmapEndpoint<Ext> for E {
-    fn handle(&self, endpoint: &Ext, params: ResolvedArguments<'_>) -> HashMap<String, Value> {
-        let res = <Self as JmapEndpoint<Ext>>::handle(
-            self,
-            endpoint,
-            Deserialize::deserialize(params).unwrap(),
-        );
+#[async_trait]
+impl<Ext: JmapExtension, E: JmapEndpoint<Ext> + Sync> ErasedJmapEndpoint<Ext> for E {
+    fn id_arguments(&self) -> IdArguments {
+        E::ID_ARGUMENTS
+    }
+
+    async fn handle(
+        &self,
+        endpoint: &Ext,
+        call: &MethodCall<'_>,
+        params: ResolvedArguments<'_>,
+    ) -> Result<Arguments<'static>, MethodError> {
+        let params = Deserialize::deserialize(params).map_err(|error| {
+            debug!(%error, "Method called with invalid arguments");
+            MethodError::InvalidArguments
+        })?;
+
+        let res = <Self as JmapEndpoint<Ext>>::handle(self, endpoint, call, params).await?;
+
+        // serialised once, then split into its top-level arguments without
+        // parsing any of their values
+        let res = serde_json::value::to_raw_value(&res).unwrap();
+        let arguments: HashMap<String, &RawValue> = serde_json::from_str(res.get()).unwrap();
 
-        serde_json::from_value(serde_json::to_value(res).unwrap()).unwrap()
+        Ok(Arguments(
+            arguments
+                .into_iter()
+                .map(|(k, v)| (Cow::Owned(k), Argument::Raw(Cow::Owned(v.to_owned()))))
+                .collect(),
+        ))
     }
 }
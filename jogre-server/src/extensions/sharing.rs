@@ -2,14 +2,17 @@ use jmap_proto::{
     common::Id,
     extensions::sharing::{
         Principal, PrincipalsAccountCapabilities, PrincipalsOwnerAccountCapabilities,
-        PrincipalsSessionCapabilities, ShareNotification,
+        PrincipalsPrincipalCapabilities, PrincipalsSessionCapabilities, ShareNotification,
     },
 };
 use uuid::Uuid;
 
-use crate::extensions::{
-    router::ExtensionRouter, Get, JmapAccountCapabilityExtension, JmapDataExtension, JmapExtension,
-    JmapSessionCapabilityExtension,
+use crate::{
+    extensions::{
+        DataType, JmapAccountCapabilityExtension, JmapDataExtension, JmapExtension,
+        JmapPrincipalCapabilityExtension, JmapSessionCapabilityExtension,
+    },
+    store::Account,
 };
 
 /// Represents support for the `Principal` and `ShareNotification` data types and associated API
@@ -18,12 +21,6 @@ pub struct Principals {}
 
 impl JmapExtension for Principals {
     const EXTENSION: &'static str = "urn:ietf:params:jmap:principals";
-
-    fn router(&self) -> ExtensionRouter<Self> {
-        ExtensionRouter::default()
-            .register(Get::<Principal<'static>>::default())
-            .register(Get::<ShareNotification<'static>>::default())
-    }
 }
 
 impl JmapSessionCapabilityExtension for Principals {
@@ -37,19 +34,37 @@ impl JmapSessionCapabilityExtension for Principals {
 impl JmapAccountCapabilityExtension for Principals {
     type Metadata = PrincipalsAccountCapabilities<'static>;
 
-    fn build(&self, _user: Uuid, _account: Uuid) -> Self::Metadata {
-        PrincipalsAccountCapabilities {
+    fn build(&self, _user: Uuid, _account: &Account) -> Option<Self::Metadata> {
+        Some(PrincipalsAccountCapabilities {
             current_user_principal_id: None,
+        })
+    }
+}
+
+impl JmapPrincipalCapabilityExtension for Principals {
+    type Metadata = PrincipalsPrincipalCapabilities;
+
+    fn build(&self, principal: &Principal<'_>) -> Self::Metadata {
+        PrincipalsPrincipalCapabilities {
+            type_: principal.type_,
         }
     }
 }
 
+impl DataType for Principal<'static> {}
+
+impl DataType for ShareNotification<'static> {}
+
 impl JmapDataExtension<Principal<'static>> for Principals {
     const ENDPOINT: &'static str = "Principal";
+    // principals aren't stored yet, so none of their methods are served
+    const METHODS: &'static [&'static str] = &[];
 }
 
 impl JmapDataExtension<ShareNotification<'static>> for Principals {
     const ENDPOINT: &'static str = "ShareNotification";
+    // notifications aren't stored yet, so none of their methods are served
+    const METHODS: &'static [&'static str] = &[];
 }
 
 /// This URI is solely used as a key in an account’s accountCapabilities property;
@@ -64,10 +79,18 @@ impl JmapExtension for PrincipalsOwner {
 impl JmapAccountCapabilityExtension for PrincipalsOwner {
     type Metadata = PrincipalsOwnerAccountCapabilities<'static>;
 
-    fn build(&self, _user: Uuid, _account: Uuid) -> Self::Metadata {
-        PrincipalsOwnerAccountCapabilities {
-            account_id_for_principal: Id("test".into()),
-            principal_id: Id("test".into()),
+    /// Shared accounts are owned by a principal of the same id, held within
+    /// the account itself. Personal accounts aren't owned by a principal.
+    fn build(&self, _user: Uuid, account: &Account) -> Option<Self::Metadata> {
+        if account.is_personal {
+            return None;
         }
+
+        let id = Id(account.id.to_string().into());
+
+        Some(PrincipalsOwnerAccountCapabilities {
+            account_id_for_principal: id.clone(),
+            principal_id: id,
+        })
     }
 }
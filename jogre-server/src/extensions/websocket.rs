@@ -0,0 +1,38 @@
+use jmap_proto::websocket::WebSocketCapabilities;
+use url::Url;
+use uuid::Uuid;
+
+use crate::extensions::{JmapExtension, JmapSessionCapabilityExtension};
+
+/// Represents support for JMAP over WebSocket (RFC 8887).
+pub struct WebSocket {
+    /// The `ws(s)://` URL clients open the WebSocket at.
+    url: Box<str>,
+}
+
+impl WebSocket {
+    /// Advertises the WebSocket served at the given `http(s)://` URL.
+    pub fn new(mut url: Url) -> Self {
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme).unwrap();
+
+        Self {
+            url: url.to_string().into_boxed_str(),
+        }
+    }
+}
+
+impl JmapExtension for WebSocket {
+    const EXTENSION: &'static str = "urn:ietf:params:jmap:websocket";
+}
+
+impl JmapSessionCapabilityExtension for WebSocket {
+    type Metadata = WebSocketCapabilities<'static>;
+
+    fn build(&self, _user: Uuid) -> Self::Metadata {
+        WebSocketCapabilities {
+            url: self.url.to_string().into(),
+            supports_push: true,
+        }
+    }
+}
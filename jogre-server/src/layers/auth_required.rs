@@ -2,14 +2,15 @@ use std::sync::Arc;
 
 use axum::{
     extract::State,
-    http::Request,
+    http::{Request, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
     RequestExt,
 };
-use oxide_auth::frontends::simple::endpoint;
+use oxide_auth::{frontends::simple::endpoint, primitives::grant::Grant};
 use oxide_auth_axum::{OAuthResource, WebError};
 use tracing::{debug, error};
+use uuid::Uuid;
 
 use crate::context::Context;
 
@@ -34,9 +35,44 @@ pub async fn auth_required_middleware<B: Send + 'static>(
         }
     };
 
+    // grants issued before they were tied to user ids are owned by a
+    // username, and need to be replaced by logging in again
+    if Uuid::parse_str(&grant.owner_id).is_err() {
+        error!("Rejecting request due to it being granted to a username");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
     debug!(?grant, "Request authorized");
 
     request.extensions_mut().insert(grant);
 
     next.run(request).await
 }
+
+/// The id of the user the request was authorized for.
+pub fn user_id(grant: &Grant) -> Uuid {
+    Uuid::parse_str(&grant.owner_id).expect("grants are checked to be owned by a user id")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::str::FromStr;
+
+    use chrono::Utc;
+    use oxide_auth::primitives::{grant::Extensions, scope::Scope};
+
+    use super::*;
+
+    /// A grant authorizing requests as the user, as the middleware would
+    /// hand to the handlers behind it.
+    pub(crate) fn grant(user_id: Uuid) -> Grant {
+        Grant {
+            owner_id: user_id.to_string(),
+            client_id: "client".to_string(),
+            scope: Scope::from_str("test").unwrap(),
+            redirect_uri: "https://client.example/callback".parse().unwrap(),
+            until: Utc::now() + chrono::Duration::minutes(10),
+            extensions: Extensions::new(),
+        }
+    }
+}
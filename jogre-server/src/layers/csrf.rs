@@ -0,0 +1,241 @@
+//! Protects authenticated endpoints from cross-site request forgery when
+//! they're reached using a browser's cookies rather than a bearer token.
+//!
+//! Browsers attach cookies to requests made by any site, so a request
+//! authenticated by them must come from a page served from `base_url` and
+//! carry the `X-Jogre-CSRF` header, which other sites can neither read nor
+//! set without a CORS preflight. Requests with an `Authorization` header
+//! are left alone, as browsers never attach one on another site's behalf.
+//!
+//! Forms such as the OAuth login page can't set headers, and carry the token
+//! as a form field checked by their handler instead, so only have their
+//! origin checked here.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tower_cookies::Cookies;
+use tracing::warn;
+use url::Url;
+
+use crate::{context::Context, util::CsrfToken};
+
+/// Header a page sends the CSRF token it was given back in.
+pub const CSRF_HEADER: &str = "x-jogre-csrf";
+
+pub async fn csrf_middleware<B: Send + 'static>(
+    State(context): State<Arc<Context>>,
+    cookies: Cookies,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let headers = request.headers();
+
+    if is_exempt(&request) {
+        return next.run(request).await;
+    }
+
+    if !is_same_origin(headers, &context.base_url) {
+        warn!("Rejecting cookie authenticated request from another origin");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let token = headers
+        .get(CSRF_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    if !CsrfToken::verify(&context.oauth2.derived_keys, &cookies, token) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Rejects cookie-bound form submissions made from other sites, leaving
+/// the form's handler to check its CSRF token field.
+pub async fn same_origin_middleware<B: Send + 'static>(
+    State(context): State<Arc<Context>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !is_exempt(&request) && !is_same_origin(request.headers(), &context.base_url) {
+        warn!("Rejecting cookie bound form submission from another origin");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Whether the request can't have been forged by another site, as it's a
+/// read, is authorized by a header or carries no cookies.
+fn is_exempt<B>(request: &Request<B>) -> bool {
+    let headers = request.headers();
+
+    // reads can't change anything, and same-origin GETs carry no Origin
+    matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) || headers.contains_key(header::AUTHORIZATION)
+        || !headers.contains_key(header::COOKIE)
+}
+
+/// Whether the request was made by a page served from `base_url`, going by
+/// its `Origin`, or its `Referer` for browsers that don't always send one.
+fn is_same_origin(headers: &HeaderMap, base_url: &Url) -> bool {
+    let source = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| Url::parse(v).ok());
+
+    source.is_some_and(|source| source.origin() == base_url.origin())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use axum::{body::Body, routing::post, Router};
+    use tower::ServiceExt;
+    use tower_cookies::CookieManagerLayer;
+
+    use super::*;
+
+    /// Which of the middlewares a request goes through.
+    #[derive(Copy, Clone)]
+    enum Guard {
+        Api,
+        Form,
+    }
+
+    /// Sends a POST with the given headers through the middleware to a
+    /// route that accepts anything.
+    async fn post_with(
+        context: &Arc<Context>,
+        guard: Guard,
+        headers: &[(&str, &str)],
+    ) -> StatusCode {
+        let router = Router::new().route("/", post(|| async { StatusCode::OK }));
+        let router = match guard {
+            Guard::Api => router.layer(axum::middleware::from_fn_with_state(
+                context.clone(),
+                csrf_middleware,
+            )),
+            Guard::Form => router.layer(axum::middleware::from_fn_with_state(
+                context.clone(),
+                same_origin_middleware,
+            )),
+        };
+
+        let mut request = Request::post("/");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        router
+            .layer(CookieManagerLayer::new())
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    /// A token as a page would be handed it, and the cookie holding its
+    /// signed half.
+    pub(crate) fn token(context: &Context) -> (String, String) {
+        let token = CsrfToken::new(&context.oauth2.derived_keys);
+        let cookies = Cookies::default();
+        token.write_cookie(&cookies, false);
+
+        let cookie = cookies.get("csrf_token").unwrap();
+        (token.form_value(), format!("csrf_token={}", cookie.value()))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cookie_requests_need_same_origin_and_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+        let (value, cookie) = token(&context);
+        let same_origin = context.base_url.origin().ascii_serialization();
+
+        let status = |headers: Vec<(&'static str, String)>| {
+            let context = context.clone();
+            async move {
+                let headers: Vec<_> = headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+                post_with(&context, Guard::Api, &headers).await
+            }
+        };
+
+        assert_eq!(
+            status(vec![
+                ("cookie", cookie.clone()),
+                ("origin", "https://evil.example".to_string()),
+                (CSRF_HEADER, value.clone()),
+            ])
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(vec![
+                ("cookie", cookie.clone()),
+                ("origin", same_origin.clone())
+            ])
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(vec![
+                ("cookie", cookie.clone()),
+                ("origin", same_origin),
+                (CSRF_HEADER, value),
+            ])
+            .await,
+            StatusCode::OK
+        );
+
+        // bearer tokens can't be attached by another site
+        assert_eq!(
+            status(vec![
+                ("cookie", cookie),
+                ("origin", "https://evil.example".to_string()),
+                ("authorization", "Bearer token".to_string()),
+            ])
+            .await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn forms_are_only_accepted_from_same_origin() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+        let same_origin = context.base_url.origin().ascii_serialization();
+        let cookie = "csrf_token=00";
+
+        assert_eq!(
+            post_with(
+                &context,
+                Guard::Form,
+                &[("cookie", cookie), ("origin", "https://evil.example")]
+            )
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            post_with(
+                &context,
+                Guard::Form,
+                &[
+                    ("cookie", cookie),
+                    ("referer", &format!("{same_origin}/oauth/authorize"))
+                ]
+            )
+            .await,
+            StatusCode::OK
+        );
+    }
+}
@@ -1,2 +1,4 @@
 pub mod auth_required;
+pub mod csrf;
 pub mod logger;
+pub mod read_only;
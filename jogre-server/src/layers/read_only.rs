@@ -0,0 +1,43 @@
+//! Rejects requests that would write to the store while running as a read
+//! replica, pointing the client at the primary instead.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::context::Context;
+
+/// Header containing the URL of the primary when a write is rejected.
+pub const PRIMARY_URL_HEADER: &str = "jogre-primary-url";
+
+pub async fn read_only_middleware<B: Send + 'static>(
+    State(context): State<Arc<Context>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if context.store.is_read_only() && !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return read_only_response(&context);
+    }
+
+    next.run(request).await
+}
+
+/// Builds a `503 Service Unavailable` response for a write against a read
+/// replica.
+pub fn read_only_response(context: &Context) -> Response {
+    let mut response = StatusCode::SERVICE_UNAVAILABLE.into_response();
+
+    if let Some(primary_url) = &context.primary_url {
+        response.headers_mut().insert(
+            PRIMARY_URL_HEADER,
+            HeaderValue::from_str(primary_url.as_str()).unwrap(),
+        );
+    }
+
+    response
+}
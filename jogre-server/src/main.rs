@@ -6,53 +6,138 @@ mod context;
 mod extensions;
 mod layers;
 mod methods;
+mod pagination;
+mod reload;
 mod store;
 mod util;
 
-use std::{path::PathBuf, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
 use clap::Parser;
+use metrics_exporter_prometheus::PrometheusBuilder;
 use rand::RngCore;
-use tracing::info;
+use tracing::{info, warn};
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
+use uuid::Uuid;
 
 use crate::{
-    context::Context,
+    config::Config,
+    context::{events::EventBus, Context},
+    reload::{LogFilterHandle, Reloader},
     store::{AccountAccessLevel, AccountProvider, UserProvider},
 };
 
+/// Name of the account created to hold principals when none is configured.
+const PRINCIPALS_ACCOUNT: &str = "principals";
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 pub struct Args {
     /// Path to the config file (eg. config.toml)
     #[clap(long, short)]
     config: PathBuf,
+    /// Check the store for dangling references and malformed values, then
+    /// exit rather than starting the server
+    #[clap(long)]
+    fsck: bool,
+    /// Remove the dangling index entries found by `--fsck`
+    #[clap(long, requires = "fsck")]
+    repair: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
-    let subscriber = tracing_subscriber::fmt();
-    #[cfg(debug_assertions)]
-    let subscriber = subscriber.pretty();
-    subscriber.init();
+    let raw_config: toml::Value = toml::from_str(&tokio::fs::read_to_string(&args.config).await?)?;
+    let config: Config = raw_config.clone().try_into()?;
+
+    let log_filter = init_logging(config.log_filter.parse()?);
+
+    if args.fsck {
+        return fsck(config, args.repair).await;
+    }
 
-    let config = toml::from_str(&tokio::fs::read_to_string(&args.config).await?)?;
+    let metrics = PrometheusBuilder::new().install_recorder()?;
 
-    let context = Arc::new(Context::new(config));
+    let listen = config.listen;
+    let principals_account = config.principals_account;
+    let context = Arc::new(Context::new(config, metrics)?);
 
-    create_root_if_none_exists(&context).await;
+    reload::spawn_sighup_handler(
+        Reloader::new(args.config, raw_config, log_filter),
+        context.clone(),
+    )?;
 
-    axum::Server::bind(&"0.0.0.0:8888".parse().unwrap())
-        .serve(methods::router(context).into_make_service())
+    create_root_if_none_exists(&context).await?;
+    designate_principals_account(&context, principals_account).await?;
+
+    axum::Server::bind(&listen)
+        .serve(methods::router(context).into_make_service_with_connect_info::<SocketAddr>())
         .await?;
 
     Ok(())
 }
 
-async fn create_root_if_none_exists(context: &Context) {
-    if context.store.has_any_users().await.unwrap() {
-        return;
+/// Checks the store for broken invariants, without starting anything else
+/// that might write to it.
+async fn fsck(config: Config, repair: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let store = store::Store::from_config(config.store, EventBus::new())
+        .map_err(|error| format!("failed to open store: {error}"))?;
+
+    let inconsistencies = store
+        .check_consistency(repair)
+        .await
+        .map_err(|error| format!("failed to check store: {error}"))?;
+
+    for inconsistency in &inconsistencies {
+        let action = match (repair, inconsistency.is_repairable()) {
+            (true, true) => "repaired",
+            (false, true) => "repairable with --repair",
+            (_, false) => "needs manual repair",
+        };
+
+        warn!(%inconsistency, action, "Found inconsistency");
+    }
+
+    let remaining = inconsistencies
+        .iter()
+        .filter(|v| !repair || !v.is_repairable())
+        .count();
+
+    info!(
+        found = inconsistencies.len(),
+        repaired = inconsistencies.len() - remaining,
+        remaining,
+        "Checked store"
+    );
+
+    // exits non-zero, so scripts running the check can tell
+    if remaining > 0 {
+        return Err(format!("store has {remaining} unresolved inconsistencies").into());
+    }
+
+    Ok(())
+}
+
+/// Installs the global subscriber, returning a handle through which its
+/// filter can be swapped when the config is reloaded.
+fn init_logging(filter: Targets) -> LogFilterHandle {
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(filter);
+
+    let fmt = tracing_subscriber::fmt::layer();
+    #[cfg(debug_assertions)]
+    let fmt = fmt.pretty();
+
+    tracing_subscriber::registry().with(filter).with(fmt).init();
+
+    handle
+}
+
+async fn create_root_if_none_exists(context: &Context) -> Result<(), Box<dyn std::error::Error>> {
+    // read replicas can't write, the primary will create the user for them
+    if context.store.is_read_only() || context.store.has_any_users().await? {
+        return Ok(());
     }
 
     let mut password = [0_u8; 32];
@@ -61,17 +146,48 @@ async fn create_root_if_none_exists(context: &Context) {
 
     info!("User root created with password {password}");
 
-    let root_user = store::User::new("root".into(), &password);
-    let root_user_id = root_user.id;
-    context.store.create_user(root_user).await.unwrap();
+    let root_user = store::User::new("root".into(), &password, &context.argon2);
+    context.store.create_user(root_user).await?;
 
-    let root_account = store::Account::new("root".into(), true, false);
-    let root_account_id = root_account.id;
-    context.store.create_account(root_account).await.unwrap();
+    Ok(())
+}
 
-    context
-        .store
-        .attach_account_to_user(root_account_id, root_user_id, AccountAccessLevel::Owner)
-        .await
-        .unwrap();
+/// Designates the account `Principal` and `ShareNotification` objects are kept
+/// in, creating a dedicated one if none is configured or designated already,
+/// and gives every user read access to it.
+async fn designate_principals_account(
+    context: &Context,
+    configured: Option<Uuid>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // read replicas can't write, the primary will designate it for them
+    if context.store.is_read_only() {
+        return Ok(());
+    }
+
+    let designated = context.store.get_principals_account().await?;
+
+    let account = if let Some(account) = configured.or(designated) {
+        account
+    } else {
+        let account = store::Account::new(PRINCIPALS_ACCOUNT.into(), false, false);
+        let account_id = account.id;
+        context.store.create_account(account).await?;
+
+        info!(%account_id, "Principals account created");
+
+        account_id
+    };
+
+    if designated != Some(account) {
+        context.store.set_principals_account(account).await?;
+    }
+
+    for user in context.store.get_user_ids().await? {
+        context
+            .store
+            .attach_account_to_user(account, user, AccountAccessLevel::Reader, false)
+            .await?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use oxide_auth::primitives::grant::Grant;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    context::Context,
+    layers::auth_required::user_id,
+    methods::store_failure,
+    store::{self, UserProvider},
+};
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    old_password: String,
+    new_password: String,
+}
+
+/// Changes the password of the user making the request, who has to prove
+/// they know the current one first.
+pub async fn change_password(
+    State(context): State<Arc<Context>>,
+    Extension(grant): Extension<Grant>,
+    Json(body): Json<ChangePassword>,
+) -> Result<StatusCode, Response> {
+    if body.new_password.is_empty() {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let Some(user) = context
+        .store
+        .get_by_id(user_id(&grant))
+        .await
+        .map_err(store_failure)?
+    else {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    };
+
+    // hashing is slow by design, so it's kept off the async workers
+    let argon2 = context.argon2.clone();
+    let user = tokio::task::spawn_blocking(move || {
+        if !user.verify_password(&body.old_password) {
+            return None;
+        }
+
+        let mut user = user;
+        user.set_password(&body.new_password, &argon2);
+        Some(user)
+    })
+    .await
+    .unwrap()
+    .ok_or_else(|| StatusCode::FORBIDDEN.into_response())?;
+
+    let id = user.id;
+
+    // the user may have been deleted while the password was being hashed
+    match context.store.update_user(user).await {
+        Ok(()) => {}
+        Err(store::Error::UserNotFound) => return Err(StatusCode::FORBIDDEN.into_response()),
+        Err(error) => return Err(store_failure(error)),
+    }
+
+    info!(user = %id, "Password changed");
+
+    Ok(StatusCode::NO_CONTENT)
+}
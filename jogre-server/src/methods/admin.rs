@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use oxide_auth::primitives::grant::Grant;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    context::Context,
+    layers::auth_required::user_id,
+    methods::store_failure,
+    pagination::{resolve_limit, Cursor},
+    store::{AccountAccessLevel, AccountProvider, UserProvider},
+};
+
+/// Name of the account created alongside the root user on first start.
+const ROOT_ACCOUNT: &str = "root";
+
+/// The most users listed on a single page.
+const MAX_USERS_PER_PAGE: u64 = 100;
+
+#[derive(Deserialize)]
+pub struct ListUsersQuery {
+    /// The cursor returned with the previous page, if any.
+    after: Option<String>,
+    limit: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListUsersResponse {
+    users: Vec<ListedUser>,
+    /// The cursor to fetch the next page with, if there is one.
+    next: Option<String>,
+    /// The limit applied, if it isn't the one asked for.
+    limit: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ListedUser {
+    id: Uuid,
+    username: String,
+}
+
+/// Lists users a page at a time, in order of their ids. Only the owner of
+/// the root account may list users.
+pub async fn list_users(
+    State(context): State<Arc<Context>>,
+    Extension(grant): Extension<Grant>,
+    Query(query): Query<ListUsersQuery>,
+) -> Result<Json<ListUsersResponse>, Response> {
+    if !owns_root_account(&context, user_id(&grant)).await? {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let after = match query.after.as_deref().map(Cursor::decode) {
+        Some(Some(cursor)) => Some(cursor.id()),
+        Some(None) => return Err(StatusCode::BAD_REQUEST.into_response()),
+        None => None,
+    };
+
+    let (limit, echoed) = resolve_limit(query.limit, Some(MAX_USERS_PER_PAGE));
+    let limit = usize::try_from(limit.unwrap_or(MAX_USERS_PER_PAGE)).unwrap_or(usize::MAX);
+
+    // one more than a page is fetched to tell whether there's another page
+    let mut users = context
+        .store
+        .list_users(after, limit.saturating_add(1))
+        .await
+        .map_err(store_failure)?;
+
+    let next = if users.len() > limit {
+        users.truncate(limit);
+        users.last().map(|user| Cursor::after(user.id).encode())
+    } else {
+        None
+    };
+
+    Ok(Json(ListUsersResponse {
+        users: users
+            .into_iter()
+            .map(|user| ListedUser {
+                id: user.id,
+                username: user.username,
+            })
+            .collect(),
+        next,
+        limit: echoed,
+    }))
+}
+
+/// Removes a user, along with their access to accounts and any tokens issued
+/// to them. Only the owner of the root account may remove users, and never
+/// themselves.
+pub async fn delete_user(
+    State(context): State<Arc<Context>>,
+    Extension(grant): Extension<Grant>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, Response> {
+    let admin = user_id(&grant);
+
+    if !owns_root_account(&context, admin).await? {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+
+    if id == admin {
+        return Err(StatusCode::CONFLICT.into_response());
+    }
+
+    if !context.store.delete_user(id).await.map_err(store_failure)? {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+
+    info!(%admin, user = %id, "User deleted");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Whether the user is the owner of the root account.
+async fn owns_root_account(context: &Context, user: Uuid) -> Result<bool, Response> {
+    let access_levels = context
+        .store
+        .get_access_levels_for_user(user)
+        .await
+        .map_err(store_failure)?;
+
+    Ok(context
+        .store
+        .get_accounts_for_user(user)
+        .await
+        .map_err(store_failure)?
+        .iter()
+        .any(|account| {
+            account.name == ROOT_ACCOUNT
+                && access_levels.get(&account.id) == Some(&AccountAccessLevel::Owner)
+        }))
+}
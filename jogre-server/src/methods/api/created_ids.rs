@@ -0,0 +1,217 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use jmap_proto::{
+    common::{CreationId, Id},
+    endpoints::{Argument, Arguments},
+    Value,
+};
+use serde_json::Map;
+
+use crate::extensions::{jogre::DRY_RUN, IdArguments, ResolvedArgument, ResolvedArguments};
+
+/// A map of client-specified creation ids to the ids the server assigned to
+/// the records once created, seeded from the `createdIds` of the request and
+/// extended as each `/set` call creates new records.
+pub struct CreatedIds<'a>(HashMap<CreationId<'a>, Id<'a>>);
+
+impl<'a> CreatedIds<'a> {
+    pub fn new(created_ids: Option<HashMap<CreationId<'a>, Id<'a>>>) -> Self {
+        Self(created_ids.unwrap_or_default())
+    }
+
+    /// Replaces the `#creationId` references in the arguments of a call with
+    /// the ids of the records they refer to.
+    ///
+    /// Only the arguments and properties typed as ids are resolved, any other
+    /// string is left as the client sent it. References to creation ids that
+    /// haven't been seen in this request are also left alone, for the method
+    /// to reject the record holding them.
+    pub fn resolve(&self, id_arguments: IdArguments, arguments: &mut ResolvedArguments<'_>) {
+        match id_arguments {
+            IdArguments::None => {}
+            IdArguments::Get => {
+                resolve_argument(arguments, "ids", |ids| self.resolve_ids(ids));
+            }
+            IdArguments::Set(properties) => {
+                resolve_argument(arguments, "create", |create| {
+                    if let Value::Object(create) = create {
+                        for object in create.values_mut() {
+                            self.resolve_object(object, properties);
+                        }
+                    }
+                });
+                resolve_argument(arguments, "update", |update| {
+                    if let Value::Object(update) = update {
+                        self.resolve_keys(update);
+
+                        for patch in update.values_mut() {
+                            self.resolve_patch(patch, properties);
+                        }
+                    }
+                });
+                resolve_argument(arguments, "destroy", |destroy| self.resolve_ids(destroy));
+            }
+            IdArguments::Copy(properties) => {
+                resolve_argument(arguments, "create", |create| {
+                    if let Value::Object(create) = create {
+                        for object in create.values_mut() {
+                            self.resolve_object(object, &["id"]);
+                            self.resolve_object(object, properties);
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    /// Picks out the records created by a `/set` call from the `created`
+    /// argument of its response, so they can be referenced by later calls.
+    /// The ids of a dry run were never created, so are left out.
+    pub fn extend_from_response(&mut self, arguments: &Arguments<'_>) {
+        let dry_run = match arguments.0.get(DRY_RUN) {
+            Some(Argument::Raw(raw)) => raw.get() == "true",
+            Some(Argument::Absolute(value)) => value.as_bool() == Some(true),
+            _ => false,
+        };
+
+        if dry_run {
+            return;
+        }
+
+        let created = match arguments.0.get("created") {
+            Some(Argument::Raw(raw)) => Cow::Owned(serde_json::from_str(raw.get()).unwrap()),
+            Some(Argument::Absolute(value)) => Cow::Borrowed(value),
+            _ => return,
+        };
+
+        let Value::Object(created) = &*created else {
+            return;
+        };
+
+        for (creation_id, record) in created {
+            if let Some(Value::String(id)) = record.get("id") {
+                self.0.insert(
+                    CreationId(Id(Cow::Owned(creation_id.clone()))),
+                    Id(Cow::Owned(id.clone())),
+                );
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> HashMap<CreationId<'a>, Id<'a>> {
+        self.0
+    }
+
+    fn resolve_id(&self, id: &mut String) {
+        if let Some(resolved) = CreationId::from_reference(id).and_then(|v| self.0.get(&v)) {
+            *id = resolved.0.to_string();
+        }
+    }
+
+    fn resolve_ids(&self, ids: &mut Value) {
+        if let Value::Array(ids) = ids {
+            for id in ids {
+                if let Value::String(id) = id {
+                    self.resolve_id(id);
+                }
+            }
+        }
+    }
+
+    fn resolve_keys(&self, map: &mut Map<String, Value>) {
+        *map = std::mem::take(map)
+            .into_iter()
+            .map(|(mut id, value)| {
+                self.resolve_id(&mut id);
+                (id, value)
+            })
+            .collect();
+    }
+
+    /// Resolves an id-valued property, which holds either a single id, a
+    /// list of them, or a map keyed by them.
+    fn resolve_property(&self, value: &mut Value) {
+        match value {
+            Value::String(id) => self.resolve_id(id),
+            Value::Array(_) => self.resolve_ids(value),
+            Value::Object(map) => self.resolve_keys(map),
+            _ => {}
+        }
+    }
+
+    fn resolve_object(&self, object: &mut Value, properties: &[&str]) {
+        let Value::Object(object) = object else {
+            return;
+        };
+
+        for property in properties {
+            if let Some(value) = object.get_mut(*property) {
+                self.resolve_property(value);
+            }
+        }
+    }
+
+    /// Resolves the id-valued properties a patch sets, whether it replaces
+    /// the whole property or points into a map keyed by ids (ie.
+    /// `addressBookIds/#a`).
+    fn resolve_patch(&self, patch: &mut Value, properties: &[&str]) {
+        let Value::Object(patch) = patch else {
+            return;
+        };
+
+        *patch = std::mem::take(patch)
+            .into_iter()
+            .map(|(pointer, mut value)| {
+                let (property, rest) = pointer.split_once('/').unwrap_or((&pointer, ""));
+
+                if !properties.contains(&property) {
+                    return (pointer, value);
+                }
+
+                if pointer.len() == property.len() {
+                    self.resolve_property(&mut value);
+                    return (pointer, value);
+                }
+
+                let (id, rest) = rest
+                    .split_once('/')
+                    .map_or((rest, None), |(id, rest)| (id, Some(rest)));
+                let mut id = id.to_string();
+                self.resolve_id(&mut id);
+
+                let resolved = match rest {
+                    Some(rest) => format!("{property}/{id}/{rest}"),
+                    None => format!("{property}/{id}"),
+                };
+
+                (resolved, value)
+            })
+            .collect();
+    }
+}
+
+/// Rewrites the named argument, if it was given. Arguments sent by the
+/// client are only parsed if they could contain a reference, the rest are
+/// left for the method to deserialize directly.
+fn resolve_argument(
+    arguments: &mut ResolvedArguments<'_>,
+    name: &str,
+    resolve: impl FnOnce(&mut Value),
+) {
+    let Some(argument) = arguments.0.get_mut(name) else {
+        return;
+    };
+
+    match argument {
+        ResolvedArgument::Raw(raw) if raw.get().contains("\"#") => {
+            let Ok(mut value) = serde_json::from_str(raw.get()) else {
+                return;
+            };
+
+            resolve(&mut value);
+            *argument = ResolvedArgument::Value(Cow::Owned(value));
+        }
+        ResolvedArgument::Raw(_) => {}
+        ResolvedArgument::Value(value) => resolve(value.to_mut()),
+    }
+}
@@ -0,0 +1,95 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use jmap_proto::{
+    endpoints::{Argument, Arguments},
+    Value,
+};
+
+use crate::extensions::{ResolvedArgument, ResolvedArguments};
+
+/// The originals of the objects a `Foo/copy` call copies, for the implicit
+/// `Foo/set` call destroying them once the copy has been made, as asked for
+/// with `onSuccessDestroyOriginal`.
+pub struct DestroyOriginals {
+    /// The `Foo/set` method destroying them.
+    method: String,
+    from_account_id: Value,
+    if_in_state: Option<Value>,
+    /// The id of each original, keyed by the creation id of its copy.
+    originals: HashMap<String, Value>,
+}
+
+impl DestroyOriginals {
+    /// Picks out the originals from the arguments of a call, if it's a
+    /// `Foo/copy` call asking for them to be destroyed. Arguments that can't
+    /// be read are left for the copy itself to reject.
+    pub fn from_call(method: &str, arguments: &ResolvedArguments<'_>) -> Option<Self> {
+        let Some((namespace, "copy")) = method.rsplit_once('/') else {
+            return None;
+        };
+
+        let argument = |name| match arguments.0.get(name)? {
+            ResolvedArgument::Raw(raw) => serde_json::from_str::<Value>(raw.get()).ok(),
+            ResolvedArgument::Value(value) => Some(value.clone().into_owned()),
+        };
+
+        if argument("onSuccessDestroyOriginal")? != Value::Bool(true) {
+            return None;
+        }
+
+        let Value::Object(create) = argument("create")? else {
+            return None;
+        };
+
+        Some(Self {
+            method: format!("{namespace}/set"),
+            from_account_id: argument("fromAccountId")?,
+            if_in_state: argument("destroyFromIfInState").filter(|state| !state.is_null()),
+            originals: create
+                .into_iter()
+                .filter_map(|(creation_id, object)| Some((creation_id, object.get("id")?.clone())))
+                .collect(),
+        })
+    }
+
+    /// The `Foo/set` call destroying the original of every object listed as
+    /// created in the response to the copy.
+    pub fn into_call(self, response: &Arguments<'_>) -> (String, ResolvedArguments<'static>) {
+        let created = match response.0.get("created") {
+            Some(Argument::Raw(raw)) => serde_json::from_str(raw.get()).unwrap_or_default(),
+            Some(Argument::Absolute(value)) => value.clone(),
+            _ => Value::Null,
+        };
+
+        let destroy = created
+            .as_object()
+            .into_iter()
+            .flat_map(|created| created.keys())
+            .filter_map(|creation_id| self.originals.get(creation_id).cloned())
+            .collect();
+
+        let mut arguments = HashMap::from([
+            ("accountId", self.from_account_id),
+            ("destroy", Value::Array(destroy)),
+        ]);
+
+        if let Some(if_in_state) = self.if_in_state {
+            arguments.insert("ifInState", if_in_state);
+        }
+
+        (
+            self.method,
+            ResolvedArguments(
+                arguments
+                    .into_iter()
+                    .map(|(name, value)| {
+                        (
+                            Cow::Borrowed(name),
+                            ResolvedArgument::Value(Cow::Owned(value)),
+                        )
+                    })
+                    .collect(),
+            ),
+        )
+    }
+}
@@ -1,40 +1,123 @@
-use std::{borrow::Cow, collections::HashMap, sync::Arc};
+mod created_ids;
+mod destroy_originals;
 
-use axum::{body::Bytes, extract::State, Extension};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use axum::{
+    extract::{RawBody, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use http_body::{LengthLimitError, Limited};
 use jmap_proto::{
     common::SessionState,
     endpoints::{Argument, Arguments, Invocation, Request, Response},
-    errors::MethodError,
+    errors::{MethodError, RequestError},
+    pointer::{PointerError, ReferencePath},
 };
+use metrics::increment_counter;
 use oxide_auth::primitives::grant::Grant;
+use serde_json::error::Category;
+use tracing::debug;
+use uuid::Uuid;
+
+use self::{created_ids::CreatedIds, destroy_originals::DestroyOriginals};
+use crate::{
+    context::{concurrency::RETRY_AFTER, Context},
+    extensions::{ExtensionRegistry, IdArguments, MethodCall, ResolvedArgument, ResolvedArguments},
+    layers::{auth_required::user_id, read_only::read_only_response},
+    methods::store_failure,
+    store::{self, UserProvider},
+};
 
-use crate::{context::Context, extensions::ResolvedArguments, store::UserProvider};
+/// Sent by clients that still read renamed response fields by their old
+/// names.
+const LEGACY_FIELD_NAMES_HEADER: &str = "jmap-legacy-field-names";
 
 pub async fn handle(
     State(context): State<Arc<Context>>,
     Extension(grant): Extension<Grant>,
-    body: Bytes,
-) {
-    let payload: Request<'_> = serde_json::from_slice(&body).unwrap();
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> Result<axum::response::Response, axum::response::Response> {
+    let Some(_permit) = context.api_concurrency.try_acquire(&grant.owner_id) else {
+        return Err(rate_limited(&too_many_concurrent_requests(&context)));
+    };
+
+    record_legacy_field_names(&context, &headers);
 
-    // TODO: `using`
-    // TODO: `method_calls`
-    // TODO: `created_ids`
+    // read per request rather than baked into the router, so a reloaded
+    // limit applies straight away
+    let max_size = usize::try_from(context.config.load().core_capabilities.max_size_request)
+        .unwrap_or(usize::MAX);
+
+    let body = hyper::body::to_bytes(Limited::new(body, max_size))
+        .await
+        .map_err(|error| body_rejected(&context, &*error))?;
 
-    let username = grant.owner_id;
+    let payload = parse_request(&body).map_err(|e| request_error(&e))?;
 
-    let user = context
+    let response = process(&context, user_id(&grant), payload)
+        .await
+        .map_err(|rejection| match rejection {
+            Rejection::Problem(error) => request_error(&error),
+            Rejection::ReadOnly => read_only_response(&context),
+            Rejection::UnknownUser => StatusCode::FORBIDDEN.into_response(),
+            Rejection::Store(error) => store_failure(error),
+        })?;
+
+    // serialised here as the response borrows from the request body
+    Ok(Json(response).into_response())
+}
+
+/// Why a request was turned away before any of its method calls were run.
+pub(super) enum Rejection {
+    Problem(RequestError),
+    /// The request would write to the store, but it's a read replica.
+    ReadOnly,
+    /// The user the request was authenticated as has since been deleted.
+    UnknownUser,
+    Store(store::Error),
+}
+
+/// Runs the method calls of a request in order on behalf of the user,
+/// however the request reached the server.
+pub(super) async fn process<'a>(
+    context: &Context,
+    user_id: Uuid,
+    payload: Request<'a>,
+) -> Result<Response<'a>, Rejection> {
+    check_limits(context, &payload).map_err(Rejection::Problem)?;
+
+    if context.store.is_read_only()
+        && payload
+            .method_calls
+            .iter()
+            .any(|call| !is_read_only_method(&call.name))
+    {
+        return Err(Rejection::ReadOnly);
+    }
+
+    let Some(user) = context
         .store
-        .get_by_username(&username)
+        .get_by_id(user_id)
         .await
-        .unwrap()
-        .unwrap();
+        .map_err(Rejection::Store)?
+    else {
+        return Err(Rejection::UnknownUser);
+    };
 
     let session_state = context
         .store
         .fetch_seq_number_for_user(user.id)
         .await
-        .unwrap();
+        .map_err(Rejection::Store)?;
 
     let mut response = Response {
         method_responses: Vec::with_capacity(payload.method_calls.len()),
@@ -42,68 +125,1334 @@ pub async fn handle(
         session_state: SessionState(session_state.to_string().into()),
     };
 
+    let call = MethodCall {
+        context,
+        user_id: user.id,
+        store_unavailable: AtomicBool::new(false),
+    };
+
+    let echo_created_ids = payload.created_ids.is_some();
+    let mut created_ids = CreatedIds::new(payload.created_ids);
+
     for invocation_request in payload.method_calls {
-        let Some(resolved_arguments) = resolve_arguments(&response, invocation_request.arguments)
-        else {
-            response.method_responses.push(
-                MethodError::InvalidResultReference.into_invocation(invocation_request.request_id),
-            );
+        // every call still gets a response, but once the store has failed
+        // there's no point attempting the rest
+        if call.is_store_unavailable() {
+            response.method_responses.push(method_error(
+                context,
+                &invocation_request.name,
+                invocation_request.request_id,
+                MethodError::ServerUnavailable,
+            ));
             continue;
-        };
+        }
 
-        // let Some(_request) =
-        //     ConcreteData::parse(invocation_request.name.as_ref(), resolved_arguments)
-        // else {
-        //     response
-        //         .method_responses
-        //         .push(MethodError::UnknownMethod.into_invocation(invocation_request.request_id));
-        //     continue;
-        // };
-
-        let arguments = if let Some(v) = context.extension_router_registry.handle(
-            invocation_request.name.as_ref(),
-            &context.extension_registry,
-            resolved_arguments,
-        ) {
-            v.into_iter()
-                .map(|(k, v)| (Cow::Owned(k), Argument::Absolute(v)))
-                .collect()
-        } else {
-            response
-                .method_responses
-                .push(MethodError::UnknownMethod.into_invocation(invocation_request.request_id));
-            continue;
-        };
+        let mut resolved_arguments =
+            match resolve_arguments(&response, invocation_request.arguments) {
+                Ok(v) => v,
+                Err(e) => {
+                    let invocation = invalid_result_reference(
+                        context,
+                        &invocation_request.name,
+                        invocation_request.request_id,
+                        &e,
+                    );
+                    response.method_responses.push(invocation);
+                    continue;
+                }
+            };
 
-        response.method_responses.push(Invocation {
-            name: invocation_request.name,
-            arguments: Arguments(arguments),
-            request_id: invocation_request.request_id,
-        });
+        let id_arguments = context
+            .extension_router_registry
+            .id_arguments(&invocation_request.name)
+            .unwrap_or(IdArguments::None);
+        created_ids.resolve(id_arguments, &mut resolved_arguments);
+
+        let destroy_originals =
+            DestroyOriginals::from_call(&invocation_request.name, &resolved_arguments);
+
+        match call_method(&call, &invocation_request.name, resolved_arguments).await {
+            Ok(arguments) => {
+                created_ids.extend_from_response(&arguments);
+                let destroy_originals = destroy_originals.map(|d| d.into_call(&arguments));
+
+                response.method_responses.push(Invocation {
+                    name: invocation_request.name,
+                    arguments,
+                    request_id: invocation_request.request_id.clone(),
+                });
+
+                if let Some(destroy_originals) = destroy_originals {
+                    response.method_responses.push(
+                        implicit_call(&call, destroy_originals, invocation_request.request_id)
+                            .await,
+                    );
+                }
+            }
+            Err(e) => {
+                response
+                    .method_responses
+                    .push(e.into_invocation(invocation_request.request_id));
+            }
+        }
+    }
+
+    if echo_created_ids {
+        response.created_ids = Some(created_ids.into_inner());
+    }
+
+    Ok(response)
+}
+
+/// Parses the body of the request, telling the client whether it wasn't
+/// JSON at all or just wasn't a `Request`.
+pub(super) fn parse_request(body: &[u8]) -> Result<Request<'_>, RequestError> {
+    // I-JSON is always UTF-8, checked up front as serde only notices bytes
+    // that aren't once they're within a string
+    let body = std::str::from_utf8(body)
+        .map_err(|_| RequestError::not_json("The request body is not valid UTF-8"))?;
+
+    serde_json::from_str(body).map_err(|error| parse_error(&error))
+}
+
+/// Tells the client whether what they sent wasn't JSON at all, or was JSON
+/// of the wrong shape.
+pub(super) fn parse_error(error: &serde_json::Error) -> RequestError {
+    match error.classify() {
+        Category::Data => RequestError::not_request(error.to_string()),
+        Category::Syntax | Category::Eof | Category::Io => {
+            RequestError::not_json(error.to_string())
+        }
+    }
+}
+
+/// Builds the response for a request whose body couldn't be read, which is
+/// a `limit` error if it was larger than advertised.
+fn body_rejected(
+    context: &Context,
+    error: &(dyn std::error::Error + Send + Sync + 'static),
+) -> axum::response::Response {
+    if error.is::<LengthLimitError>() {
+        request_error(&RequestError::limit(
+            "maxSizeRequest",
+            format!(
+                "Requests may be at most {} octets in size",
+                context.config.load().core_capabilities.max_size_request
+            ),
+        ))
+    } else {
+        debug!(%error, "Failed to read request body");
+        StatusCode::BAD_REQUEST.into_response()
+    }
+}
+
+/// Enforces the limits advertised to clients on a parsed request, and
+/// checks it only uses capabilities the server supports.
+fn check_limits(context: &Context, payload: &Request<'_>) -> Result<(), RequestError> {
+    let config = context.config.load();
+
+    if payload.using.len() as u64 > config.request_limits.max_using {
+        return Err(RequestError::limit(
+            "maxUsing",
+            format!(
+                "Requests may use at most {} capabilities",
+                config.request_limits.max_using
+            ),
+        ));
+    }
+
+    if let Some(unknown) = payload
+        .using
+        .iter()
+        .find(|capability| !ExtensionRegistry::CAPABILITIES.contains(&capability.as_ref()))
+    {
+        return Err(RequestError::unknown_capability(unknown));
+    }
+
+    if payload.method_calls.len() as u64 > config.core_capabilities.max_calls_in_request {
+        return Err(RequestError::limit(
+            "maxCallsInRequest",
+            format!(
+                "Requests may contain at most {} method calls",
+                config.core_capabilities.max_calls_in_request
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Builds the problem for a user that already has as many requests in
+/// flight as they're allowed.
+pub(super) fn too_many_concurrent_requests(context: &Context) -> RequestError {
+    let mut error = RequestError::limit(
+        "maxConcurrentRequests",
+        format!(
+            "At most {} requests may be in flight at once",
+            context.api_concurrency.limit()
+        ),
+    );
+    error.status = StatusCode::TOO_MANY_REQUESTS.as_u16();
+    error
+}
+
+/// Whether the method only ever reads from the store, and can therefore be
+/// served by a read replica.
+fn is_read_only_method(name: &str) -> bool {
+    matches!(
+        name.rsplit_once('/'),
+        Some((_, "get" | "query" | "changes" | "queryChanges" | "echo"))
+    )
+}
+
+/// Builds a problem details response (RFC 7807) for a request-level error.
+pub(super) fn request_error(error: &RequestError) -> axum::response::Response {
+    (
+        StatusCode::from_u16(error.status).unwrap_or(StatusCode::BAD_REQUEST),
+        [(header::CONTENT_TYPE, "application/problem+json")],
+        serde_json::to_string(error).unwrap(),
+    )
+        .into_response()
+}
+
+/// Builds the response for a request turned away by a limiter, telling the
+/// client when it's worth trying again.
+pub(super) fn rate_limited(error: &RequestError) -> axum::response::Response {
+    let mut response = request_error(error);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, retry_after());
+    response
+}
+
+/// The `Retry-After` header value, in whole seconds, for limited requests.
+pub(super) fn retry_after() -> HeaderValue {
+    HeaderValue::from(RETRY_AFTER.as_secs().max(1))
+}
+
+/// Makes a call the server makes on the client's behalf, such as the
+/// `Foo/set` destroying the originals of a `Foo/copy`, answered under the
+/// call id of the call that led to it.
+async fn implicit_call<'a>(
+    call: &MethodCall<'_>,
+    (name, arguments): (String, ResolvedArguments<'_>),
+    request_id: Cow<'a, str>,
+) -> Invocation<'a> {
+    match call_method(call, &name, arguments).await {
+        Ok(arguments) => Invocation {
+            name: name.into(),
+            arguments,
+            request_id,
+        },
+        Err(e) => e.into_invocation(request_id),
+    }
+}
+
+/// Calls the method with its references already resolved, recording the
+/// outcome.
+async fn call_method(
+    call: &MethodCall<'_>,
+    name: &str,
+    arguments: ResolvedArguments<'_>,
+) -> Result<Arguments<'static>, MethodError> {
+    match call
+        .context
+        .extension_router_registry
+        .handle(name, call, arguments)
+        .await
+    {
+        Some(Ok(arguments)) => {
+            record_outcome(name, "ok");
+            Ok(arguments)
+        }
+        Some(Err(e)) => {
+            record_outcome(name, &e.to_string());
+            Err(e)
+        }
+        None => {
+            // method names are client-controlled, so don't label unknown ones
+            // with the name they were called with
+            record_outcome("unknown", &MethodError::UnknownMethod.to_string());
+            Err(MethodError::UnknownMethod)
+        }
+    }
+}
+
+/// Builds the error response to a call that wasn't handed to its method,
+/// recording the outcome.
+fn method_error<'a>(
+    context: &Context,
+    name: &str,
+    request_id: Cow<'a, str>,
+    error: MethodError,
+) -> Invocation<'a> {
+    record_outcome(metric_method(context, name), &error.to_string());
+    error.into_invocation(request_id)
+}
+
+/// Counts requests from clients that still read renamed fields by their old
+/// names, labelled by whether the server is sending them.
+fn record_legacy_field_names(context: &Context, headers: &HeaderMap) {
+    if headers.contains_key(LEGACY_FIELD_NAMES_HEADER) {
+        increment_counter!(
+            "jmap_legacy_field_names_requests_total",
+            "served" => context.legacy_field_names.to_string(),
+        );
+    }
+}
+
+/// The method to label a call's metrics with. Method names are
+/// client-controlled, so any that aren't registered are counted together as
+/// `unknown`.
+fn metric_method<'a>(context: &Context, name: &'a str) -> &'a str {
+    if context.extension_router_registry.contains(name) {
+        name
+    } else {
+        "unknown"
     }
 }
 
+/// Counts the outcome of a method call, labelled by the method and either
+/// `ok` or the type of error returned.
+fn record_outcome(method: &str, outcome: &str) {
+    increment_counter!(
+        "jmap_method_calls_total",
+        "method" => method.to_string(),
+        "outcome" => outcome.to_string(),
+    );
+}
+
+/// Why a result reference in a call's arguments couldn't be resolved.
+#[derive(Debug)]
+enum ResultReferenceError<'a> {
+    /// No previous call in the request has the referenced call id.
+    CallNotFound { result_of: Cow<'a, str> },
+    /// The referenced call failed, so has no results to reference.
+    CallFailed { result_of: Cow<'a, str> },
+    /// The referenced call has no response with the referenced name.
+    NameNotFound {
+        result_of: Cow<'a, str>,
+        name: Cow<'a, str>,
+    },
+    /// The path isn't a valid JSON pointer.
+    InvalidPath {
+        path: Cow<'a, str>,
+        error: PointerError,
+    },
+    /// The path doesn't point to anything in the referenced response.
+    PathNotFound {
+        result_of: Cow<'a, str>,
+        name: Cow<'a, str>,
+        path: Cow<'a, str>,
+    },
+}
+
+impl fmt::Display for ResultReferenceError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CallNotFound { result_of } => {
+                write!(f, "No previous method call has the id {result_of:?}")
+            }
+            Self::CallFailed { result_of } => {
+                write!(f, "Method call {result_of:?} returned an error")
+            }
+            Self::NameNotFound { result_of, name } => {
+                write!(f, "Method call {result_of:?} has no {name:?} response")
+            }
+            Self::InvalidPath { path, error } => write!(f, "Invalid path {path:?}: {error}"),
+            Self::PathNotFound {
+                result_of,
+                name,
+                path,
+            } => write!(
+                f,
+                "The {name:?} response to method call {result_of:?} has nothing at {path:?}"
+            ),
+        }
+    }
+}
+
+/// Builds the response for a call with a result reference that couldn't be
+/// resolved.
+fn invalid_result_reference<'a>(
+    context: &Context,
+    method: &str,
+    request_id: Cow<'a, str>,
+    error: &ResultReferenceError<'_>,
+) -> Invocation<'a> {
+    debug!(%error, "Call has an invalid result reference");
+    record_outcome(
+        metric_method(context, method),
+        &MethodError::InvalidResultReference.to_string(),
+    );
+
+    MethodError::InvalidResultReference
+        .into_invocation_with_description(request_id, error.to_string())
+}
+
 fn resolve_arguments<'a>(
     response: &'a Response,
     args: Arguments<'a>,
-) -> Option<ResolvedArguments<'a>> {
+) -> Result<ResolvedArguments<'a>, ResultReferenceError<'a>> {
     let mut res = HashMap::with_capacity(args.0.len());
 
     for (key, value) in args.0 {
         let value = match value {
             Argument::Reference(refer) => {
-                let referenced_response = response
+                // a single call can respond several times under different
+                // names, references resolve against the first response with
+                // the referenced name
+                let mut responses = response
                     .method_responses
                     .iter()
-                    .find(|inv| inv.request_id == refer.result_of && inv.name == refer.name)?;
+                    .filter(|inv| inv.request_id == refer.result_of)
+                    .peekable();
+
+                if responses.peek().is_none() {
+                    return Err(ResultReferenceError::CallNotFound {
+                        result_of: refer.result_of,
+                    });
+                }
+
+                let mut failed = false;
+                let referenced_response = responses.find(|inv| {
+                    // error responses have no results to reference, whatever
+                    // name the reference gives
+                    failed |= inv.name == "error";
+                    inv.name != "error" && inv.name == refer.name
+                });
+
+                let Some(referenced_response) = referenced_response else {
+                    return Err(if failed {
+                        ResultReferenceError::CallFailed {
+                            result_of: refer.result_of,
+                        }
+                    } else {
+                        ResultReferenceError::NameNotFound {
+                            result_of: refer.result_of,
+                            name: refer.name,
+                        }
+                    });
+                };
 
-                referenced_response.arguments.pointer(&refer.path)?
+                let path = match ReferencePath::parse(&refer.path) {
+                    Ok(path) => path,
+                    Err(error) => {
+                        return Err(ResultReferenceError::InvalidPath {
+                            path: refer.path,
+                            error,
+                        })
+                    }
+                };
+
+                let Some(value) = referenced_response.arguments.pointer(&path) else {
+                    return Err(ResultReferenceError::PathNotFound {
+                        result_of: refer.result_of,
+                        name: refer.name,
+                        path: refer.path,
+                    });
+                };
+
+                ResolvedArgument::Value(value)
             }
-            Argument::Absolute(value) => Cow::Owned(value),
+            Argument::Absolute(value) => ResolvedArgument::Value(Cow::Owned(value)),
+            Argument::Raw(value) => ResolvedArgument::Raw(value),
         };
 
         res.insert(key, value);
     }
 
-    Some(ResolvedArguments(res))
+    Ok(ResolvedArguments(res))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use jmap_proto::{endpoints::object::ObjectState, errors::ProblemType};
+    use tracing_subscriber::{filter::Targets, reload};
+
+    use super::*;
+    use crate::{
+        layers::auth_required::tests::grant,
+        methods::event_source::tests::{connect, next_change},
+        reload::Reloader,
+        store::{AccountAccessLevel, AccountProvider, User},
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn request_for_deleted_user_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+
+        let user = User::new("alice".to_string(), "password", &context.argon2);
+        let (user_id, _) = context.store.create_user(user).await.unwrap();
+        context.store.delete_user(user_id).await.unwrap();
+
+        let payload = parse_request(br#"{"using":[],"methodCalls":[]}"#).unwrap();
+
+        assert!(matches!(
+            process(&context, user_id, payload).await,
+            Err(Rejection::UnknownUser)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dry_run_creations_are_not_added_to_created_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+
+        let user = User::new("alice".to_string(), "password", &context.argon2);
+        let (user_id, account_id) = context.store.create_user(user).await.unwrap();
+
+        let payload = format!(
+            r#"{{
+                "using": [],
+                "createdIds": {{}},
+                "methodCalls": [
+                    ["AddressBook/set", {{
+                        "accountId": "{account_id}",
+                        "create": {{"provisional": {{"name": "Friends"}}}},
+                        "urn:jogre:dryRun": true
+                    }}, "0"],
+                    ["AddressBook/set", {{
+                        "accountId": "{account_id}",
+                        "create": {{"real": {{"name": "Family"}}}}
+                    }}, "1"]
+                ]
+            }}"#
+        );
+
+        let Ok(response) = process(
+            &context,
+            user_id,
+            parse_request(payload.as_bytes()).unwrap(),
+        )
+        .await
+        else {
+            panic!("request was rejected");
+        };
+
+        let created_ids = response.created_ids.unwrap();
+        let created_ids: Vec<_> = created_ids.keys().map(|id| id.0 .0.as_ref()).collect();
+
+        assert_eq!(created_ids, ["real"]);
+    }
+
+    /// Lowers the limits on calls per request and objects per get.
+    fn lower_limits(context: &Context, limit: u64) {
+        let mut config = (**context.config.load()).clone();
+        config.core_capabilities.max_calls_in_request = limit;
+        config.core_capabilities.max_objects_in_get = limit;
+        context.config.store(Arc::new(config));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn too_many_calls_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        lower_limits(&context, 2);
+
+        let payload = |calls: usize| {
+            let calls = vec![r#"["Core/echo", {}, "0"]"#; calls].join(",");
+            format!(r#"{{"using": [], "methodCalls": [{calls}]}}"#)
+        };
+
+        assert!(check_limits(&context, &parse_request(payload(2).as_bytes()).unwrap()).is_ok());
+
+        let error =
+            check_limits(&context, &parse_request(payload(3).as_bytes()).unwrap()).unwrap_err();
+        assert!(matches!(error.type_, ProblemType::OverLimit));
+        assert_eq!(error.meta["limit"], "maxCallsInRequest");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn too_many_capabilities_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+
+        let mut config = (**context.config.load()).clone();
+        config.request_limits.max_using = 2;
+        context.config.store(Arc::new(config));
+
+        let user = User::new("alice".to_string(), "password", &context.argon2);
+        let (user_id, _) = context.store.create_user(user).await.unwrap();
+
+        let request = |using: &[&str]| {
+            let payload = serde_json::json!({"using": using, "methodCalls": []}).to_string();
+
+            handle(
+                State(context.clone()),
+                Extension(grant(user_id)),
+                HeaderMap::new(),
+                RawBody(payload.into()),
+            )
+        };
+
+        let known = ExtensionRegistry::CAPABILITIES;
+        let response = request(&known[..2]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = request(&known[..3]).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+
+        let problem: serde_json::Value =
+            serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap())
+                .unwrap();
+        assert_eq!(problem["type"], "urn:ietf:params:jmap:error:limit");
+        assert_eq!(problem["limit"], "maxUsing");
+
+        // counted before the capabilities are looked at, so a long list
+        // isn't searched
+        let unknown = ["urn:example:1", "urn:example:2", "urn:example:3"];
+        let error = check_limits(
+            &context,
+            &parse_request(
+                serde_json::json!({"using": unknown, "methodCalls": []})
+                    .to_string()
+                    .as_bytes(),
+            )
+            .unwrap(),
+        )
+        .unwrap_err();
+        assert!(matches!(error.type_, ProblemType::OverLimit));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn too_many_objects_in_get_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        lower_limits(&context, 2);
+
+        let user = User::new("alice".to_string(), "password", &context.argon2);
+        let (user_id, account_id) = context.store.create_user(user).await.unwrap();
+
+        let get = |ids: &[&str]| {
+            serde_json::json!({
+                "using": [],
+                "methodCalls": [["AddressBook/get", {"accountId": account_id, "ids": ids}, "0"]]
+            })
+        };
+
+        let (responses, _) = process_json(&context, user_id, &get(&["a", "b"])).await;
+        assert_eq!(responses[0]["notFound"], serde_json::json!(["a", "b"]));
+
+        let (responses, _) = process_json(&context, user_id, &get(&["a", "b", "c"])).await;
+        assert_eq!(responses[0]["type"], "requestTooLarge");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unknown_capabilities_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+
+        let payload = |using: &str| format!(r#"{{"using": ["{using}"], "methodCalls": []}}"#);
+
+        let known = payload("urn:ietf:params:jmap:contacts");
+        assert!(check_limits(&context, &parse_request(known.as_bytes()).unwrap()).is_ok());
+
+        let unknown = payload("urn:ietf:params:jmap:mail");
+        let error =
+            check_limits(&context, &parse_request(unknown.as_bytes()).unwrap()).unwrap_err();
+        assert!(matches!(error.type_, ProblemType::UnknownCapability));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reloaded_size_limit_is_enforced() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+
+        let user = User::new("alice".to_string(), "password", &context.argon2);
+        let (user_id, _) = context.store.create_user(user).await.unwrap();
+
+        let request = || async {
+            let payload = format!(
+                r#"{{"using":[],"methodCalls":[],"padding":"{}"}}"#,
+                "x".repeat(100)
+            );
+
+            match handle(
+                State(context.clone()),
+                Extension(grant(user_id)),
+                HeaderMap::new(),
+                RawBody(payload.into()),
+            )
+            .await
+            {
+                Ok(response) | Err(response) => response.status(),
+            }
+        };
+
+        assert_eq!(request().await, StatusCode::OK);
+
+        let config = dir.path().join("config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                "private-key = \"testtesttesttesttesttesttesttest\"\n\
+                 base-url = \"http://127.0.0.1:8888\"\n\
+                 [store]\n\
+                 type = \"rocksdb\"\n\
+                 path = {:?}\n\
+                 [core-capabilities]\n\
+                 max-size-request = 64\n",
+                dir.path()
+            ),
+        )
+        .unwrap();
+
+        let (_, log_filter) = reload::Layer::new(Targets::new());
+        let reloaded = Reloader::new(config, toml::Value::Table(toml::Table::new()), log_filter)
+            .reload(&context)
+            .await
+            .unwrap();
+
+        assert!(reloaded
+            .applied
+            .contains(&"core-capabilities.max-size-request".to_string()));
+        assert_eq!(request().await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_over_the_concurrency_limit_are_told_when_to_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+        context.api_concurrency.set_limit(1);
+
+        let user = User::new("alice".to_string(), "password", &context.argon2);
+        let (user_id, _) = context.store.create_user(user).await.unwrap();
+
+        let request = || async {
+            match handle(
+                State(context.clone()),
+                Extension(grant(user_id)),
+                HeaderMap::new(),
+                RawBody(r#"{"using":[],"methodCalls":[]}"#.into()),
+            )
+            .await
+            {
+                Ok(response) | Err(response) => response,
+            }
+        };
+
+        let in_flight = context
+            .api_concurrency
+            .try_acquire(&user_id.to_string())
+            .unwrap();
+
+        let response = request().await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        drop(in_flight);
+
+        let response = request().await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    /// Processes the request as the user, returning the arguments of each
+    /// response along with the response's `createdIds`.
+    pub(crate) async fn process_json(
+        context: &Context,
+        user_id: Uuid,
+        payload: &serde_json::Value,
+    ) -> (Vec<serde_json::Value>, serde_json::Value) {
+        let payload = payload.to_string();
+
+        let Ok(response) =
+            process(context, user_id, parse_request(payload.as_bytes()).unwrap()).await
+        else {
+            panic!("request was rejected");
+        };
+
+        let arguments = response
+            .method_responses
+            .iter()
+            .map(|invocation| serde_json::to_value(&invocation.arguments).unwrap())
+            .collect();
+
+        (
+            arguments,
+            serde_json::to_value(response.created_ids).unwrap(),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn references_are_only_resolved_where_ids_are_expected() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+
+        let user = User::new("alice".to_string(), "password", &context.argon2);
+        let (user_id, account_id) = context.store.create_user(user).await.unwrap();
+
+        let (responses, _) = process_json(
+            &context,
+            user_id,
+            &serde_json::json!({
+                "using": [],
+                "methodCalls": [
+                    ["AddressBook/set", {
+                        "accountId": account_id,
+                        "create": {"a": {"name": "#a"}, "b": {"name": "Family"}}
+                    }, "0"],
+                    ["ContactCard/set", {
+                        "accountId": account_id,
+                        "create": {"card": {
+                            "uid": "urn:uuid:1",
+                            "addressBookIds": {"#a": true},
+                            "fullName": "#a"
+                        }}
+                    }, "1"],
+                    ["ContactCard/set", {
+                        "accountId": account_id,
+                        "update": {"#card": {"addressBookIds/#b": true}}
+                    }, "2"],
+                    ["ContactCard/get", {"accountId": account_id, "ids": ["#card"]}, "3"]
+                ]
+            }),
+        )
+        .await;
+
+        let a = &responses[0]["created"]["a"]["id"];
+        let b = &responses[0]["created"]["b"]["id"];
+        let card = &responses[3]["list"][0];
+
+        assert_eq!(card["id"], responses[1]["created"]["card"]["id"]);
+        assert_eq!(card["fullName"], "#a");
+        assert_eq!(
+            card["addressBookIds"],
+            serde_json::json!({a.as_str().unwrap(): true, b.as_str().unwrap(): true})
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn copy_destroys_originals_once_copied() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+
+        let alice = User::new("alice".to_string(), "password", &context.argon2);
+        let (alice, personal) = context.store.create_user(alice).await.unwrap();
+        let bob = User::new("bob".to_string(), "password", &context.argon2);
+        let (_, shared) = context.store.create_user(bob).await.unwrap();
+
+        context
+            .store
+            .set_account_access(shared, alice, AccountAccessLevel::Writer)
+            .await
+            .unwrap();
+
+        let (responses, _) = process_json(
+            &context,
+            alice,
+            &serde_json::json!({
+                "using": [],
+                "methodCalls": [
+                    ["AddressBook/set", {
+                        "accountId": personal,
+                        "create": {"a": {"name": "Friends"}}
+                    }, "0"],
+                    ["ContactCard/set", {
+                        "accountId": personal,
+                        "create": {"card": {
+                            "uid": "urn:uuid:1",
+                            "addressBookIds": {"#a": true},
+                            "fullName": "Jane Doe"
+                        }}
+                    }, "1"],
+                    ["AddressBook/set", {
+                        "accountId": shared,
+                        "create": {"b": {"name": "Imported"}}
+                    }, "2"],
+                    ["ContactCard/copy", {
+                        "fromAccountId": personal,
+                        "accountId": shared,
+                        "create": {
+                            "copy": {"id": "#card", "addressBookIds": {"#b": true}},
+                            "missing": {"id": "00000000-0000-0000-0000-000000000000"}
+                        },
+                        "onSuccessDestroyOriginal": true
+                    }, "3"],
+                    ["ContactCard/get", {"accountId": shared, "ids": ["#copy"]}, "4"],
+                    ["ContactCard/get", {"accountId": personal, "ids": ["#card"]}, "5"],
+                    ["ContactCard/copy", {
+                        "fromAccountId": shared,
+                        "accountId": shared,
+                        "create": {}
+                    }, "6"]
+                ]
+            }),
+        )
+        .await;
+
+        let original = &responses[1]["created"]["card"]["id"];
+        let copy = &responses[3]["created"]["copy"]["id"];
+        assert_ne!(copy, original);
+        assert_eq!(responses[3]["notCreated"]["missing"]["type"], "notFound");
+
+        // the implicit destroy of the original follows the copy's response
+        assert_eq!(responses[4]["accountId"], personal.to_string());
+        assert_eq!(responses[4]["destroyed"], serde_json::json!([original]));
+
+        let copied = &responses[5]["list"][0];
+        assert_eq!(copied["id"], *copy);
+        assert_eq!(copied["uid"], "urn:uuid:1");
+        assert_eq!(copied["fullName"], "Jane Doe");
+        assert_eq!(
+            copied["addressBookIds"],
+            serde_json::json!({responses[2]["created"]["b"]["id"].as_str().unwrap(): true})
+        );
+
+        assert_eq!(responses[6]["notFound"], serde_json::json!([original]));
+        assert_eq!(responses[7]["type"], "invalidArguments");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unknown_creation_ids_are_rejected_per_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+
+        let user = User::new("alice".to_string(), "password", &context.argon2);
+        let (user_id, account_id) = context.store.create_user(user).await.unwrap();
+
+        let (responses, created_ids) = process_json(
+            &context,
+            user_id,
+            &serde_json::json!({
+                "using": [],
+                "createdIds": {"earlier": "abc"},
+                "methodCalls": [
+                    ["AddressBook/set", {
+                        "accountId": account_id,
+                        "create": {"book": {"name": "Friends"}},
+                        "update": {"#missing": {"name": "Family"}},
+                        "destroy": ["#gone"]
+                    }, "0"],
+                    ["ContactCard/set", {
+                        "accountId": account_id,
+                        "create": {"card": {
+                            "uid": "urn:uuid:1",
+                            "addressBookIds": {"#missing": true}
+                        }}
+                    }, "1"]
+                ]
+            }),
+        )
+        .await;
+
+        assert_eq!(responses[0]["notUpdated"]["#missing"]["type"], "notFound");
+        assert_eq!(responses[0]["notDestroyed"]["#gone"]["type"], "notFound");
+        assert_eq!(
+            responses[1]["notCreated"]["card"]["properties"],
+            serde_json::json!(["addressBookIds"])
+        );
+        assert_eq!(
+            created_ids,
+            serde_json::json!({"earlier": "abc", "book": responses[0]["created"]["book"]["id"]})
+        );
+    }
+
+    /// Fetches every card in the account, along with the state they're at.
+    async fn fetch_cards(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+    ) -> (HashMap<String, serde_json::Value>, String) {
+        let (responses, _) = process_json(
+            context,
+            user_id,
+            &serde_json::json!({
+                "using": [],
+                "methodCalls": [["ContactCard/get", {"accountId": account_id}, "0"]]
+            }),
+        )
+        .await;
+
+        let cards = responses[0]["list"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|card| (card["id"].as_str().unwrap().to_string(), card.clone()))
+            .collect();
+
+        (cards, responses[0]["state"].as_str().unwrap().to_string())
+    }
+
+    /// Brings a cache of cards up to date the way a client would, following
+    /// `ContactCard/changes` a page at a time and fetching what changed by
+    /// back-reference. Returns the state the cache is then at.
+    async fn sync_cards(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+        cache: &mut HashMap<String, serde_json::Value>,
+        mut state: String,
+    ) -> String {
+        loop {
+            let fetch = |path: &str, call_id: &str| {
+                serde_json::json!(["ContactCard/get", {
+                    "accountId": account_id,
+                    "#ids": {"resultOf": "0", "name": "ContactCard/changes", "path": path}
+                }, call_id])
+            };
+
+            let (responses, _) = process_json(
+                context,
+                user_id,
+                &serde_json::json!({
+                    "using": [],
+                    "methodCalls": [
+                        ["ContactCard/changes", {
+                            "accountId": account_id,
+                            "sinceState": state,
+                            "maxChanges": 2
+                        }, "0"],
+                        fetch("/created", "1"),
+                        fetch("/updated", "2")
+                    ]
+                }),
+            )
+            .await;
+
+            for id in responses[0]["destroyed"].as_array().unwrap() {
+                cache.remove(id.as_str().unwrap());
+            }
+
+            for fetched in &responses[1..] {
+                // everything reported as changed can be read
+                assert_eq!(fetched["notFound"], serde_json::json!([]));
+
+                for card in fetched["list"].as_array().unwrap() {
+                    cache.insert(card["id"].as_str().unwrap().to_string(), card.clone());
+                }
+            }
+
+            state = responses[0]["newState"].as_str().unwrap().to_string();
+
+            if responses[0]["hasMoreChanges"] == false {
+                return state;
+            }
+        }
+    }
+
+    /// Calls `ContactCard/set` as the user, expecting every change to be
+    /// made.
+    async fn set_cards(
+        context: &Context,
+        user_id: Uuid,
+        arguments: serde_json::Value,
+    ) -> serde_json::Value {
+        let (mut responses, _) = process_json(
+            context,
+            user_id,
+            &serde_json::json!({
+                "using": [],
+                "methodCalls": [["ContactCard/set", arguments, "0"]]
+            }),
+        )
+        .await;
+
+        let response = responses.remove(0);
+        assert_eq!(response["notCreated"], serde_json::json!({}));
+        assert_eq!(response["notUpdated"], serde_json::json!({}));
+        assert_eq!(response["notDestroyed"], serde_json::json!({}));
+
+        response
+    }
+
+    /// The ids of every card in the account, as `ContactCard/query` returns
+    /// them, sorted.
+    async fn query_cards(context: &Context, user_id: Uuid, account_id: Uuid) -> Vec<String> {
+        let (responses, _) = process_json(
+            context,
+            user_id,
+            &serde_json::json!({
+                "using": [],
+                "methodCalls": [["ContactCard/query", {"accountId": account_id}, "0"]]
+            }),
+        )
+        .await;
+
+        let mut ids: Vec<_> = responses[0]["ids"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|id| id.as_str().unwrap().to_string())
+            .collect();
+        ids.sort();
+
+        ids
+    }
+
+    /// The ids of the cards in the account, most recently updated first, as
+    /// a client caching the query would fetch them, along with the state
+    /// of the query.
+    async fn query_recently_updated(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+    ) -> (Vec<serde_json::Value>, String) {
+        let (responses, _) = process_json(
+            context,
+            user_id,
+            &serde_json::json!({
+                "using": [],
+                "methodCalls": [["ContactCard/query", {
+                    "accountId": account_id,
+                    "sort": [{"property": "updated", "isAscending": false}]
+                }, "0"]]
+            }),
+        )
+        .await;
+
+        (
+            responses[0]["ids"].as_array().unwrap().clone(),
+            responses[0]["queryState"].as_str().unwrap().to_string(),
+        )
+    }
+
+    /// Brings a cached [`query_recently_updated`] up to date with
+    /// `ContactCard/queryChanges`, returning the query state it's then at.
+    async fn sync_query(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+        ids: &mut Vec<serde_json::Value>,
+        query_state: &str,
+    ) -> String {
+        let (responses, _) = process_json(
+            context,
+            user_id,
+            &serde_json::json!({
+                "using": [],
+                "methodCalls": [["ContactCard/queryChanges", {
+                    "accountId": account_id,
+                    "sort": [{"property": "updated", "isAscending": false}],
+                    "sinceQueryState": query_state
+                }, "0"]]
+            }),
+        )
+        .await;
+
+        let removed = responses[0]["removed"].as_array().unwrap();
+        ids.retain(|id| !removed.contains(id));
+
+        for added in responses[0]["added"].as_array().unwrap() {
+            let index = usize::try_from(added["index"].as_u64().unwrap()).unwrap();
+            ids.insert(index, added["id"].clone());
+        }
+
+        responses[0]["newQueryState"].as_str().unwrap().to_string()
+    }
+
+    /// Creates a book in the account holding two cards, shared with the
+    /// other user for writing, returning the ids of the book and its cards.
+    async fn share_book(
+        context: &Context,
+        user_id: Uuid,
+        account_id: Uuid,
+        share_with: Uuid,
+    ) -> [String; 3] {
+        context
+            .store
+            .set_account_access(account_id, share_with, store::AccountAccessLevel::Writer)
+            .await
+            .unwrap();
+
+        let rights = serde_json::json!({
+            "mayRead": true,
+            "mayWrite": true,
+            "mayAdmin": false,
+            "mayDelete": false,
+        });
+        let (responses, _) = process_json(
+            context,
+            user_id,
+            &serde_json::json!({
+                "using": [],
+                "methodCalls": [
+                    ["AddressBook/set", {
+                        "accountId": account_id,
+                        "create": {"book": {"name": "Shared", "shareWith": {share_with.to_string(): rights}}}
+                    }, "0"],
+                    ["ContactCard/set", {
+                        "accountId": account_id,
+                        "create": {
+                            "first": {"uid": "urn:uuid:1", "addressBookIds": {"#book": true}},
+                            "second": {"uid": "urn:uuid:2", "addressBookIds": {"#book": true}}
+                        }
+                    }, "1"]
+                ]
+            }),
+        )
+        .await;
+
+        let created = |response: &serde_json::Value, creation_id: &str| {
+            response["created"][creation_id]["id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+
+        [
+            created(&responses[0], "book"),
+            created(&responses[1], "first"),
+            created(&responses[1], "second"),
+        ]
+    }
+
+    /// Creates a card in the user's own account and copies it into the
+    /// book in another account, returning the id of the copy.
+    async fn copy_own_card(
+        context: &Context,
+        user_id: Uuid,
+        own_account_id: Uuid,
+        account_id: Uuid,
+        book: &str,
+    ) -> String {
+        let (responses, _) = process_json(
+            context,
+            user_id,
+            &serde_json::json!({
+                "using": [],
+                "methodCalls": [
+                    ["AddressBook/set", {
+                        "accountId": own_account_id,
+                        "create": {"own": {"name": "Own"}}
+                    }, "0"],
+                    ["ContactCard/set", {
+                        "accountId": own_account_id,
+                        "create": {"own": {
+                            "uid": "urn:uuid:5",
+                            "addressBookIds": {"#own": true},
+                            "fullName": "Copied by Bob"
+                        }}
+                    }, "1"],
+                    ["ContactCard/copy", {
+                        "fromAccountId": own_account_id,
+                        "accountId": account_id,
+                        "create": {"copy": {"id": "#own", "addressBookIds": {book: true}}}
+                    }, "2"]
+                ]
+            }),
+        )
+        .await;
+
+        responses[2]["created"]["copy"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// The card states pushed for the account down the event source stream
+    /// since connecting, in the order they were pushed.
+    async fn pushed_card_states(body: &mut axum::body::BoxBody, account_id: Uuid) -> Vec<u64> {
+        let mut pushed = Vec::new();
+
+        while let Some(change) = next_change(body).await {
+            if let Some(state) = change["changed"][account_id.to_string()]["ContactCard"].as_str() {
+                let state = ObjectState(state.to_string().into());
+                pushed.push(store::decode_object_state(&state).unwrap());
+            }
+        }
+
+        pushed
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn incremental_sync_matches_a_fresh_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+
+        let alice = User::new("alice".to_string(), "password", &context.argon2);
+        let (alice, account_id) = context.store.create_user(alice).await.unwrap();
+        let bob = User::new("bob".to_string(), "password", &context.argon2);
+        let (bob, bob_account_id) = context.store.create_user(bob).await.unwrap();
+
+        let [book, first, second] = share_book(&context, alice, account_id, bob).await;
+        let created = |response: &serde_json::Value, creation_id: &str| {
+            response["created"][creation_id]["id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let (mut cache, state) = fetch_cards(&context, alice, account_id).await;
+        assert_eq!(cache.len(), 2);
+        let (mut query, query_state) = query_recently_updated(&context, alice, account_id).await;
+
+        let mut events = connect(&context, alice, None).await;
+
+        // bob changes the shared book alongside alice
+        set_cards(
+            &context,
+            bob,
+            serde_json::json!({
+                "accountId": account_id,
+                "update": {&first: {"fullName": "Changed by Bob"}},
+                "create": {"third": {"uid": "urn:uuid:3", "addressBookIds": {&book: true}}}
+            }),
+        )
+        .await;
+        set_cards(
+            &context,
+            alice,
+            serde_json::json!({"accountId": account_id, "destroy": [&second]}),
+        )
+        .await;
+
+        // bob copies one of his own cards into the shared book
+        let copy = copy_own_card(&context, bob, bob_account_id, account_id, &book).await;
+
+        // created and destroyed between syncs, so never seen by the client
+        let fleeting = set_cards(
+            &context,
+            alice,
+            serde_json::json!({
+                "accountId": account_id,
+                "create": {"fleeting": {"uid": "urn:uuid:4", "addressBookIds": {&book: true}}}
+            }),
+        )
+        .await;
+        set_cards(
+            &context,
+            alice,
+            serde_json::json!({
+                "accountId": account_id,
+                "destroy": [created(&fleeting, "fleeting")]
+            }),
+        )
+        .await;
+
+        let synced = sync_cards(&context, alice, account_id, &mut cache, state).await;
+        let (fresh, fresh_state) = fetch_cards(&context, alice, account_id).await;
+
+        assert_eq!(cache, fresh);
+        assert_eq!(synced, fresh_state);
+        assert_eq!(cache[&first]["fullName"], "Changed by Bob");
+        assert_eq!(cache[&copy]["fullName"], "Copied by Bob");
+
+        let synced_query = sync_query(&context, alice, account_id, &mut query, &query_state).await;
+        let (fresh_query, fresh_query_state) =
+            query_recently_updated(&context, alice, account_id).await;
+
+        assert_eq!(query, fresh_query);
+        assert_eq!(synced_query, fresh_query_state);
+
+        // changes were pushed in order, the last naming the state the client
+        // synced to
+        let pushed = pushed_card_states(&mut events, account_id).await;
+        assert!(pushed.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            pushed.last().copied(),
+            store::decode_object_state(&ObjectState(fresh_state.into()))
+        );
+
+        let mut cached: Vec<_> = cache.into_keys().collect();
+        cached.sort();
+
+        assert_eq!(query_cards(&context, alice, account_id).await, cached);
+    }
 }
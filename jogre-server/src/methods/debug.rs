@@ -0,0 +1,225 @@
+use std::{collections::HashSet, convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension,
+};
+use futures::{stream, Stream};
+use oxide_auth::primitives::grant::Grant;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    context::{events::DomainEvent, Context},
+    layers::auth_required::user_id,
+    methods::{api::retry_after, store_failure},
+    store::{AccountProvider, UserProvider},
+};
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Only stream events within this account.
+    account: Option<Uuid>,
+    /// Only stream events relating to these data types, comma separated.
+    types: Option<String>,
+}
+
+/// Decides which events are sent down a stream.
+struct Filter {
+    /// The user watching the stream.
+    user_id: Uuid,
+    /// The accounts the user may watch, or `None` if they may watch any.
+    visible_accounts: Option<HashSet<Uuid>>,
+    account: Option<Uuid>,
+    types: Option<HashSet<String>>,
+}
+
+impl Filter {
+    fn matches(&self, event: &DomainEvent) -> bool {
+        match event {
+            DomainEvent::ObjectsChanged { .. } => {}
+            // events about users aren't scoped to an account or type, so
+            // are only filtered on who can see them
+            DomainEvent::UserStateChanged { user_id, .. } => {
+                return self.visible_accounts.is_none() || *user_id == self.user_id;
+            }
+        }
+
+        self.visible_accounts
+            .as_ref()
+            .is_none_or(|visible| event.account_id().is_some_and(|v| visible.contains(&v)))
+            && self.account.is_none_or(|v| event.account_id() == Some(v))
+            && self.types.as_ref().is_none_or(|types| {
+                event
+                    .data_type()
+                    .is_some_and(|data_type| types.contains(data_type))
+            })
+    }
+}
+
+/// Streams changes made within the server as they happen, as JSON events,
+/// for debugging clients. Admins may watch any account, other users may only
+/// watch the accounts they have access to.
+pub async fn events(
+    State(context): State<Arc<Context>>,
+    Extension(grant): Extension<Grant>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Response> {
+    let Ok(permit) = context.debug_event_streams.clone().try_acquire_owned() else {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after())],
+        )
+            .into_response());
+    };
+
+    // the user may have been deleted since their token was issued
+    let Some(user) = context
+        .store
+        .get_by_id(user_id(&grant))
+        .await
+        .map_err(store_failure)?
+    else {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    };
+
+    let visible_accounts = if context.debug_events.admins.contains(&user.username) {
+        None
+    } else {
+        Some(
+            context
+                .store
+                .get_accounts_for_user(user.id)
+                .await
+                .map_err(store_failure)?
+                .into_iter()
+                .map(|account| account.id)
+                .collect::<HashSet<_>>(),
+        )
+    };
+
+    if let (Some(visible), Some(account)) = (&visible_accounts, query.account) {
+        if !visible.contains(&account) {
+            return Err(StatusCode::FORBIDDEN.into_response());
+        }
+    }
+
+    let filter = Filter {
+        user_id: user.id,
+        visible_accounts,
+        account: query.account,
+        types: query
+            .types
+            .map(|types| types.split(',').map(ToString::to_string).collect()),
+    };
+
+    // the permit is held by the stream, so is released once the client
+    // disconnects
+    let state = (context.events.subscribe(), filter, permit);
+
+    let stream = stream::unfold(state, |(mut receiver, filter, permit)| async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    let event = Event::default().event("lagged").data(missed.to_string());
+                    return Some((Ok(event), (receiver, filter, permit)));
+                }
+                Err(RecvError::Closed) => return None,
+            };
+
+            if !filter.matches(&event.event) {
+                continue;
+            }
+
+            match Event::default().json_data(&*event) {
+                Ok(event) => return Some((Ok(event), (receiver, filter, permit))),
+                Err(error) => error!(%error, id = event.id, "Failed to serialise event, skipping"),
+            }
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::HttpBody;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        extensions::{contacts::tests::create_card, tests::user},
+        layers::auth_required::tests::grant,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_changes_to_watched_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut context = Context::for_tests(dir.path());
+        context.debug_events.enabled = true;
+        let context = Arc::new(context);
+
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let Ok(stream) = events(
+            State(context.clone()),
+            Extension(grant(user_id)),
+            Query(EventsQuery {
+                account: Some(account_id),
+                types: Some("ContactCard".to_string()),
+            }),
+        )
+        .await
+        else {
+            panic!("stream refused");
+        };
+        let mut body = stream.into_response().into_body();
+
+        let created = create_card(
+            &context,
+            user_id,
+            account_id,
+            json!({"uid": "urn:uuid:1", "fullName": "Bob"}),
+        )
+        .await;
+        assert!(created["created"]["c"].is_object());
+
+        let chunk = body.data().await.unwrap().unwrap();
+        let chunk = std::str::from_utf8(&chunk).unwrap();
+        let data = chunk
+            .lines()
+            .find_map(|line| line.strip_prefix("data:"))
+            .unwrap();
+        let event: serde_json::Value = serde_json::from_str(data).unwrap();
+
+        assert_eq!(event["type"], "ObjectsChanged");
+        assert_eq!(event["accountId"], account_id.to_string());
+        assert_eq!(event["dataType"], "ContactCard");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deleted_user_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+
+        let response = events(
+            State(context),
+            Extension(grant(Uuid::new_v4())),
+            Query(EventsQuery {
+                account: None,
+                types: None,
+            }),
+        )
+        .await;
+
+        assert!(matches!(response, Err(response) if response.status() == StatusCode::FORBIDDEN));
+    }
+}
@@ -0,0 +1,142 @@
+use std::{fmt::Write, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use jmap_proto::errors::RequestError;
+use oxide_auth::primitives::grant::Grant;
+use serde::Deserialize;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    context::Context,
+    layers::auth_required::user_id,
+    methods::{api::request_error, store_failure, variables},
+    store::{AccountProvider, BlobId, BlobProvider, BlobReferenceProvider},
+};
+
+/// Content type of downloads that don't ask for their own.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    /// The content type to serve the blob as.
+    accept: Option<String>,
+}
+
+/// Returns the contents of a blob within an account, as an attachment with
+/// the name given by the client.
+pub async fn handle(
+    State(context): State<Arc<Context>>,
+    Extension(grant): Extension<Grant>,
+    Path((account_id, blob_id, name)): Path<(String, String, String)>,
+    Query(query): Query<DownloadQuery>,
+) -> Response {
+    let account_id = match variables::id("accountId", &account_id) {
+        Ok(account_id) => Uuid::parse_str(account_id).ok(),
+        Err(error) => return error.into_response(),
+    };
+
+    let blob_id = match variables::id("blobId", &blob_id) {
+        Ok(blob_id) => BlobId::parse(blob_id),
+        Err(error) => return error.into_response(),
+    };
+
+    let name = match variables::name(&name) {
+        Ok(name) => name,
+        Err(error) => return error.into_response(),
+    };
+
+    let content_type = match query.accept.as_deref().map(variables::media_type) {
+        Some(Ok(content_type)) => HeaderValue::from_str(content_type).unwrap(),
+        Some(Err(error)) => return error.into_response(),
+        None => HeaderValue::from_static(DEFAULT_CONTENT_TYPE),
+    };
+
+    let Some(account_id) = account_id else {
+        return not_found("The account does not exist");
+    };
+
+    let accounts = match context.store.get_accounts_for_user(user_id(&grant)).await {
+        Ok(accounts) => accounts,
+        Err(error) => return store_failure(error),
+    };
+
+    if !accounts.iter().any(|account| account.id == account_id) {
+        return not_found("The account does not exist");
+    }
+
+    let Some(blob_id) = blob_id else {
+        return not_found("The blob does not exist");
+    };
+
+    match context.store.is_blob_linked(account_id, blob_id).await {
+        Ok(true) => {}
+        Ok(false) => return not_found("The blob does not exist"),
+        Err(error) => return store_failure(error),
+    }
+
+    let contents = match context.blob_store.get_blob(blob_id, None).await {
+        Ok(Some(contents)) => contents,
+        Ok(None) => return not_found("The blob does not exist"),
+        Err(error) => {
+            error!(?error, %blob_id, "Failed to fetch blob");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, content_disposition(&name)),
+            // the content type is chosen by the client, so browsers mustn't
+            // be allowed to second-guess it
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+        ],
+        contents,
+    )
+        .into_response()
+}
+
+fn not_found(detail: &'static str) -> Response {
+    request_error(&RequestError::not_found(detail))
+}
+
+/// Builds an `attachment` disposition for the given filename (RFC 6266).
+///
+/// The name is given both as a quoted ASCII approximation for clients that
+/// only understand `filename`, and in full as UTF-8 through `filename*`.
+fn content_disposition(name: &str) -> HeaderValue {
+    let mut out = String::from("attachment; filename=\"");
+
+    for c in name.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_ascii() && !c.is_ascii_control() => out.push(c),
+            _ => out.push('_'),
+        }
+    }
+
+    out.push_str("\"; filename*=UTF-8''");
+
+    for byte in name.bytes() {
+        // attr-char from RFC 5987, everything else is percent-encoded
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            out.push(char::from(byte));
+        } else {
+            write!(out, "%{byte:02X}").unwrap();
+        }
+    }
+
+    HeaderValue::from_str(&out).unwrap()
+}
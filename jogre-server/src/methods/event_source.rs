@@ -0,0 +1,413 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+    Extension,
+};
+use futures::{stream, Stream};
+use jmap_proto::{
+    common::Id,
+    endpoints::object::ObjectState,
+    events::{state_change::StateChange, Event as _},
+};
+use oxide_auth::primitives::grant::Grant;
+use serde::Deserialize;
+use tokio::sync::{
+    broadcast::{
+        error::{RecvError, TryRecvError},
+        Receiver,
+    },
+    mpsc::{self, error::SendTimeoutError},
+};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+use crate::{
+    context::{
+        events::{DomainEvent, Missed, TimestampedEvent},
+        Context,
+    },
+    extensions::ExtensionRegistry,
+    layers::auth_required::user_id,
+    methods::variables::{self, InvalidVariable},
+    store::{self, AccountProvider, ObjectProvider},
+};
+
+/// How many events may be waiting to be written to a client before no more
+/// are queued for it.
+const OUTBOUND_BUFFER: usize = 16;
+
+/// How long a client may leave its buffer full before it's disconnected.
+/// Clients resync when they reconnect, so dropping one loses nothing.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sent by clients reconnecting, with the id of the last event they were
+/// sent. Ids from before a restart can't be resumed from, so such clients
+/// are immediately sent everything as changed.
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// The variables of the event source's URI template, each parsed by
+/// [`variables`]. Clients leaving one out get the default noted.
+#[derive(Deserialize)]
+pub struct EventSourceQuery {
+    /// The data types the client wants to be notified about, comma separated,
+    /// or `*` for all of them. Defaults to `*`.
+    types: Option<String>,
+    /// Either `state` to close the stream after the first `StateChange`, or
+    /// `no` to leave it open. Defaults to `no`.
+    closeafter: Option<String>,
+    /// How often, in seconds, to ping the client while there are no changes
+    /// to send, or `0` to never ping. Defaults to `0`.
+    ping: Option<String>,
+}
+
+/// Pushes a `StateChange` to the client whenever data visible to them
+/// changes, as described in RFC 8620 section 7.3.
+pub async fn handle(
+    State(context): State<Arc<Context>>,
+    Extension(grant): Extension<Grant>,
+    headers: HeaderMap,
+    Query(query): Query<EventSourceQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, InvalidVariable> {
+    let types = query
+        .types
+        .as_deref()
+        .map_or_else(|| Ok(ExtensionRegistry::data_types()), variables::types)?;
+    let close_after_state = query
+        .closeafter
+        .as_deref()
+        .map_or(Ok(false), variables::close_after)?;
+    let ping = query.ping.as_deref().map_or(Ok(None), variables::ping)?;
+
+    // subscribed to before anything is read from the store, so no change
+    // made in between is missed, and clients that are reconnecting are sent
+    // the changes made while they were away
+    let (receiver, missed) = match headers.get(LAST_EVENT_ID).and_then(|v| v.to_str().ok()) {
+        Some(event_id) => context.events.subscribe_since(event_id),
+        None => (context.events.subscribe(), Missed::Events(Vec::new())),
+    };
+
+    let (replay, resync) = match missed {
+        Missed::Events(events) => (events.into(), false),
+        Missed::Unknown => (VecDeque::new(), true),
+    };
+
+    let subscriber = Subscriber {
+        user_id: user_id(&grant),
+        accounts: HashSet::new(),
+        types,
+        close_after_state,
+        context,
+        receiver,
+        replay,
+        resync,
+        last_id: 0,
+    };
+
+    let (sender, outbound) = mpsc::channel(OUTBOUND_BUFFER);
+    tokio::spawn(forward(subscriber, sender));
+
+    let stream = stream::unfold(outbound, |mut outbound| async move {
+        let event = outbound.recv().await?;
+        Some((Ok(event), outbound))
+    });
+
+    let mut sse = Sse::new(stream);
+
+    if let Some(ping) = ping {
+        sse = sse.keep_alive(KeepAlive::new().interval(ping).text("ping"));
+    }
+
+    Ok(sse)
+}
+
+/// Queues changes for the client until it goes away, the stream is closed
+/// after the first change, or the client stops reading for longer than
+/// [`DRAIN_TIMEOUT`].
+async fn forward(mut subscriber: Subscriber, sender: mpsc::Sender<Event>) {
+    loop {
+        let change = tokio::select! {
+            change = subscriber.next() => change,
+            () = sender.closed() => return,
+        };
+
+        let Some(change) = change else {
+            return;
+        };
+
+        let event = Event::default()
+            .event("state")
+            .id(subscriber.context.events.event_id(subscriber.last_id))
+            .json_data(change.into_event())
+            .unwrap();
+
+        match sender.send_timeout(event, DRAIN_TIMEOUT).await {
+            Ok(()) => {}
+            Err(SendTimeoutError::Timeout(_)) => {
+                debug!(user = %subscriber.user_id, "Disconnecting client not reading events");
+                return;
+            }
+            Err(SendTimeoutError::Closed(_)) => return,
+        }
+
+        if subscriber.close_after_state {
+            return;
+        }
+    }
+}
+
+/// A client listening for changes.
+pub(super) struct Subscriber {
+    user_id: Uuid,
+    /// The accounts the user has access to, refreshed whenever the user's own
+    /// state changes.
+    accounts: HashSet<Uuid>,
+    /// The data types the client wants to be notified about.
+    types: Vec<&'static str>,
+    close_after_state: bool,
+    context: Arc<Context>,
+    receiver: Receiver<Arc<TimestampedEvent>>,
+    /// Events the client missed while disconnected, to be sent before any
+    /// from the receiver.
+    replay: VecDeque<Arc<TimestampedEvent>>,
+    /// Whether the client missed more while disconnected than can be
+    /// replayed, so everything has to be reported as changed.
+    resync: bool,
+    /// The id of the last event seen, which the client is given to resume
+    /// from if it reconnects.
+    last_id: u64,
+}
+
+impl Subscriber {
+    /// Listens for changes to the data types made from now on.
+    pub(super) fn new(context: Arc<Context>, user_id: Uuid, types: Vec<&'static str>) -> Self {
+        Self {
+            user_id,
+            accounts: HashSet::new(),
+            types,
+            close_after_state: false,
+            receiver: context.events.subscribe(),
+            context,
+            replay: VecDeque::new(),
+            resync: false,
+            last_id: 0,
+        }
+    }
+
+    /// Waits for the next change visible to the user, returning `None` once
+    /// no more events will be published or if the store fails.
+    ///
+    /// Changes already published by the time the first is seen are sent
+    /// along with it, so that a `/set` changing several objects or types
+    /// reaches the client as a single `StateChange`.
+    pub(super) async fn next(&mut self) -> Option<StateChange<'static>> {
+        self.next_or_error()
+            .await
+            .map_err(|error| error!(%error, "Store failed while handling request"))
+            .ok()
+            .flatten()
+    }
+
+    async fn next_or_error(&mut self) -> Result<Option<StateChange<'static>>, store::Error> {
+        if self.accounts.is_empty() {
+            self.refresh_accounts().await?;
+        }
+
+        if std::mem::take(&mut self.resync) {
+            return self.everything_missed().await.map(Some);
+        }
+
+        let mut change: Option<StateChange<'static>> = None;
+
+        loop {
+            let event = if let Some(event) = self.replay.pop_front() {
+                event
+            } else if change.is_some() {
+                match self.receiver.try_recv() {
+                    Ok(event) => event,
+                    // send what's been gathered so far, then everything
+                    Err(TryRecvError::Lagged(_)) => {
+                        self.resync = true;
+                        return Ok(change);
+                    }
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => return Ok(change),
+                }
+            } else {
+                match self.receiver.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => return self.everything_missed().await.map(Some),
+                    Err(RecvError::Closed) => return Ok(None),
+                }
+            };
+
+            self.last_id = self.last_id.max(event.id);
+
+            match &event.event {
+                DomainEvent::UserStateChanged { user_id, .. } if *user_id == self.user_id => {
+                    return self.everything_changed().await.map(Some);
+                }
+                DomainEvent::UserStateChanged { .. } => {}
+                DomainEvent::ObjectsChanged {
+                    account_id,
+                    data_type,
+                    new_state,
+                } => {
+                    if self.accounts.contains(account_id) && self.types.contains(&&**data_type) {
+                        // events are published in order, so later states
+                        // replace earlier ones
+                        change
+                            .get_or_insert_with(|| StateChange {
+                                changed: HashMap::new(),
+                            })
+                            .changed
+                            .entry(Id(account_id.to_string().into()))
+                            .or_default()
+                            .insert(
+                                data_type.to_string().into(),
+                                ObjectState(new_state.clone().into()),
+                            );
+                    }
+                }
+            }
+        }
+    }
+
+    async fn refresh_accounts(&mut self) -> Result<(), store::Error> {
+        self.accounts = self
+            .context
+            .store
+            .get_accounts_for_user(self.user_id)
+            .await?
+            .into_iter()
+            .map(|account| account.id)
+            .collect();
+
+        Ok(())
+    }
+
+    /// Reports everything the user can see as changed, as changes to it were
+    /// missed.
+    async fn everything_missed(&mut self) -> Result<StateChange<'static>, store::Error> {
+        // taken before the state is read, so a client resuming from here
+        // misses nothing made after it
+        self.last_id = self.context.events.last_id();
+
+        self.everything_changed().await
+    }
+
+    /// Reports every data type the client is interested in within every
+    /// account the user has access to as being at its current state, which
+    /// is the same state `/get` returns for it.
+    async fn everything_changed(&mut self) -> Result<StateChange<'static>, store::Error> {
+        self.refresh_accounts().await?;
+
+        let mut changed = HashMap::new();
+
+        for account_id in &self.accounts {
+            let mut states = HashMap::new();
+
+            for data_type in &self.types {
+                let state = self.context.store.state_for(*account_id, data_type).await?;
+
+                states.insert((*data_type).into(), state);
+            }
+
+            changed.insert(Id(account_id.to_string().into()), states);
+        }
+
+        Ok(StateChange { changed })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use axum::{body::HttpBody, http::HeaderValue, response::IntoResponse};
+
+    use super::*;
+    use crate::{
+        context::events::REPLAY_CAPACITY, extensions::tests::user,
+        layers::auth_required::tests::grant,
+    };
+
+    /// Connects as the user, resuming from the event id if given.
+    pub(crate) async fn connect(
+        context: &Arc<Context>,
+        user_id: Uuid,
+        last_event_id: Option<&str>,
+    ) -> axum::body::BoxBody {
+        let mut headers = HeaderMap::new();
+
+        if let Some(last_event_id) = last_event_id {
+            headers.insert(LAST_EVENT_ID, HeaderValue::from_str(last_event_id).unwrap());
+        }
+
+        let Ok(sse) = handle(
+            State(context.clone()),
+            Extension(grant(user_id)),
+            headers,
+            Query(EventSourceQuery {
+                types: None,
+                closeafter: None,
+                ping: None,
+            }),
+        )
+        .await
+        else {
+            panic!("event source refused");
+        };
+
+        sse.into_response().into_body()
+    }
+
+    /// Waits briefly for the next `StateChange` sent down the stream.
+    pub(crate) async fn next_change(body: &mut axum::body::BoxBody) -> Option<serde_json::Value> {
+        let chunk = tokio::time::timeout(Duration::from_millis(500), body.data())
+            .await
+            .ok()??
+            .unwrap();
+        let chunk = std::str::from_utf8(&chunk).unwrap();
+        let data = chunk.lines().find_map(|line| line.strip_prefix("data:"))?;
+
+        Some(serde_json::from_str(data).unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn outdated_last_event_id_is_sent_everything_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let first_id = context.events.event_id(context.events.last_id());
+
+        // pushes the first event out of the replay buffer
+        for _ in 0..=REPLAY_CAPACITY {
+            context.events.publish(DomainEvent::UserStateChanged {
+                user_id: Uuid::new_v4(),
+                new_state: 0,
+            });
+        }
+
+        let current_id = context.events.event_id(context.events.last_id());
+        let from_another_run = "0-1";
+
+        for last_event_id in [first_id.as_str(), from_another_run] {
+            let mut body = connect(&context, user_id, Some(last_event_id)).await;
+            let change = next_change(&mut body).await.unwrap();
+
+            assert_eq!(change["@type"], "StateChange");
+            assert!(change["changed"][account_id.to_string()]["AddressBook"].is_string());
+            assert!(change["changed"][account_id.to_string()]["ContactCard"].is_string());
+        }
+
+        // nothing was missed since the latest event, so nothing is sent
+        let mut body = connect(&context, user_id, Some(&current_id)).await;
+        assert!(next_change(&mut body).await.is_none());
+    }
+}
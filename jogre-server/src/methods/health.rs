@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+
+use crate::context::Context;
+
+/// Reports whether the instance is able to serve requests, so it can be taken
+/// out of rotation while its store is failing.
+pub async fn readyz(State(context): State<Arc<Context>>) -> (StatusCode, &'static str) {
+    if context.store.is_healthy() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "store unhealthy")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, http::Request};
+
+    use super::*;
+    use crate::{
+        methods::tests::{body, send},
+        store::tests::stop_writes,
+    };
+
+    /// Asks the instance whether it's ready.
+    async fn readyz(context: &Arc<Context>) -> (StatusCode, String) {
+        let response = send(
+            context,
+            Request::get("/readyz").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let status = response.status();
+
+        (
+            status,
+            String::from_utf8(body(response).await.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn not_ready_once_the_store_refuses_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+
+        assert_eq!(readyz(&context).await, (StatusCode::OK, "ok".to_string()));
+
+        stop_writes(&context.store, dir.path());
+
+        let not_ready = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let (status, body) = readyz(&context).await;
+                if status != StatusCode::OK {
+                    return (status, body);
+                }
+
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("still ready");
+
+        assert_eq!(
+            not_ready,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "store unhealthy".to_string()
+            )
+        );
+    }
+}
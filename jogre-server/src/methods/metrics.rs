@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+
+use crate::context::Context;
+
+/// Renders all recorded metrics in the Prometheus exposition format.
+pub async fn get(State(context): State<Arc<Context>>) -> String {
+    context.metrics.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+
+    use super::*;
+    use crate::{
+        extensions::tests::user,
+        methods::{
+            api::tests::process_json,
+            tests::{body, send},
+        },
+    };
+
+    /// How many calls to the method have had the outcome, as scraped from
+    /// `/metrics`.
+    async fn calls(context: &Arc<Context>, method: &str, outcome: &str) -> u64 {
+        let response = send(
+            context,
+            Request::get("/metrics").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let metrics = String::from_utf8(body(response).await.to_vec()).unwrap();
+        let method = format!("method=\"{method}\"");
+        let outcome = format!("outcome=\"{outcome}\"");
+
+        metrics
+            .lines()
+            .filter(|line| line.starts_with("jmap_method_calls_total{"))
+            .find(|line| line.contains(&method) && line.contains(&outcome))
+            .map_or(0, |line| line.rsplit(' ').next().unwrap().parse().unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn method_calls_are_counted_by_outcome() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        // other tests share the recorder, so only these outcomes, which no
+        // other test produces, are compared
+        let counted = || async {
+            (
+                calls(&context, "AddressBook/changes", "cannotCalculateChanges").await,
+                calls(&context, "unknown", "unknownMethod").await,
+            )
+        };
+        let (changes, unknown) = counted().await;
+
+        for _ in 0..2 {
+            process_json(
+                &context,
+                user_id,
+                &serde_json::json!({
+                    "using": [],
+                    "methodCalls": [
+                        ["AddressBook/changes", {"accountId": account_id, "sinceState": "bogus"}, "0"],
+                        ["Unregistered/method", {}, "1"],
+                    ]
+                }),
+            )
+            .await;
+        }
+
+        assert_eq!(counted().await, (changes + 2, unknown + 2));
+    }
+}
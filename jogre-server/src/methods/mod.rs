@@ -1,32 +1,215 @@
+mod account;
+mod admin;
 mod api;
+mod debug;
+mod download;
+mod event_source;
+mod health;
+mod metrics;
 mod oauth;
+pub(crate) mod routes;
 mod session;
+mod upload;
+mod variables;
+mod websocket;
 
 use std::sync::Arc;
 
 use axum::{
-    routing::{any, get},
+    extract::DefaultBodyLimit,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{any, delete, get, post},
     Router,
 };
 use tower::layer::layer_fn;
 use tower_cookies::CookieManagerLayer;
+use tower_http::compression::{
+    predicate::{Predicate, SizeAbove},
+    CompressionLayer, DefaultPredicate,
+};
+use tracing::error;
 
 use crate::{
     context::Context,
-    layers::{auth_required::auth_required_middleware, logger::LoggingMiddleware},
+    layers::{
+        auth_required::auth_required_middleware,
+        csrf::csrf_middleware,
+        logger::{GenericError, LoggingMiddleware},
+        read_only::read_only_middleware,
+    },
 };
 
 pub fn router(context: Arc<Context>) -> Router {
-    Router::new()
+    let mut router = Router::new()
         .route("/.well-known/jmap", get(session::get))
-        .route("/api", any(api::handle))
+        .route(
+            &routes::API.path(),
+            // the handler enforces `maxSizeRequest` itself, so changes to it
+            // apply on reload
+            any(api::handle).layer(DefaultBodyLimit::disable()),
+        )
+        .route(&routes::DOWNLOAD.path(), get(download::handle))
+        .route(&routes::EVENT_SOURCE.path(), get(event_source::handle))
+        .route(&routes::WEBSOCKET.path(), get(websocket::handle))
+        .route(
+            &routes::UPLOAD.path(),
+            post(upload::handle).layer(axum::middleware::from_fn_with_state(
+                context.clone(),
+                read_only_middleware,
+            )),
+        )
+        .route(
+            "/account/password",
+            post(account::change_password).layer(axum::middleware::from_fn_with_state(
+                context.clone(),
+                read_only_middleware,
+            )),
+        )
+        .route("/admin/users", get(admin::list_users))
+        .route(
+            "/admin/users/:id",
+            delete(admin::delete_user).layer(axum::middleware::from_fn_with_state(
+                context.clone(),
+                read_only_middleware,
+            )),
+        );
+
+    if context.debug_events.enabled {
+        router = router.route("/debug/events", get(debug::events));
+    }
+
+    router
         // only apply auth requirement on endpoints above
         .layer(axum::middleware::from_fn_with_state(
             context.clone(),
             auth_required_middleware,
         ))
-        .nest("/oauth", oauth::router())
+        .layer(axum::middleware::from_fn_with_state(
+            context.clone(),
+            csrf_middleware,
+        ))
+        .nest(
+            "/oauth",
+            oauth::router(context.clone()).layer(axum::middleware::from_fn_with_state(
+                context.clone(),
+                read_only_middleware,
+            )),
+        )
+        .route("/metrics", get(metrics::get))
+        .route("/readyz", get(health::readyz))
         .layer(layer_fn(LoggingMiddleware))
         .layer(CookieManagerLayer::new())
+        // the default predicate already leaves event streams and images
+        // alone, the threshold keeps small responses like `Core/echo` from
+        // being compressed for no gain
+        .layer(CompressionLayer::new().compress_when(
+            DefaultPredicate::new().and(SizeAbove::new(context.compression_min_size)),
+        ))
         .with_state(context)
 }
+
+/// Logs a failure to reach the store, which the client is only told about
+/// as an internal server error. The error is attached to the response for
+/// the logging middleware to include in the request's log line.
+fn store_failure(error: impl GenericError + 'static) -> Response {
+    error!(%error, "Store failed while handling request");
+
+    let mut response = StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    response
+        .extensions_mut()
+        .insert(Box::new(error) as Box<dyn GenericError>);
+    response
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use axum::{body::Body, http::header};
+    use tempfile::TempDir;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        extensions::tests::user,
+        methods::oauth::tests::{access_token, register_clients},
+    };
+
+    /// Sends the request through the server's full stack of routes and
+    /// middleware.
+    pub(crate) async fn send(
+        context: &Arc<Context>,
+        request: axum::http::Request<Body>,
+    ) -> Response {
+        router(context.clone()).oneshot(request).await.unwrap()
+    }
+
+    /// Reads the whole of a response's body.
+    pub(crate) async fn body(response: Response) -> axum::body::Bytes {
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+    }
+
+    /// Echoes a string of the given length as the user holding the token,
+    /// accepting a gzipped response, and returns how the response was
+    /// encoded.
+    async fn echo_encoding(
+        context: &Arc<Context>,
+        access_token: &str,
+        len: usize,
+    ) -> Option<String> {
+        let request = serde_json::json!({
+            "using": [],
+            "methodCalls": [["Core/echo", {"padding": "x".repeat(len)}, "0"]]
+        });
+
+        let request = axum::http::Request::post("/api")
+            .header(header::AUTHORIZATION, format!("Bearer {access_token}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::from(request.to_string()))
+            .unwrap();
+
+        let response = send(context, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|encoding| encoding.to_str().unwrap().to_string())
+    }
+
+    /// A context compressing responses above the threshold, and a token
+    /// for `alice` to make requests to it with. The directory holding its
+    /// store has to outlive it.
+    async fn logged_in(compression_min_size: u16) -> (TempDir, Arc<Context>, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut context = Context::for_tests(dir.path());
+        // read when the router is built
+        context.compression_min_size = compression_min_size;
+        register_clients(&context);
+        user(&context, "alice").await;
+
+        let context = Arc::new(context);
+        let access_token = access_token(&context).await;
+
+        (dir, context, access_token)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn only_responses_above_the_threshold_are_compressed() {
+        let (_dir, context, token) = logged_in(1024).await;
+
+        assert_eq!(echo_encoding(&context, &token, 100).await, None);
+        assert_eq!(
+            echo_encoding(&context, &token, 2000).await.as_deref(),
+            Some("gzip")
+        );
+
+        let (_dir, context, token) = logged_in(4096).await;
+
+        assert_eq!(echo_encoding(&context, &token, 2000).await, None);
+        assert_eq!(
+            echo_encoding(&context, &token, 5000).await.as_deref(),
+            Some("gzip")
+        );
+    }
+}
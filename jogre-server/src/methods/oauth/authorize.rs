@@ -16,3 +16,53 @@ pub async fn handle(
         .await
         .map_err(endpoint::Error::pack)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+
+    use super::*;
+    use crate::methods::{
+        oauth::tests::{form, register_clients, REDIRECT_URI},
+        tests::send,
+    };
+
+    /// Opens the login form served from `base_url`, returning the CSRF
+    /// cookie it sets.
+    async fn login_form_cookie(base_url: &str) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests_at(dir.path(), base_url));
+        register_clients(&context);
+
+        let query = form(&[
+            ("response_type", "code"),
+            ("client_id", "confidential"),
+            ("redirect_uri", REDIRECT_URI),
+        ]);
+        let request = Request::get(format!("/oauth/authorize?{query}"))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = send(&context, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn csrf_cookie_is_secure_when_served_over_https() {
+        let cookie = login_form_cookie("https://jmap.example").await;
+        assert!(cookie.starts_with("csrf_token="));
+        assert!(cookie.split("; ").any(|attribute| attribute == "Secure"));
+
+        let cookie = login_form_cookie("http://127.0.0.1:8888").await;
+        assert!(cookie.starts_with("csrf_token="));
+        assert!(!cookie.split("; ").any(|attribute| attribute == "Secure"));
+    }
+}
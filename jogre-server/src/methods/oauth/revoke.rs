@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, Form};
+use serde::Deserialize;
+
+use crate::context::{oauth2::RevokeError, Context};
+
+#[derive(Deserialize)]
+pub struct RevokeRequest {
+    token: String,
+    token_type_hint: Option<String>,
+    client_id: String,
+    client_secret: Option<String>,
+}
+
+/// Revokes an access or refresh token, as defined by [RFC 7009].
+///
+/// [RFC 7009]: https://datatracker.ietf.org/doc/html/rfc7009
+pub async fn handle(
+    State(context): State<Arc<Context>>,
+    Form(request): Form<RevokeRequest>,
+) -> StatusCode {
+    let result = context
+        .oauth2
+        .revoke(
+            &request.client_id,
+            request.client_secret.as_deref(),
+            &request.token,
+            request.token_type_hint.as_deref(),
+        )
+        .await;
+
+    match result {
+        Ok(()) => StatusCode::OK,
+        Err(RevokeError::InvalidClient) => StatusCode::UNAUTHORIZED,
+        Err(RevokeError::Store) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
+
+    use super::*;
+    use crate::{
+        extensions::tests::user,
+        methods::{
+            oauth::tests::{access_token, form, register_clients},
+            tests::send,
+        },
+    };
+
+    /// Fetches the session with the access token.
+    async fn session(context: &Arc<Context>, access_token: &str) -> StatusCode {
+        let request = Request::get("/.well-known/jmap")
+            .header(header::AUTHORIZATION, format!("Bearer {access_token}"))
+            .body(Body::empty())
+            .unwrap();
+
+        send(context, request).await.status()
+    }
+
+    /// Revokes the token as the confidential client.
+    async fn revoke(context: &Arc<Context>, token: &str, client_secret: &str) -> StatusCode {
+        let request = Request::post("/oauth/revoke")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form(&[
+                ("token", token),
+                ("client_id", "confidential"),
+                ("client_secret", client_secret),
+            ])))
+            .unwrap();
+
+        send(context, request).await.status()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn revoked_token_is_rejected_on_the_next_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+        register_clients(&context);
+        user(&context, "alice").await;
+
+        let access_token = &access_token(&context).await;
+
+        assert_eq!(session(&context, access_token).await, StatusCode::OK);
+
+        // the client has to prove who it is before anything's revoked
+        assert_eq!(
+            revoke(&context, access_token, "wrong").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(session(&context, access_token).await, StatusCode::OK);
+
+        assert_eq!(
+            revoke(&context, access_token, "secret").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            session(&context, access_token).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // revoking again reveals nothing about the token
+        assert_eq!(
+            revoke(&context, access_token, "secret").await,
+            StatusCode::OK
+        );
+    }
+}
@@ -16,3 +16,55 @@ pub async fn handle(
         .await
         .map_err(endpoint::Error::pack)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+    use crate::{
+        extensions::tests::user,
+        methods::{
+            oauth::tests::{authorization_code, exchange, register_clients, CHALLENGE, VERIFIER},
+            tests::body,
+        },
+    };
+
+    /// Exchanges a code issued for the challenge with the verifier as the
+    /// public client, returning the status and error, if any.
+    async fn redeem(
+        context: &Arc<Context>,
+        verifier: Option<&str>,
+    ) -> (StatusCode, Option<String>) {
+        let code = authorization_code(context, "public", Some(CHALLENGE)).await;
+        let response = exchange(context, "public", &code, verifier).await;
+        let status = response.status();
+
+        let body: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        (status, body["error"].as_str().map(ToString::to_string))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn code_is_only_exchanged_with_its_verifier() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+        register_clients(&context);
+        user(&context, "alice").await;
+
+        let invalid_grant = (StatusCode::BAD_REQUEST, Some("invalid_grant".to_string()));
+
+        assert_eq!(
+            redeem(
+                &context,
+                Some("a-verifier-that-does-not-match-the-challenge")
+            )
+            .await,
+            invalid_grant
+        );
+        assert_eq!(redeem(&context, None).await, invalid_grant);
+        assert_eq!(
+            redeem(&context, Some(VERIFIER)).await,
+            (StatusCode::OK, None)
+        );
+    }
+}
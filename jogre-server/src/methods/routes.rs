@@ -0,0 +1,101 @@
+//! Paths of the endpoints advertised to clients in the session object.
+//!
+//! The router and the session both build their paths from the definitions
+//! here, so the URLs handed to clients can't drift from the routes that are
+//! actually registered.
+
+use std::fmt::Write;
+
+use url::Url;
+
+/// A single segment of a route's path.
+pub enum Segment {
+    /// A segment matched exactly.
+    Literal(&'static str),
+    /// A segment filled in by the client, by the name it is given in the URI
+    /// template.
+    Variable(&'static str),
+}
+
+pub struct Route {
+    path: &'static [Segment],
+    /// Query parameters the client is expected to fill in, as pairs of the
+    /// parameter and the name of the variable in the URI template.
+    query: &'static [(&'static str, &'static str)],
+}
+
+pub const API: Route = Route {
+    path: &[Segment::Literal("api")],
+    query: &[],
+};
+
+pub const DOWNLOAD: Route = Route {
+    path: &[
+        Segment::Literal("download"),
+        Segment::Variable("accountId"),
+        Segment::Variable("blobId"),
+        Segment::Variable("name"),
+    ],
+    query: &[("accept", "type")],
+};
+
+pub const UPLOAD: Route = Route {
+    path: &[Segment::Literal("upload"), Segment::Variable("accountId")],
+    query: &[],
+};
+
+pub const EVENT_SOURCE: Route = Route {
+    path: &[Segment::Literal("eventsource")],
+    query: &[
+        ("types", "types"),
+        ("closeafter", "closeafter"),
+        ("ping", "ping"),
+    ],
+};
+
+pub const WEBSOCKET: Route = Route {
+    path: &[Segment::Literal("jmap"), Segment::Literal("ws")],
+    query: &[],
+};
+
+impl Route {
+    /// The path the route is registered at in the router, eg.
+    /// `/upload/:accountId`.
+    pub fn path(&self) -> String {
+        let mut out = String::new();
+
+        for segment in self.path {
+            match segment {
+                Segment::Literal(v) => write!(out, "/{v}"),
+                Segment::Variable(v) => write!(out, "/:{v}"),
+            }
+            .unwrap();
+        }
+
+        out
+    }
+
+    /// The URI template (RFC 6570) advertised to clients for the route, eg.
+    /// `https://example.com/upload/{accountId}`.
+    pub fn uri_template(&self, base_url: &Url) -> String {
+        let mut out = base_url.join("./").unwrap().to_string();
+
+        for (i, segment) in self.path.iter().enumerate() {
+            if i > 0 {
+                out.push('/');
+            }
+
+            match segment {
+                Segment::Literal(v) => out.push_str(v),
+                Segment::Variable(v) => write!(out, "{{{v}}}").unwrap(),
+            }
+        }
+
+        for (i, (parameter, variable)) in self.query.iter().enumerate() {
+            let separator = if i == 0 { '?' } else { '&' };
+            write!(out, "{separator}{parameter}={{{variable}}}").unwrap();
+        }
+
+        out
+    }
+}
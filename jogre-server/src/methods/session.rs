@@ -1,18 +1,37 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     sync::{Arc, OnceLock},
 };
 
-use axum::{extract::State, Extension, Json};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
 use jmap_proto::{
     common::{Id, SessionState},
-    endpoints::session::{Account, AccountCapabilities, Session},
+    endpoints::session::{Account, Session},
+    errors::RequestError,
 };
 use oxide_auth::primitives::grant::Grant;
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+use uuid::Uuid;
 
 use crate::{
     context::Context,
-    store::{AccountProvider, UserProvider},
+    extensions::{contacts::ContactCard, sharing, JmapExtension},
+    layers::auth_required::user_id,
+    methods::{
+        api::request_error,
+        routes::{self, Route},
+        store_failure,
+    },
+    store,
+    store::{AccountAccessLevel, AccountProvider, UserProvider},
 };
 
 static API_URL: OnceLock<Box<str>> = OnceLock::new();
@@ -20,92 +39,309 @@ static DOWNLOAD_URL: OnceLock<Box<str>> = OnceLock::new();
 static UPLOAD_URL: OnceLock<Box<str>> = OnceLock::new();
 static EVENT_SOURCE_URL: OnceLock<Box<str>> = OnceLock::new();
 
+/// The session changes whenever an account is added or removed, so it must
+/// never be served from a cache.
+const CACHE_CONTROL: &str = "no-cache, no-store, must-revalidate";
+
+#[derive(Deserialize)]
+pub struct SessionQuery {
+    /// A jogre extension to RFC 8620: the top-level properties to return,
+    /// comma separated, so that clients polling only for `state` needn't be
+    /// sent every account. `state` is always returned. Defaults to every
+    /// property.
+    properties: Option<String>,
+}
+
 pub async fn get(
     State(context): State<Arc<Context>>,
     Extension(grant): Extension<Grant>,
-) -> Json<Session<'static>> {
-    let username = grant.owner_id;
+    Query(query): Query<SessionQuery>,
+) -> Result<Response, Response> {
+    let properties = query
+        .properties
+        .as_deref()
+        .map(properties)
+        .transpose()
+        .map_err(|property| {
+            request_error(&RequestError::invalid_variable(
+                "properties",
+                format!("unknown session property `{property}`"),
+            ))
+        })?;
 
-    let user = context
+    let Some(user) = context
         .store
-        .get_by_username(&username)
+        .get_by_id(user_id(&grant))
         .await
-        .unwrap()
-        .unwrap();
+        .map_err(store_failure)?
+    else {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    };
 
-    let (accounts, user_seq_number) = tokio::join!(
+    let (accounts, user_seq_number, principals_account) = tokio::try_join!(
         async {
-            context
+            let access_levels = context
+                .store
+                .get_access_levels_for_user(user.id)
+                .await
+                .map_err(store_failure)?;
+
+            let mut accounts = HashMap::new();
+
+            for acc in context
                 .store
                 .get_accounts_for_user(user.id)
                 .await
-                .unwrap()
-                .into_iter()
-                .map(|acc| {
-                    (
-                        Id(acc.id.to_string().into()),
-                        Account {
-                            name: acc.name.into(),
-                            is_personal: acc.is_personal,
-                            is_read_only: acc.is_read_only,
-                            account_capabilities: AccountCapabilities {},
-                        },
-                    )
-                })
-                .collect()
+                .map_err(store_failure)?
+            {
+                let access = access_levels.get(&acc.id).copied();
+                let name = account_name(&context, user.id, &acc, access)
+                    .await
+                    .map_err(store_failure)?;
+
+                accounts.insert(
+                    Id(acc.id.to_string().into()),
+                    Account {
+                        name: name.into(),
+                        is_personal: acc.is_personal,
+                        is_read_only: acc.is_read_only_for(access),
+                        account_capabilities: context
+                            .extension_registry
+                            .build_account_capabilities(user.id, &acc),
+                    },
+                );
+            }
+
+            Ok::<_, Response>(accounts)
         },
         async {
             context
                 .store
                 .fetch_seq_number_for_user(user.id)
                 .await
-                .unwrap()
+                .map_err(store_failure)
+        },
+        async {
+            context
+                .store
+                .get_principals_account()
+                .await
+                .map_err(store_failure)
         }
-    );
+    )?;
 
-    Json(Session {
+    let primary_accounts = principals_account
+        .map(|account| Id(account.to_string().into()))
+        .filter(|account| accounts.contains_key(account))
+        .map(|account| (Cow::Borrowed(sharing::Principals::EXTENSION), account))
+        .into_iter()
+        .collect();
+
+    let session = Session {
         capabilities: context
             .extension_registry
             .build_session_capabilities(user.id),
         accounts,
-        primary_accounts: HashMap::default(),
-        username: username.into(),
-        api_url: API_URL
-            .get_or_init(|| {
-                context
-                    .base_url
-                    .join("api/")
-                    .unwrap()
-                    .to_string()
-                    .into_boxed_str()
-            })
-            .as_ref()
-            .into(),
-        download_url: DOWNLOAD_URL
-            .get_or_init(|| {
-                let base = context.base_url.join("download/").unwrap();
-                format!("{base}{{accountId}}/{{blobId}}/{{name}}?accept={{type}}").into_boxed_str()
-            })
-            .as_ref()
-            .into(),
-        upload_url: UPLOAD_URL
-            .get_or_init(|| {
-                let base = context.base_url.join("upload/").unwrap();
-                format!("{base}{{accountId}}/").into_boxed_str()
-            })
-            .as_ref()
-            .into(),
-        event_source_url: EVENT_SOURCE_URL
-            .get_or_init(|| {
-                context
-                    .base_url
-                    .join("eventsource/?types={types}&closeafter={closeafter}&ping={ping}")
-                    .unwrap()
-                    .to_string()
-                    .into_boxed_str()
-            })
-            .as_ref()
+        primary_accounts,
+        username: user.username.into(),
+        api_url: uri_template(&API_URL, &routes::API, &context.base_url).into(),
+        download_url: uri_template(&DOWNLOAD_URL, &routes::DOWNLOAD, &context.base_url).into(),
+        upload_url: uri_template(&UPLOAD_URL, &routes::UPLOAD, &context.base_url).into(),
+        event_source_url: uri_template(&EVENT_SOURCE_URL, &routes::EVENT_SOURCE, &context.base_url)
             .into(),
         state: SessionState(user_seq_number.to_string().into()),
+    };
+
+    let headers = [(header::CACHE_CONTROL, CACHE_CONTROL)];
+
+    Ok(match properties {
+        Some(properties) => (headers, Json(project(&session, &properties))).into_response(),
+        None => (headers, Json(session)).into_response(),
     })
 }
+
+/// The URI template of a route, built on first use as the base URL can't
+/// change while running.
+fn uri_template(cell: &'static OnceLock<Box<str>>, route: &Route, base_url: &Url) -> &'static str {
+    cell.get_or_init(|| route.uri_template(base_url).into_boxed_str())
+}
+
+/// Parses the `properties` the session is to be projected to, returning the
+/// first the session doesn't have as the error.
+fn properties(value: &str) -> Result<Vec<&str>, &str> {
+    value
+        .split(',')
+        .map(|property| {
+            if Session::PROPERTIES.contains(&property) {
+                Ok(property)
+            } else {
+                Err(property)
+            }
+        })
+        .collect()
+}
+
+/// Serialises the session with only the given properties, along with
+/// `state` so clients can always tell whether they're missing changes.
+fn project(session: &Session<'_>, properties: &[&str]) -> Value {
+    let mut session = serde_json::to_value(session).unwrap();
+
+    if let Value::Object(session) = &mut session {
+        session
+            .retain(|property, _| property == "state" || properties.contains(&property.as_str()));
+    }
+
+    session
+}
+
+/// The name to show the user for an account. The name of a personal account
+/// is often just the username, so the full name from the user's own card is
+/// preferred where they have one in their own personal account.
+async fn account_name(
+    context: &Context,
+    user: Uuid,
+    account: &store::Account,
+    access: Option<AccountAccessLevel>,
+) -> Result<String, store::Error> {
+    if account.is_personal && access == Some(AccountAccessLevel::Owner) {
+        if let Some(full_name) =
+            ContactCard::own_full_name(&context.store, account.id, user).await?
+        {
+            return Ok(full_name);
+        }
+    }
+
+    Ok(account.name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        extensions::{
+            contacts::{own_card_uid, tests::create_card},
+            tests::user,
+        },
+        layers::auth_required::tests::grant,
+        store::{AccountAccessLevel, AccountProvider},
+    };
+
+    /// Fetches the session as the user, projected to the properties if given.
+    async fn fetch(context: Arc<Context>, user_id: Uuid, properties: Option<&str>) -> Response {
+        get(
+            State(context),
+            Extension(grant(user_id)),
+            Query(SessionQuery {
+                properties: properties.map(ToString::to_string),
+            }),
+        )
+        .await
+        .unwrap_or_else(|response| response)
+    }
+
+    /// Reads a response body as JSON.
+    async fn json(response: Response) -> Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Fetches the session as the user.
+    async fn session(context: Arc<Context>, user_id: Uuid) -> Value {
+        json(fetch(context, user_id, None).await).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn personal_account_is_named_after_own_card() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        let card = serde_json::json!({"uid": own_card_uid(user_id), "fullName": "Alice Liddell"});
+        create_card(&context, user_id, account_id, card).await;
+
+        let session = session(Arc::new(context), user_id).await;
+
+        assert_eq!(
+            session["accounts"][account_id.to_string()]["name"],
+            "Alice Liddell"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn personal_account_without_own_card_keeps_its_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, account_id) = user(&context, "alice").await;
+
+        // a contact's card doesn't name the account
+        let card = serde_json::json!({"uid": "urn:uuid:1", "fullName": "Bob"});
+        create_card(&context, user_id, account_id, card).await;
+
+        let session = session(Arc::new(context), user_id).await;
+
+        assert_eq!(session["accounts"][account_id.to_string()]["name"], "alice");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shared_account_is_read_only_to_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (_, account_id) = user(&context, "alice").await;
+        let (reader, _) = user(&context, "bob").await;
+        let (writer, _) = user(&context, "carol").await;
+
+        for (user_id, access) in [
+            (reader, AccountAccessLevel::Reader),
+            (writer, AccountAccessLevel::Writer),
+        ] {
+            context
+                .store
+                .set_account_access(account_id, user_id, access)
+                .await
+                .unwrap();
+        }
+
+        let context = Arc::new(context);
+        let is_read_only =
+            |session: Value| session["accounts"][account_id.to_string()]["isReadOnly"].clone();
+
+        assert_eq!(is_read_only(session(context.clone(), reader).await), true);
+        assert_eq!(is_read_only(session(context, writer).await), false);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn projected_session_keeps_only_the_properties_and_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, _) = user(&context, "alice").await;
+        let context = Arc::new(context);
+
+        let full = session(context.clone(), user_id).await;
+
+        let state_only = json(fetch(context.clone(), user_id, Some("state")).await).await;
+        assert_eq!(state_only, serde_json::json!({"state": full["state"]}));
+
+        let accounts = json(fetch(context, user_id, Some("accounts,username")).await).await;
+        assert_eq!(
+            accounts,
+            serde_json::json!({
+                "accounts": full["accounts"],
+                "username": full["username"],
+                "state": full["state"],
+            })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unknown_session_property_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Context::for_tests(dir.path());
+        let (user_id, _) = user(&context, "alice").await;
+
+        let response = fetch(Arc::new(context), user_id, Some("state,bogus")).await;
+        assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST);
+
+        let problem = json(response).await;
+        assert!(problem["detail"].as_str().unwrap().contains("`bogus`"));
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::{BodyStream, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use futures::{StreamExt, TryStreamExt};
+use jmap_proto::{
+    common::{Id, UnsignedInt},
+    endpoints::blob::upload::UploadResponse,
+    errors::RequestError,
+};
+use oxide_auth::primitives::grant::Grant;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    context::Context,
+    layers::auth_required::user_id,
+    methods::{
+        api::{rate_limited, request_error},
+        store_failure, variables,
+    },
+    store::{AccountProvider, BlobProvider, BlobReferenceProvider},
+};
+
+/// Content type of uploads that don't specify their own.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Stores the body of the request as a blob within the account, returning the
+/// id it can be referenced by.
+pub async fn handle(
+    State(context): State<Arc<Context>>,
+    Extension(grant): Extension<Grant>,
+    Path(account_id): Path<String>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Result<Json<UploadResponse<'static>>, Response> {
+    // ids that are well formed but not a uuid can't be an account
+    let Ok(account_id) = variables::id("accountId", &account_id)
+        .map_err(IntoResponse::into_response)?
+        .parse::<Uuid>()
+    else {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let max_size = context.config.load().core_capabilities.max_size_upload;
+
+    // reject uploads that say up front they're too large before reading any
+    // of them, the size is checked again as the body is read regardless
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if content_length.is_some_and(|len| len > max_size) {
+        return Err(too_large(max_size));
+    }
+
+    let accounts = context
+        .store
+        .get_accounts_for_user(user_id(&grant))
+        .await
+        .map_err(store_failure)?;
+
+    let Some(account) = accounts
+        .into_iter()
+        .find(|account| account.id == account_id)
+    else {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let access = context
+        .store
+        .get_access_level(user_id(&grant), account_id)
+        .await
+        .map_err(store_failure)?;
+
+    if account.is_read_only_for(access) {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+
+    // uploads are limited per account rather than per user, so that users
+    // sharing an account can't between them saturate it
+    let Some(_permit) = context
+        .upload_concurrency
+        .try_acquire(&account_id.to_string())
+    else {
+        return Err(too_many_concurrent_uploads(&context));
+    };
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+        .to_string();
+
+    let size = Arc::new(AtomicU64::new(0));
+    let exceeded = Arc::new(AtomicBool::new(false));
+
+    let contents = {
+        let size = size.clone();
+        let exceeded = exceeded.clone();
+
+        body.map_err(io::Error::other)
+            .and_then(move |chunk| {
+                let total =
+                    size.fetch_add(chunk.len() as u64, Ordering::Relaxed) + chunk.len() as u64;
+
+                let res = if total > max_size {
+                    exceeded.store(true, Ordering::Relaxed);
+                    Err(io::Error::other("upload exceeds maxSizeUpload"))
+                } else {
+                    Ok(chunk)
+                };
+
+                async move { res }
+            })
+            .boxed()
+    };
+
+    let blob_id = match context.blob_store.put_blob(contents).await {
+        Ok(blob_id) => blob_id,
+        Err(_) if exceeded.load(Ordering::Relaxed) => return Err(too_large(max_size)),
+        Err(error) => {
+            error!(?error, "Failed to store upload");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+
+    context
+        .store
+        .link_blob(account_id, blob_id)
+        .await
+        .map_err(store_failure)?;
+
+    // only reachable if the configured limit is past what can be advertised
+    let size = UnsignedInt::try_from(size.load(Ordering::Relaxed))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    Ok(Json(UploadResponse {
+        account_id: Id(account_id.to_string().into()),
+        blob_id: Id(blob_id.to_string().into()),
+        type_: content_type.into(),
+        size,
+    }))
+}
+
+fn too_large(max_size: u64) -> Response {
+    request_error(&RequestError::limit(
+        "maxSizeUpload",
+        format!("Uploads may be at most {max_size} octets in size"),
+    ))
+}
+
+/// Builds the response for an account that already has as many uploads in
+/// flight as it's allowed.
+fn too_many_concurrent_uploads(context: &Context) -> Response {
+    let mut error = RequestError::limit(
+        "maxConcurrentUpload",
+        format!(
+            "At most {} uploads may be in flight at once",
+            context.upload_concurrency.limit()
+        ),
+    );
+    error.status = StatusCode::TOO_MANY_REQUESTS.as_u16();
+
+    rate_limited(&error)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+
+    use super::*;
+    use crate::{
+        extensions::tests::user,
+        methods::{
+            oauth::tests::{access_token, register_clients},
+            tests::send,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uploads_over_the_concurrency_limit_are_told_when_to_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+        register_clients(&context);
+        let (_, account_id) = user(&context, "alice").await;
+        let access_token = access_token(&context).await;
+        context.upload_concurrency.set_limit(1);
+
+        let upload = || {
+            let request = Request::post(format!("/upload/{account_id}"))
+                .header(header::AUTHORIZATION, format!("Bearer {access_token}"))
+                .header(header::CONTENT_TYPE, "text/plain")
+                .body(Body::from("hello"))
+                .unwrap();
+
+            send(&context, request)
+        };
+
+        let in_flight = context
+            .upload_concurrency
+            .try_acquire(&account_id.to_string())
+            .unwrap();
+
+        let response = upload().await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        drop(in_flight);
+
+        assert_eq!(upload().await.status(), StatusCode::OK);
+    }
+}
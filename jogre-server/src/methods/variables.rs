@@ -0,0 +1,175 @@
+//! Parsing of the variables clients fill into the URI templates advertised
+//! in the session, shared by the handlers of those templates.
+//!
+//! Clients substitute whatever they're given, so every variable is checked
+//! strictly and a value that isn't valid for it is rejected with a problem
+//! naming the variable, rather than being passed on to the store or echoed
+//! back in a header.
+
+use std::time::Duration;
+
+use axum::response::{IntoResponse, Response};
+use jmap_proto::{common::Id, errors::RequestError};
+
+use crate::{extensions::ExtensionRegistry, methods::api::request_error};
+
+/// The shortest interval clients may ask to be pinged at.
+const MIN_PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The longest interval clients may ask to be pinged at, beyond which
+/// proxies are likely to have given up on the connection.
+const MAX_PING_INTERVAL: Duration = Duration::from_mins(5);
+
+/// The longest filename, in octets, downloads may be given.
+const MAX_NAME_LEN: usize = 255;
+
+/// A variable filled in with a value that isn't valid for it.
+#[derive(Debug)]
+pub struct InvalidVariable {
+    /// The name of the variable in the URI template.
+    pub variable: &'static str,
+    pub detail: &'static str,
+}
+
+impl InvalidVariable {
+    const fn new(variable: &'static str, detail: &'static str) -> Self {
+        Self { variable, detail }
+    }
+}
+
+impl IntoResponse for InvalidVariable {
+    fn into_response(self) -> Response {
+        request_error(&RequestError::invalid_variable(self.variable, self.detail))
+    }
+}
+
+/// Checks that an `accountId` or `blobId` is a valid [`Id`]. Whether it
+/// refers to anything is left to the handler, so that an id that's well
+/// formed but unknown is reported as not found.
+pub fn id<'a>(variable: &'static str, value: &'a str) -> Result<&'a str, InvalidVariable> {
+    if Id(value.into()).is_valid() {
+        Ok(value)
+    } else {
+        Err(InvalidVariable::new(
+            variable,
+            "must be 1 to 255 characters from the base64url alphabet",
+        ))
+    }
+}
+
+/// The filename a download is served under, with any path separators
+/// replaced so that clients saving it as given can't be led outside of the
+/// directory they chose.
+pub fn name(value: &str) -> Result<String, InvalidVariable> {
+    if value.is_empty() {
+        return Err(InvalidVariable::new("name", "must not be empty"));
+    }
+
+    if value.len() > MAX_NAME_LEN {
+        return Err(InvalidVariable::new("name", "must be at most 255 octets"));
+    }
+
+    if value.chars().any(char::is_control) {
+        return Err(InvalidVariable::new(
+            "name",
+            "must not contain control characters",
+        ));
+    }
+
+    if value.chars().all(|c| c == '.') {
+        return Err(InvalidVariable::new("name", "must not be only dots"));
+    }
+
+    Ok(value.replace(['/', '\\'], "_"))
+}
+
+/// The media type a download is served as, `type "/" subtype` optionally
+/// followed by parameters (RFC 2045).
+pub fn media_type(value: &str) -> Result<&str, InvalidVariable> {
+    let invalid = || InvalidVariable::new("type", "must be a media type");
+
+    let mut parts = value.split(';');
+    let (type_, subtype) = parts
+        .next()
+        .unwrap_or_default()
+        .split_once('/')
+        .ok_or_else(invalid)?;
+
+    if !is_token(type_) || !is_token(subtype) {
+        return Err(invalid());
+    }
+
+    for parameter in parts {
+        let (attribute, argument) = parameter.trim_start().split_once('=').ok_or_else(invalid)?;
+
+        let is_quoted = argument.len() >= 2
+            && argument.starts_with('"')
+            && argument.ends_with('"')
+            && argument[1..argument.len() - 1]
+                .bytes()
+                .all(|c| (c == b'\t' || c == b' ' || c.is_ascii_graphic()) && c != b'"');
+
+        if !is_token(attribute) || !(is_token(argument) || is_quoted) {
+            return Err(invalid());
+        }
+    }
+
+    Ok(value)
+}
+
+/// The data types an event source is interested in, either `*` for all of
+/// them or a comma separated list of type names. Types the server doesn't
+/// know of are left out, as a client may ask after types it supports that
+/// this server doesn't.
+pub fn types(value: &str) -> Result<Vec<&'static str>, InvalidVariable> {
+    if value == "*" {
+        return Ok(ExtensionRegistry::data_types());
+    }
+
+    let wanted = value.split(',').collect::<Vec<_>>();
+
+    if wanted.iter().any(|v| v.is_empty()) {
+        return Err(InvalidVariable::new(
+            "types",
+            "must be `*` or a comma separated list of type names",
+        ));
+    }
+
+    Ok(ExtensionRegistry::data_types()
+        .into_iter()
+        .filter(|data_type| wanted.contains(data_type))
+        .collect())
+}
+
+/// Whether an event source is closed after its first `StateChange`.
+pub fn close_after(value: &str) -> Result<bool, InvalidVariable> {
+    match value {
+        "state" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(InvalidVariable::new(
+            "closeafter",
+            "must be either `state` or `no`",
+        )),
+    }
+}
+
+/// How often an event source pings the client while there's nothing to
+/// send, or `None` to never ping. Intervals outside of those the server
+/// supports are clamped to them.
+pub fn ping(value: &str) -> Result<Option<Duration>, InvalidVariable> {
+    let seconds = value
+        .parse::<u64>()
+        .map_err(|_| InvalidVariable::new("ping", "must be a whole number of seconds"))?;
+
+    Ok((seconds > 0)
+        .then(|| Duration::from_secs(seconds).clamp(MIN_PING_INTERVAL, MAX_PING_INTERVAL)))
+}
+
+/// Whether the value is a `token` (RFC 2045), which excludes spaces,
+/// controls and separators.
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|c| c.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?=".contains(&c))
+}
@@ -0,0 +1,297 @@
+//! JMAP over WebSocket ([RFC 8887]), carrying the same requests as the API
+//! endpoint, and optionally pushed `StateChange`s, over one connection.
+//!
+//! [RFC 8887]: https://datatracker.ietf.org/doc/html/rfc8887
+
+use std::{borrow::Cow, sync::Arc};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+    Extension,
+};
+use jmap_proto::{
+    errors::RequestError,
+    events::{state_change::StateChange, Event as _},
+    websocket::{
+        ClientMessageHeader, ClientMessageType, WebSocketPushEnable, WebSocketRequestError,
+        WebSocketResponse, SUBPROTOCOL,
+    },
+};
+use oxide_auth::primitives::grant::Grant;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::error;
+
+use crate::{
+    context::Context,
+    extensions::ExtensionRegistry,
+    layers::auth_required::user_id,
+    methods::{
+        api::{parse_error, parse_request, process, too_many_concurrent_requests, Rejection},
+        event_source::Subscriber,
+    },
+};
+
+/// How many changes may be waiting to be pushed to a client before no more
+/// are queued for it, after which it's sent everything once it catches up.
+const PUSH_BUFFER: usize = 16;
+
+/// Upgrades the connection to a WebSocket, over which the client sends
+/// requests and may ask to be pushed changes.
+pub async fn handle(
+    State(context): State<Arc<Context>>,
+    Extension(grant): Extension<Grant>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let max_size = usize::try_from(context.config.load().core_capabilities.max_size_request)
+        .unwrap_or(usize::MAX);
+
+    upgrade
+        .protocols([SUBPROTOCOL])
+        .max_message_size(max_size)
+        .on_upgrade(move |socket| serve(socket, context, grant))
+}
+
+/// What the connection was woken up by.
+enum Wake {
+    Message(Option<Result<Message, axum::Error>>),
+    Change(StateChange<'static>),
+}
+
+/// Answers the client's messages, and pushes changes to it while it's asked
+/// for them, until either side closes the connection.
+async fn serve(mut socket: WebSocket, context: Arc<Context>, grant: Grant) {
+    let mut push: Option<Push> = None;
+
+    loop {
+        let wake = tokio::select! {
+            message = socket.recv() => Wake::Message(message),
+            Some(change) = next_change(&mut push) => Wake::Change(change),
+        };
+
+        let reply = match wake {
+            Wake::Message(Some(Ok(Message::Text(text)))) => {
+                handle_message(&context, &grant, &text, &mut push).await
+            }
+            Wake::Message(Some(Ok(Message::Binary(_)))) => Some(problem(
+                RequestError::not_json("Messages must be sent as text"),
+                None,
+            )),
+            // pings are answered by axum
+            Wake::Message(Some(Ok(Message::Ping(_) | Message::Pong(_)))) => None,
+            Wake::Message(Some(Ok(Message::Close(_)) | Err(_)) | None) => return,
+            Wake::Change(change) => Some(serde_json::to_string(&change.into_event()).unwrap()),
+        };
+
+        if let Some(reply) = reply {
+            if socket.send(Message::Text(reply)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Handles a message from the client, returning the reply to send to it if
+/// there is one.
+async fn handle_message(
+    context: &Arc<Context>,
+    grant: &Grant,
+    text: &str,
+    push: &mut Option<Push>,
+) -> Option<String> {
+    let header: ClientMessageHeader<'_> = match serde_json::from_str(text) {
+        Ok(header) => header,
+        Err(error) => return Some(problem(parse_error(&error), None)),
+    };
+
+    match header.type_ {
+        ClientMessageType::Request => Some(handle_request(context, grant, text, header.id).await),
+        ClientMessageType::WebSocketPushEnable => {
+            let enable: WebSocketPushEnable = match serde_json::from_str(text) {
+                Ok(enable) => enable,
+                Err(error) => return Some(problem(parse_error(&error), None)),
+            };
+
+            // types the server doesn't know of are left out, as with the
+            // event source. `pushState` isn't supported, so clients are
+            // only pushed changes made from now on
+            let types = match enable.data_types {
+                Some(wanted) => ExtensionRegistry::data_types()
+                    .into_iter()
+                    .filter(|data_type| wanted.iter().any(|v| v == data_type))
+                    .collect(),
+                None => ExtensionRegistry::data_types(),
+            };
+
+            *push = Some(Push::new(Subscriber::new(
+                context.clone(),
+                user_id(grant),
+                types,
+            )));
+
+            None
+        }
+        ClientMessageType::WebSocketPushDisable => {
+            *push = None;
+            None
+        }
+    }
+}
+
+/// Runs a `Request` sent over the WebSocket, returning the `Response` or
+/// `RequestError` to reply with.
+async fn handle_request(
+    context: &Context,
+    grant: &Grant,
+    text: &str,
+    id: Option<Cow<'_, str>>,
+) -> String {
+    let Some(_permit) = context.api_concurrency.try_acquire(&grant.owner_id) else {
+        return problem(too_many_concurrent_requests(context), id);
+    };
+
+    let payload = match parse_request(text.as_bytes()) {
+        Ok(payload) => payload,
+        Err(error) => return problem(error, id),
+    };
+
+    match process(context, user_id(grant), payload).await {
+        Ok(response) => serde_json::to_string(
+            &WebSocketResponse {
+                response,
+                request_id: id,
+            }
+            .into_event(),
+        )
+        .unwrap(),
+        Err(Rejection::Problem(error)) => problem(error, id),
+        Err(Rejection::ReadOnly) => problem(
+            RequestError::unavailable(
+                "This server is a read replica, writes must be sent to the primary",
+            ),
+            id,
+        ),
+        Err(Rejection::UnknownUser) => problem(
+            RequestError::forbidden("The user this connection was opened for no longer exists"),
+            id,
+        ),
+        Err(Rejection::Store(error)) => {
+            error!(%error, "Store failed while handling request");
+            problem(RequestError::internal("The store failed"), id)
+        }
+    }
+}
+
+/// Builds a `RequestError` message, tagged with the id of the request it's
+/// about if it had one.
+fn problem(error: RequestError, request_id: Option<Cow<'_, str>>) -> String {
+    serde_json::to_string(&WebSocketRequestError { error, request_id }.into_event()).unwrap()
+}
+
+/// Resolves with the next change to push, or never while pushes are off.
+async fn next_change(push: &mut Option<Push>) -> Option<StateChange<'static>> {
+    match push {
+        Some(push) => push.changes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Changes being pushed to the client, gathered by a task of their own so
+/// none are lost when the connection is woken by a message instead.
+struct Push {
+    changes: mpsc::Receiver<StateChange<'static>>,
+    task: JoinHandle<()>,
+}
+
+impl Push {
+    fn new(mut subscriber: Subscriber) -> Self {
+        let (sender, changes) = mpsc::channel(PUSH_BUFFER);
+
+        let task = tokio::spawn(async move {
+            while let Some(change) = subscriber.next().await {
+                if sender.send(change).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Self { changes, task }
+    }
+}
+
+impl Drop for Push {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+    use super::*;
+    use crate::{
+        extensions::tests::user,
+        methods::{
+            oauth::tests::{access_token, register_clients},
+            router,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn echo_round_trips_over_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = Arc::new(Context::for_tests(dir.path()));
+        register_clients(&context);
+        user(&context, "alice").await;
+        let access_token = access_token(&context).await;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router(context).into_make_service());
+        tokio::spawn(server);
+
+        let mut request = format!("ws://{address}/jmap/ws")
+            .into_client_request()
+            .unwrap();
+        let headers = request.headers_mut();
+        headers.insert(
+            "authorization",
+            format!("Bearer {access_token}").parse().unwrap(),
+        );
+        headers.insert("sec-websocket-protocol", SUBPROTOCOL.parse().unwrap());
+
+        let (mut socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(response.headers()["sec-websocket-protocol"], SUBPROTOCOL);
+
+        let arguments = serde_json::json!({"hello": true, "nested": [1, 2]});
+        let request = serde_json::json!({
+            "@type": "Request",
+            "id": "r1",
+            "using": ["urn:ietf:params:jmap:core"],
+            "methodCalls": [["Core/echo", arguments, "c1"]]
+        });
+        socket
+            .send(tungstenite::Message::Text(request.to_string()))
+            .await
+            .unwrap();
+
+        let tungstenite::Message::Text(reply) = socket.next().await.unwrap().unwrap() else {
+            panic!("expected a text message");
+        };
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+
+        assert_eq!(reply["@type"], "Response");
+        assert_eq!(reply["requestId"], "r1");
+        assert_eq!(
+            reply["methodResponses"],
+            serde_json::json!([["Core/echo", arguments, "c1"]])
+        );
+    }
+}
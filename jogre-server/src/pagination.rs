@@ -0,0 +1,248 @@
+//! Splitting long listings into the pages clients are sent, shared by every
+//! endpoint that lists so that they agree on the edge cases.
+//!
+//! `Foo/query` results are cut down to a [`Window`]. Queries may match far
+//! more records than a client asks to see at once, so the window is applied
+//! while iterating over the matching ids rather than after collecting all of
+//! them. Only the ids within the window are held in memory, along with at
+//! most as many ids as the window reaches back when it's positioned
+//! relative to the end of the results or to an anchor.
+//!
+//! Listings outside of JMAP, such as those of the admin API, are paged with
+//! a [`Cursor`] instead, so that records added or removed while a client is
+//! paging through don't shift the pages after them.
+
+use std::{collections::VecDeque, iter};
+
+use uuid::Uuid;
+
+/// Where a window starts within the full list of results.
+pub enum WindowStart<T> {
+    /// The zero-based index of the first result to return, negative values
+    /// are counted back from the end of the results.
+    Position(i64),
+    /// The index of the first result to return relative to the index of the
+    /// given id.
+    Anchor { anchor: T, offset: i64 },
+}
+
+/// The part of a query's results the client asked to be returned.
+pub struct Window<T> {
+    pub start: WindowStart<T>,
+    /// The maximum number of results to return, if any.
+    pub limit: Option<u64>,
+    /// The most results the server returns at once, however many the client
+    /// asks for.
+    pub max_limit: Option<u64>,
+    /// Whether the total number of results should be counted, which requires
+    /// iterating over every result.
+    pub calculate_total: bool,
+}
+
+/// The results of a query within a [`Window`].
+#[derive(Debug)]
+pub struct Windowed<T> {
+    /// The zero-based index of the first result in `ids`.
+    pub position: u64,
+    pub ids: Vec<T>,
+    /// The total number of results, if it was asked for.
+    pub total: Option<u64>,
+    /// The limit the server applied, only given when it isn't the one the
+    /// client asked for.
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum WindowError {
+    /// The window was anchored to an id that isn't in the results.
+    AnchorNotFound,
+}
+
+impl<T: PartialEq> Window<T> {
+    /// A window over every result, such as to compare the results as a
+    /// whole with those a client already has.
+    pub fn everything() -> Self {
+        Self {
+            start: WindowStart::Position(0),
+            limit: None,
+            max_limit: None,
+            calculate_total: false,
+        }
+    }
+
+    /// Applies the window to the results of a query, consuming no more of
+    /// them than needed.
+    pub fn apply(self, results: impl IntoIterator<Item = T>) -> Result<Windowed<T>, WindowError> {
+        let (limit, echoed) = resolve_limit(self.limit, self.max_limit);
+
+        let limit = limit.map_or(usize::MAX, |limit| {
+            usize::try_from(limit).unwrap_or(usize::MAX)
+        });
+
+        let mut windowed = match self.start {
+            WindowStart::Position(position) => match u64::try_from(position) {
+                Ok(position) => from_start(results, position, limit, self.calculate_total),
+                Err(_) => from_end(
+                    results,
+                    position.unsigned_abs(),
+                    limit,
+                    self.calculate_total,
+                ),
+            },
+            WindowStart::Anchor { anchor, offset } => {
+                from_anchor(results, &anchor, offset, limit, self.calculate_total)?
+            }
+        };
+
+        windowed.limit = echoed;
+
+        Ok(windowed)
+    }
+}
+
+/// Resolves the limit a client asked for against the most the server
+/// returns at once, returning the limit to apply along with the limit to
+/// tell the client of, which is only given when the two differ.
+pub fn resolve_limit(requested: Option<u64>, max: Option<u64>) -> (Option<u64>, Option<u64>) {
+    let applied = match (requested, max) {
+        (Some(requested), Some(max)) => Some(requested.min(max)),
+        (requested, max) => requested.or(max),
+    };
+
+    let echoed = applied.filter(|&applied| Some(applied) != requested);
+
+    (applied, echoed)
+}
+
+/// The position within a listing ordered by id that the next page starts
+/// after.
+///
+/// Clients are given cursors encoded as opaque strings, and must hand them
+/// back unchanged.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cursor(Uuid);
+
+impl Cursor {
+    /// A cursor for the page following the record with the given id.
+    pub const fn after(id: Uuid) -> Self {
+        Self(id)
+    }
+
+    /// The id of the last record on the previous page.
+    pub const fn id(self) -> Uuid {
+        self.0
+    }
+
+    pub fn encode(self) -> String {
+        hex::encode(self.0.as_bytes())
+    }
+
+    /// Decodes a cursor given back by a client, or `None` if it isn't one
+    /// the server handed out.
+    pub fn decode(value: &str) -> Option<Self> {
+        let mut out = [0_u8; 16];
+        hex::decode_to_slice(value, &mut out).ok()?;
+        Some(Self(Uuid::from_bytes(out)))
+    }
+}
+
+fn from_start<T>(
+    results: impl IntoIterator<Item = T>,
+    position: u64,
+    limit: usize,
+    calculate_total: bool,
+) -> Windowed<T> {
+    let mut results = results.into_iter();
+
+    let skipped = results
+        .by_ref()
+        .take(usize::try_from(position).unwrap_or(usize::MAX))
+        .count() as u64;
+    let ids: Vec<_> = results.by_ref().take(limit).collect();
+    let total = calculate_total.then(|| skipped + ids.len() as u64 + results.count() as u64);
+
+    Windowed {
+        position,
+        ids,
+        total,
+        limit: None,
+    }
+}
+
+fn from_end<T>(
+    results: impl IntoIterator<Item = T>,
+    back: u64,
+    limit: usize,
+    calculate_total: bool,
+) -> Windowed<T> {
+    let back = usize::try_from(back).unwrap_or(usize::MAX);
+
+    let mut tail = VecDeque::new();
+    let mut total = 0_u64;
+
+    for id in results {
+        if tail.len() == back {
+            tail.pop_front();
+        }
+
+        tail.push_back(id);
+        total += 1;
+    }
+
+    Windowed {
+        position: total - tail.len() as u64,
+        ids: tail.into_iter().take(limit).collect(),
+        total: calculate_total.then_some(total),
+        limit: None,
+    }
+}
+
+fn from_anchor<T: PartialEq>(
+    results: impl IntoIterator<Item = T>,
+    anchor: &T,
+    offset: i64,
+    limit: usize,
+    calculate_total: bool,
+) -> Result<Windowed<T>, WindowError> {
+    let mut results = results.into_iter();
+
+    // a negative offset can start the window before the anchor, so hold on
+    // to as many of the results before it as the offset could reach
+    let reach_back = usize::try_from(offset.min(0).unsigned_abs()).unwrap_or(usize::MAX);
+    let mut preceding = VecDeque::new();
+    let mut index = 0_u64;
+
+    let found = loop {
+        let Some(id) = results.next() else {
+            return Err(WindowError::AnchorNotFound);
+        };
+
+        if &id == anchor {
+            break id;
+        }
+
+        if reach_back > 0 {
+            if preceding.len() == reach_back {
+                preceding.pop_front();
+            }
+
+            preceding.push_back(id);
+        }
+
+        index += 1;
+    };
+
+    let remaining_start = index - preceding.len() as u64;
+    let start = index.saturating_add_signed(offset).max(remaining_start);
+
+    let remaining = preceding
+        .into_iter()
+        .chain(iter::once(found))
+        .chain(results);
+    let mut windowed = from_start(remaining, start - remaining_start, limit, calculate_total);
+
+    windowed.position += remaining_start;
+    windowed.total = windowed.total.map(|total| total + remaining_start);
+
+    Ok(windowed)
+}
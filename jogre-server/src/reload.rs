@@ -0,0 +1,198 @@
+//! Reloading of the config file while the server is running, triggered by
+//! sending the server SIGHUP.
+//!
+//! Only the settings matched by [`ReloadableConfig::is_reloadable`] are
+//! applied, a change to anything else is logged and left until the server
+//! is restarted. A config that can't be read or parsed leaves the running
+//! config untouched.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+use tracing_subscriber::{
+    filter::{ParseError, Targets},
+    reload, Registry,
+};
+
+use crate::{
+    config::{Config, ReloadableConfig},
+    context::Context,
+};
+
+/// Swaps the filter deciding which logs are written.
+pub type LogFilterHandle = reload::Handle<Targets, Registry>;
+
+pub struct Reloader {
+    path: PathBuf,
+    /// The config the server was started with, which settings that can't be
+    /// reloaded are compared against.
+    started_with: toml::Value,
+    /// The config as of the last reload.
+    applied: Mutex<toml::Value>,
+    log_filter: LogFilterHandle,
+}
+
+impl Reloader {
+    pub fn new(path: PathBuf, started_with: toml::Value, log_filter: LogFilterHandle) -> Self {
+        Self {
+            path,
+            applied: Mutex::new(started_with.clone()),
+            started_with,
+            log_filter,
+        }
+    }
+
+    /// Re-reads the config file, applying the settings that can change at
+    /// runtime.
+    pub async fn reload(&self, context: &Context) -> Result<Reloaded, ReloadError> {
+        let raw: toml::Value = toml::from_str(
+            &tokio::fs::read_to_string(&self.path)
+                .await
+                .map_err(ReloadError::Read)?,
+        )
+        .map_err(ReloadError::Parse)?;
+
+        let config: Config = raw.clone().try_into().map_err(ReloadError::Parse)?;
+        let log_filter: Targets = config.log_filter.parse().map_err(ReloadError::LogFilter)?;
+
+        let mut applied = self.applied.lock().unwrap();
+
+        let reloaded = Reloaded {
+            applied: changed_paths(&applied, &raw)
+                .into_iter()
+                .filter(|path| ReloadableConfig::is_reloadable(path))
+                .collect(),
+            pending_restart: changed_paths(&self.started_with, &raw)
+                .into_iter()
+                .filter(|path| !ReloadableConfig::is_reloadable(path))
+                .collect(),
+        };
+
+        let reloadable = ReloadableConfig::from(&config);
+
+        context
+            .api_concurrency
+            .set_limit(reloadable.core_capabilities.max_concurrent_requests);
+        context
+            .upload_concurrency
+            .set_limit(reloadable.core_capabilities.max_concurrent_upload);
+        context.oauth2.reload_clients(&config.oauth.clients);
+
+        if let Err(error) = self.log_filter.reload(log_filter) {
+            error!(%error, "Failed to swap log filter");
+        }
+
+        context.config.store(Arc::new(reloadable));
+        *applied = raw;
+
+        Ok(reloaded)
+    }
+}
+
+/// The settings that changed in a reload, as paths into the config file
+/// (eg. `request-limits.max-using`).
+#[derive(Debug, Default)]
+pub struct Reloaded {
+    pub applied: Vec<String>,
+    /// Settings that differ from those the server was started with, but
+    /// can't be changed without a restart.
+    pub pending_restart: Vec<String>,
+}
+
+impl Reloaded {
+    fn log(&self) {
+        if self.applied.is_empty() && self.pending_restart.is_empty() {
+            info!("Reloaded config, nothing changed");
+        }
+
+        for setting in &self.applied {
+            info!(setting, "Applied config change");
+        }
+
+        for setting in &self.pending_restart {
+            warn!(setting, "Config change needs a restart to apply");
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReloadError {
+    Read(std::io::Error),
+    Parse(toml::de::Error),
+    LogFilter(ParseError),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(e) => write!(f, "failed to read config: {e}"),
+            Self::Parse(e) => write!(f, "invalid config: {e}"),
+            Self::LogFilter(e) => write!(f, "invalid log-filter: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {}
+
+/// Reloads the config each time the server receives SIGHUP.
+pub fn spawn_sighup_handler(reloader: Reloader, context: Arc<Context>) -> std::io::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match reloader.reload(&context).await {
+                Ok(changes) => changes.log(),
+                Err(error) => error!(%error, "Failed to reload config, keeping the running config"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// The paths of every setting that differs between the two configs.
+fn changed_paths(old: &toml::Value, new: &toml::Value) -> Vec<String> {
+    let (mut old_settings, mut new_settings) = (BTreeMap::new(), BTreeMap::new());
+    flatten(String::new(), old, &mut old_settings);
+    flatten(String::new(), new, &mut new_settings);
+
+    let mut changed: Vec<String> = old_settings
+        .iter()
+        .filter(|(path, value)| new_settings.get(*path) != Some(value))
+        .map(|(path, _)| path.clone())
+        .collect();
+
+    changed.extend(
+        new_settings
+            .into_keys()
+            .filter(|path| !old_settings.contains_key(path)),
+    );
+    changed.sort();
+
+    changed
+}
+
+/// Collects every setting within the value keyed by its path, tables are
+/// descended into while arrays, such as `oauth.client`, are kept whole.
+fn flatten<'a>(path: String, value: &'a toml::Value, out: &mut BTreeMap<String, &'a toml::Value>) {
+    let toml::Value::Table(table) = value else {
+        out.insert(path, value);
+        return;
+    };
+
+    for (key, value) in table {
+        let path = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+
+        flatten(path, value, out);
+    }
+}
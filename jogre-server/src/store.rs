@@ -1,17 +1,34 @@
+mod filesystem;
 mod rocksdb;
+mod s3;
+mod stored;
+
+use std::{collections::HashMap, fmt, ops::Range, sync::Arc};
 
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use axum::async_trait;
+use axum::{async_trait, body::Bytes};
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use jmap_proto::endpoints::object::ObjectState;
+use oxide_auth::primitives::{
+    grant::{Extensions, Grant, Value},
+    scope::ParseScopeErr,
+};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use tracing::error;
+use url::Url;
 use uuid::Uuid;
 
+pub use self::{rocksdb::Error, stored::Persisted};
+use crate::{config::Argon2Config, context::events::EventBus};
+
 /// A user corresponds to an actual end user that can login to the service,
 /// objects aren't directly stored under users though - users are granted
 /// access to a set of accounts that objects are stored under.
 ///
 /// Each user automatically has a "personal" account created for them.
-#[derive(Serialize, Deserialize)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
@@ -20,26 +37,61 @@ pub struct User {
 
 impl User {
     /// Builds a new `User` with the given username and password.
-    pub fn new(username: String, password: &str) -> Self {
-        let password = Argon2::default()
-            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
-            .unwrap()
-            .to_string();
-
+    pub fn new(username: String, password: &str, argon2: &Argon2) -> Self {
         Self {
             id: Uuid::new_v4(),
             username,
-            password,
+            password: hash_password(argon2, password),
         }
     }
 
-    /// Verifies if the given password is valid for the user.
+    /// Replaces the user's password, hashed with a fresh salt.
+    pub fn set_password(&mut self, new: &str, argon2: &Argon2) {
+        self.password = hash_password(argon2, new);
+    }
+
+    /// Verifies if the given password is valid for the user, using the costs
+    /// the password was hashed with rather than those configured now.
+    /// A stored hash that can't be parsed never verifies.
     pub fn verify_password(&self, password: &str) -> bool {
-        let parsed_hash = PasswordHash::new(&self.password).unwrap();
+        let parsed_hash = match PasswordHash::new(&self.password) {
+            Ok(hash) => hash,
+            Err(error) => {
+                error!(user = %self.id, %error, "Stored password hash is corrupt");
+                return false;
+            }
+        };
+
         Argon2::default()
             .verify_password(password.as_bytes(), &parsed_hash)
             .is_ok()
     }
+
+    /// Whether the password was hashed with anything other than the
+    /// algorithm and costs `argon2` hashes with now, so should be hashed
+    /// again the next time it's known.
+    pub fn needs_rehash(&self, argon2: &Argon2) -> bool {
+        let Ok(hash) = PasswordHash::new(&self.password) else {
+            return true;
+        };
+
+        hash.algorithm != Argon2Config::ALGORITHM.ident()
+            || hash.version != Some(Argon2Config::VERSION.into())
+            || argon2::Params::try_from(&hash).map_or(true, |params| {
+                let current = argon2.params();
+
+                params.m_cost() != current.m_cost()
+                    || params.t_cost() != current.t_cost()
+                    || params.p_cost() != current.p_cost()
+            })
+    }
+}
+
+fn hash_password(argon2: &Argon2, password: &str) -> String {
+    argon2
+        .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+        .unwrap()
+        .to_string()
 }
 
 #[async_trait]
@@ -52,13 +104,37 @@ pub trait UserProvider {
 
     async fn has_any_users(&self) -> Result<bool, Self::Error>;
 
-    async fn create_user(&self, user: User) -> Result<(), Self::Error>;
+    /// Fetches the ids of every user.
+    async fn get_user_ids(&self) -> Result<Vec<Uuid>, Self::Error>;
+
+    /// Fetches a page of users in order of their ids, starting after the
+    /// given id, or from the first user if there isn't one.
+    async fn list_users(&self, after: Option<Uuid>, limit: usize)
+        -> Result<Vec<User>, Self::Error>;
+
+    /// Creates a user along with their personal account, named after them
+    /// and owned by them, returning the ids of both. Fails with
+    /// [`Error::UsernameTaken`] rather than overwriting another user with
+    /// the same username.
+    async fn create_user(&self, user: User) -> Result<(Uuid, Uuid), Self::Error>;
 
     async fn get_by_username(&self, username: &str) -> Result<Option<User>, Self::Error>;
+
+    async fn get_by_id(&self, id: Uuid) -> Result<Option<User>, Self::Error>;
+
+    /// Overwrites an existing user, whose username must not have changed.
+    /// Fails with [`Error::UserNotFound`] rather than recreating a user
+    /// that has since been deleted.
+    async fn update_user(&self, user: User) -> Result<(), Self::Error>;
+
+    /// Removes the user along with their access to accounts and the tokens
+    /// issued to them, leaving the accounts themselves in place. Returns
+    /// whether the user existed.
+    async fn delete_user(&self, id: Uuid) -> Result<bool, Self::Error>;
 }
 
 /// An entity which contains many objects, these can be shared among users.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 pub struct Account {
     /// ID of the account
     pub id: Uuid,
@@ -66,7 +142,9 @@ pub struct Account {
     pub name: String,
     /// Whether or not the account is a user's primary account.
     pub is_personal: bool,
-    /// Whether or not the entire account is read-only.
+    /// Whether or not the entire account is read-only, for everyone with
+    /// access to it including its owner. Use [`Account::is_read_only_for`]
+    /// for whether a particular user may write to it.
     pub is_read_only: bool,
 }
 
@@ -79,6 +157,13 @@ impl Account {
             is_read_only,
         }
     }
+
+    /// Whether the account is read-only to a user with the given access to
+    /// it. Both what's advertised in the session and what's enforced on
+    /// writes must come from here, so the two can never disagree.
+    pub fn is_read_only_for(&self, access: Option<AccountAccessLevel>) -> bool {
+        self.is_read_only || !access.is_some_and(AccountAccessLevel::can_write)
+    }
 }
 
 #[async_trait]
@@ -88,21 +173,612 @@ pub trait AccountProvider {
     /// Creates or updates an account in the data store.
     async fn create_account(&self, account: Account) -> Result<(), Self::Error>;
 
-    /// Grants a user access to an account.
+    /// Grants a user access to an account. Any greater access the user
+    /// already has is kept unless `force` is set, returning the access
+    /// they're left with.
     async fn attach_account_to_user(
         &self,
         account: Uuid,
         user: Uuid,
         access: AccountAccessLevel,
+        force: bool,
+    ) -> Result<AccountAccessLevel, Self::Error>;
+
+    /// Sets the user's access to an account, whether that raises or lowers
+    /// it.
+    async fn set_account_access(
+        &self,
+        account: Uuid,
+        user: Uuid,
+        access: AccountAccessLevel,
     ) -> Result<(), Self::Error>;
 
+    /// Revokes a user's access to an account, returning whether they had
+    /// any. The owner of a personal account can't be detached from it.
+    async fn detach_account_from_user(
+        &self,
+        account: Uuid,
+        user: Uuid,
+    ) -> Result<bool, Self::Error>;
+
     /// Fetches a list of accounts for the given user.
     async fn get_accounts_for_user(&self, user_id: Uuid) -> Result<Vec<Account>, Self::Error>;
+
+    /// Fetches the user's access to the account, or `None` if they have no
+    /// access to it.
+    async fn get_access_level(
+        &self,
+        user: Uuid,
+        account: Uuid,
+    ) -> Result<Option<AccountAccessLevel>, Self::Error>;
+
+    /// Fetches the user's access to each account they have access to.
+    async fn get_access_levels_for_user(
+        &self,
+        user: Uuid,
+    ) -> Result<HashMap<Uuid, AccountAccessLevel>, Self::Error>;
+
+    /// Fetches every user with access to the account, along with their
+    /// access to it.
+    async fn get_users_for_account(
+        &self,
+        account: Uuid,
+    ) -> Result<Vec<(Uuid, AccountAccessLevel)>, Self::Error>;
+
+    /// Fetches the account `Principal` and `ShareNotification` objects are
+    /// kept in, if one has been designated.
+    async fn get_principals_account(&self) -> Result<Option<Uuid>, Self::Error>;
+
+    /// Designates the account `Principal` and `ShareNotification` objects
+    /// are kept in.
+    async fn set_principals_account(&self, account: Uuid) -> Result<(), Self::Error>;
+}
+
+/// A persistable copy of an OAuth [`Grant`], `Grant` itself isn't
+/// serialisable so it gets converted to and from this at the store boundary.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OAuthGrant {
+    pub owner_id: String,
+    pub client_id: String,
+    pub scope: String,
+    pub redirect_uri: Url,
+    pub until: DateTime<Utc>,
+    pub public_extensions: HashMap<String, Option<String>>,
+    pub private_extensions: HashMap<String, Option<String>>,
+}
+
+impl OAuthGrant {
+    /// Whether or not the grant has passed its expiry time.
+    pub fn is_expired(&self) -> bool {
+        self.until < Utc::now()
+    }
+}
+
+impl From<Grant> for OAuthGrant {
+    fn from(grant: Grant) -> Self {
+        let public_extensions = grant
+            .extensions
+            .public()
+            .map(|(k, v)| (k.to_string(), v.map(ToString::to_string)))
+            .collect();
+        let private_extensions = grant
+            .extensions
+            .private()
+            .map(|(k, v)| (k.to_string(), v.map(ToString::to_string)))
+            .collect();
+
+        Self {
+            owner_id: grant.owner_id,
+            client_id: grant.client_id,
+            scope: grant.scope.to_string(),
+            redirect_uri: grant.redirect_uri,
+            until: grant.until,
+            public_extensions,
+            private_extensions,
+        }
+    }
+}
+
+impl TryFrom<OAuthGrant> for Grant {
+    type Error = ParseScopeErr;
+
+    /// Fails if the stored scope is no longer a valid one, which can only
+    /// happen to a corrupted record as scopes are validated on the way in.
+    fn try_from(grant: OAuthGrant) -> Result<Self, Self::Error> {
+        let mut extensions = Extensions::new();
+
+        for (k, v) in grant.public_extensions {
+            extensions.set_raw(k, Value::public(v));
+        }
+
+        for (k, v) in grant.private_extensions {
+            extensions.set_raw(k, Value::private(v));
+        }
+
+        Ok(Self {
+            owner_id: grant.owner_id,
+            client_id: grant.client_id,
+            scope: grant.scope.parse()?,
+            redirect_uri: grant.redirect_uri,
+            until: grant.until,
+            extensions,
+        })
+    }
+}
+
+/// An access token, and optionally its refresh token, issued for a grant.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IssuedOAuthToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// The grant the access token was issued for, expiring along with the
+    /// access token.
+    pub grant: OAuthGrant,
+    /// When the refresh token stops being usable however often it's used,
+    /// carried over to each token refreshed from it.
+    pub refresh_until: DateTime<Utc>,
+    /// When the refresh token stops being usable if it isn't used, pushed
+    /// back each time the token is refreshed.
+    pub refresh_idle_until: DateTime<Utc>,
+}
+
+impl IssuedOAuthToken {
+    /// When the refresh token expires, if it has one.
+    pub fn refresh_expires_at(&self) -> Option<DateTime<Utc>> {
+        self.refresh_token
+            .as_ref()
+            .map(|_| self.refresh_until.min(self.refresh_idle_until))
+    }
+
+    /// Whether neither the access token nor the refresh token can be used
+    /// any longer.
+    pub fn is_expired(&self) -> bool {
+        self.grant.is_expired()
+            && self
+                .refresh_expires_at()
+                .is_none_or(|until| until < Utc::now())
+    }
+}
+
+#[async_trait]
+pub trait OAuthProvider {
+    type Error;
+
+    /// Stores an authorization code, to be exchanged for a token by the
+    /// client.
+    async fn store_auth_code(&self, code: &str, grant: OAuthGrant) -> Result<(), Self::Error>;
+
+    /// Fetches and removes an authorization code, codes are single-use.
+    async fn take_auth_code(&self, code: &str) -> Result<Option<OAuthGrant>, Self::Error>;
+
+    /// Stores a newly issued token, indexed by both its access and refresh
+    /// tokens.
+    async fn store_token(&self, token: IssuedOAuthToken) -> Result<(), Self::Error>;
+
+    /// Fetches an issued token by its access token.
+    async fn get_by_access_token(
+        &self,
+        access_token: &str,
+    ) -> Result<Option<IssuedOAuthToken>, Self::Error>;
+
+    /// Fetches an issued token by its refresh token.
+    async fn get_by_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<IssuedOAuthToken>, Self::Error>;
+
+    /// Removes both the access and refresh tokens of an issued token.
+    async fn remove_token(&self, token: &IssuedOAuthToken) -> Result<(), Self::Error>;
+
+    /// Removes every issued token whose access and refresh tokens have both
+    /// expired, returning the number of tokens removed.
+    async fn remove_expired_tokens(&self) -> Result<u64, Self::Error>;
+}
+
+/// Persists the objects of every JMAP data type (eg. `AddressBook`), keyed
+/// by the account they belong to, the name of their data type and their id.
+///
+/// Each data type within an account has its own state, a number bumped by
+/// every change to its objects, from which the type's state string is
+/// built.
+#[async_trait]
+pub trait ObjectProvider {
+    type Error;
+
+    /// Fetches an object.
+    async fn get_object<D: Persisted + Send + 'static>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        id: Uuid,
+    ) -> Result<Option<D>, Self::Error>;
+
+    /// Fetches every object of the data type within the account, in order
+    /// of their ids.
+    async fn list_objects<D: Persisted + Send + 'static>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+    ) -> Result<Vec<(Uuid, D)>, Self::Error>;
+
+    /// Hands the objects of the data type within the account to `query`, in
+    /// order of their ids, reading each only once `query` asks for it.
+    /// Queries that stop early, such as those returning the first page of a
+    /// long listing, never read the rest.
+    async fn query_objects<D, R, F>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        query: F,
+    ) -> Result<R, Self::Error>
+    where
+        D: Persisted + 'static,
+        R: Send + 'static,
+        F: FnOnce(&mut dyn Iterator<Item = Result<(Uuid, D), Self::Error>>) -> R + Send + 'static;
+
+    /// Creates the object, or replaces it if it already exists, bumping the
+    /// data type's state.
+    async fn put_object<D: Persisted + Sync>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        id: Uuid,
+        object: &D,
+    ) -> Result<(), Self::Error>;
+
+    /// Applies every write of a `Foo/set` call in one go, so that either all
+    /// of the objects and their change log entries are written or none are.
+    /// Returns the data type's state afterwards.
+    async fn apply_set<D: Persisted + Sync>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        writes: &[ObjectWrite<D>],
+    ) -> Result<ObjectState<'static>, Self::Error>;
+
+    /// The current state of the data type within the account, encoded as
+    /// the opaque string handed to clients. Data types none of whose objects
+    /// have ever changed are at the state encoding zero.
+    async fn state_for(
+        &self,
+        account: Uuid,
+        data_type: &str,
+    ) -> Result<ObjectState<'static>, Self::Error>;
+
+    /// The objects of the data type that have changed since the given state,
+    /// stopping early once at least `max_changes` objects have changed.
+    /// Returns `None` if changes can't be calculated from the state, either
+    /// because they've been compacted away or the state never existed.
+    async fn get_object_changes(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        since: u64,
+        max_changes: Option<usize>,
+    ) -> Result<Option<ObjectChanges>, Self::Error>;
+}
+
+/// Encodes the state of a data type as the string handed to clients, which
+/// they must treat as opaque.
+pub fn encode_object_state(state: u64) -> ObjectState<'static> {
+    ObjectState(format!("{state:x}").into())
+}
+
+/// Decodes a state string handed to a client, or `None` if it was never
+/// handed out by [`encode_object_state`].
+pub fn decode_object_state(state: &ObjectState<'_>) -> Option<u64> {
+    u64::from_str_radix(&state.0, 16)
+        .ok()
+        .filter(|decoded| encode_object_state(*decoded) == *state)
+}
+
+/// A change to an object, validated by a `Foo/set` call and written along
+/// with the rest of the call's changes by [`ObjectProvider::apply_set`].
+pub enum ObjectWrite<D> {
+    Put(Uuid, D),
+    Delete(Uuid),
+}
+
+/// The objects of a data type that changed between two states, each object
+/// appearing at most once. Objects created and destroyed again between the
+/// states don't appear at all.
+#[derive(Debug)]
+pub struct ObjectChanges {
+    /// The state the changes lead to, which is older than the current state
+    /// if there are more changes to come.
+    pub new_state: u64,
+    pub has_more_changes: bool,
+    pub created: Vec<Uuid>,
+    pub updated: Vec<Uuid>,
+    pub destroyed: Vec<Uuid>,
+}
+
+/// Failed attempts to log in as a user, counted over a window starting at
+/// the first failure so that they're forgotten once it has passed.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct LoginFailures {
+    pub count: u32,
+    /// When the window ends, and the failures are forgotten.
+    pub expires: DateTime<Utc>,
+}
+
+impl LoginFailures {
+    pub fn is_expired(&self) -> bool {
+        self.expires <= Utc::now()
+    }
+}
+
+/// Keeps count of failed logins, so that guessing a user's password can be
+/// stopped for a while after too many wrong guesses. Counts are kept in the
+/// store so a restart doesn't reset them.
+#[async_trait]
+pub trait LoginFailureProvider {
+    type Error;
+
+    /// Fetches the failures still within their window for the username.
+    async fn get_login_failures(
+        &self,
+        username: &str,
+    ) -> Result<Option<LoginFailures>, Self::Error>;
+
+    /// Counts a failed login, starting a new window of `window` if the last
+    /// one has passed, and returns the updated count.
+    async fn record_login_failure(
+        &self,
+        username: &str,
+        window: chrono::Duration,
+    ) -> Result<LoginFailures, Self::Error>;
+
+    /// Forgets the failures for the username, after a successful login.
+    async fn clear_login_failures(&self, username: &str) -> Result<(), Self::Error>;
+
+    /// Removes every count whose window has passed, returning the number of
+    /// counts removed.
+    async fn remove_expired_login_failures(&self) -> Result<u64, Self::Error>;
+}
+
+/// The contents of a blob as it's being uploaded, blobs can be far larger
+/// than we'd want to hold in memory at once.
+pub type BlobStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Identifies a blob by the SHA3-256 digest of its contents, so the same
+/// contents are only ever stored once regardless of how many times they're
+/// uploaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BlobId(pub [u8; 32]);
+
+impl BlobId {
+    /// Builds the id of a blob from a hasher that has been fed its contents.
+    pub fn from_hasher(hasher: Sha3_256) -> Self {
+        Self(hasher.finalize().into())
+    }
+
+    /// Parses the hex-encoded form of the id, as returned by its `Display`
+    /// impl.
+    pub fn parse(id: &str) -> Option<Self> {
+        let mut out = [0_u8; 32];
+        hex::decode_to_slice(id, &mut out).ok()?;
+        Some(Self(out))
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// A range of bytes within a blob, `end` is exclusive and the range runs to
+/// the end of the blob if it isn't given.
+#[derive(Copy, Clone, Debug)]
+pub struct BlobRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl BlobRange {
+    /// Resolves the range against a blob of the given length, clamping it to
+    /// the bounds of the blob.
+    pub fn clamp(self, len: u64) -> Range<u64> {
+        let end = self.end.map_or(len, |end| end.min(len));
+        self.start.min(end)..end
+    }
+}
+
+/// Stores the contents of blobs, which may live somewhere other than the rest
+/// of the data.
+///
+/// Blobs are immutable and keyed by their contents, tracking which objects
+/// reference a blob is left to the main store so that it works the same
+/// regardless of where the contents end up.
+#[async_trait]
+pub trait BlobProvider {
+    type Error;
+
+    /// Stores the contents of a blob, returning the id it can be fetched by.
+    async fn put_blob(&self, contents: BlobStream) -> Result<BlobId, Self::Error>;
+
+    /// Fetches the contents of a blob, or a range of them.
+    async fn get_blob(
+        &self,
+        id: BlobId,
+        range: Option<BlobRange>,
+    ) -> Result<Option<Bytes>, Self::Error>;
+
+    /// Fetches the size of a blob in octets, without reading its contents.
+    async fn blob_size(&self, id: BlobId) -> Result<Option<u64>, Self::Error>;
+
+    /// Removes the contents of a blob, deleting a blob that doesn't exist
+    /// isn't an error.
+    async fn delete_blob(&self, id: BlobId) -> Result<(), Self::Error>;
+}
+
+/// Tracks which accounts each blob is available within, and which blobs are
+/// still referenced by objects.
+///
+/// Blobs themselves are shared between every account that uploads the same
+/// contents, so an account can only fetch a blob once it has been linked to
+/// it. Blobs that no object references are garbage collected once they've
+/// gone long enough without being uploaded again.
+#[async_trait]
+pub trait BlobReferenceProvider {
+    type Error;
+
+    /// Makes the blob available within the account as it's uploaded to it,
+    /// restarting the blob's grace period before it can be collected.
+    async fn link_blob(&self, account: Uuid, blob: BlobId) -> Result<(), Self::Error>;
+
+    /// Removes the blob from the account, unlinking a blob that isn't linked
+    /// isn't an error.
+    async fn unlink_blob(&self, account: Uuid, blob: BlobId) -> Result<(), Self::Error>;
+
+    /// Whether the blob is available within the account.
+    async fn is_blob_linked(&self, account: Uuid, blob: BlobId) -> Result<bool, Self::Error>;
+
+    /// Records that an object references the blob, such as a contact's
+    /// photo, keeping it from being collected until the reference is
+    /// removed.
+    async fn reference_blob(&self, blob: BlobId) -> Result<(), Self::Error>;
+
+    /// Removes a reference added by [`Self::reference_blob`], as the object
+    /// referencing the blob is destroyed.
+    async fn unreference_blob(&self, blob: BlobId) -> Result<(), Self::Error>;
+
+    /// Fetches the blobs that no object references and that haven't been
+    /// uploaded since `uploaded_before`.
+    async fn get_unreferenced_blobs(
+        &self,
+        uploaded_before: DateTime<Utc>,
+    ) -> Result<Vec<BlobId>, Self::Error>;
+
+    /// Removes the blob from every account and forgets it was ever uploaded,
+    /// returning `false` and leaving it be if it has been referenced or
+    /// uploaded again since `uploaded_before`.
+    async fn forget_blob(
+        &self,
+        blob: BlobId,
+        uploaded_before: DateTime<Utc>,
+    ) -> Result<bool, Self::Error>;
+}
+
+/// A URL registered by a client to have changes to a user's data pushed to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PushSubscription {
+    pub id: Uuid,
+    /// The user the subscription belongs to.
+    pub user_id: Uuid,
+    pub device_client_id: String,
+    pub url: Url,
+    /// The code sent to the URL when the subscription was created.
+    pub verification_code: String,
+    /// Whether the client has proven it receives what's pushed to the URL by
+    /// setting the subscription's `verificationCode`. Nothing but the
+    /// verification is pushed until it has.
+    pub verified: bool,
+    pub expires: DateTime<Utc>,
+    /// The data types the client wants to be notified about, or `None` for
+    /// all of them.
+    pub types: Option<Vec<String>>,
+}
+
+impl PushSubscription {
+    /// Whether changes of the given data type should be pushed to the
+    /// subscription.
+    pub fn wants(&self, data_type: &str) -> bool {
+        self.types
+            .as_ref()
+            .is_none_or(|types| types.iter().any(|t| t == data_type))
+    }
+}
+
+#[async_trait]
+pub trait PushSubscriptionProvider {
+    type Error;
+
+    /// Creates the subscription, or replaces it if it already exists.
+    async fn put_push_subscription(
+        &self,
+        subscription: PushSubscription,
+    ) -> Result<(), Self::Error>;
+
+    /// Fetches every subscription belonging to the user, including any that
+    /// have expired.
+    async fn get_push_subscriptions_for_user(
+        &self,
+        user: Uuid,
+    ) -> Result<Vec<PushSubscription>, Self::Error>;
+
+    /// Removes the subscription, returning whether it existed.
+    async fn delete_push_subscription(&self, user: Uuid, id: Uuid) -> Result<bool, Self::Error>;
 }
 
+/// How much of an account a user has been granted access to.
+///
+/// Persisted as its discriminant, so the value of each variant must never
+/// change. Levels are ordered by how much they allow, rather than by their
+/// discriminant.
 #[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum AccountAccessLevel {
-    Owner,
+    Owner = 0,
+    /// May read the account, but not change anything within it.
+    Reader = 1,
+    /// May read and change objects within the account.
+    Writer = 2,
+    /// May change objects within the account and who it's shared with.
+    Admin = 3,
+}
+
+impl AccountAccessLevel {
+    /// Whether the user may change objects within the account.
+    pub fn can_write(self) -> bool {
+        !matches!(self, Self::Reader)
+    }
+
+    fn rank(self) -> u8 {
+        match self {
+            Self::Reader => 0,
+            Self::Writer => 1,
+            Self::Admin => 2,
+            Self::Owner => 3,
+        }
+    }
+}
+
+impl PartialOrd for AccountAccessLevel {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for AccountAccessLevel {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
+
+impl TryFrom<u8> for AccountAccessLevel {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Owner),
+            1 => Ok(Self::Reader),
+            2 => Ok(Self::Writer),
+            3 => Ok(Self::Admin),
+            v => Err(v),
+        }
+    }
+}
+
+/// Whether a store instance accepts writes, or only serves reads while
+/// following a primary.
+#[derive(Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StoreRole {
+    #[default]
+    Primary,
+    ReadReplica,
 }
 
 #[derive(Deserialize)]
@@ -112,72 +788,258 @@ pub enum StoreConfig {
     RocksDb(rocksdb::Config),
 }
 
+/// Where blobs are stored, independent of the main store.
+#[derive(Deserialize, Default)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum BlobStoreConfig {
+    /// Blobs are kept in the main store alongside everything else.
+    #[default]
+    #[serde(rename = "rocksdb")]
+    RocksDb,
+    Filesystem(filesystem::Config),
+    S3(s3::Config),
+}
+
+/// Everything other than blobs, behind the provider traits.
+///
+/// A write is visible to every read started after it returns, from any task
+/// of the same instance, as it has been applied to the database by then
+/// whichever thread it ran on. Nothing is cached in front of the database,
+/// so there's nothing a read could be served from that a write left stale.
+///
+/// A read replica only sees the primary's writes once it next catches up
+/// with it, so may lag behind by up to its catch-up interval. As replicas
+/// reject writes, a request they serve never reads back its own writes.
 pub enum Store {
     RocksDb(rocksdb::RocksDb),
 }
 
 impl Store {
-    pub fn from_config(config: StoreConfig) -> Self {
+    /// Opens the configured store, publishing changes to user state to
+    /// `events`.
+    pub fn from_config(config: StoreConfig, events: EventBus) -> Result<Self, rocksdb::Error> {
         match config {
-            StoreConfig::RocksDb(config) => Self::RocksDb(rocksdb::RocksDb::new(config)),
+            StoreConfig::RocksDb(config) => {
+                Ok(Self::RocksDb(rocksdb::RocksDb::new(config, events)?))
+            }
         }
     }
-}
 
-#[async_trait]
-impl AccountProvider for Store {
-    type Error = rocksdb::Error;
+    /// Whether the store is a read replica, rejecting all writes.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Store::RocksDb(db) => db.is_read_only(),
+        }
+    }
 
-    async fn create_account(&self, account: Account) -> Result<(), Self::Error> {
+    /// Whether the store is able to serve requests.
+    pub fn is_healthy(&self) -> bool {
         match self {
-            Store::RocksDb(db) => db.create_account(account).await,
+            Store::RocksDb(db) => db.is_healthy(),
         }
     }
 
-    async fn attach_account_to_user(
+    /// Scans the store for broken invariants, removing any dangling index
+    /// entries found if `repair` is set. Nothing is changed otherwise.
+    pub async fn check_consistency(
         &self,
-        account: Uuid,
-        user: Uuid,
-        access: AccountAccessLevel,
-    ) -> Result<(), Self::Error> {
+        repair: bool,
+    ) -> Result<Vec<Inconsistency>, rocksdb::Error> {
         match self {
-            Store::RocksDb(db) => db.attach_account_to_user(account, user, access).await,
+            Store::RocksDb(db) => db.check_consistency(repair).await,
         }
     }
+}
 
-    async fn get_accounts_for_user(&self, user_id: Uuid) -> Result<Vec<Account>, Self::Error> {
+/// A broken invariant found by [`Store::check_consistency`].
+#[derive(Debug)]
+pub enum Inconsistency {
+    /// The username is indexed to a user that doesn't exist, or that has
+    /// since been given another username.
+    DanglingUsername { username: String },
+    /// The user has been granted access to an account that doesn't exist.
+    DanglingAccountAccess { user: Uuid, account: Uuid },
+    /// The user's access to the account is recorded differently, or only
+    /// in one of, the indexes keyed by user and by account.
+    MismatchedAccountAccess { user: Uuid, account: Uuid },
+    /// The user's sequence number can't be read as a 64-bit integer.
+    MalformedSeqNumber { user: Uuid },
+    /// The record, keyed by the hex encoded and possibly truncated key,
+    /// can't be decoded.
+    CorruptRecord { cf: &'static str, key: String },
+}
+
+impl Inconsistency {
+    /// Whether the inconsistency is a dangling or mismatched index entry
+    /// that's fixed when repairing the store.
+    pub fn is_repairable(&self) -> bool {
+        matches!(
+            self,
+            Self::DanglingUsername { .. }
+                | Self::DanglingAccountAccess { .. }
+                | Self::MismatchedAccountAccess { .. }
+        )
+    }
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Store::RocksDb(db) => db.get_accounts_for_user(user_id).await,
+            Self::DanglingUsername { username } => {
+                write!(f, "username {username:?} is indexed to a missing user")
+            }
+            Self::DanglingAccountAccess { user, account } => {
+                write!(f, "user {user} has access to missing account {account}")
+            }
+            Self::MismatchedAccountAccess { user, account } => {
+                write!(
+                    f,
+                    "access of user {user} to account {account} is indexed inconsistently"
+                )
+            }
+            Self::MalformedSeqNumber { user } => {
+                write!(f, "user {user} has a malformed sequence number")
+            }
+            Self::CorruptRecord { cf, key } => write!(f, "record {key} in {cf} is corrupt"),
         }
     }
 }
 
 #[async_trait]
-impl UserProvider for Store {
+impl AccountProvider for Store {
     type Error = rocksdb::Error;
 
-    async fn increment_seq_number_for_user(&self, user: Uuid) -> Result<(), Self::Error> {
+    async fn create_account(&self, account: Account) -> Result<(), Self::Error> {
         match self {
-            Store::RocksDb(db) => db.increment_seq_number_for_user(user).await,
+            Store::RocksDb(db) => db.create_account(account).await,
         }
     }
 
-    async fn fetch_seq_number_for_user(&self, user: Uuid) -> Result<u64, Self::Error> {
+    async fn attach_account_to_user(
+        &self,
+        account: Uuid,
+        user: Uuid,
+        access: AccountAccessLevel,
+        force: bool,
+    ) -> Result<AccountAccessLevel, Self::Error> {
         match self {
-            Store::RocksDb(db) => db.fetch_seq_number_for_user(user).await,
+            Store::RocksDb(db) => {
+                db.attach_account_to_user(account, user, access, force)
+                    .await
+            }
         }
     }
 
-    /// Checks if any users have been registered to decide whether a root
-    /// account should be created at boot.
-    async fn has_any_users(&self) -> Result<bool, Self::Error> {
+    async fn set_account_access(
+        &self,
+        account: Uuid,
+        user: Uuid,
+        access: AccountAccessLevel,
+    ) -> Result<(), Self::Error> {
         match self {
-            Store::RocksDb(db) => db.has_any_users().await,
+            Store::RocksDb(db) => db.set_account_access(account, user, access).await,
         }
     }
 
-    /// Creates or updates a user in the store.
-    async fn create_user(&self, user: User) -> Result<(), Self::Error> {
+    async fn detach_account_from_user(
+        &self,
+        account: Uuid,
+        user: Uuid,
+    ) -> Result<bool, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.detach_account_from_user(account, user).await,
+        }
+    }
+
+    async fn get_accounts_for_user(&self, user_id: Uuid) -> Result<Vec<Account>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_accounts_for_user(user_id).await,
+        }
+    }
+
+    async fn get_access_level(
+        &self,
+        user: Uuid,
+        account: Uuid,
+    ) -> Result<Option<AccountAccessLevel>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_access_level(user, account).await,
+        }
+    }
+
+    async fn get_access_levels_for_user(
+        &self,
+        user: Uuid,
+    ) -> Result<HashMap<Uuid, AccountAccessLevel>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_access_levels_for_user(user).await,
+        }
+    }
+
+    async fn get_users_for_account(
+        &self,
+        account: Uuid,
+    ) -> Result<Vec<(Uuid, AccountAccessLevel)>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_users_for_account(account).await,
+        }
+    }
+
+    async fn get_principals_account(&self) -> Result<Option<Uuid>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_principals_account().await,
+        }
+    }
+
+    async fn set_principals_account(&self, account: Uuid) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.set_principals_account(account).await,
+        }
+    }
+}
+
+#[async_trait]
+impl UserProvider for Store {
+    type Error = rocksdb::Error;
+
+    async fn increment_seq_number_for_user(&self, user: Uuid) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.increment_seq_number_for_user(user).await,
+        }
+    }
+
+    async fn fetch_seq_number_for_user(&self, user: Uuid) -> Result<u64, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.fetch_seq_number_for_user(user).await,
+        }
+    }
+
+    /// Checks if any users have been registered to decide whether a root
+    /// account should be created at boot.
+    async fn has_any_users(&self) -> Result<bool, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.has_any_users().await,
+        }
+    }
+
+    async fn get_user_ids(&self) -> Result<Vec<Uuid>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_user_ids().await,
+        }
+    }
+
+    async fn list_users(
+        &self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<User>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.list_users(after, limit).await,
+        }
+    }
+
+    /// Creates a new user in the store, along with their personal account.
+    async fn create_user(&self, user: User) -> Result<(Uuid, Uuid), Self::Error> {
         match self {
             Store::RocksDb(db) => db.create_user(user).await,
         }
@@ -189,4 +1051,562 @@ impl UserProvider for Store {
             Store::RocksDb(db) => db.get_by_username(username).await,
         }
     }
+
+    /// Fetches a user by their id, which unlike their username never
+    /// changes.
+    async fn get_by_id(&self, id: Uuid) -> Result<Option<User>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_by_id(id).await,
+        }
+    }
+
+    async fn update_user(&self, user: User) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.update_user(user).await,
+        }
+    }
+
+    async fn delete_user(&self, id: Uuid) -> Result<bool, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.delete_user(id).await,
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectProvider for Store {
+    type Error = rocksdb::Error;
+
+    async fn get_object<D: Persisted + Send + 'static>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        id: Uuid,
+    ) -> Result<Option<D>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_object(account, data_type, id).await,
+        }
+    }
+
+    async fn list_objects<D: Persisted + Send + 'static>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+    ) -> Result<Vec<(Uuid, D)>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.list_objects(account, data_type).await,
+        }
+    }
+
+    async fn query_objects<D, R, F>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        query: F,
+    ) -> Result<R, Self::Error>
+    where
+        D: Persisted + 'static,
+        R: Send + 'static,
+        F: FnOnce(&mut dyn Iterator<Item = Result<(Uuid, D), Self::Error>>) -> R + Send + 'static,
+    {
+        match self {
+            Store::RocksDb(db) => db.query_objects(account, data_type, query).await,
+        }
+    }
+
+    async fn put_object<D: Persisted + Sync>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        id: Uuid,
+        object: &D,
+    ) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.put_object(account, data_type, id, object).await,
+        }
+    }
+
+    async fn apply_set<D: Persisted + Sync>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        writes: &[ObjectWrite<D>],
+    ) -> Result<ObjectState<'static>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.apply_set(account, data_type, writes).await,
+        }
+    }
+
+    async fn state_for(
+        &self,
+        account: Uuid,
+        data_type: &str,
+    ) -> Result<ObjectState<'static>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.state_for(account, data_type).await,
+        }
+    }
+
+    async fn get_object_changes(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        since: u64,
+        max_changes: Option<usize>,
+    ) -> Result<Option<ObjectChanges>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => {
+                db.get_object_changes(account, data_type, since, max_changes)
+                    .await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LoginFailureProvider for Store {
+    type Error = rocksdb::Error;
+
+    async fn get_login_failures(
+        &self,
+        username: &str,
+    ) -> Result<Option<LoginFailures>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_login_failures(username).await,
+        }
+    }
+
+    async fn record_login_failure(
+        &self,
+        username: &str,
+        window: chrono::Duration,
+    ) -> Result<LoginFailures, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.record_login_failure(username, window).await,
+        }
+    }
+
+    async fn clear_login_failures(&self, username: &str) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.clear_login_failures(username).await,
+        }
+    }
+
+    async fn remove_expired_login_failures(&self) -> Result<u64, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.remove_expired_login_failures().await,
+        }
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for Store {
+    type Error = rocksdb::Error;
+
+    async fn store_auth_code(&self, code: &str, grant: OAuthGrant) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.store_auth_code(code, grant).await,
+        }
+    }
+
+    async fn take_auth_code(&self, code: &str) -> Result<Option<OAuthGrant>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.take_auth_code(code).await,
+        }
+    }
+
+    async fn store_token(&self, token: IssuedOAuthToken) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.store_token(token).await,
+        }
+    }
+
+    async fn get_by_access_token(
+        &self,
+        access_token: &str,
+    ) -> Result<Option<IssuedOAuthToken>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_by_access_token(access_token).await,
+        }
+    }
+
+    async fn get_by_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<IssuedOAuthToken>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_by_refresh_token(refresh_token).await,
+        }
+    }
+
+    async fn remove_token(&self, token: &IssuedOAuthToken) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.remove_token(token).await,
+        }
+    }
+
+    async fn remove_expired_tokens(&self) -> Result<u64, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.remove_expired_tokens().await,
+        }
+    }
+}
+
+#[async_trait]
+impl BlobProvider for Store {
+    type Error = rocksdb::Error;
+
+    async fn put_blob(&self, contents: BlobStream) -> Result<BlobId, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.put_blob(contents).await,
+        }
+    }
+
+    async fn get_blob(
+        &self,
+        id: BlobId,
+        range: Option<BlobRange>,
+    ) -> Result<Option<Bytes>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_blob(id, range).await,
+        }
+    }
+
+    async fn blob_size(&self, id: BlobId) -> Result<Option<u64>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.blob_size(id).await,
+        }
+    }
+
+    async fn delete_blob(&self, id: BlobId) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.delete_blob(id).await,
+        }
+    }
+}
+
+#[async_trait]
+impl BlobReferenceProvider for Store {
+    type Error = rocksdb::Error;
+
+    async fn link_blob(&self, account: Uuid, blob: BlobId) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.link_blob(account, blob).await,
+        }
+    }
+
+    async fn unlink_blob(&self, account: Uuid, blob: BlobId) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.unlink_blob(account, blob).await,
+        }
+    }
+
+    async fn is_blob_linked(&self, account: Uuid, blob: BlobId) -> Result<bool, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.is_blob_linked(account, blob).await,
+        }
+    }
+
+    async fn reference_blob(&self, blob: BlobId) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.reference_blob(blob).await,
+        }
+    }
+
+    async fn unreference_blob(&self, blob: BlobId) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.unreference_blob(blob).await,
+        }
+    }
+
+    async fn get_unreferenced_blobs(
+        &self,
+        uploaded_before: DateTime<Utc>,
+    ) -> Result<Vec<BlobId>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_unreferenced_blobs(uploaded_before).await,
+        }
+    }
+
+    async fn forget_blob(
+        &self,
+        blob: BlobId,
+        uploaded_before: DateTime<Utc>,
+    ) -> Result<bool, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.forget_blob(blob, uploaded_before).await,
+        }
+    }
+}
+
+#[async_trait]
+impl PushSubscriptionProvider for Store {
+    type Error = rocksdb::Error;
+
+    async fn put_push_subscription(
+        &self,
+        subscription: PushSubscription,
+    ) -> Result<(), Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.put_push_subscription(subscription).await,
+        }
+    }
+
+    async fn get_push_subscriptions_for_user(
+        &self,
+        user: Uuid,
+    ) -> Result<Vec<PushSubscription>, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.get_push_subscriptions_for_user(user).await,
+        }
+    }
+
+    async fn delete_push_subscription(&self, user: Uuid, id: Uuid) -> Result<bool, Self::Error> {
+        match self {
+            Store::RocksDb(db) => db.delete_push_subscription(user, id).await,
+        }
+    }
+}
+
+pub enum BlobStore {
+    Store(Arc<Store>),
+    Filesystem(filesystem::Filesystem),
+    S3(s3::S3),
+}
+
+impl BlobStore {
+    pub fn from_config(config: BlobStoreConfig, store: Arc<Store>) -> Self {
+        match config {
+            BlobStoreConfig::RocksDb => Self::Store(store),
+            BlobStoreConfig::Filesystem(config) => {
+                Self::Filesystem(filesystem::Filesystem::new(config))
+            }
+            BlobStoreConfig::S3(config) => Self::S3(s3::S3::new(config)),
+        }
+    }
+}
+
+#[async_trait]
+impl BlobProvider for BlobStore {
+    type Error = rocksdb::Error;
+
+    async fn put_blob(&self, contents: BlobStream) -> Result<BlobId, Self::Error> {
+        match self {
+            BlobStore::Store(store) => store.put_blob(contents).await,
+            BlobStore::Filesystem(fs) => Ok(fs.put_blob(contents).await?),
+            BlobStore::S3(s3) => Ok(s3.put_blob(contents).await?),
+        }
+    }
+
+    async fn get_blob(
+        &self,
+        id: BlobId,
+        range: Option<BlobRange>,
+    ) -> Result<Option<Bytes>, Self::Error> {
+        match self {
+            BlobStore::Store(store) => store.get_blob(id, range).await,
+            BlobStore::Filesystem(fs) => Ok(fs.get_blob(id, range).await?),
+            BlobStore::S3(s3) => Ok(s3.get_blob(id, range).await?),
+        }
+    }
+
+    async fn blob_size(&self, id: BlobId) -> Result<Option<u64>, Self::Error> {
+        match self {
+            BlobStore::Store(store) => store.blob_size(id).await,
+            BlobStore::Filesystem(fs) => Ok(fs.blob_size(id).await?),
+            BlobStore::S3(s3) => Ok(s3.blob_size(id).await?),
+        }
+    }
+
+    async fn delete_blob(&self, id: BlobId) -> Result<(), Self::Error> {
+        match self {
+            BlobStore::Store(store) => store.delete_blob(id).await,
+            BlobStore::Filesystem(fs) => Ok(fs.delete_blob(id).await?),
+            BlobStore::S3(s3) => Ok(s3.delete_blob(id).await?),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::extensions::contacts::AddressBook;
+
+    /// Leaves the store, opened at `dir`, refusing writes as it would after
+    /// losing its disk.
+    pub(crate) fn stop_writes(store: &Store, dir: &Path) {
+        match store {
+            Store::RocksDb(db) => rocksdb::tests::stop_writes(db, dir),
+        }
+    }
+
+    /// Every provider the store can be configured with, opened fresh within
+    /// the directory, which has to outlive them.
+    fn providers(dir: &Path) -> Vec<Arc<Store>> {
+        let rocksdb = format!("type = \"rocksdb\"\npath = {:?}", dir.join("rocksdb"));
+
+        vec![Arc::new(
+            Store::from_config(toml::from_str(&rocksdb).unwrap(), EventBus::new()).unwrap(),
+        )]
+    }
+
+    /// An address book whose name records which write it came from.
+    fn book(id: Uuid, name: String) -> AddressBook {
+        AddressBook {
+            id,
+            name,
+            is_subscribed: false,
+            owner: Uuid::nil(),
+            share_with: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn corrupt_password_hash_never_verifies() {
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "alice".to_string(),
+            password: "not a hash".to_string(),
+        };
+
+        assert!(!user.verify_password("not a hash"));
+        assert!(!user.verify_password(""));
+    }
+
+    #[test]
+    fn grant_with_malformed_scope_isnt_recovered() {
+        let grant = OAuthGrant {
+            owner_id: "owner".to_string(),
+            client_id: "client".to_string(),
+            scope: "bad\"scope".to_string(),
+            redirect_uri: "https://client.example/callback".parse().unwrap(),
+            until: Utc::now(),
+            public_extensions: HashMap::new(),
+            private_extensions: HashMap::new(),
+        };
+
+        assert!(Grant::try_from(grant).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn writes_are_read_back_from_other_tasks() {
+        const WRITERS: usize = 8;
+        const VERSIONS: usize = 25;
+
+        let dir = tempfile::tempdir().unwrap();
+
+        for store in providers(dir.path()) {
+            let account = Uuid::new_v4();
+
+            let writers = (0..WRITERS).map(|writer| {
+                let store = store.clone();
+
+                tokio::spawn(async move {
+                    let id = Uuid::new_v4();
+
+                    for version in 0..VERSIONS {
+                        let name = format!("{writer}-{version}");
+                        store
+                            .put_object(account, "AddressBook", id, &book(id, name.clone()))
+                            .await
+                            .unwrap();
+
+                        // read back from a task other than the one that wrote
+                        let store = store.clone();
+                        let read = tokio::spawn(async move {
+                            store
+                                .get_object::<AddressBook>(account, "AddressBook", id)
+                                .await
+                                .unwrap()
+                        });
+                        assert_eq!(read.await.unwrap().map(|book| book.name), Some(name));
+                    }
+
+                    (id, format!("{writer}-{}", VERSIONS - 1))
+                })
+            });
+
+            let mut expected = Vec::new();
+            for writer in futures::future::join_all(writers).await {
+                expected.push(writer.unwrap());
+            }
+            expected.sort();
+
+            let listed: Vec<_> = store
+                .list_objects::<AddressBook>(account, "AddressBook")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(id, book)| (id, book.name))
+                .collect();
+            assert_eq!(listed, expected);
+
+            assert_eq!(
+                store.state_for(account, "AddressBook").await.unwrap(),
+                encode_object_state((WRITERS * VERSIONS) as u64)
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn reads_see_every_write_behind_the_state_they_follow() {
+        const SETS: u64 = 50;
+
+        let dir = tempfile::tempdir().unwrap();
+
+        for store in providers(dir.path()) {
+            let account = Uuid::new_v4();
+            let ids = [Uuid::new_v4(), Uuid::new_v4()];
+
+            let writer = {
+                let store = store.clone();
+
+                tokio::spawn(async move {
+                    for version in 0..SETS {
+                        let writes =
+                            ids.map(|id| ObjectWrite::Put(id, book(id, version.to_string())));
+                        store
+                            .apply_set(account, "AddressBook", &writes)
+                            .await
+                            .unwrap();
+                    }
+                })
+            };
+
+            let reader = {
+                let store = store.clone();
+
+                tokio::spawn(async move {
+                    loop {
+                        let state = store.state_for(account, "AddressBook").await.unwrap();
+                        let state = decode_object_state(&state).unwrap();
+
+                        // every set bumps the state once for each object it writes
+                        let Some(written) = (state / 2).checked_sub(1) else {
+                            tokio::task::yield_now().await;
+                            continue;
+                        };
+
+                        for id in ids {
+                            let book = store
+                                .get_object::<AddressBook>(account, "AddressBook", id)
+                                .await
+                                .unwrap()
+                                .unwrap();
+                            assert!(book.name.parse::<u64>().unwrap() >= written);
+                        }
+
+                        if written == SETS - 1 {
+                            break;
+                        }
+                    }
+                })
+            };
+
+            writer.await.unwrap();
+            reader.await.unwrap();
+        }
+    }
 }
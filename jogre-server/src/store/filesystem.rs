@@ -0,0 +1,129 @@
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::PathBuf,
+};
+
+use axum::{async_trait, body::Bytes};
+use futures::TryStreamExt;
+use serde::Deserialize;
+use sha3::{Digest, Sha3_256};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+use crate::store::{BlobId, BlobProvider, BlobRange, BlobStream};
+
+/// Directory within the blob store that uploads are written to until their
+/// id is known.
+const STAGING_DIR: &str = "staging";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    /// Directory to write blobs to.
+    path: PathBuf,
+}
+
+/// Stores blobs as files on the local filesystem.
+pub struct Filesystem {
+    path: PathBuf,
+}
+
+impl Filesystem {
+    pub fn new(config: Config) -> Self {
+        Self { path: config.path }
+    }
+
+    /// Blobs are spread across directories by the first byte of their id to
+    /// keep the number of files in any one directory manageable.
+    fn blob_path(&self, id: BlobId) -> PathBuf {
+        let id = id.to_string();
+        self.path.join(&id[..2]).join(id)
+    }
+
+    async fn write_blob(
+        &self,
+        mut contents: BlobStream,
+        staging_path: &PathBuf,
+    ) -> std::io::Result<BlobId> {
+        let mut file = File::create(staging_path).await?;
+        let mut hasher = Sha3_256::new();
+
+        while let Some(chunk) = contents.try_next().await? {
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+
+        file.sync_all().await?;
+
+        let id = BlobId::from_hasher(hasher);
+        let path = self.blob_path(id);
+
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        fs::rename(staging_path, path).await?;
+
+        Ok(id)
+    }
+}
+
+#[async_trait]
+impl BlobProvider for Filesystem {
+    type Error = std::io::Error;
+
+    async fn put_blob(&self, contents: BlobStream) -> Result<BlobId, Self::Error> {
+        // the id of the blob isn't known until all of it has been read, so it
+        // is written to a staging file and moved into place once complete
+        let staging_dir = self.path.join(STAGING_DIR);
+        fs::create_dir_all(&staging_dir).await?;
+
+        let staging_path = staging_dir.join(Uuid::new_v4().to_string());
+        let res = self.write_blob(contents, &staging_path).await;
+
+        if res.is_err() {
+            // best effort, the upload itself has already failed
+            drop(fs::remove_file(&staging_path).await);
+        }
+
+        res
+    }
+
+    async fn get_blob(
+        &self,
+        id: BlobId,
+        range: Option<BlobRange>,
+    ) -> Result<Option<Bytes>, Self::Error> {
+        let mut file = match File::open(self.blob_path(id)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let len = file.metadata().await?.len();
+        let range = range.map_or(0..len, |range| range.clamp(len));
+        let range_len = range.end - range.start;
+
+        file.seek(SeekFrom::Start(range.start)).await?;
+
+        let mut out = Vec::with_capacity(usize::try_from(range_len).unwrap_or_default());
+        file.take(range_len).read_to_end(&mut out).await?;
+
+        Ok(Some(out.into()))
+    }
+
+    async fn blob_size(&self, id: BlobId) -> Result<Option<u64>, Self::Error> {
+        match fs::metadata(self.blob_path(id)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete_blob(&self, id: BlobId) -> Result<(), Self::Error> {
+        match fs::remove_file(self.blob_path(id)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,14 +1,203 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
 
-use axum::async_trait;
-use rocksdb::{IteratorMode, MergeOperands, Options, DB};
-use serde::Deserialize;
+use axum::{async_trait, body::Bytes};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use jmap_proto::endpoints::object::ObjectState;
+use metrics::increment_counter;
+use rocksdb::{
+    properties, ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, MergeOperands,
+    Options, SliceTransform, WriteBatch, DB,
+};
+use serde::{
+    de::{DeserializeOwned, Error as _},
+    Deserialize, Deserializer, Serialize,
+};
+use sha3::{Digest, Sha3_256};
+use tracing::{error, info};
 use uuid::Uuid;
 
-use crate::store::{Account, AccountAccessLevel, AccountProvider, User, UserProvider};
+use crate::{
+    context::events::{DomainEvent, EventBus},
+    extensions::{
+        contacts::{AddressBook, ContactCard, Contacts},
+        JmapDataExtension,
+    },
+    layers::logger::GenericError,
+    store::{
+        encode_object_state,
+        stored::{
+            unversioned, Persisted, StoredAccount, StoredAddressBook, StoredContactCard, StoredUser,
+        },
+        Account, AccountAccessLevel, AccountProvider, BlobId, BlobProvider, BlobRange,
+        BlobReferenceProvider, BlobStream, Inconsistency, IssuedOAuthToken, LoginFailureProvider,
+        LoginFailures, OAuthGrant, OAuthProvider, ObjectChanges, ObjectProvider, ObjectWrite,
+        PushSubscription, PushSubscriptionProvider, StoreRole, User, UserProvider,
+    },
+};
 
 #[derive(Debug)]
-pub enum Error {}
+pub enum Error {
+    /// A write was attempted against a read replica.
+    ReadOnly,
+    /// The blob store failed to read or write a blob.
+    Blob(std::io::Error),
+    /// The database failed to read or write.
+    Db(rocksdb::Error),
+    /// A value couldn't be encoded to be written.
+    Encode(bincode::error::EncodeError),
+    /// A record read back couldn't be decoded, most likely because it's
+    /// corrupt or was written by an incompatible version. The key is hex
+    /// encoded, and truncated if long.
+    Corrupt {
+        cf: &'static str,
+        key: String,
+        error: bincode::error::DecodeError,
+    },
+    /// A key or value read back wasn't the shape expected of its column
+    /// family.
+    Malformed(&'static str),
+    /// A column family wasn't opened with the database.
+    MissingColumnFamily(&'static str),
+    /// A user couldn't be created as their username belongs to another.
+    UsernameTaken,
+    /// The owner of a personal account can't be detached from it.
+    PersonalAccountOwner,
+    /// A user couldn't be updated as they no longer exist.
+    UserNotFound,
+    /// A read replica was configured without a `secondary-path`.
+    MissingSecondaryPath,
+    /// The store was written by a newer version of the server, using a
+    /// layout this version doesn't understand.
+    NewerStorageVersion(u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ReadOnly => f.write_str("store is a read replica"),
+            Self::Blob(error) => write!(f, "failed to access blob: {error}"),
+            Self::Db(error) => write!(f, "database error: {error}"),
+            Self::Encode(error) => write!(f, "failed to encode value: {error}"),
+            Self::Corrupt { cf, key, error } => {
+                write!(f, "corrupt record {key} in {cf}: {error}")
+            }
+            Self::Malformed(cf) => write!(f, "malformed entry in {cf}"),
+            Self::MissingColumnFamily(cf) => write!(f, "missing column family {cf}"),
+            Self::UsernameTaken => f.write_str("username is already taken"),
+            Self::PersonalAccountOwner => {
+                f.write_str("owner can't be detached from their personal account")
+            }
+            Self::UserNotFound => f.write_str("user does not exist"),
+            Self::MissingSecondaryPath => {
+                f.write_str("secondary-path must be set for read replicas")
+            }
+            Self::NewerStorageVersion(version) => write!(
+                f,
+                "store was written by a newer version of the server (storage version {version}, \
+                 expected at most {STORAGE_VERSION})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Blob(error) => Some(error),
+            Self::Db(error) => Some(error),
+            Self::Encode(error) => Some(error),
+            Self::Corrupt { error, .. } => Some(error),
+            Self::ReadOnly
+            | Self::Malformed(_)
+            | Self::MissingColumnFamily(_)
+            | Self::UsernameTaken
+            | Self::PersonalAccountOwner
+            | Self::UserNotFound
+            | Self::MissingSecondaryPath
+            | Self::NewerStorageVersion(_) => None,
+        }
+    }
+}
+
+impl GenericError for Error {}
+
+impl Error {
+    /// Whether the error means the database itself has failed, such that
+    /// anything else done with it is likely to fail too, rather than just
+    /// the operation that returned it.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Self::Db(error) => matches!(
+                error.kind(),
+                rocksdb::ErrorKind::IOError
+                    | rocksdb::ErrorKind::Corruption
+                    | rocksdb::ErrorKind::ShutdownInProgress
+                    | rocksdb::ErrorKind::ColumnFamilyDropped
+            ),
+            Self::MissingColumnFamily(_)
+            | Self::MissingSecondaryPath
+            | Self::NewerStorageVersion(_) => true,
+            Self::ReadOnly
+            | Self::Blob(_)
+            | Self::Encode(_)
+            | Self::Corrupt { .. }
+            | Self::Malformed(_)
+            | Self::UsernameTaken
+            | Self::PersonalAccountOwner
+            | Self::UserNotFound => false,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Self::Blob(error)
+    }
+}
+
+impl From<rocksdb::Error> for Error {
+    fn from(error: rocksdb::Error) -> Self {
+        Self::Db(error)
+    }
+}
+
+impl From<bincode::error::EncodeError> for Error {
+    fn from(error: bincode::error::EncodeError) -> Self {
+        Self::Encode(error)
+    }
+}
+
+/// Fetches the handle to a column family, which is only missing if it
+/// wasn't passed in when opening the database.
+fn cf<'a>(db: &'a DB, name: &'static str) -> Result<&'a ColumnFamily, Error> {
+    db.cf_handle(name).ok_or(Error::MissingColumnFamily(name))
+}
+
+/// The options a column family is opened with.
+fn column_family_options(db_options: &Options, name: &str) -> Options {
+    let mut options = db_options.clone();
+
+    if UUID_PREFIXED.contains(&name) {
+        options.set_prefix_extractor(SliceTransform::create_fixed_prefix(16));
+    }
+
+    options
+}
+
+/// Decodes a UUID stored as part of a key or value in the column family.
+fn decode_uuid(bytes: &[u8], cf: &'static str) -> Result<Uuid, Error> {
+    Uuid::from_slice(bytes).map_err(|_| Error::Malformed(cf))
+}
 
 const USER_BY_USERNAME_CF: &str = "users_by_username";
 const USER_BY_UUID_CF: &str = "users_by_uuid";
@@ -16,42 +205,667 @@ const USER_SEQ_NUMBER: &str = "users_seq_number";
 
 const ACCOUNTS_BY_UUID: &str = "accounts_by_uuid";
 const ACCOUNTS_ACCESS_BY_USER: &str = "accounts_access_by_user";
+/// The same access as [`ACCOUNTS_ACCESS_BY_USER`], keyed by account first.
+const ACCOUNTS_ACCESS_BY_ACCOUNT: &str = "accounts_access_by_account";
+
+const OAUTH_TOKENS: &str = "oauth_tokens";
+const OAUTH_REFRESH: &str = "oauth_refresh";
+const OAUTH_AUTH_CODES: &str = "oauth_auth_codes";
+const LOGIN_FAILURES: &str = "login_failures";
+
+const BLOBS: &str = "blobs";
+const BLOBS_BY_ACCOUNT: &str = "blobs_by_account";
+const BLOB_REFERENCES: &str = "blob_references";
+const BLOB_UPLOADED_AT: &str = "blob_uploaded_at";
+
+const PUSH_SUBSCRIPTIONS: &str = "push_subscriptions";
+
+const OBJECTS: &str = "objects";
+const OBJECT_STATES: &str = "object_states";
+const CHANGE_LOG: &str = "change_log";
+const CHANGE_LOG_FLOORS: &str = "change_log_floors";
+
+const META: &str = "meta";
+
+/// The column families keyed by the UUID of whatever their records are
+/// listed by, which are given a prefix extractor of its length so that
+/// iterating over the records of one UUID can't wander into the next.
+/// Scans across every UUID in them have to use a total order iterator.
+const UUID_PREFIXED: [&str; 8] = [
+    ACCOUNTS_ACCESS_BY_USER,
+    ACCOUNTS_ACCESS_BY_ACCOUNT,
+    PUSH_SUBSCRIPTIONS,
+    BLOBS_BY_ACCOUNT,
+    OBJECTS,
+    OBJECT_STATES,
+    CHANGE_LOG,
+    CHANGE_LOG_FLOORS,
+];
+
+/// Version of the layout of the records in the store, bumped whenever
+/// existing records have to be rewritten when the store is opened.
+const STORAGE_VERSION: u64 = 2;
+const STORAGE_VERSION_KEY: &[u8] = b"storage_version";
+const PRINCIPALS_ACCOUNT_KEY: &[u8] = b"principals_account";
 
 const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
 
+type RecordConfig<const LIMIT: usize> = bincode::config::Configuration<
+    bincode::config::LittleEndian,
+    bincode::config::Varint,
+    bincode::config::Limit<LIMIT>,
+>;
+
+// Caps on how much a single record of each kind may claim to hold once
+// decoded, so a garbage length prefix in a corrupt record fails to decode
+// rather than attempting a huge allocation. Each is far beyond anything the
+// server writes.
+const USER_RECORD: RecordConfig<{ 64 * 1024 }> = BINCODE_CONFIG.with_limit();
+const ACCOUNT_RECORD: RecordConfig<{ 64 * 1024 }> = BINCODE_CONFIG.with_limit();
+const OAUTH_RECORD: RecordConfig<{ 1024 * 1024 }> = BINCODE_CONFIG.with_limit();
+const LOGIN_FAILURES_RECORD: RecordConfig<{ 4 * 1024 }> = BINCODE_CONFIG.with_limit();
+const CHANGE_LOG_RECORD: RecordConfig<{ 4 * 1024 }> = BINCODE_CONFIG.with_limit();
+const PUSH_SUBSCRIPTION_RECORD: RecordConfig<{ 64 * 1024 }> = BINCODE_CONFIG.with_limit();
+const OBJECT_RECORD: RecordConfig<{ 16 * 1024 * 1024 }> = BINCODE_CONFIG.with_limit();
+
+/// Decodes a record read back from the column family, counting and logging
+/// it as corrupt if it can't be.
+fn decode<T: DeserializeOwned>(
+    config: impl bincode::config::Config,
+    bytes: &[u8],
+    cf: &'static str,
+    key: &[u8],
+) -> Result<T, Error> {
+    bincode::serde::decode_from_slice(bytes, config)
+        .map(|(value, _)| value)
+        .map_err(|error| {
+            /// Keys longer than this are truncated in errors.
+            const MAX_KEY_LEN: usize = 32;
+
+            let truncated = key.len() > MAX_KEY_LEN;
+
+            let mut key = hex::encode(&key[..key.len().min(MAX_KEY_LEN)]);
+            if truncated {
+                key.push_str("...");
+            }
+
+            increment_counter!("jmap_store_corrupt_records_total", "cf" => cf);
+            error!(cf, key, %error, "Failed to decode record");
+
+            Error::Corrupt { cf, key, error }
+        })
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
     path: PathBuf,
+    /// Whether this instance owns the database at `path`, or follows the
+    /// writes of the instance that does as a read replica.
+    #[serde(default)]
+    role: StoreRole,
+    /// Directory a read replica keeps its own logs in, required for read
+    /// replicas.
+    secondary_path: Option<PathBuf>,
+    /// How often, in seconds, a read replica catches up with the primary.
+    #[serde(default = "Config::default_catch_up_interval")]
+    catch_up_interval: u64,
+    /// How often, in seconds, the database is checked for background errors,
+    /// and a primary for whether it still accepts writes.
+    #[serde(default = "Config::default_health_check_interval")]
+    health_check_interval: u64,
+    /// How many changes to each data type within an account are kept to
+    /// serve `Foo/changes`, clients further behind have to fetch everything
+    /// again.
+    #[serde(default = "Config::default_change_log_max_entries")]
+    change_log_max_entries: u64,
+    /// How long, in seconds, changes are kept to serve `Foo/changes`.
+    #[serde(
+        default = "Config::default_change_log_max_age",
+        deserialize_with = "Config::deserialize_change_log_max_age"
+    )]
+    change_log_max_age: chrono::Duration,
+    /// How often, in seconds, changes past either limit are removed.
+    #[serde(default = "Config::default_change_log_compaction_interval")]
+    change_log_compaction_interval: u64,
+}
+
+impl Config {
+    const fn default_catch_up_interval() -> u64 {
+        5
+    }
+
+    const fn default_health_check_interval() -> u64 {
+        10
+    }
+
+    const fn default_change_log_max_entries() -> u64 {
+        10_000
+    }
+
+    fn default_change_log_max_age() -> chrono::Duration {
+        chrono::Duration::days(30)
+    }
+
+    /// Reads the age in seconds, rejecting ages too long to subtract from
+    /// a timestamp.
+    fn deserialize_change_log_max_age<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<chrono::Duration, D::Error> {
+        let seconds = u64::deserialize(deserializer)?;
+
+        chrono::Duration::from_std(Duration::from_secs(seconds))
+            .ok()
+            .filter(|age| Utc::now().checked_sub_signed(*age).is_some())
+            .ok_or_else(|| D::Error::custom(format!("{seconds} seconds is too long an age")))
+    }
+
+    const fn default_change_log_compaction_interval() -> u64 {
+        60 * 60
+    }
 }
 
-// TODO: lots of blocking on async thread
+/// Every call into the database blocks, so each operation runs on Tokio's
+/// blocking pool rather than stalling the async worker it was called from.
 pub struct RocksDb {
     db: Arc<DB>,
+    events: EventBus,
+    read_only: bool,
+    /// Cleared while the database reports background errors or refuses
+    /// writes.
+    healthy: Arc<AtomicBool>,
+    /// Held while writing objects, so each change is given its own state.
+    object_writes: Arc<Mutex<()>>,
+    /// Held while changing a user's access to an account, so it isn't
+    /// lowered by accident.
+    access_writes: Arc<Mutex<()>>,
+    /// Held while creating, updating or deleting users, so two can't claim
+    /// the same username and a deleted user isn't written back.
+    user_writes: Arc<Mutex<()>>,
+    /// Held while redeeming an authorization code, so it can only be
+    /// redeemed once.
+    auth_code_writes: Arc<Mutex<()>>,
 }
 
 impl RocksDb {
-    pub fn new(config: Config) -> Self {
+    /// Opens the database, upgrading the layout of its records if it was
+    /// written by an older version.
+    pub fn new(config: Config, events: EventBus) -> Result<Self, Error> {
         let mut db_options = Options::default();
         db_options.create_if_missing(true);
         db_options.set_merge_operator_associative("test operator", rocksdb_merger);
         db_options.create_missing_column_families(true);
 
-        let db = DB::open_cf_with_opts(
-            &db_options,
-            config.path,
-            [
-                (USER_BY_USERNAME_CF, db_options.clone()),
-                (USER_BY_UUID_CF, db_options.clone()),
-                (ACCOUNTS_BY_UUID, db_options.clone()),
-                (ACCOUNTS_ACCESS_BY_USER, db_options.clone()),
-                (USER_SEQ_NUMBER, db_options.clone()),
-            ],
-        )
-        .unwrap();
+        let column_families = [
+            USER_BY_USERNAME_CF,
+            USER_BY_UUID_CF,
+            ACCOUNTS_BY_UUID,
+            ACCOUNTS_ACCESS_BY_USER,
+            ACCOUNTS_ACCESS_BY_ACCOUNT,
+            USER_SEQ_NUMBER,
+            OAUTH_TOKENS,
+            OAUTH_REFRESH,
+            OAUTH_AUTH_CODES,
+            LOGIN_FAILURES,
+            BLOBS,
+            BLOBS_BY_ACCOUNT,
+            BLOB_REFERENCES,
+            BLOB_UPLOADED_AT,
+            PUSH_SUBSCRIPTIONS,
+            OBJECTS,
+            OBJECT_STATES,
+            CHANGE_LOG,
+            CHANGE_LOG_FLOORS,
+            META,
+        ];
+
+        // opened once at startup before anything is served, so blocking
+        // while the write-ahead log is replayed doesn't hold up any requests
+        let db = match config.role {
+            StoreRole::Primary => DB::open_cf_with_opts(
+                &db_options,
+                config.path,
+                column_families.map(|cf| (cf, column_family_options(&db_options, cf))),
+            )
+            .map_err(Error::from),
+            StoreRole::ReadReplica => {
+                // secondaries need to keep every file open to follow the primary
+                db_options.set_max_open_files(-1);
+
+                let secondary_path = config.secondary_path.ok_or(Error::MissingSecondaryPath)?;
+
+                DB::open_cf_descriptors_as_secondary(
+                    &db_options,
+                    config.path.as_path(),
+                    secondary_path.as_path(),
+                    column_families.map(|cf| {
+                        ColumnFamilyDescriptor::new(cf, column_family_options(&db_options, cf))
+                    }),
+                )
+                .map_err(Error::from)
+            }
+        }?;
+
+        let db = Arc::new(db);
+
+        // read replicas can't write, they wait for the primary to upgrade
+        // the records
+        match config.role {
+            StoreRole::Primary => upgrade_storage(&db),
+            StoreRole::ReadReplica => check_storage_version(&db).map(|_| ()),
+        }?;
+
+        if config.role == StoreRole::ReadReplica {
+            spawn_catch_up_with_primary(
+                Arc::downgrade(&db),
+                Duration::from_secs(config.catch_up_interval),
+            );
+        }
+
+        let healthy = Arc::new(AtomicBool::new(true));
+
+        spawn_health_check(
+            Arc::downgrade(&db),
+            healthy.clone(),
+            Duration::from_secs(config.health_check_interval),
+            config.role == StoreRole::Primary,
+        );
+
+        // read replicas can't write, the primary compacts for them
+        if config.role == StoreRole::Primary {
+            spawn_change_log_compaction(
+                Arc::downgrade(&db),
+                Duration::from_secs(config.change_log_compaction_interval),
+                config.change_log_max_entries,
+                config.change_log_max_age,
+            );
+        }
+
+        Ok(Self {
+            db,
+            events,
+            read_only: config.role == StoreRole::ReadReplica,
+            healthy,
+            object_writes: Arc::default(),
+            access_writes: Arc::default(),
+            user_writes: Arc::default(),
+            auth_code_writes: Arc::default(),
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether the database was free of background errors, and accepting
+    /// writes, when it was last checked.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn ensure_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn check_consistency(&self, repair: bool) -> Result<Vec<Inconsistency>, Error> {
+        if repair {
+            self.ensure_writable()?;
+        }
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let by_username_handle = cf(&db, USER_BY_USERNAME_CF)?;
+            let by_uuid_handle = cf(&db, USER_BY_UUID_CF)?;
+            let seq_handle = cf(&db, USER_SEQ_NUMBER)?;
+
+            let mut found = Vec::new();
+            let mut batch = WriteBatch::default();
+
+            find_corrupt_records::<StoredUser>(&db, USER_BY_UUID_CF, USER_RECORD, &mut found)?;
+            find_corrupt_records::<StoredAccount>(
+                &db,
+                ACCOUNTS_BY_UUID,
+                ACCOUNT_RECORD,
+                &mut found,
+            )?;
+            find_corrupt_records::<IssuedOAuthToken>(&db, OAUTH_TOKENS, OAUTH_RECORD, &mut found)?;
+            find_corrupt_records::<IssuedOAuthToken>(&db, OAUTH_REFRESH, OAUTH_RECORD, &mut found)?;
+            find_corrupt_records::<OAuthGrant>(&db, OAUTH_AUTH_CODES, OAUTH_RECORD, &mut found)?;
+            find_corrupt_records::<LoginFailures>(
+                &db,
+                LOGIN_FAILURES,
+                LOGIN_FAILURES_RECORD,
+                &mut found,
+            )?;
+            find_corrupt_records::<ChangeLogEntry>(&db, CHANGE_LOG, CHANGE_LOG_RECORD, &mut found)?;
+            find_corrupt_records::<PushSubscription>(
+                &db,
+                PUSH_SUBSCRIPTIONS,
+                PUSH_SUBSCRIPTION_RECORD,
+                &mut found,
+            )?;
+            find_corrupt_objects(&db, &mut found)?;
+
+            for entry in db.iterator_cf(by_username_handle, IteratorMode::Start) {
+                let (username, id) = entry?;
+
+                let user = match db
+                    .get_pinned_cf(by_uuid_handle, &id)?
+                    .map(|bytes| decode(USER_RECORD, &bytes, USER_BY_UUID_CF, &id))
+                    .transpose()
+                    .map(|user| user.map(User::from_stored))
+                {
+                    Ok(user) => user,
+                    // already reported above
+                    Err(Error::Corrupt { .. }) => continue,
+                    Err(error) => return Err(error),
+                };
+
+                if user.is_none_or(|user| user.username.as_bytes() != username.as_ref()) {
+                    found.push(Inconsistency::DanglingUsername {
+                        username: String::from_utf8_lossy(&username).into_owned(),
+                    });
+                    batch.delete_cf(by_username_handle, username);
+                }
+            }
+
+            find_inconsistent_access(&db, &mut batch, &mut found)?;
+
+            for entry in db.iterator_cf(seq_handle, IteratorMode::Start) {
+                let (user, value) = entry?;
+
+                if value.len() != std::mem::size_of::<u64>() {
+                    found.push(Inconsistency::MalformedSeqNumber {
+                        user: decode_uuid(&user, USER_SEQ_NUMBER)?,
+                    });
+                }
+            }
+
+            if repair {
+                db.write(batch)?;
+            }
+
+            Ok(found)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+/// Checks every user's access to accounts is to an account that exists, and
+/// is indexed the same by user and by account, adding the fixes to `batch`.
+fn find_inconsistent_access(
+    db: &DB,
+    batch: &mut WriteBatch,
+    found: &mut Vec<Inconsistency>,
+) -> Result<(), Error> {
+    let accounts_handle = cf(db, ACCOUNTS_BY_UUID)?;
+    let access_handle = cf(db, ACCOUNTS_ACCESS_BY_USER)?;
+    let by_account_handle = cf(db, ACCOUNTS_ACCESS_BY_ACCOUNT)?;
+
+    for entry in db.full_iterator_cf(access_handle, IteratorMode::Start) {
+        let (key, value) = entry?;
+        let user = decode_uuid(&key[..16], ACCOUNTS_ACCESS_BY_USER)?;
+        let account = decode_uuid(&key[16..], ACCOUNTS_ACCESS_BY_USER)?;
+        let reverse_key = account_users_key(account, user);
+
+        if db
+            .get_pinned_cf(accounts_handle, account.as_bytes())?
+            .is_none()
+        {
+            found.push(Inconsistency::DanglingAccountAccess { user, account });
+            batch.delete_cf(access_handle, key);
+            batch.delete_cf(by_account_handle, reverse_key);
+        } else if db
+            .get_pinned_cf(by_account_handle, reverse_key)?
+            .is_none_or(|reverse| *reverse != *value)
+        {
+            found.push(Inconsistency::MismatchedAccountAccess { user, account });
+            batch.put_cf(by_account_handle, reverse_key, value);
+        }
+    }
+
+    for entry in db.full_iterator_cf(by_account_handle, IteratorMode::Start) {
+        let (key, _) = entry?;
+        let account = decode_uuid(&key[..16], ACCOUNTS_ACCESS_BY_ACCOUNT)?;
+        let user = decode_uuid(&key[16..], ACCOUNTS_ACCESS_BY_ACCOUNT)?;
+
+        if db
+            .get_pinned_cf(access_handle, account_access_key(user, account))?
+            .is_none()
+        {
+            found.push(Inconsistency::MismatchedAccountAccess { user, account });
+            batch.delete_cf(by_account_handle, key);
+        }
+    }
 
-        Self { db: Arc::new(db) }
+    Ok(())
+}
+
+/// Decodes every record in the column family, noting those that can't be.
+fn find_corrupt_records<T: DeserializeOwned>(
+    db: &DB,
+    cf_name: &'static str,
+    config: impl bincode::config::Config,
+    found: &mut Vec<Inconsistency>,
+) -> Result<(), Error> {
+    for entry in db.full_iterator_cf(cf(db, cf_name)?, IteratorMode::Start) {
+        let (key, value) = entry?;
+
+        match decode::<T>(config, &value, cf_name, &key) {
+            Ok(_) => {}
+            Err(Error::Corrupt { cf, key, .. }) => {
+                found.push(Inconsistency::CorruptRecord { cf, key });
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes every object as the data type named in its key, noting those
+/// that can't be. Objects of data types the server doesn't know the layout
+/// of are left alone.
+fn find_corrupt_objects(db: &DB, found: &mut Vec<Inconsistency>) -> Result<(), Error> {
+    let address_book = <Contacts as JmapDataExtension<AddressBook>>::ENDPOINT.as_bytes();
+    let contact_card = <Contacts as JmapDataExtension<ContactCard>>::ENDPOINT.as_bytes();
+
+    for entry in db.full_iterator_cf(cf(db, OBJECTS)?, IteratorMode::Start) {
+        let (key, value) = entry?;
+
+        let data_type = key
+            .get(16..)
+            .and_then(|rest| rest.split(|byte| *byte == 0).next());
+
+        let decoded = match data_type {
+            Some(data_type) if data_type == address_book => {
+                decode::<StoredAddressBook>(OBJECT_RECORD, &value, OBJECTS, &key).map(|_| ())
+            }
+            Some(data_type) if data_type == contact_card => {
+                decode::<StoredContactCard>(OBJECT_RECORD, &value, OBJECTS, &key).map(|_| ())
+            }
+            _ => continue,
+        };
+
+        match decoded {
+            Ok(()) => {}
+            Err(Error::Corrupt { cf, key, .. }) => {
+                found.push(Inconsistency::CorruptRecord { cf, key });
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Rewrites any records written by older versions of the server in their
+/// current layout.
+fn upgrade_storage(db: &DB) -> Result<(), Error> {
+    let version = check_storage_version(db)?;
+
+    if version == STORAGE_VERSION {
+        return Ok(());
+    }
+
+    let mut batch = WriteBatch::default();
+
+    if version < 1 {
+        rewrite_records::<unversioned::User, StoredUser>(
+            db,
+            USER_BY_UUID_CF,
+            USER_RECORD,
+            &mut batch,
+        )?;
+        rewrite_records::<unversioned::Account, StoredAccount>(
+            db,
+            ACCOUNTS_BY_UUID,
+            ACCOUNT_RECORD,
+            &mut batch,
+        )?;
+    }
+
+    if version < 2 {
+        let by_account_handle = cf(db, ACCOUNTS_ACCESS_BY_ACCOUNT)?;
+
+        for entry in db.full_iterator_cf(cf(db, ACCOUNTS_ACCESS_BY_USER)?, IteratorMode::Start) {
+            let (key, value) = entry?;
+            let user = decode_uuid(&key[..16], ACCOUNTS_ACCESS_BY_USER)?;
+            let account = decode_uuid(&key[16..], ACCOUNTS_ACCESS_BY_USER)?;
+
+            batch.put_cf(by_account_handle, account_users_key(account, user), value);
+        }
+    }
+
+    batch.put_cf(
+        cf(db, META)?,
+        STORAGE_VERSION_KEY,
+        STORAGE_VERSION.to_be_bytes(),
+    );
+    db.write(batch)?;
+
+    info!(from = version, to = STORAGE_VERSION, "Upgraded store");
+
+    Ok(())
+}
+
+/// Reads the version of the layout of the records in the store, refusing
+/// to go on with a store written by a newer version of the server.
+fn check_storage_version(db: &DB) -> Result<u64, Error> {
+    let version = read_counter(db, META, STORAGE_VERSION_KEY)?;
+
+    if version > STORAGE_VERSION {
+        return Err(Error::NewerStorageVersion(version));
+    }
+
+    Ok(version)
+}
+
+/// Adds every record in the column family to the batch, decoded from an
+/// old layout and encoded in a new one.
+fn rewrite_records<Old: DeserializeOwned, New: Serialize + From<Old>>(
+    db: &DB,
+    cf_name: &'static str,
+    config: impl bincode::config::Config,
+    batch: &mut WriteBatch,
+) -> Result<(), Error> {
+    let handle = cf(db, cf_name)?;
+
+    for entry in db.iterator_cf(handle, IteratorMode::Start) {
+        let (key, value) = entry?;
+        let old: Old = decode(config, &value, cf_name, &key)?;
+
+        batch.put_cf(
+            handle,
+            key,
+            bincode::serde::encode_to_vec(New::from(old), BINCODE_CONFIG)?,
+        );
     }
+
+    Ok(())
+}
+
+/// Periodically tails the primary's logs into a read replica, until the
+/// database is dropped.
+fn spawn_catch_up_with_primary(db: Weak<DB>, every: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+
+        loop {
+            interval.tick().await;
+
+            let Some(db) = db.upgrade() else {
+                break;
+            };
+
+            if let Err(error) = tokio::task::spawn_blocking(move || db.try_catch_up_with_primary())
+                .await
+                .unwrap()
+            {
+                error!(?error, "Failed to catch up with primary");
+            }
+        }
+    });
+}
+
+/// Periodically checks the database for background errors, such as a failed
+/// flush or compaction after the disk fills up, which stop the database
+/// accepting writes until they're resolved. Not every such failure is
+/// counted as a background error, so primaries also try an empty write,
+/// which fails while writes are stopped. Runs until the database is dropped.
+fn spawn_health_check(db: Weak<DB>, healthy: Arc<AtomicBool>, every: Duration, probe_writes: bool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+
+        loop {
+            interval.tick().await;
+
+            let Some(db) = db.upgrade() else {
+                break;
+            };
+
+            let (background_errors, writable) = tokio::task::spawn_blocking(move || {
+                let writable = if probe_writes {
+                    db.write(WriteBatch::default())
+                } else {
+                    Ok(())
+                };
+                (
+                    db.property_int_value(properties::BACKGROUND_ERRORS),
+                    writable,
+                )
+            })
+            .await
+            .unwrap();
+
+            let count = match background_errors {
+                Ok(count) => count.unwrap_or_default(),
+                Err(error) => {
+                    error!(?error, "Failed to read RocksDB background error count");
+                    continue;
+                }
+            };
+
+            let is_healthy = count == 0 && writable.is_ok();
+            let was_healthy = healthy.swap(is_healthy, Ordering::Relaxed);
+
+            if was_healthy && !is_healthy {
+                error!(
+                    count,
+                    write_error = writable.err().map(rocksdb::Error::into_string),
+                    "RocksDB has encountered errors, writes may be failing"
+                );
+            } else if !was_healthy && is_healthy {
+                info!("RocksDB errors have cleared");
+            }
+        }
+    });
 }
 
 #[allow(clippy::unnecessary_wraps)] // rocksdb api restriction
@@ -86,6 +900,10 @@ fn rocksdb_merger(
                     new_val.fill(0);
                 }
             }
+            Some(MergeOperation::Decrement) => {
+                let current = <[u8; 8]>::try_from(new_val.as_slice()).map_or(0, u64::from_be_bytes);
+                new_val = current.saturating_sub(1).to_be_bytes().to_vec();
+            }
             None => {
                 panic!("unknown operand: {operand:?}");
             }
@@ -97,12 +915,16 @@ fn rocksdb_merger(
 
 enum MergeOperation {
     Increment,
+    /// Decrements a counter, stopping at zero.
+    Decrement,
 }
 
 impl MergeOperation {
     pub fn parse(v: &[u8]) -> (Option<MergeOperation>, &[u8]) {
         if v == b"INCR" {
             (Some(Self::Increment), &[])
+        } else if v == b"DECR" {
+            (Some(Self::Decrement), &[])
         } else {
             (None, v)
         }
@@ -114,14 +936,15 @@ impl AccountProvider for RocksDb {
     type Error = Error;
 
     async fn create_account(&self, account: Account) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
         let db = self.db.clone();
 
         tokio::task::spawn_blocking(move || {
-            let bytes = bincode::serde::encode_to_vec(&account, BINCODE_CONFIG).unwrap();
+            let bytes = bincode::serde::encode_to_vec(account.to_stored(), BINCODE_CONFIG)?;
 
-            let by_uuid_handle = db.cf_handle(ACCOUNTS_BY_UUID).unwrap();
-            db.put_cf(by_uuid_handle, account.id.as_bytes(), bytes)
-                .unwrap();
+            let by_uuid_handle = cf(&db, ACCOUNTS_BY_UUID)?;
+            db.put_cf(by_uuid_handle, account.id.as_bytes(), bytes)?;
 
             Ok(())
         })
@@ -134,126 +957,460 @@ impl AccountProvider for RocksDb {
         account: Uuid,
         user: Uuid,
         access: AccountAccessLevel,
+        force: bool,
+    ) -> Result<AccountAccessLevel, Self::Error> {
+        self.write_access(account, user, access, force).await
+    }
+
+    async fn set_account_access(
+        &self,
+        account: Uuid,
+        user: Uuid,
+        access: AccountAccessLevel,
     ) -> Result<(), Self::Error> {
+        self.write_access(account, user, access, true)
+            .await
+            .map(|_| ())
+    }
+
+    async fn detach_account_from_user(&self, account: Uuid, user: Uuid) -> Result<bool, Error> {
+        self.ensure_writable()?;
+
         let db = self.db.clone();
+        let access_writes = self.access_writes.clone();
 
-        tokio::task::spawn_blocking(move || {
-            let access_handle = db.cf_handle(ACCOUNTS_ACCESS_BY_USER).unwrap();
+        let detached = tokio::task::spawn_blocking(move || {
+            let access_handle = cf(&db, ACCOUNTS_ACCESS_BY_USER)?;
+            let by_account_handle = cf(&db, ACCOUNTS_ACCESS_BY_ACCOUNT)?;
+            let account_handle = cf(&db, ACCOUNTS_BY_UUID)?;
+            let key = account_access_key(user, account);
 
-            let mut compound_key = [0_u8; 32];
-            compound_key[..16].copy_from_slice(user.as_bytes());
-            compound_key[16..].copy_from_slice(account.as_bytes());
+            let _guard = access_writes.lock().unwrap();
 
-            db.put_cf(access_handle, compound_key, (access as u8).to_be_bytes())
-                .unwrap();
+            let Some(existing) = db
+                .get_pinned_cf(access_handle, key)?
+                .map(|value| decode_access_level(&value))
+                .transpose()?
+            else {
+                return Ok(false);
+            };
+
+            if existing == AccountAccessLevel::Owner {
+                let is_personal = db
+                    .get_pinned_cf(account_handle, account.as_bytes())?
+                    .map(|bytes| {
+                        decode(ACCOUNT_RECORD, &bytes, ACCOUNTS_BY_UUID, account.as_bytes())
+                    })
+                    .transpose()?
+                    .is_some_and(|stored| Account::from_stored(stored).is_personal);
+
+                if is_personal {
+                    return Err(Error::PersonalAccountOwner);
+                }
+            }
+
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(access_handle, key);
+            batch.delete_cf(by_account_handle, account_users_key(account, user));
+            db.write(batch)?;
+
+            Ok::<_, Error>(true)
         })
         .await
-        .unwrap();
+        .unwrap()?;
 
-        self.increment_seq_number_for_user(user).await.unwrap();
+        if detached {
+            self.increment_seq_number_for_user(user).await?;
+        }
 
-        Ok(())
+        Ok(detached)
     }
 
     async fn get_accounts_for_user(&self, user_id: Uuid) -> Result<Vec<Account>, Self::Error> {
         let db = self.db.clone();
 
         tokio::task::spawn_blocking(move || {
-            let access_handle = db.cf_handle(ACCOUNTS_ACCESS_BY_USER).unwrap();
-            let account_handle = db.cf_handle(ACCOUNTS_BY_UUID).unwrap();
+            let access_handle = cf(&db, ACCOUNTS_ACCESS_BY_USER)?;
+            let account_handle = cf(&db, ACCOUNTS_BY_UUID)?;
 
-            Ok(db
-                .prefix_iterator_cf(access_handle, user_id.as_bytes())
-                .map(Result::unwrap)
-                .filter_map(|(key, _access_level)| {
-                    let Some(account) = key.strip_prefix(user_id.as_bytes()) else {
-                        panic!("got invalid key from rocksdb");
-                    };
+            let mut accounts = Vec::new();
 
-                    let Some(account_bytes) = db.get_cf(account_handle, account).unwrap() else {
-                        return None;
-                    };
+            for entry in db.prefix_iterator_cf(access_handle, user_id.as_bytes()) {
+                let (key, _access_level) = entry?;
 
-                    let (res, _): (Account, _) =
-                        bincode::serde::decode_from_slice(&account_bytes, BINCODE_CONFIG).unwrap();
+                let Some(account) = key.strip_prefix(user_id.as_bytes()) else {
+                    break;
+                };
 
-                    Some(res)
-                })
-                .collect())
+                let Some(account_bytes) = db.get_pinned_cf(account_handle, account)? else {
+                    continue;
+                };
+
+                let account = Account::from_stored(decode(
+                    ACCOUNT_RECORD,
+                    &account_bytes,
+                    ACCOUNTS_BY_UUID,
+                    account,
+                )?);
+
+                accounts.push(account);
+            }
+
+            Ok(accounts)
         })
         .await
         .unwrap()
     }
-}
-
-#[async_trait]
-impl UserProvider for RocksDb {
-    type Error = Error;
 
-    async fn increment_seq_number_for_user(&self, user: Uuid) -> Result<(), Self::Error> {
+    async fn get_access_level(
+        &self,
+        user: Uuid,
+        account: Uuid,
+    ) -> Result<Option<AccountAccessLevel>, Self::Error> {
         let db = self.db.clone();
 
         tokio::task::spawn_blocking(move || {
-            let seq_handle = db.cf_handle(USER_SEQ_NUMBER).unwrap();
-            db.merge_cf(seq_handle, user.as_bytes(), "INCR").unwrap();
-            Ok(())
+            let handle = cf(&db, ACCOUNTS_ACCESS_BY_USER)?;
+
+            db.get_pinned_cf(handle, account_access_key(user, account))?
+                .map(|value| decode_access_level(&value))
+                .transpose()
         })
         .await
         .unwrap()
     }
 
-    async fn fetch_seq_number_for_user(&self, user: Uuid) -> Result<u64, Self::Error> {
+    async fn get_access_levels_for_user(
+        &self,
+        user: Uuid,
+    ) -> Result<HashMap<Uuid, AccountAccessLevel>, Self::Error> {
         let db = self.db.clone();
 
         tokio::task::spawn_blocking(move || {
-            let seq_handle = db.cf_handle(USER_SEQ_NUMBER).unwrap();
+            let handle = cf(&db, ACCOUNTS_ACCESS_BY_USER)?;
 
-            let Some(bytes) = db.get_pinned_cf(seq_handle, user.as_bytes()).unwrap() else {
-                return Ok(0);
-            };
+            let mut levels = HashMap::new();
 
-            let mut val = [0_u8; std::mem::size_of::<u64>()];
-            val.copy_from_slice(&bytes);
+            for entry in db.prefix_iterator_cf(handle, user.as_bytes()) {
+                let (key, value) = entry?;
 
-            Ok(u64::from_be_bytes(val))
-        })
-        .await
-        .unwrap()
-    }
+                if !key.starts_with(user.as_bytes()) {
+                    break;
+                }
 
-    async fn has_any_users(&self) -> Result<bool, Self::Error> {
-        let db = self.db.clone();
+                let account = decode_uuid(&key[16..], ACCOUNTS_ACCESS_BY_USER)?;
+                levels.insert(account, decode_access_level(&value)?);
+            }
 
-        tokio::task::spawn_blocking(move || {
-            let by_uuid_handle = db.cf_handle(USER_BY_UUID_CF).unwrap();
-            Ok(db
-                .full_iterator_cf(by_uuid_handle, IteratorMode::Start)
-                .next()
-                .is_some())
+            Ok(levels)
         })
         .await
         .unwrap()
     }
 
-    async fn create_user(&self, user: User) -> Result<(), Self::Error> {
+    async fn get_users_for_account(
+        &self,
+        account: Uuid,
+    ) -> Result<Vec<(Uuid, AccountAccessLevel)>, Self::Error> {
         let db = self.db.clone();
 
         tokio::task::spawn_blocking(move || {
-            let bytes = bincode::serde::encode_to_vec(&user, BINCODE_CONFIG).unwrap();
+            let handle = cf(&db, ACCOUNTS_ACCESS_BY_ACCOUNT)?;
 
-            let by_uuid_handle = db.cf_handle(USER_BY_UUID_CF).unwrap();
-            db.put_cf(by_uuid_handle, user.id.as_bytes(), bytes)
-                .unwrap();
+            let mut users = Vec::new();
 
-            let by_username_handle = db.cf_handle(USER_BY_USERNAME_CF).unwrap();
-            db.put_cf(
+            for entry in db.prefix_iterator_cf(handle, account.as_bytes()) {
+                let (key, value) = entry?;
+
+                if !key.starts_with(account.as_bytes()) {
+                    break;
+                }
+
+                let user = decode_uuid(&key[16..], ACCOUNTS_ACCESS_BY_ACCOUNT)?;
+                users.push((user, decode_access_level(&value)?));
+            }
+
+            Ok(users)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_principals_account(&self) -> Result<Option<Uuid>, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            db.get_pinned_cf(cf(&db, META)?, PRINCIPALS_ACCOUNT_KEY)?
+                .map(|value| decode_uuid(&value, META))
+                .transpose()
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn set_principals_account(&self, account: Uuid) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            db.put_cf(cf(&db, META)?, PRINCIPALS_ACCOUNT_KEY, account.as_bytes())?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
+
+impl RocksDb {
+    /// Grants a user access to an account, keeping any greater access they
+    /// already have unless `force` is set. Returns the access they're left
+    /// with.
+    async fn write_access(
+        &self,
+        account: Uuid,
+        user: Uuid,
+        access: AccountAccessLevel,
+        force: bool,
+    ) -> Result<AccountAccessLevel, Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let access_writes = self.access_writes.clone();
+
+        let (access, changed) = tokio::task::spawn_blocking(move || {
+            let access_handle = cf(&db, ACCOUNTS_ACCESS_BY_USER)?;
+            let by_account_handle = cf(&db, ACCOUNTS_ACCESS_BY_ACCOUNT)?;
+            let key = account_access_key(user, account);
+
+            // two grants racing could otherwise both read the old level and
+            // the lower of them win
+            let _guard = access_writes.lock().unwrap();
+
+            let existing = db
+                .get_pinned_cf(access_handle, key)?
+                .map(|value| decode_access_level(&value))
+                .transpose()?;
+
+            match existing {
+                Some(existing) if existing == access => return Ok((existing, false)),
+                Some(existing) if existing > access && !force => return Ok((existing, false)),
+                _ => {}
+            }
+
+            let value = (access as u8).to_be_bytes();
+
+            let mut batch = WriteBatch::default();
+            batch.put_cf(access_handle, key, value);
+            batch.put_cf(by_account_handle, account_users_key(account, user), value);
+            db.write(batch)?;
+
+            Ok::<_, Error>((access, true))
+        })
+        .await
+        .unwrap()?;
+
+        if changed {
+            self.increment_seq_number_for_user(user).await?;
+        }
+
+        Ok(access)
+    }
+}
+
+/// Key of a user's access to an account, prefixed by the user so the
+/// accounts they have access to can be iterated over.
+fn account_access_key(user: Uuid, account: Uuid) -> [u8; 32] {
+    let mut key = [0_u8; 32];
+    key[..16].copy_from_slice(user.as_bytes());
+    key[16..].copy_from_slice(account.as_bytes());
+    key
+}
+
+/// Key of a user's access to an account in [`ACCOUNTS_ACCESS_BY_ACCOUNT`],
+/// prefixed by the account so the users with access to it can be iterated
+/// over.
+fn account_users_key(account: Uuid, user: Uuid) -> [u8; 32] {
+    let mut key = [0_u8; 32];
+    key[..16].copy_from_slice(account.as_bytes());
+    key[16..].copy_from_slice(user.as_bytes());
+    key
+}
+
+fn decode_access_level(value: &[u8]) -> Result<AccountAccessLevel, Error> {
+    let [level] = value else {
+        return Err(Error::Malformed(ACCOUNTS_ACCESS_BY_USER));
+    };
+
+    AccountAccessLevel::try_from(*level).map_err(|_| Error::Malformed(ACCOUNTS_ACCESS_BY_USER))
+}
+
+#[async_trait]
+impl UserProvider for RocksDb {
+    type Error = Error;
+
+    async fn increment_seq_number_for_user(&self, user: Uuid) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let seq_handle = cf(&db, USER_SEQ_NUMBER)?;
+            db.merge_cf(seq_handle, user.as_bytes(), "INCR")?;
+
+            Ok::<_, Error>(())
+        })
+        .await
+        .unwrap()?;
+
+        let new_state = self.fetch_seq_number_for_user(user).await?;
+
+        self.events.publish(DomainEvent::UserStateChanged {
+            user_id: user,
+            new_state,
+        });
+
+        Ok(())
+    }
+
+    async fn fetch_seq_number_for_user(&self, user: Uuid) -> Result<u64, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let seq_handle = cf(&db, USER_SEQ_NUMBER)?;
+
+            let Some(bytes) = db.get_pinned_cf(seq_handle, user.as_bytes())? else {
+                return Ok(0);
+            };
+
+            let val = <[u8; 8]>::try_from(bytes.as_ref())
+                .map_err(|_| Error::Malformed(USER_SEQ_NUMBER))?;
+
+            Ok(u64::from_be_bytes(val))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn has_any_users(&self) -> Result<bool, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let by_uuid_handle = cf(&db, USER_BY_UUID_CF)?;
+            Ok(db
+                .full_iterator_cf(by_uuid_handle, IteratorMode::Start)
+                .next()
+                .transpose()?
+                .is_some())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_user_ids(&self) -> Result<Vec<Uuid>, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let by_uuid_handle = cf(&db, USER_BY_UUID_CF)?;
+
+            db.full_iterator_cf(by_uuid_handle, IteratorMode::Start)
+                .map(|entry| decode_uuid(&entry?.0, USER_BY_UUID_CF))
+                .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn list_users(
+        &self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<User>, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let by_uuid_handle = cf(&db, USER_BY_UUID_CF)?;
+
+            let mode = match &after {
+                Some(after) => IteratorMode::From(after.as_bytes(), Direction::Forward),
+                None => IteratorMode::Start,
+            };
+
+            // the user the page starts after is skipped if it still exists
+            db.iterator_cf(by_uuid_handle, mode)
+                .skip_while(|entry| match (entry, after) {
+                    (Ok((key, _)), Some(after)) => **key == *after.as_bytes(),
+                    _ => false,
+                })
+                .take(limit)
+                .map(|entry| {
+                    let (key, bytes) = entry?;
+
+                    Ok(User::from_stored(decode(
+                        USER_RECORD,
+                        &bytes,
+                        USER_BY_UUID_CF,
+                        &key,
+                    )?))
+                })
+                .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn create_user(&self, user: User) -> Result<(Uuid, Uuid), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let user_writes = self.user_writes.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let account = Account::new(user.username.clone(), true, false);
+
+            let bytes = bincode::serde::encode_to_vec(user.to_stored(), BINCODE_CONFIG)?;
+            let account_bytes = bincode::serde::encode_to_vec(account.to_stored(), BINCODE_CONFIG)?;
+            let access = (AccountAccessLevel::Owner as u8).to_be_bytes();
+
+            let by_uuid_handle = cf(&db, USER_BY_UUID_CF)?;
+            let by_username_handle = cf(&db, USER_BY_USERNAME_CF)?;
+            let account_handle = cf(&db, ACCOUNTS_BY_UUID)?;
+            let access_handle = cf(&db, ACCOUNTS_ACCESS_BY_USER)?;
+            let by_account_handle = cf(&db, ACCOUNTS_ACCESS_BY_ACCOUNT)?;
+
+            let _guard = user_writes.lock().unwrap();
+
+            if db
+                .get_pinned_cf(by_username_handle, user.username.as_bytes())?
+                .is_some()
+            {
+                return Err(Error::UsernameTaken);
+            }
+
+            let mut batch = WriteBatch::default();
+            batch.put_cf(by_uuid_handle, user.id.as_bytes(), bytes);
+            batch.put_cf(
                 by_username_handle,
                 user.username.as_bytes(),
                 user.id.as_bytes(),
-            )
-            .unwrap();
+            );
+            // written with the user so there's never a user without a
+            // personal account to sign in to
+            batch.put_cf(account_handle, account.id.as_bytes(), account_bytes);
+            batch.put_cf(
+                access_handle,
+                account_access_key(user.id, account.id),
+                access,
+            );
+            batch.put_cf(
+                by_account_handle,
+                account_users_key(account.id, user.id),
+                access,
+            );
+            db.write(batch)?;
 
-            Ok(())
+            Ok((user.id, account.id))
         })
         .await
         .unwrap()
@@ -265,8 +1422,8 @@ impl UserProvider for RocksDb {
 
         tokio::task::spawn_blocking(move || {
             let uuid = {
-                let by_username_handle = db.cf_handle(USER_BY_USERNAME_CF).unwrap();
-                db.get_pinned_cf(by_username_handle, username).unwrap()
+                let by_username_handle = cf(&db, USER_BY_USERNAME_CF)?;
+                db.get_pinned_cf(by_username_handle, username)?
             };
 
             let Some(uuid) = uuid else {
@@ -274,21 +1431,1747 @@ impl UserProvider for RocksDb {
             };
 
             let user_bytes = {
-                let by_uuid_handle = db.cf_handle(USER_BY_UUID_CF).unwrap();
-                db.get_pinned_cf(by_uuid_handle, &uuid).unwrap()
+                let by_uuid_handle = cf(&db, USER_BY_UUID_CF)?;
+                db.get_pinned_cf(by_uuid_handle, &uuid)?
             };
 
             let Some(user_bytes) = user_bytes else {
                 return Ok(None);
             };
 
-            Ok(Some(
-                bincode::serde::decode_from_slice(&user_bytes, BINCODE_CONFIG)
-                    .unwrap()
-                    .0,
-            ))
+            Ok(Some(User::from_stored(decode(
+                USER_RECORD,
+                &user_bytes,
+                USER_BY_UUID_CF,
+                &uuid,
+            )?)))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_by_id(&self, id: Uuid) -> Result<Option<User>, Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let by_uuid_handle = cf(&db, USER_BY_UUID_CF)?;
+
+            let Some(user_bytes) = db.get_pinned_cf(by_uuid_handle, id.as_bytes())? else {
+                return Ok(None);
+            };
+
+            Ok(Some(User::from_stored(decode(
+                USER_RECORD,
+                &user_bytes,
+                USER_BY_UUID_CF,
+                id.as_bytes(),
+            )?)))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn update_user(&self, user: User) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let user_writes = self.user_writes.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let bytes = bincode::serde::encode_to_vec(user.to_stored(), BINCODE_CONFIG)?;
+
+            let by_uuid_handle = cf(&db, USER_BY_UUID_CF)?;
+
+            // held so the user can't be deleted between checking they exist
+            // and writing them back, which would leave them orphaned
+            let _guard = user_writes.lock().unwrap();
+
+            if db
+                .get_pinned_cf(by_uuid_handle, user.id.as_bytes())?
+                .is_none()
+            {
+                return Err(Error::UserNotFound);
+            }
+
+            db.put_cf(by_uuid_handle, user.id.as_bytes(), bytes)?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn delete_user(&self, id: Uuid) -> Result<bool, Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let user_writes = self.user_writes.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let by_uuid_handle = cf(&db, USER_BY_UUID_CF)?;
+            let by_username_handle = cf(&db, USER_BY_USERNAME_CF)?;
+            let seq_handle = cf(&db, USER_SEQ_NUMBER)?;
+            let access_handle = cf(&db, ACCOUNTS_ACCESS_BY_USER)?;
+            let by_account_handle = cf(&db, ACCOUNTS_ACCESS_BY_ACCOUNT)?;
+            let tokens_handle = cf(&db, OAUTH_TOKENS)?;
+            let refresh_handle = cf(&db, OAUTH_REFRESH)?;
+
+            let _guard = user_writes.lock().unwrap();
+
+            let Some(user_bytes) = db.get_pinned_cf(by_uuid_handle, id.as_bytes())? else {
+                return Ok(false);
+            };
+
+            let user = User::from_stored(decode(
+                USER_RECORD,
+                &user_bytes,
+                USER_BY_UUID_CF,
+                id.as_bytes(),
+            )?);
+
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(by_uuid_handle, id.as_bytes());
+            batch.delete_cf(seq_handle, id.as_bytes());
+
+            // the username may have since been taken by another user
+            if db
+                .get_pinned_cf(by_username_handle, user.username.as_bytes())?
+                .is_some_and(|owner| owner.as_ref() == id.as_bytes())
+            {
+                batch.delete_cf(by_username_handle, user.username.as_bytes());
+            }
+
+            for entry in db.prefix_iterator_cf(access_handle, id.as_bytes()) {
+                let (key, _) = entry?;
+
+                if !key.starts_with(id.as_bytes()) {
+                    break;
+                }
+
+                let account = decode_uuid(&key[16..], ACCOUNTS_ACCESS_BY_USER)?;
+                batch.delete_cf(by_account_handle, account_users_key(account, id));
+                batch.delete_cf(access_handle, key);
+            }
+
+            // tokens are keyed by the token itself, so finding the user's
+            // means walking all of them
+            let owner_id = id.to_string();
+
+            for entry in db.iterator_cf(tokens_handle, IteratorMode::Start) {
+                let (key, bytes) = entry?;
+                let token: IssuedOAuthToken = decode(OAUTH_RECORD, &bytes, OAUTH_TOKENS, &key)?;
+
+                if token.grant.owner_id != owner_id {
+                    continue;
+                }
+
+                batch.delete_cf(tokens_handle, &token.access_token);
+
+                if let Some(refresh_token) = &token.refresh_token {
+                    batch.delete_cf(refresh_handle, refresh_token);
+                }
+            }
+
+            db.write(batch)?;
+
+            Ok(true)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for RocksDb {
+    type Error = Error;
+
+    async fn store_auth_code(&self, code: &str, grant: OAuthGrant) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let code = code.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let bytes = bincode::serde::encode_to_vec(&grant, BINCODE_CONFIG)?;
+
+            let auth_codes_handle = cf(&db, OAUTH_AUTH_CODES)?;
+            db.put_cf(auth_codes_handle, code, bytes)?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn take_auth_code(&self, code: &str) -> Result<Option<OAuthGrant>, Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let auth_code_writes = self.auth_code_writes.clone();
+        let code = code.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let auth_codes_handle = cf(&db, OAUTH_AUTH_CODES)?;
+
+            // held from reading the code to deleting it, otherwise two
+            // concurrent exchanges could both read it before either deletes it
+            let _guard = auth_code_writes.lock().unwrap();
+
+            let Some(bytes) = db.get_cf(auth_codes_handle, &code)? else {
+                return Ok(None);
+            };
+
+            db.delete_cf(auth_codes_handle, &code)?;
+
+            Ok(Some(decode(
+                OAUTH_RECORD,
+                &bytes,
+                OAUTH_AUTH_CODES,
+                code.as_bytes(),
+            )?))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn store_token(&self, token: IssuedOAuthToken) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let bytes = bincode::serde::encode_to_vec(&token, BINCODE_CONFIG)?;
+
+            let mut batch = WriteBatch::default();
+            batch.put_cf(cf(&db, OAUTH_TOKENS)?, &token.access_token, &bytes);
+
+            if let Some(refresh_token) = &token.refresh_token {
+                batch.put_cf(cf(&db, OAUTH_REFRESH)?, refresh_token, &bytes);
+            }
+
+            db.write(batch)?;
+
+            Ok(())
         })
         .await
         .unwrap()
     }
+
+    async fn get_by_access_token(
+        &self,
+        access_token: &str,
+    ) -> Result<Option<IssuedOAuthToken>, Self::Error> {
+        self.get_token(OAUTH_TOKENS, access_token).await
+    }
+
+    async fn get_by_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<IssuedOAuthToken>, Self::Error> {
+        self.get_token(OAUTH_REFRESH, refresh_token).await
+    }
+
+    async fn remove_token(&self, token: &IssuedOAuthToken) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let access_token = token.access_token.clone();
+        let refresh_token = token.refresh_token.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(cf(&db, OAUTH_TOKENS)?, access_token);
+
+            if let Some(refresh_token) = refresh_token {
+                batch.delete_cf(cf(&db, OAUTH_REFRESH)?, refresh_token);
+            }
+
+            db.write(batch)?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn remove_expired_tokens(&self) -> Result<u64, Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let tokens_handle = cf(&db, OAUTH_TOKENS)?;
+            let refresh_handle = cf(&db, OAUTH_REFRESH)?;
+
+            let mut batch = WriteBatch::default();
+            let mut removed = 0;
+
+            for entry in db.full_iterator_cf(tokens_handle, IteratorMode::Start) {
+                let (key, bytes) = entry?;
+                let token: IssuedOAuthToken = decode(OAUTH_RECORD, &bytes, OAUTH_TOKENS, &key)?;
+
+                if !token.is_expired() {
+                    continue;
+                }
+
+                batch.delete_cf(tokens_handle, &token.access_token);
+
+                if let Some(refresh_token) = &token.refresh_token {
+                    batch.delete_cf(refresh_handle, refresh_token);
+                }
+
+                removed += 1;
+            }
+
+            db.write(batch)?;
+
+            Ok(removed)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+#[async_trait]
+impl ObjectProvider for RocksDb {
+    type Error = Error;
+
+    async fn get_object<D: Persisted + Send + 'static>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        id: Uuid,
+    ) -> Result<Option<D>, Self::Error> {
+        let db = self.db.clone();
+        let key = object_key(account, data_type, id);
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, OBJECTS)?;
+
+            db.get_pinned_cf(handle, &key)?
+                .map(|bytes| decode(OBJECT_RECORD, &bytes, OBJECTS, &key).map(D::from_stored))
+                .transpose()
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn list_objects<D: Persisted + Send + 'static>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+    ) -> Result<Vec<(Uuid, D)>, Self::Error> {
+        self.query_objects(account, data_type, |objects| objects.collect())
+            .await?
+    }
+
+    async fn query_objects<D, R, F>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        query: F,
+    ) -> Result<R, Self::Error>
+    where
+        D: Persisted + 'static,
+        R: Send + 'static,
+        F: FnOnce(&mut dyn Iterator<Item = Result<(Uuid, D), Self::Error>>) -> R + Send + 'static,
+    {
+        let db = self.db.clone();
+        let prefix = object_type_key(account, data_type);
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, OBJECTS)?;
+
+            let mut objects = db
+                .prefix_iterator_cf(handle, &prefix)
+                .take_while(|entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |(key, _)| key.starts_with(&prefix))
+                })
+                .map(|entry| {
+                    let (key, value) = entry?;
+
+                    let id = decode_uuid(&key[prefix.len()..], OBJECTS)?;
+                    let object = decode(OBJECT_RECORD, &value, OBJECTS, &key)?;
+                    Ok((id, D::from_stored(object)))
+                });
+
+            Ok(query(&mut objects))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn put_object<D: Persisted + Sync>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        id: Uuid,
+        object: &D,
+    ) -> Result<(), Self::Error> {
+        let bytes = bincode::serde::encode_to_vec(object.to_stored(), BINCODE_CONFIG)?;
+
+        self.write_objects(account, data_type, vec![(id, Some(bytes))])
+            .await
+            .map(|_| ())
+    }
+
+    async fn apply_set<D: Persisted + Sync>(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        writes: &[ObjectWrite<D>],
+    ) -> Result<ObjectState<'static>, Self::Error> {
+        let writes = writes
+            .iter()
+            .map(|write| match write {
+                ObjectWrite::Put(id, object) => Ok((
+                    *id,
+                    Some(bincode::serde::encode_to_vec(
+                        object.to_stored(),
+                        BINCODE_CONFIG,
+                    )?),
+                )),
+                ObjectWrite::Delete(id) => Ok((*id, None)),
+            })
+            .collect::<Result<_, Error>>()?;
+
+        match self.write_objects(account, data_type, writes).await? {
+            Some(state) => Ok(encode_object_state(state)),
+            None => self.state_for(account, data_type).await,
+        }
+    }
+
+    async fn state_for(
+        &self,
+        account: Uuid,
+        data_type: &str,
+    ) -> Result<ObjectState<'static>, Self::Error> {
+        let db = self.db.clone();
+        let key = object_type_key(account, data_type);
+
+        let state = tokio::task::spawn_blocking(move || read_counter(&db, OBJECT_STATES, &key))
+            .await
+            .unwrap()?;
+
+        Ok(encode_object_state(state))
+    }
+
+    async fn get_object_changes(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        since: u64,
+        max_changes: Option<usize>,
+    ) -> Result<Option<ObjectChanges>, Self::Error> {
+        let db = self.db.clone();
+        let type_key = object_type_key(account, data_type);
+
+        tokio::task::spawn_blocking(move || {
+            let log_handle = cf(&db, CHANGE_LOG)?;
+
+            let state = read_counter(&db, OBJECT_STATES, &type_key)?;
+
+            // changes before the floor have been compacted away
+            if since < read_counter(&db, CHANGE_LOG_FLOORS, &type_key)? || since > state {
+                return Ok(None);
+            }
+
+            let mut kinds = HashMap::new();
+            let mut new_state = state;
+            let mut has_more_changes = false;
+
+            let from = change_log_key(&type_key, since + 1);
+
+            for entry in db.prefix_iterator_cf(log_handle, &from) {
+                let (key, value) = entry?;
+
+                if !key.starts_with(&type_key) {
+                    break;
+                }
+
+                let entry: ChangeLogEntry = decode(CHANGE_LOG_RECORD, &value, CHANGE_LOG, &key)?;
+
+                // the response is cut short between changes so the state it
+                // returns accounts for everything before it and nothing after
+                if max_changes.is_some_and(|max| kinds.len() >= max)
+                    && !kinds.contains_key(&entry.id)
+                {
+                    has_more_changes = true;
+                    break;
+                }
+
+                new_state = decode_counter(&key[type_key.len()..], CHANGE_LOG)?;
+
+                let kind = match kinds.get(&entry.id) {
+                    Some(previous) => ChangeKind::combine(*previous, entry.kind),
+                    None => entry.kind,
+                };
+                kinds.insert(entry.id, kind);
+            }
+
+            let mut changes = ObjectChanges {
+                new_state,
+                has_more_changes,
+                created: Vec::new(),
+                updated: Vec::new(),
+                destroyed: Vec::new(),
+            };
+
+            for (id, kind) in kinds {
+                match kind {
+                    ChangeKind::Created => changes.created.push(id),
+                    ChangeKind::Updated => changes.updated.push(id),
+                    ChangeKind::Destroyed => changes.destroyed.push(id),
+                    ChangeKind::Transient => {}
+                }
+            }
+
+            Ok(Some(changes))
+        })
+        .await
+        .unwrap()
+    }
+}
+
+impl RocksDb {
+    /// Writes an object, or removes it given `None`, bumping its data type's
+    /// state and recording the change in the type's change log in the same
+    /// batch. Returns the new state, or `None` if there was nothing to
+    /// remove.
+    /// Writes each of the objects, or removes those without bytes, in a
+    /// single batch. Each change bumps the data type's state and is logged
+    /// under the state it leads to. Returns the final state, or `None` if
+    /// nothing changed as every removed object was already gone.
+    async fn write_objects(
+        &self,
+        account: Uuid,
+        data_type: &str,
+        writes: Vec<(Uuid, Option<Vec<u8>>)>,
+    ) -> Result<Option<u64>, Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let object_writes = self.object_writes.clone();
+        let type_key = object_type_key(account, data_type);
+        let data_type_owned = data_type.to_string();
+
+        let new_state = tokio::task::spawn_blocking(move || {
+            let objects_handle = cf(&db, OBJECTS)?;
+            let states_handle = cf(&db, OBJECT_STATES)?;
+            let log_handle = cf(&db, CHANGE_LOG)?;
+
+            // changes are keyed by the state they lead to, so the state has
+            // to be read and bumped without another write slipping between
+            let _guard = object_writes.lock().unwrap();
+
+            let mut state = read_counter(&db, OBJECT_STATES, &type_key)?;
+            let mut batch = WriteBatch::default();
+            // whether objects exist once the earlier writes in the batch
+            // have been applied, which the database can't see yet
+            let mut exists = HashMap::new();
+            let now = Utc::now().timestamp();
+
+            for (id, bytes) in writes {
+                let key = object_key(account, &data_type_owned, id);
+
+                let existed = match exists.get(&id) {
+                    Some(existed) => *existed,
+                    None => db.get_pinned_cf(objects_handle, &key)?.is_some(),
+                };
+
+                let kind = match (&bytes, existed) {
+                    (Some(_), false) => ChangeKind::Created,
+                    (Some(_), true) => ChangeKind::Updated,
+                    (None, true) => ChangeKind::Destroyed,
+                    (None, false) => continue,
+                };
+
+                exists.insert(id, bytes.is_some());
+                state += 1;
+
+                match bytes {
+                    Some(bytes) => batch.put_cf(objects_handle, key, bytes),
+                    None => batch.delete_cf(objects_handle, key),
+                }
+
+                let entry = ChangeLogEntry { id, kind, at: now };
+
+                batch.put_cf(
+                    log_handle,
+                    change_log_key(&type_key, state),
+                    bincode::serde::encode_to_vec(entry, BINCODE_CONFIG)?,
+                );
+            }
+
+            if batch.is_empty() {
+                return Ok(None);
+            }
+
+            batch.put_cf(states_handle, &type_key, state.to_be_bytes());
+            db.write(batch)?;
+
+            Ok::<_, Error>(Some(state))
+        })
+        .await
+        .unwrap()?;
+
+        if let Some(new_state) = new_state {
+            self.events.publish(DomainEvent::ObjectsChanged {
+                account_id: account,
+                data_type: data_type.to_string().into(),
+                new_state: encode_object_state(new_state).0.into_owned(),
+            });
+        }
+
+        Ok(new_state)
+    }
+}
+
+/// A change to an object, as recorded in its data type's change log.
+#[derive(Serialize, Deserialize)]
+struct ChangeLogEntry {
+    id: Uuid,
+    kind: ChangeKind,
+    /// When the change was made, in seconds since the epoch.
+    at: i64,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+enum ChangeKind {
+    Created,
+    Updated,
+    Destroyed,
+    /// Created and destroyed again between two states, which clients never
+    /// need to hear about. Never written to the log.
+    Transient,
+}
+
+impl ChangeKind {
+    /// The overall change to an object that was changed by `previous` then
+    /// by `next`.
+    fn combine(previous: Self, next: Self) -> Self {
+        match (previous, next) {
+            (Self::Created | Self::Transient, Self::Created) | (Self::Created, Self::Updated) => {
+                Self::Created
+            }
+            (Self::Created, Self::Destroyed) => Self::Transient,
+            (Self::Destroyed, Self::Created) => Self::Updated,
+            (_, next) => next,
+        }
+    }
+}
+
+/// Key of a change within a data type's change log, suffixed by the state it
+/// led to so changes are iterated over in the order they were made.
+fn change_log_key(type_key: &[u8], state: u64) -> Vec<u8> {
+    let mut key = type_key.to_vec();
+    key.extend_from_slice(&state.to_be_bytes());
+    key
+}
+
+/// Reads a big-endian counter, such as a data type's state, that's zero if
+/// it has never been written.
+fn read_counter(db: &DB, cf_name: &'static str, key: &[u8]) -> Result<u64, Error> {
+    db.get_pinned_cf(cf(db, cf_name)?, key)?
+        .map_or(Ok(0), |bytes| decode_counter(&bytes, cf_name))
+}
+
+/// Decodes a big-endian counter stored as part of a key or value in the
+/// column family.
+fn decode_counter(bytes: &[u8], cf: &'static str) -> Result<u64, Error> {
+    <[u8; 8]>::try_from(bytes)
+        .map(u64::from_be_bytes)
+        .map_err(|_| Error::Malformed(cf))
+}
+
+/// Periodically removes changes that are past either limit from every
+/// change log, until the database is dropped.
+fn spawn_change_log_compaction(
+    db: Weak<DB>,
+    every: Duration,
+    max_entries: u64,
+    max_age: chrono::Duration,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+
+        loop {
+            interval.tick().await;
+
+            let Some(db) = db.upgrade() else {
+                break;
+            };
+
+            match tokio::task::spawn_blocking(move || compact_change_log(&db, max_entries, max_age))
+                .await
+                .unwrap()
+            {
+                Ok(0) => {}
+                Ok(removed) => info!(removed, "Compacted change logs"),
+                Err(error) => error!(%error, "Failed to compact change logs"),
+            }
+        }
+    });
+}
+
+/// Removes the oldest changes from each data type's change log until it
+/// holds no more than `max_entries`, none of them older than `max_age`,
+/// raising the type's floor so that changes can no longer be calculated
+/// from before them. Returns the number of changes removed.
+fn compact_change_log(db: &DB, max_entries: u64, max_age: chrono::Duration) -> Result<u64, Error> {
+    let log_handle = cf(db, CHANGE_LOG)?;
+    let floors_handle = cf(db, CHANGE_LOG_FLOORS)?;
+
+    let type_key = |key: &[u8]| {
+        key.len()
+            .checked_sub(std::mem::size_of::<u64>())
+            .map(|len| key[..len].to_vec())
+            .ok_or(Error::Malformed(CHANGE_LOG))
+    };
+
+    let mut remaining: HashMap<Vec<u8>, u64> = HashMap::new();
+
+    for entry in db.full_iterator_cf(log_handle, IteratorMode::Start) {
+        let (key, _) = entry?;
+        *remaining.entry(type_key(&key)?).or_default() += 1;
+    }
+
+    let oldest_kept = (Utc::now() - max_age).timestamp();
+    let mut batch = WriteBatch::default();
+    let mut removed = 0;
+
+    for entry in db.full_iterator_cf(log_handle, IteratorMode::Start) {
+        let (key, value) = entry?;
+        let type_key = type_key(&key)?;
+
+        // changes written since counting are left for the next run
+        let Some(count) = remaining.get_mut(&type_key) else {
+            continue;
+        };
+
+        let entry: ChangeLogEntry = decode(CHANGE_LOG_RECORD, &value, CHANGE_LOG, &key)?;
+
+        if *count <= max_entries && entry.at >= oldest_kept {
+            continue;
+        }
+
+        // changes are iterated over oldest first, so the last floor written
+        // for a type is the state of the newest change removed
+        batch.put_cf(floors_handle, &type_key, &key[type_key.len()..]);
+        batch.delete_cf(log_handle, key);
+
+        *count -= 1;
+        removed += 1;
+    }
+
+    db.write(batch)?;
+
+    Ok(removed)
+}
+
+/// Key of the objects of a data type within an account, and of the type's
+/// state. Names of data types never contain a NUL, so terminating the name
+/// with one stops one type's objects being mistaken for those of another
+/// type whose name it prefixes.
+fn object_type_key(account: Uuid, data_type: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(16 + data_type.len() + 1);
+    key.extend_from_slice(account.as_bytes());
+    key.extend_from_slice(data_type.as_bytes());
+    key.push(0);
+    key
+}
+
+/// Key of an object, prefixed by the account and data type it belongs to so
+/// the type's objects can be iterated over in order of their ids.
+fn object_key(account: Uuid, data_type: &str, id: Uuid) -> Vec<u8> {
+    let mut key = object_type_key(account, data_type);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+#[async_trait]
+impl LoginFailureProvider for RocksDb {
+    type Error = Error;
+
+    async fn get_login_failures(
+        &self,
+        username: &str,
+    ) -> Result<Option<LoginFailures>, Self::Error> {
+        let db = self.db.clone();
+        let username = username.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, LOGIN_FAILURES)?;
+            get_login_failures(&db, handle, &username)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn record_login_failure(
+        &self,
+        username: &str,
+        window: chrono::Duration,
+    ) -> Result<LoginFailures, Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let username = username.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, LOGIN_FAILURES)?;
+
+            // concurrent failures for the same user may race and undercount,
+            // which only lets an attacker a handful of extra guesses
+            let failures = match get_login_failures(&db, handle, &username)? {
+                Some(failures) => LoginFailures {
+                    count: failures.count.saturating_add(1),
+                    ..failures
+                },
+                None => LoginFailures {
+                    count: 1,
+                    expires: Utc::now() + window,
+                },
+            };
+
+            let bytes = bincode::serde::encode_to_vec(failures, BINCODE_CONFIG)?;
+            db.put_cf(handle, username.as_bytes(), bytes)?;
+
+            Ok(failures)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn clear_login_failures(&self, username: &str) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+        let username = username.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, LOGIN_FAILURES)?;
+            db.delete_cf(handle, username.as_bytes())?;
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn remove_expired_login_failures(&self) -> Result<u64, Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, LOGIN_FAILURES)?;
+
+            let mut batch = WriteBatch::default();
+            let mut removed = 0;
+
+            for entry in db.full_iterator_cf(handle, IteratorMode::Start) {
+                let (username, bytes) = entry?;
+                let failures: LoginFailures =
+                    decode(LOGIN_FAILURES_RECORD, &bytes, LOGIN_FAILURES, &username)?;
+
+                if failures.is_expired() {
+                    batch.delete_cf(handle, username);
+                    removed += 1;
+                }
+            }
+
+            db.write(batch)?;
+
+            Ok(removed)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+/// Fetches the failed logins for the username, ignoring any whose window
+/// has passed but that haven't been swept yet.
+fn get_login_failures(
+    db: &DB,
+    handle: &ColumnFamily,
+    username: &str,
+) -> Result<Option<LoginFailures>, Error> {
+    let Some(bytes) = db.get_pinned_cf(handle, username.as_bytes())? else {
+        return Ok(None);
+    };
+
+    let failures: LoginFailures = decode(
+        LOGIN_FAILURES_RECORD,
+        &bytes,
+        LOGIN_FAILURES,
+        username.as_bytes(),
+    )?;
+
+    Ok(Some(failures).filter(|failures| !failures.is_expired()))
+}
+
+#[async_trait]
+impl BlobProvider for RocksDb {
+    type Error = Error;
+
+    async fn put_blob(&self, contents: BlobStream) -> Result<BlobId, Self::Error> {
+        self.ensure_writable()?;
+
+        let mut hasher = Sha3_256::new();
+        let contents = contents
+            .try_fold(Vec::new(), |mut acc, chunk| {
+                hasher.update(&chunk);
+                acc.extend_from_slice(&chunk);
+                async move { Ok(acc) }
+            })
+            .await?;
+        let id = BlobId::from_hasher(hasher);
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, BLOBS)?;
+            db.put_cf(handle, id.0, contents)?;
+            Ok(id)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_blob(
+        &self,
+        id: BlobId,
+        range: Option<BlobRange>,
+    ) -> Result<Option<Bytes>, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, BLOBS)?;
+
+            let Some(bytes) = db.get_pinned_cf(handle, id.0)? else {
+                return Ok(None);
+            };
+
+            let Some(range) = range else {
+                return Ok(Some(Bytes::copy_from_slice(&bytes)));
+            };
+
+            // the range is clamped to the length of the blob, so always fits
+            let range = range.clamp(bytes.len() as u64);
+            let range = usize::try_from(range.start).unwrap()..usize::try_from(range.end).unwrap();
+
+            Ok(Some(Bytes::copy_from_slice(&bytes[range])))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn blob_size(&self, id: BlobId) -> Result<Option<u64>, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, BLOBS)?;
+
+            Ok(db
+                .get_pinned_cf(handle, id.0)?
+                .map(|bytes| bytes.len() as u64))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn delete_blob(&self, id: BlobId) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, BLOBS)?;
+            db.delete_cf(handle, id.0)?;
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
+
+#[async_trait]
+impl BlobReferenceProvider for RocksDb {
+    type Error = Error;
+
+    async fn link_blob(&self, account: Uuid, blob: BlobId) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let by_account_handle = cf(&db, BLOBS_BY_ACCOUNT)?;
+            let uploaded_at_handle = cf(&db, BLOB_UPLOADED_AT)?;
+
+            let mut batch = WriteBatch::default();
+            batch.put_cf(by_account_handle, blob_reference_key(account, blob), []);
+            batch.put_cf(
+                uploaded_at_handle,
+                blob.0,
+                Utc::now().timestamp().to_be_bytes(),
+            );
+            db.write(batch)?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn unlink_blob(&self, account: Uuid, blob: BlobId) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, BLOBS_BY_ACCOUNT)?;
+            db.delete_cf(handle, blob_reference_key(account, blob))?;
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn is_blob_linked(&self, account: Uuid, blob: BlobId) -> Result<bool, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, BLOBS_BY_ACCOUNT)?;
+
+            Ok(db
+                .get_pinned_cf(handle, blob_reference_key(account, blob))?
+                .is_some())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn reference_blob(&self, blob: BlobId) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, BLOB_REFERENCES)?;
+            db.merge_cf(handle, blob.0, "INCR")?;
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn unreference_blob(&self, blob: BlobId) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, BLOB_REFERENCES)?;
+            db.merge_cf(handle, blob.0, "DECR")?;
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_unreferenced_blobs(
+        &self,
+        uploaded_before: DateTime<Utc>,
+    ) -> Result<Vec<BlobId>, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let uploaded_at_handle = cf(&db, BLOB_UPLOADED_AT)?;
+            let references_handle = cf(&db, BLOB_REFERENCES)?;
+
+            let mut unreferenced = Vec::new();
+
+            for entry in db.iterator_cf(uploaded_at_handle, IteratorMode::Start) {
+                let (key, value) = entry?;
+
+                if decode_timestamp(&value)? >= uploaded_before.timestamp() {
+                    continue;
+                }
+
+                let blob = BlobId(
+                    key.as_ref()
+                        .try_into()
+                        .map_err(|_| Error::Malformed(BLOB_UPLOADED_AT))?,
+                );
+
+                if blob_reference_count(&db, references_handle, blob)? == 0 {
+                    unreferenced.push(blob);
+                }
+            }
+
+            Ok(unreferenced)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn forget_blob(
+        &self,
+        blob: BlobId,
+        uploaded_before: DateTime<Utc>,
+    ) -> Result<bool, Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let uploaded_at_handle = cf(&db, BLOB_UPLOADED_AT)?;
+            let references_handle = cf(&db, BLOB_REFERENCES)?;
+            let by_account_handle = cf(&db, BLOBS_BY_ACCOUNT)?;
+
+            let uploaded_at = db
+                .get_pinned_cf(uploaded_at_handle, blob.0)?
+                .map(|value| decode_timestamp(&value))
+                .transpose()?;
+
+            if uploaded_at.is_some_and(|at| at >= uploaded_before.timestamp())
+                || blob_reference_count(&db, references_handle, blob)? != 0
+            {
+                return Ok(false);
+            }
+
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(uploaded_at_handle, blob.0);
+            batch.delete_cf(references_handle, blob.0);
+
+            // links are keyed by account first, so finding every account the
+            // blob was uploaded to means walking all of them
+            for entry in db.full_iterator_cf(by_account_handle, IteratorMode::Start) {
+                let (key, _) = entry?;
+
+                if key[16..] == blob.0 {
+                    batch.delete_cf(by_account_handle, key);
+                }
+            }
+
+            db.write(batch)?;
+
+            Ok(true)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+/// The number of objects referencing the blob.
+fn blob_reference_count(db: &DB, handle: &ColumnFamily, blob: BlobId) -> Result<u64, Error> {
+    Ok(db
+        .get_pinned_cf(handle, blob.0)?
+        .and_then(|value| <[u8; 8]>::try_from(value.as_ref()).ok())
+        .map_or(0, u64::from_be_bytes))
+}
+
+/// Decodes a timestamp, in seconds since the epoch, as written by
+/// [`RocksDb::link_blob`].
+fn decode_timestamp(value: &[u8]) -> Result<i64, Error> {
+    <[u8; 8]>::try_from(value)
+        .map(i64::from_be_bytes)
+        .map_err(|_| Error::Malformed(BLOB_UPLOADED_AT))
+}
+
+/// Key of the link between a blob and an account, prefixed by the account so
+/// an account's blobs can be iterated over.
+fn blob_reference_key(account: Uuid, blob: BlobId) -> [u8; 48] {
+    let mut key = [0_u8; 48];
+    key[..16].copy_from_slice(account.as_bytes());
+    key[16..].copy_from_slice(&blob.0);
+    key
+}
+
+#[async_trait]
+impl PushSubscriptionProvider for RocksDb {
+    type Error = Error;
+
+    async fn put_push_subscription(
+        &self,
+        subscription: PushSubscription,
+    ) -> Result<(), Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, PUSH_SUBSCRIPTIONS)?;
+            let bytes = bincode::serde::encode_to_vec(&subscription, BINCODE_CONFIG)?;
+
+            db.put_cf(
+                handle,
+                push_subscription_key(subscription.user_id, subscription.id),
+                bytes,
+            )?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_push_subscriptions_for_user(
+        &self,
+        user: Uuid,
+    ) -> Result<Vec<PushSubscription>, Self::Error> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, PUSH_SUBSCRIPTIONS)?;
+
+            // the prefix extractor ends the iterator with the user's last
+            // key, but it's checked for regardless as it's cheap
+            let mut subscriptions = Vec::new();
+
+            for entry in db.prefix_iterator_cf(handle, user.as_bytes()) {
+                let (key, value) = entry?;
+
+                if !key.starts_with(user.as_bytes()) {
+                    break;
+                }
+
+                let subscription: PushSubscription =
+                    decode(PUSH_SUBSCRIPTION_RECORD, &value, PUSH_SUBSCRIPTIONS, &key)?;
+                subscriptions.push(subscription);
+            }
+
+            Ok(subscriptions)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn delete_push_subscription(&self, user: Uuid, id: Uuid) -> Result<bool, Self::Error> {
+        self.ensure_writable()?;
+
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, PUSH_SUBSCRIPTIONS)?;
+            let key = push_subscription_key(user, id);
+
+            if db.get_pinned_cf(handle, key)?.is_none() {
+                return Ok(false);
+            }
+
+            db.delete_cf(handle, key)?;
+            Ok(true)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+/// Key of a push subscription, prefixed by the user it belongs to so a
+/// user's subscriptions can be iterated over.
+fn push_subscription_key(user: Uuid, id: Uuid) -> [u8; 32] {
+    let mut key = [0_u8; 32];
+    key[..16].copy_from_slice(user.as_bytes());
+    key[16..].copy_from_slice(id.as_bytes());
+    key
+}
+
+impl RocksDb {
+    async fn get_token(
+        &self,
+        cf_name: &'static str,
+        token: &str,
+    ) -> Result<Option<IssuedOAuthToken>, Error> {
+        let db = self.db.clone();
+        let token = token.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let handle = cf(&db, cf_name)?;
+
+            let Some(bytes) = db.get_pinned_cf(handle, &token)? else {
+                return Ok(None);
+            };
+
+            Ok(Some(decode(
+                OAUTH_RECORD,
+                &bytes,
+                cf_name,
+                token.as_bytes(),
+            )?))
+        })
+        .await
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashSet;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Opens a fresh primary store in a temporary directory, which has to
+    /// outlive it.
+    fn open_store() -> (TempDir, RocksDb) {
+        let dir = tempfile::tempdir().unwrap();
+        let config = toml::from_str(&format!("path = {:?}", dir.path())).unwrap();
+
+        (dir, RocksDb::new(config, EventBus::new()).unwrap())
+    }
+
+    /// Leaves the database refusing writes, as it would after failing to
+    /// flush, by removing its directory from under it.
+    pub(crate) fn stop_writes(store: &RocksDb, dir: &std::path::Path) {
+        let meta = store.db.cf_handle(META).unwrap();
+        store.db.put_cf(meta, b"unflushed", b"").unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // the memtable can't be flushed without a new log to switch to
+        assert!(store.db.flush_cf(meta).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn database_refusing_writes_is_unhealthy() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = toml::from_str(&format!(
+            "path = {:?}\nhealth-check-interval = 1",
+            dir.path()
+        ))
+        .unwrap();
+        let store = RocksDb::new(config, EventBus::new()).unwrap();
+        assert!(store.is_healthy());
+
+        stop_writes(&store, dir.path());
+
+        // not counted as a background error, only the write probe sees it
+        assert_eq!(
+            store
+                .db
+                .property_int_value(properties::BACKGROUND_ERRORS)
+                .unwrap(),
+            Some(0)
+        );
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while store.is_healthy() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("store still healthy");
+    }
+
+    #[test]
+    fn change_log_max_age_is_checked_on_load() {
+        let config = |age: u64| {
+            toml::from_str::<Config>(&format!("path = \"db\"\nchange-log-max-age = {age}"))
+        };
+
+        assert_eq!(
+            config(60).unwrap().change_log_max_age,
+            chrono::Duration::minutes(1)
+        );
+        assert!(config(u64::MAX).is_err());
+    }
+
+    fn user(id: Uuid, username: &str) -> User {
+        User {
+            id,
+            username: username.to_string(),
+            password: String::new(),
+        }
+    }
+
+    /// A minimal object to store, to test the layout of objects rather
+    /// than any particular data type.
+    #[derive(Debug, PartialEq)]
+    struct Note(String);
+
+    impl Persisted for Note {
+        type Stored = String;
+
+        fn to_stored(&self) -> Self::Stored {
+            self.0.clone()
+        }
+
+        fn from_stored(stored: Self::Stored) -> Self {
+            Self(stored)
+        }
+    }
+
+    /// UUIDs that differ only in their last byte, so the keys of one sort
+    /// immediately before those of the other.
+    fn adjacent_uuids() -> (Uuid, Uuid) {
+        let mut bytes = [0x5a; 16];
+        bytes[15] = 0x01;
+        let first = Uuid::from_bytes(bytes);
+        bytes[15] = 0x02;
+
+        (first, Uuid::from_bytes(bytes))
+    }
+
+    #[tokio::test]
+    async fn accounts_of_adjacent_users_stay_apart() {
+        let (_dir, store) = open_store();
+        let (first, second) = adjacent_uuids();
+
+        let mut expected = HashMap::new();
+
+        for (id, username) in [(first, "first"), (second, "second")] {
+            let (_, personal) = store.create_user(user(id, username)).await.unwrap();
+            let mut accounts = HashSet::from([personal]);
+
+            for i in 0..3 {
+                let account = Account::new(format!("{username}-{i}"), false, false);
+                accounts.insert(account.id);
+
+                store
+                    .attach_account_to_user(account.id, id, AccountAccessLevel::Owner, false)
+                    .await
+                    .unwrap();
+                store.create_account(account).await.unwrap();
+            }
+
+            expected.insert(id, accounts);
+        }
+
+        for (id, accounts) in expected {
+            let found: HashSet<_> = store
+                .get_accounts_for_user(id)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|account| account.id)
+                .collect();
+            assert_eq!(found, accounts);
+
+            let levels = store.get_access_levels_for_user(id).await.unwrap();
+            assert_eq!(levels.keys().copied().collect::<HashSet<_>>(), accounts);
+        }
+    }
+
+    #[tokio::test]
+    async fn objects_of_adjacent_accounts_stay_apart() {
+        let (_dir, store) = open_store();
+        let (first, second) = adjacent_uuids();
+
+        for account in [first, second] {
+            for i in 0..3 {
+                store
+                    .put_object(
+                        account,
+                        "Note",
+                        Uuid::new_v4(),
+                        &Note(format!("{account}-{i}")),
+                    )
+                    .await
+                    .unwrap();
+            }
+        }
+
+        for account in [first, second] {
+            let notes = store.list_objects::<Note>(account, "Note").await.unwrap();
+            assert_eq!(notes.len(), 3);
+            assert!(notes
+                .iter()
+                .all(|(_, note)| note.0.starts_with(&account.to_string())));
+
+            let changes = store
+                .get_object_changes(account, "Note", 0, None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(changes.created.len(), 3);
+            assert_eq!(changes.new_state, 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_is_written_in_one_batch() {
+        let (_dir, store) = open_store();
+        let account = Uuid::new_v4();
+        let (existing, created, missing) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        store
+            .put_object(account, "Note", existing, &Note("before".to_string()))
+            .await
+            .unwrap();
+
+        let mut events = store.events.subscribe();
+
+        let state = store
+            .apply_set(
+                account,
+                "Note",
+                &[
+                    ObjectWrite::Put(created, Note("new".to_string())),
+                    ObjectWrite::Put(existing, Note("after".to_string())),
+                    ObjectWrite::Delete(missing),
+                ],
+            )
+            .await
+            .unwrap();
+
+        // every change is logged under its own state, but they're written
+        // and announced together
+        assert_eq!(state, encode_object_state(3));
+
+        let event = events.try_recv().unwrap();
+        assert!(matches!(
+            &event.event,
+            DomainEvent::ObjectsChanged { new_state, .. } if *new_state == state.0
+        ));
+        assert!(events.try_recv().is_err());
+
+        let changes = store
+            .get_object_changes(account, "Note", 1, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(changes.created, [created]);
+        assert_eq!(changes.updated, [existing]);
+        assert!(changes.destroyed.is_empty());
+
+        assert_eq!(
+            store
+                .get_object::<Note>(account, "Note", existing)
+                .await
+                .unwrap(),
+            Some(Note("after".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn unversioned_store_is_upgraded_on_open() {
+        let (dir, store) = open_store();
+
+        let user = User {
+            password: "hash".to_string(),
+            ..user(Uuid::new_v4(), "alice")
+        };
+        let (user_id, account_id) = store.create_user(user).await.unwrap();
+        let account = store
+            .get_accounts_for_user(user_id)
+            .await
+            .unwrap()
+            .remove(0);
+
+        // rewrite the records as the first release laid them out, before
+        // records were versioned or access was indexed by account
+        let unversioned_user = (user_id, "alice", "hash");
+        let unversioned_account = (account_id, &account.name, true, false);
+
+        let db = &store.db;
+        db.put_cf(
+            cf(db, USER_BY_UUID_CF).unwrap(),
+            user_id.as_bytes(),
+            bincode::serde::encode_to_vec(unversioned_user, BINCODE_CONFIG).unwrap(),
+        )
+        .unwrap();
+        db.put_cf(
+            cf(db, ACCOUNTS_BY_UUID).unwrap(),
+            account_id.as_bytes(),
+            bincode::serde::encode_to_vec(unversioned_account, BINCODE_CONFIG).unwrap(),
+        )
+        .unwrap();
+        db.delete_cf(
+            cf(db, ACCOUNTS_ACCESS_BY_ACCOUNT).unwrap(),
+            account_users_key(account_id, user_id),
+        )
+        .unwrap();
+        db.delete_cf(cf(db, META).unwrap(), STORAGE_VERSION_KEY)
+            .unwrap();
+
+        drop(store);
+
+        let config = toml::from_str(&format!("path = {:?}", dir.path())).unwrap();
+        let store = RocksDb::new(config, EventBus::new()).unwrap();
+
+        assert_eq!(check_storage_version(&store.db).unwrap(), STORAGE_VERSION);
+
+        let user = store.get_by_id(user_id).await.unwrap().unwrap();
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.password, "hash");
+
+        let accounts = store.get_accounts_for_user(user_id).await.unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].id, account_id);
+        assert_eq!(accounts[0].name, account.name);
+        assert!(accounts[0].is_personal);
+
+        assert_eq!(
+            store.get_users_for_account(account_id).await.unwrap().len(),
+            1
+        );
+        assert!(store.check_consistency(false).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dangling_username_is_found_and_repaired() {
+        let (_dir, store) = open_store();
+
+        // indexed to a user that was never written
+        store
+            .db
+            .put_cf(
+                cf(&store.db, USER_BY_USERNAME_CF).unwrap(),
+                "alice",
+                Uuid::new_v4().as_bytes(),
+            )
+            .unwrap();
+
+        let found = store.check_consistency(false).await.unwrap();
+        assert!(matches!(
+            found.as_slice(),
+            [Inconsistency::DanglingUsername { username }] if username == "alice"
+        ));
+
+        let repaired = store.check_consistency(true).await.unwrap();
+        assert_eq!(repaired.len(), 1);
+        assert!(store.check_consistency(false).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn oversized_objects_are_reported_as_corrupt() {
+        let (_dir, store) = open_store();
+        let account = Uuid::new_v4();
+
+        let address_book = |name: String| AddressBook {
+            id: Uuid::new_v4(),
+            name,
+            is_subscribed: true,
+            owner: account,
+            share_with: HashMap::new(),
+        };
+
+        let oversized = address_book("x".repeat(16 * 1024 * 1024));
+        let fine = address_book("Friends".to_string());
+
+        for book in [&oversized, &fine] {
+            store
+                .put_object(account, "AddressBook", book.id, book)
+                .await
+                .unwrap();
+        }
+
+        // objects of data types the store doesn't know are skipped
+        store
+            .put_object(account, "Note", Uuid::new_v4(), &Note(String::new()))
+            .await
+            .unwrap();
+
+        let found = store.check_consistency(false).await.unwrap();
+
+        assert_eq!(found.len(), 1);
+        assert!(matches!(
+            &found[0],
+            Inconsistency::CorruptRecord { cf: OBJECTS, key }
+                if *key == hex::encode(&object_key(account, "AddressBook", oversized.id)[..32]) + "..."
+        ));
+    }
+
+    #[tokio::test]
+    async fn query_only_reads_objects_up_to_the_window() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::pagination::{Window, WindowStart};
+
+        /// Counts every object read back from the store.
+        static READ: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counted;
+
+        impl Persisted for Counted {
+            type Stored = ();
+
+            fn to_stored(&self) -> Self::Stored {}
+
+            fn from_stored((): Self::Stored) -> Self {
+                READ.fetch_add(1, Ordering::Relaxed);
+                Self
+            }
+        }
+
+        let (_dir, store) = open_store();
+        let account = Uuid::new_v4();
+
+        let mut ids: Vec<_> = (0..500).map(|_| Uuid::new_v4()).collect();
+
+        for id in &ids {
+            store
+                .put_object(account, "Counted", *id, &Counted)
+                .await
+                .unwrap();
+        }
+
+        let windowed = store
+            .query_objects(account, "Counted", |objects| {
+                Window {
+                    start: WindowStart::Position(0),
+                    limit: Some(10),
+                    max_limit: None,
+                    calculate_total: false,
+                }
+                .apply(objects.map(|object: Result<(Uuid, Counted), Error>| object.unwrap().0))
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        ids.sort_unstable();
+        assert_eq!(windowed.ids, ids[..10]);
+        assert_eq!(READ.load(Ordering::Relaxed), 10);
+    }
+
+    #[tokio::test]
+    async fn compaction_covers_every_account() {
+        let (_dir, store) = open_store();
+        let (first, second) = adjacent_uuids();
+
+        for account in [first, second] {
+            for i in 0..3 {
+                store
+                    .put_object(account, "Note", Uuid::new_v4(), &Note(i.to_string()))
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let removed = compact_change_log(&store.db, 1, chrono::Duration::days(1)).unwrap();
+        assert_eq!(removed, 4);
+
+        for account in [first, second] {
+            assert!(store
+                .get_object_changes(account, "Note", 1, None)
+                .await
+                .unwrap()
+                .is_none());
+            assert!(store
+                .get_object_changes(account, "Note", 2, None)
+                .await
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn update_user_doesnt_recreate_deleted_user() {
+        let (_dir, store) = open_store();
+        let id = Uuid::new_v4();
+
+        store.create_user(user(id, "alice")).await.unwrap();
+        assert!(store.delete_user(id).await.unwrap());
+
+        assert!(matches!(
+            store.update_user(user(id, "alice")).await,
+            Err(Error::UserNotFound)
+        ));
+        assert!(store.get_by_id(id).await.unwrap().is_none());
+    }
 }
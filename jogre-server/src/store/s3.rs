@@ -0,0 +1,313 @@
+use std::io;
+
+use aws_sdk_s3::{
+    config::{Credentials, Region},
+    error::ProvideErrorMetadata,
+    types::{CompletedMultipartUpload, CompletedPart},
+    Client,
+};
+use axum::{async_trait, body::Bytes};
+use futures::TryStreamExt;
+use serde::Deserialize;
+use sha3::{Digest, Sha3_256};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::store::{BlobId, BlobProvider, BlobRange, BlobStream};
+
+/// Prefix of the keys blobs are stored under.
+const BLOB_PREFIX: &str = "blobs/";
+
+/// Prefix of the keys uploads are written to until their id is known.
+const STAGING_PREFIX: &str = "staging/";
+
+/// Size of each part of a multipart upload, blobs smaller than this are
+/// uploaded in a single request. S3 requires every part but the last to be at
+/// least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    /// Endpoint of the S3-compatible service, defaults to AWS itself.
+    endpoint: Option<url::Url>,
+    /// Bucket to store blobs in.
+    bucket: String,
+    #[serde(default = "Config::default_region")]
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    /// Addresses the bucket as part of the path rather than as a subdomain of
+    /// the endpoint, required by most self-hosted services such as `MinIO`.
+    #[serde(default)]
+    path_style: bool,
+}
+
+impl Config {
+    fn default_region() -> String {
+        "us-east-1".to_string()
+    }
+}
+
+/// Stores blobs in a bucket on an S3-compatible object store.
+pub struct S3 {
+    client: Client,
+    bucket: String,
+}
+
+impl S3 {
+    pub fn new(config: Config) -> Self {
+        let mut builder = aws_sdk_s3::Config::builder()
+            .region(Region::new(config.region))
+            .credentials_provider(Credentials::new(
+                config.access_key_id,
+                config.secret_access_key,
+                None,
+                None,
+                "jogre",
+            ))
+            .force_path_style(config.path_style);
+
+        if let Some(endpoint) = config.endpoint {
+            builder = builder.endpoint_url(endpoint.as_str().trim_end_matches('/'));
+        }
+
+        Self {
+            client: Client::from_conf(builder.build()),
+            bucket: config.bucket,
+        }
+    }
+
+    /// Uploads a blob too large for a single request as a multipart upload
+    /// to a staging key, returning the id of the blob once all of it has been
+    /// uploaded.
+    async fn upload_parts(
+        &self,
+        staging_key: &str,
+        upload_id: &str,
+        mut part: Vec<u8>,
+        mut contents: BlobStream,
+        mut hasher: Sha3_256,
+    ) -> io::Result<BlobId> {
+        let mut parts = Vec::new();
+
+        while !part.is_empty() {
+            let part_number = i32::try_from(parts.len() + 1).map_err(io::Error::other)?;
+
+            let uploaded = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(staging_key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(part.into())
+                .send()
+                .await
+                .map_err(io::Error::other)?;
+
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(uploaded.e_tag().map(ToString::to_string))
+                    .build(),
+            );
+
+            part = read_part(&mut contents, &mut hasher).await?;
+        }
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(staging_key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+
+        Ok(BlobId::from_hasher(hasher))
+    }
+}
+
+#[async_trait]
+impl BlobProvider for S3 {
+    type Error = io::Error;
+
+    async fn put_blob(&self, mut contents: BlobStream) -> Result<BlobId, Self::Error> {
+        let mut hasher = Sha3_256::new();
+        let first_part = read_part(&mut contents, &mut hasher).await?;
+
+        if first_part.len() < PART_SIZE {
+            // the whole blob has been read, so it can go straight to its key
+            let id = BlobId::from_hasher(hasher);
+
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(blob_key(id))
+                .body(first_part.into())
+                .send()
+                .await
+                .map_err(io::Error::other)?;
+
+            return Ok(id);
+        }
+
+        // the id of the blob isn't known until all of it has been read, so
+        // it's uploaded to a staging key and copied into place once complete
+        let staging_key = format!("{STAGING_PREFIX}{}", Uuid::new_v4());
+
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&staging_key)
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+
+        let Some(upload_id) = upload.upload_id() else {
+            return Err(io::Error::other("multipart upload created without an id"));
+        };
+
+        let id = match self
+            .upload_parts(&staging_key, upload_id, first_part, contents, hasher)
+            .await
+        {
+            Ok(id) => id,
+            Err(e) => {
+                if let Err(error) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(&staging_key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    warn!(?error, staging_key, "Failed to abort multipart upload");
+                }
+
+                return Err(e);
+            }
+        };
+
+        self.client
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(format!("{}/{staging_key}", self.bucket))
+            .key(blob_key(id))
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(&staging_key)
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+
+        Ok(id)
+    }
+
+    async fn get_blob(
+        &self,
+        id: BlobId,
+        range: Option<BlobRange>,
+    ) -> Result<Option<Bytes>, Self::Error> {
+        let mut request = self.client.get_object().bucket(&self.bucket).key(blob_key(id));
+
+        if let Some(range) = range {
+            request = match range.end {
+                // an empty range can't be expressed as a Range header
+                Some(end) if end <= range.start => return Ok(Some(Bytes::new())),
+                Some(end) => request.range(format!("bytes={}-{}", range.start, end - 1)),
+                None => request.range(format!("bytes={}-", range.start)),
+            };
+        }
+
+        let object = match request.send().await {
+            Ok(object) => object,
+            Err(e) => {
+                let e = e.into_service_error();
+
+                return if e.is_no_such_key() {
+                    Ok(None)
+                } else if e.code() == Some("InvalidRange") {
+                    // the range starts past the end of the blob
+                    Ok(Some(Bytes::new()))
+                } else {
+                    Err(io::Error::other(e))
+                };
+            }
+        };
+
+        let contents = object.body.collect().await.map_err(io::Error::other)?;
+
+        Ok(Some(contents.into_bytes()))
+    }
+
+    async fn blob_size(&self, id: BlobId) -> Result<Option<u64>, Self::Error> {
+        let object = match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(blob_key(id))
+            .send()
+            .await
+        {
+            Ok(object) => object,
+            Err(e) => {
+                let e = e.into_service_error();
+
+                return if e.is_not_found() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::other(e))
+                };
+            }
+        };
+
+        Ok(Some(u64::try_from(object.content_length()).unwrap_or_default()))
+    }
+
+    async fn delete_blob(&self, id: BlobId) -> Result<(), Self::Error> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(blob_key(id))
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+}
+
+fn blob_key(id: BlobId) -> String {
+    format!("{BLOB_PREFIX}{id}")
+}
+
+/// Reads from the blob until at least a part's worth of data is available or
+/// the blob ends, an empty part means the whole blob has been read.
+async fn read_part(contents: &mut BlobStream, hasher: &mut Sha3_256) -> io::Result<Vec<u8>> {
+    let mut part = Vec::new();
+
+    while part.len() < PART_SIZE {
+        let Some(chunk) = contents.try_next().await? else {
+            break;
+        };
+
+        hasher.update(&chunk);
+        part.extend_from_slice(&chunk);
+    }
+
+    Ok(part)
+}
@@ -0,0 +1,398 @@
+//! How records are laid out in the store, kept apart from the types the rest
+//! of the server works with so that changing those, such as renaming a field
+//! in the API, can't change what's read back from disk.
+//!
+//! Each stored record is an enum of every layout it has had, so bincode
+//! prefixes it with the index of its layout. Layouts are only ever added to
+//! the end, converting from the older ones when read, and never changed once
+//! released.
+
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    extensions::contacts::{AddressBook, AddressBookRights, ContactCard},
+    store::{Account, User},
+};
+
+/// A type persisted through a dedicated storage layout.
+pub trait Persisted: Sized {
+    type Stored: Serialize + DeserializeOwned;
+
+    fn to_stored(&self) -> Self::Stored;
+
+    fn from_stored(stored: Self::Stored) -> Self;
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredUser {
+    V1 {
+        id: Uuid,
+        username: String,
+        password: String,
+    },
+}
+
+impl Persisted for User {
+    type Stored = StoredUser;
+
+    fn to_stored(&self) -> Self::Stored {
+        StoredUser::V1 {
+            id: self.id,
+            username: self.username.clone(),
+            password: self.password.clone(),
+        }
+    }
+
+    fn from_stored(stored: Self::Stored) -> Self {
+        match stored {
+            StoredUser::V1 {
+                id,
+                username,
+                password,
+            } => Self {
+                id,
+                username,
+                password,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredAccount {
+    V1 {
+        id: Uuid,
+        name: String,
+        is_personal: bool,
+        is_read_only: bool,
+    },
+}
+
+impl Persisted for Account {
+    type Stored = StoredAccount;
+
+    fn to_stored(&self) -> Self::Stored {
+        StoredAccount::V1 {
+            id: self.id,
+            name: self.name.clone(),
+            is_personal: self.is_personal,
+            is_read_only: self.is_read_only,
+        }
+    }
+
+    fn from_stored(stored: Self::Stored) -> Self {
+        match stored {
+            StoredAccount::V1 {
+                id,
+                name,
+                is_personal,
+                is_read_only,
+            } => Self {
+                id,
+                name,
+                is_personal,
+                is_read_only,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredAddressBook {
+    V1 {
+        id: Uuid,
+        name: String,
+        is_subscribed: bool,
+        owner: Uuid,
+        share_with: HashMap<Uuid, StoredAddressBookRights>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct StoredAddressBookRights {
+    may_read: bool,
+    may_write: bool,
+    may_admin: bool,
+    may_delete: bool,
+}
+
+impl Persisted for AddressBook {
+    type Stored = StoredAddressBook;
+
+    fn to_stored(&self) -> Self::Stored {
+        StoredAddressBook::V1 {
+            id: self.id,
+            name: self.name.clone(),
+            is_subscribed: self.is_subscribed,
+            owner: self.owner,
+            share_with: self
+                .share_with
+                .iter()
+                .map(|(user, rights)| {
+                    (
+                        *user,
+                        StoredAddressBookRights {
+                            may_read: rights.may_read,
+                            may_write: rights.may_write,
+                            may_admin: rights.may_admin,
+                            may_delete: rights.may_delete,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn from_stored(stored: Self::Stored) -> Self {
+        match stored {
+            StoredAddressBook::V1 {
+                id,
+                name,
+                is_subscribed,
+                owner,
+                share_with,
+            } => Self {
+                id,
+                name,
+                is_subscribed,
+                owner,
+                share_with: share_with
+                    .into_iter()
+                    .map(|(user, rights)| {
+                        (
+                            user,
+                            AddressBookRights {
+                                may_read: rights.may_read,
+                                may_write: rights.may_write,
+                                may_admin: rights.may_admin,
+                                may_delete: rights.may_delete,
+                            },
+                        )
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredContactCard {
+    V1 {
+        id: Uuid,
+        address_book_ids: Vec<Uuid>,
+        /// The card as JSON, which bincode can't hold as a value.
+        card: String,
+    },
+}
+
+impl Persisted for ContactCard {
+    type Stored = StoredContactCard;
+
+    fn to_stored(&self) -> Self::Stored {
+        StoredContactCard::V1 {
+            id: self.id,
+            address_book_ids: self.address_book_ids.iter().copied().collect(),
+            card: serde_json::to_string(&self.card).unwrap(),
+        }
+    }
+
+    fn from_stored(stored: Self::Stored) -> Self {
+        match stored {
+            StoredContactCard::V1 {
+                id,
+                address_book_ids,
+                card,
+            } => Self {
+                id,
+                address_book_ids: address_book_ids.into_iter().collect(),
+                card: serde_json::from_str(&card).unwrap(),
+            },
+        }
+    }
+}
+
+/// The layouts written before records were versioned, which are rewritten
+/// as the first versioned layout when the store is opened.
+pub mod unversioned {
+    use serde::Deserialize;
+    use uuid::Uuid;
+
+    use super::{StoredAccount, StoredUser};
+
+    #[derive(Deserialize)]
+    pub struct User {
+        id: Uuid,
+        username: String,
+        password: String,
+    }
+
+    impl From<User> for StoredUser {
+        fn from(user: User) -> Self {
+            Self::V1 {
+                id: user.id,
+                username: user.username,
+                password: user.password,
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct Account {
+        id: Uuid,
+        name: String,
+        is_personal: bool,
+        is_read_only: bool,
+    }
+
+    impl From<Account> for StoredAccount {
+        fn from(account: Account) -> Self {
+            Self::V1 {
+                id: account.id,
+                name: account.name,
+                is_personal: account.is_personal,
+                is_read_only: account.is_read_only,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    const ID: Uuid = Uuid::from_u128(0x0011_2233_4455_6677_8899_aabb_ccdd_eeff);
+    const OTHER_ID: Uuid = Uuid::from_u128(0xffee_ddcc_bbaa_9988_7766_5544_3322_1100);
+
+    /// Encodes the record as it's written to disk.
+    fn encode(stored: impl Serialize) -> Vec<u8> {
+        bincode::serde::encode_to_vec(stored, bincode::config::standard()).unwrap()
+    }
+
+    /// A uuid as bincode writes it, as 16 length-prefixed bytes.
+    fn uuid(id: Uuid) -> Vec<u8> {
+        [&[16][..], id.as_bytes()].concat()
+    }
+
+    /// A string as bincode writes it, length-prefixed.
+    fn string(value: &str) -> Vec<u8> {
+        [&[u8::try_from(value.len()).unwrap()][..], value.as_bytes()].concat()
+    }
+
+    // layouts are never changed once released, so these only ever change
+    // by adding a test for a new layout
+
+    #[test]
+    fn user_layout() {
+        let user = User {
+            id: ID,
+            username: "alice".to_string(),
+            password: "hash".to_string(),
+        };
+
+        assert_eq!(
+            encode(user.to_stored()),
+            [vec![0], uuid(ID), string("alice"), string("hash")].concat()
+        );
+    }
+
+    #[test]
+    fn account_layout() {
+        let account = Account {
+            id: ID,
+            name: "Home".to_string(),
+            is_personal: true,
+            is_read_only: false,
+        };
+
+        assert_eq!(
+            encode(account.to_stored()),
+            [vec![0], uuid(ID), string("Home"), vec![1, 0]].concat()
+        );
+    }
+
+    #[test]
+    fn address_book_layout() {
+        let address_book = AddressBook {
+            id: ID,
+            name: "Friends".to_string(),
+            is_subscribed: true,
+            owner: OTHER_ID,
+            share_with: HashMap::from([(
+                OTHER_ID,
+                AddressBookRights {
+                    may_read: true,
+                    may_write: false,
+                    may_admin: false,
+                    may_delete: true,
+                },
+            )]),
+        };
+
+        assert_eq!(
+            encode(address_book.to_stored()),
+            [
+                vec![0],
+                uuid(ID),
+                string("Friends"),
+                vec![1],
+                uuid(OTHER_ID),
+                vec![1],
+                uuid(OTHER_ID),
+                vec![1, 0, 0, 1],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn contact_card_layout() {
+        let mut card = serde_json::Map::new();
+        card.insert("uid".to_string(), "u".into());
+
+        let contact_card = ContactCard {
+            id: ID,
+            address_book_ids: HashSet::from([OTHER_ID]),
+            card,
+        };
+
+        assert_eq!(
+            encode(contact_card.to_stored()),
+            [
+                vec![0],
+                uuid(ID),
+                vec![1],
+                uuid(OTHER_ID),
+                string(r#"{"uid":"u"}"#)
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn unversioned_records_read_as_first_layout() {
+        // the unversioned layouts were plain structs, with no variant index
+        let user = [uuid(ID), string("alice"), string("hash")].concat();
+        let (user, _): (unversioned::User, _) =
+            bincode::serde::decode_from_slice(&user, bincode::config::standard()).unwrap();
+
+        assert_eq!(
+            encode(StoredUser::from(user)),
+            [vec![0], uuid(ID), string("alice"), string("hash")].concat()
+        );
+
+        let account = [uuid(ID), string("Home"), vec![1, 0]].concat();
+        let (account, _): (unversioned::Account, _) =
+            bincode::serde::decode_from_slice(&account, bincode::config::standard()).unwrap();
+
+        assert_eq!(
+            encode(StoredAccount::from(account)),
+            [vec![0], uuid(ID), string("Home"), vec![1, 0]].concat()
+        );
+    }
+}
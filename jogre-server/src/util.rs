@@ -12,6 +12,9 @@ type HmacSha3 = Hmac<Sha3_256>;
 
 const CSRF_TOKEN_COOKIE_NAME: &str = "csrf_token";
 
+/// A token protecting against cross-site request forgery, the signed half
+/// is kept in a cookie and the unsigned half is sent back by the page that
+/// was given it, either as a form field or in the `X-Jogre-CSRF` header.
 #[derive(Copy, Clone)]
 pub struct CsrfToken {
     signed: [u8; 32],
@@ -29,28 +32,31 @@ impl CsrfToken {
         Self { signed, unsigned }
     }
 
-    pub fn write_cookie(&self, cookies: &Cookies) {
+    /// Sets the signed half in a cookie, which is only sent over HTTPS if
+    /// `secure` is set.
+    pub fn write_cookie(&self, cookies: &Cookies, secure: bool) {
         cookies.add(
             CookieBuilder::new(CSRF_TOKEN_COOKIE_NAME, hex::encode(self.signed))
                 .http_only(true)
                 .max_age(Duration::hours(24))
                 .same_site(SameSite::Strict)
-                // .secure(true) // TODO
+                .secure(secure)
                 .finish(),
         );
     }
 
+    /// Checks the value sent back by the page was signed into the cookie.
     #[must_use]
-    pub fn verify(derived_keys: &DerivedKeys, cookies: &Cookies, form_value: &str) -> bool {
+    pub fn verify(derived_keys: &DerivedKeys, cookies: &Cookies, value: &str) -> bool {
         let Some(cookie) = cookies.get(CSRF_TOKEN_COOKIE_NAME) else {
             warn!("Missing CSRF token");
             return false;
         };
 
-        let form_value = match hex::decode(form_value) {
+        let value = match hex::decode(value) {
             Ok(v) => v,
             Err(error) => {
-                warn!(?error, "Invalid form CSRF token");
+                warn!(?error, "Invalid CSRF token");
                 return false;
             }
         };
@@ -64,17 +70,18 @@ impl CsrfToken {
         };
 
         let mut hmac = HmacSha3::new_from_slice(&derived_keys.csrf_hmac_key).unwrap();
-        hmac.update(&form_value);
+        hmac.update(&value);
 
         match hmac.verify_slice(&cookie_token) {
             Ok(()) => true,
             Err(error) => {
-                warn!(?error, "CSRF form value and cookie mismatch");
+                warn!(?error, "CSRF token and cookie mismatch");
                 false
             }
         }
     }
 
+    /// The value the page sends back, to be checked against the cookie.
     pub fn form_value(&self) -> String {
         hex::encode(self.unsigned.to_be_bytes())
     }